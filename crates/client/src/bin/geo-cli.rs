@@ -0,0 +1,246 @@
+use std::str::FromStr;
+
+use argh::FromArgs;
+use client::Client;
+use eyre::{eyre, WrapErr};
+use serde_json::{json, Value};
+use shared::{
+    data::{SourceId, Status},
+    track::Track,
+};
+use time::{format_description::well_known::Rfc3339, Duration, OffsetDateTime};
+use tokio::io::AsyncReadExt;
+
+#[derive(Debug, FromArgs)]
+#[argh(description = "Command-line client of the Geo Tracker HTTP API")]
+struct Opts {
+    /// base URL of the server
+    #[argh(option, default = "\"http://127.0.0.1:8000\".to_owned()")]
+    server: String,
+
+    /// API key to authenticate with, if the server requires one
+    #[argh(option)]
+    api_key: Option<String>,
+
+    #[argh(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand)]
+enum Command {
+    Latest(LatestCommand),
+    History(HistoryCommand),
+    Submit(SubmitCommand),
+    Watch(WatchCommand),
+    Stats(StatsCommand),
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "latest", description = "print the latest status of a source")]
+struct LatestCommand {
+    #[argh(positional)]
+    source_id: Id,
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "history", description = "print statuses of a source in a time range")]
+struct HistoryCommand {
+    #[argh(positional)]
+    source_id: Id,
+
+    /// start of the time range (inclusive), as a UNIX timestamp or RFC 3339
+    #[argh(option)]
+    from: Option<Timestamp>,
+
+    /// end of the time range (inclusive), as a UNIX timestamp or RFC 3339
+    #[argh(option)]
+    to: Option<Timestamp>,
+
+    /// output format: "json" (default) or "geojson"
+    #[argh(option, default = "Format::Json")]
+    format: Format,
+
+    /// seconds between statuses that split the GeoJSON track (default 600)
+    #[argh(option, default = "600")]
+    max_gap: i64,
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "submit", description = "submit statuses from a JSON file")]
+struct SubmitCommand {
+    /// file with a JSON array of statuses or one status per line. "-" reads
+    /// from standard input
+    #[argh(option)]
+    file: String,
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(
+    subcommand,
+    name = "watch",
+    description = "print statuses of a source as they arrive, one per line"
+)]
+struct WatchCommand {
+    #[argh(positional)]
+    source_id: Id,
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "stats", description = "print storage statistics")]
+struct StatsCommand {}
+
+#[derive(Debug, Clone, Copy)]
+struct Id(SourceId);
+
+impl FromStr for Id {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse::<SourceId>().map(Self).map_err(|e| e.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Timestamp(OffsetDateTime);
+
+impl FromStr for Timestamp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let ts = match s.parse::<i64>() {
+            Ok(unix) => OffsetDateTime::from_unix_timestamp(unix).map_err(|e| e.to_string())?,
+            Err(_) => OffsetDateTime::parse(s, &Rfc3339).map_err(|e| format!("{e}: {s}"))?,
+        };
+        Ok(Self(ts))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Format {
+    Json,
+    GeoJson,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Self::Json),
+            "geojson" => Ok(Self::GeoJson),
+            _ => Err(format!("unknown output format: {s}")),
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> eyre::Result<()> {
+    color_eyre::install()?;
+    let opts: Opts = argh::from_env();
+    let client = match &opts.api_key {
+        Some(key) => Client::new(&opts.server)?.with_api_key(key),
+        None => Client::new(&opts.server)?,
+    };
+
+    match opts.command {
+        Command::Latest(LatestCommand { source_id: Id(source_id) }) => {
+            let Some(status) = client.latest(source_id).await? else {
+                eprintln!("No statuses of {source_id} found");
+                std::process::exit(1);
+            };
+            println!("{}", serde_json::to_string_pretty(&status)?);
+        }
+        Command::History(cmd) => {
+            let (from, to) = (cmd.from.map(|ts| ts.0), cmd.to.map(|ts| ts.0));
+            let statuses = client.history(cmd.source_id.0, from, to).await?;
+            let output = match cmd.format {
+                Format::Json => serde_json::to_value(&statuses)?,
+                Format::GeoJson => geojson(&statuses, Duration::seconds(cmd.max_gap)),
+            };
+            println!("{}", serde_json::to_string_pretty(&output)?);
+        }
+        Command::Submit(SubmitCommand { file }) => {
+            let contents = match file.as_str() {
+                "-" => {
+                    let mut contents = String::new();
+                    tokio::io::stdin().read_to_string(&mut contents).await?;
+                    contents
+                }
+                path => tokio::fs::read_to_string(path)
+                    .await
+                    .wrap_err_with(|| eyre!("Failed to read {path}"))?,
+            };
+            let statuses = parse_statuses(&contents)?;
+            for (n, status) in statuses.iter().enumerate() {
+                client
+                    .submit(status)
+                    .await
+                    .wrap_err_with(|| eyre!("Failed to submit status #{}", n + 1))?;
+            }
+            eprintln!("Submitted {} statuses", statuses.len());
+        }
+        Command::Watch(WatchCommand { source_id: Id(source_id) }) => {
+            let mut watch = client.watch(source_id).await?;
+            while let Some(status) = watch.next().await? {
+                println!("{}", serde_json::to_string(&status)?);
+            }
+        }
+        Command::Stats(StatsCommand {}) => {
+            let stats = client.stats().await?;
+            println!("sources: {}\nstatuses: {}", stats.sources, stats.statuses);
+        }
+    }
+
+    Ok(())
+}
+
+/// Parse either a JSON array of statuses or a sequence of JSON statuses.
+fn parse_statuses(contents: &str) -> serde_json::Result<Vec<Status>> {
+    if contents.trim_start().starts_with('[') {
+        serde_json::from_str(contents)
+    } else {
+        serde_json::Deserializer::from_str(contents).into_iter().collect()
+    }
+}
+
+/// Convert a track into a GeoJSON feature collection with a `LineString` for
+/// each part of the track between gaps longer than `max_gap`, followed by a
+/// `Point` for each status with a position.
+fn geojson(statuses: &[Status], max_gap: Duration) -> Value {
+    let positioned = statuses.iter().filter(|s| s.position.is_some()).copied().collect::<Vec<_>>();
+
+    let mut features = Vec::new();
+    for part in Track::new(&positioned).split_on_gaps(max_gap) {
+        let part = part.statuses();
+        let (Some(first), Some(last)) = (part.first(), part.last()) else {
+            continue;
+        };
+        let coordinates =
+            part.iter().filter_map(|s| s.position).map(|p| json!([p.x, p.y])).collect::<Vec<_>>();
+        features.push(json!({
+            "type": "Feature",
+            "geometry": { "type": "LineString", "coordinates": coordinates },
+            "properties": {
+                "sourceId": first.source_id,
+                "from": first.timestamp.unix_timestamp(),
+                "to": last.timestamp.unix_timestamp(),
+            },
+        }));
+    }
+    for status in &positioned {
+        let Some(position) = status.position else {
+            continue;
+        };
+        let mut properties = serde_json::to_value(status).unwrap_or_default();
+        if let Some(properties) = properties.as_object_mut() {
+            properties.remove("position");
+        }
+        features.push(json!({
+            "type": "Feature",
+            "geometry": { "type": "Point", "coordinates": [position.x, position.y] },
+            "properties": properties,
+        }));
+    }
+    json!({ "type": "FeatureCollection", "features": features })
+}
@@ -0,0 +1,268 @@
+#![forbid(unsafe_code)]
+
+//! Typed client for the HTTP API of the `geo-track` server, meant to be
+//! embedded into other services as well as backing the `geo-cli` tool.
+//!
+//! Only plain HTTP is supported; TLS is expected to be terminated by a proxy in
+//! front of the server.
+
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::{body::Incoming, header, Method, Request, Response, StatusCode};
+use hyper_util::{
+    client::legacy::{connect::HttpConnector, Client as HttpClient},
+    rt::TokioExecutor,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use shared::data::{SourceId, Status};
+use thiserror::Error;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime, UtcOffset};
+
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error("invalid server URL: {url}")]
+    InvalidUrl { url: String },
+    #[error("HTTP request error")]
+    Request(#[from] hyper_util::client::legacy::Error),
+    #[error("HTTP response error")]
+    Response(#[from] hyper::Error),
+    #[error("invalid HTTP request")]
+    InvalidRequest(#[from] hyper::http::Error),
+    #[error("JSON error")]
+    Json(#[from] serde_json::Error),
+    #[error("server responded with {status}")]
+    Status { status: StatusCode },
+}
+
+pub type Result<T> = std::result::Result<T, ClientError>;
+
+/// Response header with the cursor of the next page of a listing.
+const NEXT_CURSOR: &str = "next-cursor";
+
+/// Largest page size the server allows.
+const PAGE_SIZE: usize = 1000;
+
+/// Storage statistics reported by the server.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Stats {
+    pub sources: usize,
+    pub statuses: usize,
+}
+
+/// Request body of `POST /query/latest`.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct LatestManyQuery<'a> {
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    source_ids: &'a [SourceId],
+    all: bool,
+}
+
+/// Client of a single server. Cheap to clone, with clones sharing a connection
+/// pool.
+#[derive(Clone)]
+pub struct Client {
+    /// Base URL without a trailing slash, e.g. `http://127.0.0.1:8000`.
+    base: String,
+    http: HttpClient<HttpConnector, Full<Bytes>>,
+    /// Value of the `Authorization` header, if any.
+    authorization: Option<String>,
+}
+
+impl Client {
+    /// Create a client for the server at `base_url`, e.g.
+    /// `http://127.0.0.1:8000`.
+    pub fn new(base_url: &str) -> Result<Self> {
+        let base = base_url.trim_end_matches('/');
+        match base.parse::<hyper::Uri>() {
+            Ok(uri) if uri.scheme_str() == Some("http") && uri.authority().is_some() => {}
+            _ => return Err(ClientError::InvalidUrl { url: base_url.to_owned() }),
+        }
+        let http = HttpClient::builder(TokioExecutor::new()).build_http();
+        Ok(Self { base: base.to_owned(), http, authorization: None })
+    }
+
+    /// Authenticate all requests with an API key, which scopes them to the
+    /// tenant the key belongs to. Required by servers that have API keys
+    /// configured.
+    pub fn with_api_key(mut self, key: &str) -> Self {
+        self.authorization = Some(format!("Bearer {key}"));
+        self
+    }
+
+    /// Submit a single status, returning once it's been persisted.
+    pub async fn submit(&self, status: &Status) -> Result<()> {
+        let response =
+            self.send(Method::POST, "/status", Some(serde_json::to_vec(status)?)).await?;
+        check(&response)?;
+        Ok(())
+    }
+
+    /// Latest status of a source, if any.
+    pub async fn latest(&self, source_id: SourceId) -> Result<Option<Status>> {
+        let response =
+            self.send(Method::GET, &format!("/status?source_id={source_id}"), None).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        read_json(response).await.map(Some)
+    }
+
+    /// Latest statuses of the given sources, or of all sources if `None`.
+    pub async fn latest_many(&self, source_ids: Option<&[SourceId]>) -> Result<Vec<Status>> {
+        let query =
+            LatestManyQuery { source_ids: source_ids.unwrap_or(&[]), all: source_ids.is_none() };
+        let body = serde_json::to_vec(&query)?;
+        read_json(self.send(Method::POST, "/query/latest", Some(body)).await?).await
+    }
+
+    /// Statuses of a source within an inclusive time range, unbounded on
+    /// either side if `None`. Walks all pages of the history.
+    pub async fn history(
+        &self,
+        source_id: SourceId,
+        from: Option<OffsetDateTime>,
+        to: Option<OffsetDateTime>,
+    ) -> Result<Vec<Status>> {
+        let mut path = format!("/status/{source_id}/history?limit={PAGE_SIZE}");
+        for (name, ts) in [("from", from), ("to", to)] {
+            if let Some(ts) = ts {
+                path.push_str(&format!("&{name}={}", query_timestamp(ts)));
+            }
+        }
+        self.read_pages(&path).await
+    }
+
+    /// Collects all pages of a paginated listing.
+    async fn read_pages<T: DeserializeOwned>(&self, path: &str) -> Result<Vec<T>> {
+        let mut items = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page_path = match &cursor {
+                Some(cursor) => format!("{path}&cursor={cursor}"),
+                None => path.to_owned(),
+            };
+            let response = self.send(Method::GET, &page_path, None).await?;
+            cursor = response
+                .headers()
+                .get(NEXT_CURSOR)
+                .and_then(|cursor| cursor.to_str().ok())
+                .map(str::to_owned);
+            items.extend(read_json::<Vec<T>>(response).await?);
+            if cursor.is_none() {
+                return Ok(items);
+            }
+        }
+    }
+
+    pub async fn stats(&self) -> Result<Stats> {
+        read_json(self.send(Method::GET, "/stats", None).await?).await
+    }
+
+    /// Follow statuses of a source as the server receives them.
+    pub async fn watch(&self, source_id: SourceId) -> Result<Watch> {
+        let response = self.send(Method::GET, &format!("/status/{source_id}/watch"), None).await?;
+        check(&response)?;
+        Ok(Watch { body: response.into_body(), buf: Vec::new() })
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        json: Option<Vec<u8>>,
+    ) -> Result<Response<Incoming>> {
+        let mut request = Request::builder().method(method).uri(format!("{}{path}", self.base));
+        if json.is_some() {
+            request = request.header(header::CONTENT_TYPE, "application/json");
+        }
+        if let Some(authorization) = &self.authorization {
+            request = request.header(header::AUTHORIZATION, authorization);
+        }
+        let request = request.body(Full::new(Bytes::from(json.unwrap_or_default())))?;
+        Ok(self.http.request(request).await?)
+    }
+}
+
+fn check(response: &Response<Incoming>) -> Result<()> {
+    match response.status() {
+        status if status.is_success() => Ok(()),
+        status => Err(ClientError::Status { status }),
+    }
+}
+
+async fn read_json<T: DeserializeOwned>(response: Response<Incoming>) -> Result<T> {
+    check(&response)?;
+    let body = response.into_body().collect().await?.to_bytes();
+    Ok(serde_json::from_slice(&body)?)
+}
+
+/// Stream of statuses returned by [`Client::watch`].
+pub struct Watch {
+    body: Incoming,
+    /// Received bytes not yet parsed into events.
+    buf: Vec<u8>,
+}
+
+impl Watch {
+    /// Wait for the next status. Returns `None` once the server ends the
+    /// stream.
+    pub async fn next(&mut self) -> Result<Option<Status>> {
+        loop {
+            while let Some(event) = take_event(&mut self.buf) {
+                // Events without data are keep-alive comments.
+                if !event.is_empty() {
+                    return Ok(Some(serde_json::from_str(&event)?));
+                }
+            }
+            match self.body.frame().await.transpose()? {
+                Some(frame) => {
+                    if let Ok(data) = frame.into_data() {
+                        self.buf.extend_from_slice(&data);
+                    }
+                }
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+/// Take the next complete server-sent event off the buffer and return its
+/// data.
+fn take_event(buf: &mut Vec<u8>) -> Option<String> {
+    let end = buf.windows(2).position(|w| w == b"\n\n")?;
+    let event = buf.drain(..end + 2).collect::<Vec<_>>();
+    let data = String::from_utf8_lossy(&event)
+        .lines()
+        .filter_map(|line| line.trim_end_matches('\r').strip_prefix("data:"))
+        .map(|data| data.strip_prefix(' ').unwrap_or(data))
+        .collect::<Vec<_>>()
+        .join("\n");
+    Some(data)
+}
+
+/// Formats a query bound as UNIX seconds, or in RFC 3339 if it has a
+/// sub-second part.
+fn query_timestamp(ts: OffsetDateTime) -> String {
+    match ts.nanosecond() {
+        0 => ts.unix_timestamp().to_string(),
+        _ => ts
+            .to_offset(UtcOffset::UTC)
+            .format(&Rfc3339)
+            .unwrap_or_else(|_| ts.unix_timestamp().to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::take_event;
+
+    #[test]
+    fn parse_events() {
+        let mut buf = b":\n\ndata: {\"a\":\ndata:1}\n\ndata: 2".to_vec();
+        assert_eq!(take_event(&mut buf).as_deref(), Some(""));
+        assert_eq!(take_event(&mut buf).as_deref(), Some("{\"a\":\n1}"));
+        assert_eq!(take_event(&mut buf), None);
+        assert_eq!(buf, b"data: 2");
+    }
+}
@@ -0,0 +1,103 @@
+//! Compares the at-rest size of a 10 Hz track stored as CBOR, as compact
+//! records (as in sled) and in archive partitions.
+//!
+//! Run with `cargo bench -p server --features archive --bench storage_size`.
+
+use std::{error::Error, fs, path::Path, time::Duration};
+
+use geo_types::Coord;
+use server::storage::{
+    self,
+    archive::{Archive, ArchiveConfig},
+    codec, DupeStrategy, Storage, StorageConfig,
+};
+use shared::data::{SourceId, Status, TenantId};
+use time::OffsetDateTime;
+use uom::si::{
+    angle::radian,
+    f64::{Angle, Velocity},
+    velocity::meter_per_second,
+};
+
+/// One hour of fixes at 10 Hz.
+const FIXES: usize = 36_000;
+
+fn track() -> Vec<Status> {
+    let source_id: SourceId =
+        serde_json::from_str("\"0aaec05a-0e7d-4fd5-abc0-0ba69e3cfe11\"").unwrap();
+    // Old enough to be archived.
+    let start = (OffsetDateTime::now_utc() - Duration::from_secs(3 * 86_400)).date();
+    let start = start.midnight().assume_utc();
+
+    let mut position = Coord { x: 24.745_278, y: 59.437_222 };
+    (0..FIXES)
+        .map(|i| {
+            let bearing = (i as f64 / 600.).sin() + 1.;
+            let speed = 12. + (i as f64 / 97.).cos() * 3.;
+            // Roughly 1e-5 degrees per meter.
+            position.x += bearing.sin() * speed * 0.1 * 1.8e-5;
+            position.y += bearing.cos() * speed * 0.1 * 0.9e-5;
+            let timestamp = start + Duration::from_millis(i as u64 * 100);
+            Status {
+                source_id,
+                timestamp,
+                position: Some(position),
+                bearing: Some(Angle::new::<radian>(bearing)),
+                speed: Some(Velocity::new::<meter_per_second>(speed)),
+                satellites: None,
+                fix: None,
+                odometer: None,
+                ignition: None,
+                received_at: Some(timestamp + Duration::from_millis(1_250)),
+                suspect_timestamp: false,
+                tenant_id: TenantId::DEFAULT,
+            }
+        })
+        .collect()
+}
+
+fn dir_size(dir: &Path) -> std::io::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        size += match entry.file_type()?.is_dir() {
+            true => dir_size(&entry.path())?,
+            false => entry.metadata()?.len(),
+        };
+    }
+    Ok(size)
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let track = track();
+
+    let mut cbor = 0;
+    for status in &track {
+        let mut bytes = Vec::new();
+        ciborium::ser::into_writer(status, &mut bytes)?;
+        cbor += bytes.len();
+    }
+    let compact: usize = track.iter().map(|status| codec::encode(status).len()).sum();
+
+    let dir = std::env::temp_dir().join(format!("geo-track-bench-{}", std::process::id()));
+    let archive = Archive::open(ArchiveConfig { dir: dir.clone(), after: Duration::from_secs(1) })?;
+    let engine =
+        storage::init(&"memory".parse::<StorageConfig>()?, DupeStrategy::Drop.into(), None)?;
+    for status in &track {
+        engine.persist_status(*status).await?;
+    }
+    archive.roll(&engine).await?;
+    let parquet = dir_size(&dir)?;
+    fs::remove_dir_all(&dir)?;
+
+    println!("{FIXES} statuses at 10 Hz:");
+    for (name, size) in [("CBOR", cbor as u64), ("compact", compact as u64), ("archive", parquet)] {
+        println!(
+            "{name:>8}: {size:>9} bytes, {:>6.1} bytes/status, {:>5.1}% of CBOR",
+            size as f64 / FIXES as f64,
+            size as f64 / cbor as f64 * 100.,
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,155 @@
+//! Authentication of API clients and devices, mapping their API keys to the
+//! tenants they act on behalf of.
+
+use std::{collections::HashMap, fmt::Debug, str::FromStr};
+
+use shared::data::TenantId;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum AuthError {
+    #[error("invalid API key specification; expected <tenant-id>:<key>")]
+    InvalidApiKey,
+    #[error("invalid admin key; it can't be empty or contain whitespace")]
+    InvalidAdminKey,
+    #[error("API key required")]
+    MissingKey,
+    #[error("unknown API key")]
+    UnknownKey,
+}
+
+pub type Result<T> = std::result::Result<T, AuthError>;
+
+/// A single API key along with the tenant it belongs to.
+#[derive(Clone)]
+pub struct ApiKey {
+    pub tenant_id: TenantId,
+    pub key: String,
+}
+
+/// Parses `<tenant-id>:<key>`, where the tenant ID is a UUID.
+impl FromStr for ApiKey {
+    type Err = AuthError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (tenant_id, key) = s.split_once(':').ok_or(AuthError::InvalidApiKey)?;
+        let tenant_id = tenant_id.parse().map_err(|_| AuthError::InvalidApiKey)?;
+        if key.is_empty() || key.chars().any(char::is_whitespace) {
+            return Err(AuthError::InvalidApiKey);
+        }
+        Ok(Self { tenant_id, key: key.to_owned() })
+    }
+}
+
+// Implemented manually to keep the key out of logs.
+impl Debug for ApiKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ApiKey").field("tenant_id", &self.tenant_id).finish_non_exhaustive()
+    }
+}
+
+/// All known API keys. If there are none, authentication is disabled, and
+/// everybody acts on behalf of [`TenantId::DEFAULT`].
+#[derive(Clone, Default)]
+pub struct ApiKeys {
+    tenants: HashMap<String, TenantId>,
+}
+
+impl ApiKeys {
+    pub fn new(keys: impl IntoIterator<Item = ApiKey>) -> Self {
+        Self { tenants: keys.into_iter().map(|k| (k.key, k.tenant_id)).collect() }
+    }
+
+    /// Whether clients have to present an API key.
+    pub fn is_enabled(&self) -> bool {
+        !self.tenants.is_empty()
+    }
+
+    /// Resolve the tenant of a client presenting `key`, if any.
+    pub fn authenticate(&self, key: Option<&str>) -> Result<TenantId> {
+        if !self.is_enabled() {
+            return Ok(TenantId::DEFAULT);
+        }
+        let key = key.ok_or(AuthError::MissingKey)?;
+        self.tenants.get(key).copied().ok_or(AuthError::UnknownKey)
+    }
+}
+
+// Implemented manually to keep the keys out of logs.
+impl Debug for ApiKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ApiKeys").field("count", &self.tenants.len()).finish()
+    }
+}
+
+/// Key of clients of the admin API, which isn't scoped to a tenant. It's
+/// separate from the API keys of tenants, which don't grant admin access, not
+/// even those of [`TenantId::DEFAULT`].
+#[derive(Clone)]
+pub struct AdminKey(String);
+
+impl FromStr for AdminKey {
+    type Err = AuthError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.is_empty() || s.chars().any(char::is_whitespace) {
+            return Err(AuthError::InvalidAdminKey);
+        }
+        Ok(Self(s.to_owned()))
+    }
+}
+
+impl AdminKey {
+    /// Check the key presented by a client, if any.
+    pub fn verify(&self, key: Option<&str>) -> Result<()> {
+        let key = key.ok_or(AuthError::MissingKey)?;
+        // Compares all bytes, so that the time taken doesn't tell how much of
+        // the key was guessed right.
+        let matches = key.len() == self.0.len()
+            && key.bytes().zip(self.0.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0;
+        matches.then_some(()).ok_or(AuthError::UnknownKey)
+    }
+}
+
+// Implemented manually to keep the key out of logs.
+impl Debug for AdminKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AdminKey").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::data::TenantId;
+
+    use super::{AdminKey, ApiKey, ApiKeys, AuthError};
+
+    #[test]
+    fn authenticate() {
+        assert!(matches!(ApiKeys::default().authenticate(None), Ok(TenantId::DEFAULT)));
+
+        let key: ApiKey = "0aaec05a-0e7d-4fd5-abc0-0ba69e3cfe11:s3cret".parse().unwrap();
+        assert_eq!(key.tenant_id.to_string(), "0aaec05a-0e7d-4fd5-abc0-0ba69e3cfe11");
+        assert!("s3cret".parse::<ApiKey>().is_err());
+        assert!("tenant:s3cret".parse::<ApiKey>().is_err());
+        assert!("0aaec05a-0e7d-4fd5-abc0-0ba69e3cfe11:".parse::<ApiKey>().is_err());
+
+        let keys = ApiKeys::new([key.clone()]);
+        assert_eq!(keys.authenticate(Some("s3cret")).unwrap(), key.tenant_id);
+        assert!(matches!(keys.authenticate(None), Err(AuthError::MissingKey)));
+        assert!(matches!(keys.authenticate(Some("guess")), Err(AuthError::UnknownKey)));
+    }
+
+    #[test]
+    fn admin_key() {
+        assert!("".parse::<AdminKey>().is_err());
+        assert!("s3 cret".parse::<AdminKey>().is_err());
+
+        let key: AdminKey = "s3cret".parse().unwrap();
+        assert!(key.verify(Some("s3cret")).is_ok());
+        assert!(matches!(key.verify(None), Err(AuthError::MissingKey)));
+        assert!(matches!(key.verify(Some("s3cre")), Err(AuthError::UnknownKey)));
+        assert!(matches!(key.verify(Some("s3creT")), Err(AuthError::UnknownKey)));
+        assert_eq!(format!("{key:?}"), "AdminKey { .. }");
+    }
+}
@@ -0,0 +1,335 @@
+use std::{
+    f64::consts::TAU,
+    fs,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use argh::FromArgs;
+use eyre::{bail, eyre, WrapErr};
+use geo_types::Coord;
+use server::sender::{Sender, Transport};
+use shared::data::{SourceId, Status, TenantId};
+use time::OffsetDateTime;
+use tokio::{
+    task::JoinSet,
+    time::{interval, Instant, MissedTickBehavior},
+};
+use uom::si::{
+    angle::radian,
+    f64::{Angle, Velocity},
+    velocity::meter_per_second,
+};
+use uuid::Builder;
+
+/// Approximate length of one degree of latitude, in meters.
+const METERS_PER_DEGREE: f64 = 111_320.0;
+
+#[derive(Debug, FromArgs)]
+#[argh(description = "Generate load against a running Geo Tracker server")]
+struct Opts {
+    /// number of simulated sources
+    #[argh(option, default = "100")]
+    sources: usize,
+
+    /// total number of statuses sent per second
+    #[argh(option, default = "100.0")]
+    rate: f64,
+
+    /// how long to run for, e.g. "1m". runs until interrupted if not specified
+    #[argh(option)]
+    duration: Option<humantime::Duration>,
+
+    /// protocol to send statuses over: "tcp" (default), "udp" or "http"
+    #[argh(option, default = "Transport::default()")]
+    transport: Transport,
+
+    /// server address as "host:port". defaults to the server's default
+    /// address for the chosen transport
+    #[argh(option)]
+    target: Option<String>,
+
+    /// API key to authenticate with, if the server requires one
+    #[argh(option)]
+    api_key: Option<String>,
+
+    /// number of connections to send statuses over in parallel, each serving
+    /// an equal share of sources
+    #[argh(option, default = "1")]
+    connections: usize,
+
+    /// file with a route for sources to follow, one "lon,lat" point per line.
+    /// sources start evenly spread along the route and loop over it. if not
+    /// specified, sources move randomly around --center
+    #[argh(option)]
+    route: Option<PathBuf>,
+
+    /// starting point of randomly moving sources, as "lon,lat"
+    #[argh(option, default = "Point(Coord { x: 24.745, y: 59.437 })")]
+    center: Point,
+
+    /// movement speed of sources, in m/s
+    #[argh(option, default = "15.0")]
+    speed: f64,
+
+    /// seed for source IDs and random movement
+    #[argh(option, default = "1")]
+    seed: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Point(Coord<f64>);
+
+impl std::str::FromStr for Point {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (x, y) = s.split_once(',').ok_or_else(|| format!("expected lon,lat: {s}"))?;
+        let coord = |v: &str| v.trim().parse::<f64>().map_err(|e| format!("{e}: {v}"));
+        Ok(Self(Coord { x: coord(x)?, y: coord(y)? }))
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    sent: AtomicU64,
+    failed: AtomicU64,
+}
+
+#[tokio::main]
+async fn main() -> eyre::Result<()> {
+    color_eyre::install()?;
+    let opts: Opts = argh::from_env();
+    if opts.sources == 0 || opts.connections == 0 {
+        bail!("Need at least one source and connection");
+    }
+    if !(opts.rate.is_finite() && opts.rate > 0.0) {
+        bail!("Rate must be positive");
+    }
+
+    let route = match &opts.route {
+        Some(path) => {
+            let route = Route::load(path)
+                .wrap_err_with(|| eyre!("Failed to load route from {}", path.display()))?;
+            Some(Arc::new(route))
+        }
+        None => None,
+    };
+
+    // Separate generators keep source IDs the same regardless of movement.
+    let mut ids = Rng(opts.seed.max(1));
+    let mut rng = Rng(ids.next());
+    let mut sources = (0..opts.sources)
+        .map(|i| {
+            let id = SourceId::from_uuid(Builder::from_random_bytes(ids.bytes()).into_uuid());
+            let movement = match &route {
+                Some(route) => Movement::Route {
+                    route: route.clone(),
+                    distance: route.length * i as f64 / opts.sources as f64,
+                },
+                None => Movement::RandomWalk { rng: Rng(rng.next()), bearing: rng.uniform() * TAU },
+            };
+            Source { id, position: opts.center.0, bearing: 0.0, movement }
+        })
+        .collect::<Vec<_>>();
+
+    let target = opts.target.as_deref().unwrap_or(opts.transport.default_target());
+    let counters = Arc::new(Counters::default());
+    let started = Instant::now();
+    let deadline = opts.duration.map(|d| started + d.into());
+    // Each connection sends its share of the total rate.
+    let connections = opts.connections.min(opts.sources);
+    let period = Duration::from_secs_f64(connections as f64 / opts.rate);
+    let speed = opts.speed;
+
+    let mut tasks = JoinSet::new();
+    for n in (1..=connections).rev() {
+        let mut sources = sources.split_off(sources.len() * (n - 1) / n);
+        let mut sender = Sender::connect(opts.transport, target, opts.api_key.as_deref())
+            .await
+            .wrap_err_with(|| eyre!("Failed to connect to {target} over {}", opts.transport))?;
+        let counters = counters.clone();
+        tasks.spawn(async move {
+            let mut ticks = interval(period);
+            ticks.set_missed_tick_behavior(MissedTickBehavior::Burst);
+            // Sources take turns, so each one moves by this much time per turn.
+            let step = period.mul_f64(sources.len() as f64);
+            for i in (0..sources.len()).cycle() {
+                let now = ticks.tick().await;
+                if deadline.is_some_and(|deadline| now >= deadline) {
+                    break;
+                }
+                let status = sources[i].advance(step, speed);
+                match sender.send(&status).await {
+                    Ok(()) => counters.sent.fetch_add(1, Ordering::Relaxed),
+                    Err(_) => counters.failed.fetch_add(1, Ordering::Relaxed),
+                };
+            }
+        });
+    }
+
+    let interrupted = tokio::signal::ctrl_c();
+    tokio::pin!(interrupted);
+    let mut report = interval(Duration::from_secs(1));
+    report.tick().await;
+    let (mut last_sent, mut last_failed) = (0, 0);
+    loop {
+        tokio::select! {
+            _ = report.tick() => {
+                let sent = counters.sent.load(Ordering::Relaxed);
+                let failed = counters.failed.load(Ordering::Relaxed);
+                println!(
+                    "{:>6.1}s: {} sent/s, {} failed/s",
+                    started.elapsed().as_secs_f64(),
+                    sent - last_sent,
+                    failed - last_failed,
+                );
+                (last_sent, last_failed) = (sent, failed);
+            }
+            task = tasks.join_next() => match task {
+                Some(result) => result?,
+                None => break,
+            },
+            _ = &mut interrupted => break,
+        }
+    }
+
+    let elapsed = started.elapsed().as_secs_f64();
+    let sent = counters.sent.load(Ordering::Relaxed);
+    let failed = counters.failed.load(Ordering::Relaxed);
+    let total = (sent + failed).max(1) as f64;
+    println!(
+        "Sent {sent} statuses in {elapsed:.1}s ({:.1}/s), {failed} failed ({:.2}%)",
+        sent as f64 / elapsed,
+        failed as f64 / total * 100.0,
+    );
+    Ok(())
+}
+
+struct Source {
+    id: SourceId,
+    position: Coord<f64>,
+    /// Radians from North, clockwise.
+    bearing: f64,
+    movement: Movement,
+}
+
+enum Movement {
+    RandomWalk { rng: Rng, bearing: f64 },
+    Route { route: Arc<Route>, distance: f64 },
+}
+
+impl Source {
+    /// Move for `elapsed` time at `speed` m/s and report the new status.
+    fn advance(&mut self, elapsed: Duration, speed: f64) -> Status {
+        let distance = speed * elapsed.as_secs_f64();
+        match &mut self.movement {
+            Movement::RandomWalk { rng, bearing } => {
+                // Veer up to ~20° either way.
+                *bearing = (*bearing + (rng.uniform() - 0.5) * 0.7).rem_euclid(TAU);
+                self.position = offset(self.position, *bearing, distance);
+                self.bearing = *bearing;
+            }
+            Movement::Route { route, distance: along } => {
+                *along = (*along + distance) % route.length.max(f64::MIN_POSITIVE);
+                (self.position, self.bearing) = route.locate(*along);
+            }
+        }
+        Status {
+            source_id: self.id,
+            timestamp: OffsetDateTime::now_utc(),
+            position: Some(self.position),
+            bearing: Some(Angle::new::<radian>(self.bearing)),
+            speed: Some(Velocity::new::<meter_per_second>(speed)),
+            satellites: None,
+            fix: None,
+            odometer: None,
+            ignition: None,
+            received_at: None,
+            suspect_timestamp: false,
+            tenant_id: TenantId::DEFAULT,
+        }
+    }
+}
+
+/// Closed polyline, with cumulative distances (in meters) to each point.
+struct Route {
+    points: Vec<Coord<f64>>,
+    distances: Vec<f64>,
+    length: f64,
+}
+
+impl Route {
+    fn load(path: &PathBuf) -> eyre::Result<Self> {
+        let mut points = fs::read_to_string(path)?
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| Ok(line.parse::<Point>().map_err(|e| eyre!(e))?.0))
+            .collect::<eyre::Result<Vec<_>>>()?;
+        let Some(&first) = points.first() else { bail!("Route is empty") };
+        points.push(first);
+
+        let mut distances = vec![0.0];
+        for pair in points.windows(2) {
+            distances.push(distances[distances.len() - 1] + meters(pair[0], pair[1]));
+        }
+        let length = distances[distances.len() - 1];
+        Ok(Self { points, distances, length })
+    }
+
+    /// Position and bearing at the given distance along the route.
+    fn locate(&self, distance: f64) -> (Coord<f64>, f64) {
+        let i = self.distances.partition_point(|&d| d <= distance).clamp(1, self.points.len() - 1);
+        let (from, to) = (self.points[i - 1], self.points[i]);
+        let span = self.distances[i] - self.distances[i - 1];
+        let t = if span > 0.0 { (distance - self.distances[i - 1]) / span } else { 0.0 };
+        let position = Coord { x: from.x + (to.x - from.x) * t, y: from.y + (to.y - from.y) * t };
+        let bearing =
+            ((to.x - from.x) * from.y.to_radians().cos()).atan2(to.y - from.y).rem_euclid(TAU);
+        (position, bearing)
+    }
+}
+
+/// Move `distance` meters from `from` in the direction of `bearing`, using an
+/// equirectangular approximation that's good enough for short distances.
+fn offset(from: Coord<f64>, bearing: f64, distance: f64) -> Coord<f64> {
+    let y = (from.y + distance * bearing.cos() / METERS_PER_DEGREE).clamp(-89.0, 89.0);
+    let x = from.x + distance * bearing.sin() / (METERS_PER_DEGREE * from.y.to_radians().cos());
+    Coord { x: (x + 180.0).rem_euclid(360.0) - 180.0, y }
+}
+
+/// Approximate distance between two points, in meters.
+fn meters(a: Coord<f64>, b: Coord<f64>) -> f64 {
+    let dx = (b.x - a.x) * ((a.y + b.y) / 2.0).to_radians().cos();
+    let dy = b.y - a.y;
+    dx.hypot(dy) * METERS_PER_DEGREE
+}
+
+/// Xorshift pseudo-random number generator. Not suitable for anything but
+/// simulations.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Uniformly distributed number in `[0, 1)`.
+    fn uniform(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn bytes(&mut self) -> [u8; 16] {
+        let mut bytes = [0; 16];
+        bytes[..8].copy_from_slice(&self.next().to_le_bytes());
+        bytes[8..].copy_from_slice(&self.next().to_le_bytes());
+        bytes
+    }
+}
@@ -0,0 +1,247 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use argh::FromArgs;
+use eyre::{bail, eyre, WrapErr};
+use geo_types::Coord;
+use server::sender::{Sender, Transport};
+use shared::data::{SourceId, Status, TenantId};
+use time::OffsetDateTime;
+use tokio::time::{sleep_until, Instant};
+use uom::si::{
+    angle::radian,
+    f64::{Angle, Velocity},
+    velocity::meter_per_second,
+};
+use uuid::Uuid;
+
+#[derive(Debug, FromArgs)]
+#[argh(description = "Replay recorded statuses against a running Geo Tracker server")]
+struct Opts {
+    /// file with recorded statuses, in the order to send them in
+    #[argh(positional)]
+    file: PathBuf,
+
+    /// format of the file: "cbor" (a stream of CBOR-encoded statuses),
+    /// "ndjson" (one JSON status per line) or "csv" (with columns
+    /// source_id,timestamp,lon,lat,bearing,speed, the last four optional,
+    /// timestamp in possibly fractional UNIX seconds, bearing in radians and
+    /// speed in m/s, as exported by the server). guessed from the file extension
+    /// if not specified
+    #[argh(option)]
+    format: Option<RecordFormat>,
+
+    /// protocol to send statuses over: "tcp" (default), "udp" or "http"
+    #[argh(option, default = "Transport::default()")]
+    transport: Transport,
+
+    /// server address as "host:port". defaults to the server's default
+    /// address for the chosen transport
+    #[argh(option)]
+    target: Option<String>,
+
+    /// API key to authenticate with, if the server requires one
+    #[argh(option)]
+    api_key: Option<String>,
+
+    /// playback speed relative to the recorded timing, e.g. 10 to replay ten
+    /// times faster. 0 sends everything as fast as possible. defaults to 1
+    #[argh(option, default = "1.0")]
+    speed: f64,
+
+    /// longest pause between two statuses, e.g. "5s", to skip over gaps in
+    /// the recording. measured in recorded time
+    #[argh(option)]
+    max_gap: Option<humantime::Duration>,
+
+    /// shift timestamps so that the recording starts at the current time
+    #[argh(switch)]
+    now: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum RecordFormat {
+    Cbor,
+    Ndjson,
+    Csv,
+}
+
+impl RecordFormat {
+    fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "cbor" => Some(Self::Cbor),
+            "ndjson" | "jsonl" | "json" => Some(Self::Ndjson),
+            "csv" => Some(Self::Csv),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for RecordFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cbor" => Ok(Self::Cbor),
+            "ndjson" => Ok(Self::Ndjson),
+            "csv" => Ok(Self::Csv),
+            _ => Err(format!("unknown record format: {s}")),
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> eyre::Result<()> {
+    color_eyre::install()?;
+    let opts: Opts = argh::from_env();
+    if opts.speed.is_nan() || opts.speed < 0.0 {
+        bail!("Speed must not be negative");
+    }
+
+    let format = opts
+        .format
+        .or_else(|| RecordFormat::from_path(&opts.file))
+        .ok_or_else(|| eyre!("Can't tell the format of {}, use --format", opts.file.display()))?;
+    let file =
+        File::open(&opts.file).wrap_err_with(|| eyre!("Failed to open {}", opts.file.display()))?;
+    let records = read_records(BufReader::new(file), format);
+
+    let target = opts.target.as_deref().unwrap_or(opts.transport.default_target());
+    let mut sender = Sender::connect(opts.transport, target, opts.api_key.as_deref())
+        .await
+        .wrap_err_with(|| eyre!("Failed to connect to {target} over {}", opts.transport))?;
+
+    let started = Instant::now();
+    let max_gap: Option<Duration> = opts.max_gap.map(Into::into);
+    // Recorded time of the previous status and how far into the replay it was.
+    let mut previous: Option<(OffsetDateTime, Duration)> = None;
+    let mut shift = time::Duration::ZERO;
+    let (mut sent, mut failed) = (0, 0);
+
+    for (line, record) in records.enumerate() {
+        let mut status = record.wrap_err_with(|| eyre!("Invalid record #{}", line + 1))?;
+
+        let offset = match previous {
+            None => {
+                if opts.now {
+                    shift = OffsetDateTime::now_utc() - status.timestamp;
+                }
+                Duration::ZERO
+            }
+            Some((timestamp, offset)) => {
+                let gap = Duration::try_from(status.timestamp - timestamp).unwrap_or_default();
+                offset + max_gap.map_or(gap, |max| gap.min(max))
+            }
+        };
+        previous = Some((status.timestamp, offset));
+        if opts.speed > 0.0 {
+            sleep_until(started + offset.div_f64(opts.speed)).await;
+        }
+
+        status.timestamp += shift;
+        match sender.send(&status).await {
+            Ok(()) => sent += 1,
+            Err(err) => {
+                eprintln!("Failed to send status #{}: {err}", line + 1);
+                failed += 1;
+            }
+        }
+    }
+
+    println!("Sent {sent} statuses in {:.1?} ({failed} failed)", started.elapsed());
+    Ok(())
+}
+
+/// Iterate over the statuses stored in a file.
+fn read_records(
+    reader: impl BufRead + 'static,
+    format: RecordFormat,
+) -> Box<dyn Iterator<Item = eyre::Result<Status>>> {
+    match format {
+        RecordFormat::Cbor => Box::new(CborRecords(reader)),
+        RecordFormat::Ndjson => Box::new(
+            reader
+                .lines()
+                .filter(|line| !line.as_ref().is_ok_and(|l| l.trim().is_empty()))
+                .map(|line| Ok(serde_json::from_str(&line?)?)),
+        ),
+        RecordFormat::Csv => Box::new(
+            reader
+                .lines()
+                .enumerate()
+                // Skip the header row, if any.
+                .filter(|(n, line)| {
+                    !line.as_ref().is_ok_and(|l| l.trim().is_empty() || *n == 0 && is_header(l))
+                })
+                .map(|(_, line)| parse_csv(&line?)),
+        ),
+    }
+}
+
+struct CborRecords<R>(R);
+
+impl<R: BufRead> Iterator for CborRecords<R> {
+    type Item = eyre::Result<Status>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.0.fill_buf() {
+            Ok([]) => None,
+            Ok(_) => Some(ciborium::de::from_reader(&mut self.0).map_err(Into::into)),
+            Err(err) => Some(Err(err.into())),
+        }
+    }
+}
+
+fn is_header(line: &str) -> bool {
+    line.split(',').next().is_some_and(|field| field.trim() == "source_id")
+}
+
+/// Parses UNIX seconds with an optional fractional part, e.g. `1627364719.25`.
+fn parse_seconds(s: &str) -> eyre::Result<OffsetDateTime> {
+    let (secs, frac) = s.split_once('.').unwrap_or((s, ""));
+    if frac.len() > 9 || !frac.bytes().all(|b| b.is_ascii_digit()) {
+        bail!("Invalid timestamp: {s}");
+    }
+    let nanos = format!("{frac:0<9}").parse::<i128>()?;
+    let sign = if secs.starts_with('-') { -1 } else { 1 };
+    let nanos = i128::from(secs.parse::<i64>()?) * 1_000_000_000 + sign * nanos;
+    Ok(OffsetDateTime::from_unix_timestamp_nanos(nanos)?)
+}
+
+fn parse_csv(line: &str) -> eyre::Result<Status> {
+    fn number(field: Option<&str>) -> eyre::Result<Option<f64>> {
+        match field.map(str::trim) {
+            None | Some("") => Ok(None),
+            Some(value) => Ok(Some(value.parse()?)),
+        }
+    }
+
+    let mut fields = line.split(',');
+    let source_id = fields.next().unwrap_or_default().trim().parse::<Uuid>()?;
+    let Some(timestamp) = fields.next() else { bail!("Missing timestamp") };
+    let timestamp = parse_seconds(timestamp.trim())?;
+    let position = match (number(fields.next())?, number(fields.next())?) {
+        (Some(x), Some(y)) => Some(Coord { x, y }),
+        (None, None) => None,
+        _ => bail!("Position must have both lon and lat"),
+    };
+    Ok(Status {
+        source_id: SourceId::from_uuid(source_id),
+        timestamp,
+        position,
+        bearing: number(fields.next())?.map(Angle::new::<radian>),
+        speed: number(fields.next())?.map(Velocity::new::<meter_per_second>),
+        satellites: None,
+        fix: None,
+        odometer: None,
+        ignition: None,
+        received_at: None,
+        suspect_timestamp: false,
+        tenant_id: TenantId::DEFAULT,
+    })
+}
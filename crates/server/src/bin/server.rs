@@ -1,28 +1,38 @@
-use std::{fmt::Debug, io, net::SocketAddr};
+use std::{fmt::Debug, io, net::SocketAddr, sync::Arc};
 
 use argh::FromArgs;
 
 use eyre::{eyre, WrapErr};
-use server::{
-    cq::{self, Request},
-    http, ingest,
-    storage::{self, StorageCommand, StorageQuery},
-};
+use server::{auth, cq, http, ingest, publish, settings::Settings, storage};
 use time::{format_description, macros::format_description};
-use tokio::net::lookup_host;
-use tracing::{error, info};
+use tokio::{net::lookup_host, sync::RwLock};
+use tracing::{error, info, warn};
 use tracing_error::ErrorLayer;
-use tracing_subscriber::{fmt::time::UtcTime, prelude::*, EnvFilter};
+use tracing_subscriber::{fmt::time::UtcTime, prelude::*, reload, EnvFilter};
 
 #[derive(Debug, FromArgs)]
-#[argh(description = "Geo Tracker network service")]
+#[argh(
+    description = "Geo Tracker network service",
+    note = "HTTP pagination cursors are signed with the secret in the GEO_CURSOR_SECRET \
+            environment variable. If it's not set, a random secret is used, and cursors don't \
+            survive restarts."
+)]
 struct Opts {
     /// storage to use for incoming events and computed data.
     /// supported values:
-    /// "memory" (in-memory storage; default),
-    /// "sled[:db_path]" (on-disk persistence using the embedded Sled database
-    /// engine, with an optional path to the storage directory)
-    #[argh(option, default = "storage::StorageConfig::InMemory")]
+    /// "memory[:max_per_source=N][,max_total=N][,max_age=DURATION]" (in-memory
+    /// storage, optionally evicting the oldest statuses past the given limits;
+    /// default),
+    /// "sled[:db_path][,cache=SIZE][,flush=DURATION|write][,compression=true]
+    /// [,max_size=SIZE]" (on-disk persistence using the embedded Sled
+    /// database engine, with an optional path to the storage directory, page
+    /// cache size, e.g. "256MiB", flush interval or flushing on every write,
+    /// zstd compression, and a size on disk of at least 8MiB past which the
+    /// oldest statuses are pruned),
+    /// "redis[:host:port][,history=N][,prefix=P]" (latest statuses and up to N
+    /// recent ones per source in Redis; defaults to 127.0.0.1:6379, 1000 and
+    /// "geo:")
+    #[argh(option, default = "storage::StorageConfig::InMemory { config: Default::default() }")]
     storage: storage::StorageConfig,
 
     /// strategy to use when receiving multiple statuses for the same sensor
@@ -33,6 +43,117 @@ struct Opts {
     #[argh(option, default = "storage::DupeStrategy::Merge")]
     duplicates: storage::DupeStrategy,
 
+    /// number of storage actors that writes are spread over by source, so that
+    /// writes of different sources proceed in parallel while those of a single
+    /// source are applied in order. defaults to the number of CPUs
+    #[argh(option, default = "default_storage_shards()")]
+    storage_shards: usize,
+
+    /// maximum number of storage requests, writes and queries alike, processed
+    /// at the same time by each storage actor
+    #[argh(option, default = "16")]
+    storage_concurrency: usize,
+
+    /// how to share storage processing between writes and queries when both
+    /// are waiting: "round-robin" (default), "queries-first" or
+    /// "weighted:<queries>:<writes>" (up to the given number of queries for
+    /// every given number of writes)
+    #[argh(option, default = "cq::Scheduling::default()")]
+    storage_scheduling: cq::Scheduling,
+
+    /// maximum number of writes, and separately of queries, waiting for
+    /// storage before the overflow policy applies
+    #[argh(option, default = "1024")]
+    storage_queue_size: usize,
+
+    /// what to do with new storage requests when the queue is full: "block"
+    /// (default) waits for room, "drop-oldest" discards the oldest queued
+    /// request and "reject" fails the new one
+    #[argh(option, default = "cq::Overflow::default()")]
+    storage_overflow: cq::Overflow,
+
+    /// how often to measure the size of the storage on disk and enforce its
+    /// size limit, and to repopulate the redis cache after failures
+    #[cfg(any(feature = "sled", feature = "redis"))]
+    #[argh(option, default = "std::time::Duration::from_secs(60).into()")]
+    storage_maintenance_interval: humantime::Duration,
+
+    /// geohash length (1-12) of the spatial cell index that positions are
+    /// bucketed into, enabling cell queries. disabled if not specified
+    #[argh(option)]
+    cell_precision: Option<usize>,
+
+    /// redis server to cache latest statuses in, in front of the storage, as
+    /// "host:port[,prefix=P]". caching is disabled if not specified
+    #[cfg(feature = "redis")]
+    #[argh(option)]
+    redis_cache: Option<storage::redis::RedisConfig>,
+
+    /// directory to archive old statuses into as Parquet files. archival is
+    /// disabled if not specified
+    #[cfg(feature = "archive")]
+    #[argh(option)]
+    archive_dir: Option<std::path::PathBuf>,
+
+    /// age after which statuses are moved from storage into the archive
+    #[cfg(feature = "archive")]
+    #[argh(option, default = "std::time::Duration::from_secs(30 * 24 * 60 * 60).into()")]
+    archive_after: humantime::Duration,
+
+    /// how often to check for statuses to archive
+    #[cfg(feature = "archive")]
+    #[argh(option, default = "std::time::Duration::from_secs(60 * 60).into()")]
+    archive_interval: humantime::Duration,
+
+    /// base URL of an S3-compatible service to upload archives to, e.g.
+    /// "http://127.0.0.1:9000". credentials are read from the
+    /// AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY environment variables
+    #[cfg(feature = "s3")]
+    #[argh(option)]
+    s3_endpoint: Option<String>,
+
+    /// bucket to upload archives to
+    #[cfg(feature = "s3")]
+    #[argh(option, default = "\"geo-track\".to_owned()")]
+    s3_bucket: String,
+
+    /// region of the S3 bucket
+    #[cfg(feature = "s3")]
+    #[argh(option, default = "\"us-east-1\".to_owned()")]
+    s3_region: String,
+
+    /// prefix of uploaded object keys
+    #[cfg(feature = "s3")]
+    #[argh(option, default = "String::new()")]
+    s3_prefix: String,
+
+    /// age after which uploaded archives are removed from local disk. kept
+    /// indefinitely if not specified
+    #[cfg(feature = "s3")]
+    #[argh(option)]
+    s3_keep_local: Option<humantime::Duration>,
+
+    /// age after which uploaded archives are removed from the bucket. kept
+    /// indefinitely if not specified
+    #[cfg(feature = "s3")]
+    #[argh(option)]
+    s3_expire_after: Option<humantime::Duration>,
+
+    /// message broker to publish every accepted status to. supported values:
+    /// "nats://host:port/subject", "kafka://host:port/topic[:partition]".
+    /// publishing is disabled if not specified
+    #[argh(option)]
+    publish: Option<publish::PublishTarget>,
+
+    /// encoding of published statuses: "json" (default) or "cbor"
+    #[argh(option, default = "publish::PublishFormat::Json")]
+    publish_format: publish::PublishFormat,
+
+    /// maximum number of statuses buffered in memory while the message broker
+    /// is unavailable, after which the oldest ones are dropped
+    #[argh(option, default = "10_000")]
+    publish_buffer: usize,
+
     /// network host the HTTP server will bind to
     #[argh(option, short = 'h', default = "\"127.0.0.1\".to_owned()")]
     host: String,
@@ -41,6 +162,36 @@ struct Opts {
     #[argh(option, short = 'p', default = "8000")]
     port: u16,
 
+    /// time to wait for storage to respond before failing HTTP requests
+    #[argh(option, default = "std::time::Duration::from_secs(10).into()")]
+    http_timeout: humantime::Duration,
+
+    /// origin allowed to make cross-origin requests to the HTTP API, e.g.
+    /// "https://dashboard.example.com", or "*" for any. can be repeated.
+    /// cross-origin requests are refused by browsers if not specified
+    #[argh(option)]
+    cors_origin: Vec<String>,
+
+    /// method allowed in cross-origin requests. can be repeated. defaults to
+    /// GET, HEAD and POST
+    #[argh(option)]
+    cors_method: Vec<axum::http::Method>,
+
+    /// request header allowed in cross-origin requests. can be repeated.
+    /// defaults to Accept, Authorization, Content-Type, If-None-Match,
+    /// Last-Event-ID and X-Request-Id
+    #[argh(option)]
+    cors_header: Vec<axum::http::HeaderName>,
+
+    /// allow cross-origin requests with credentials, like cookies. can't be
+    /// combined with "*" origins
+    #[argh(switch)]
+    cors_credentials: bool,
+
+    /// how long browsers may cache responses to CORS preflight requests
+    #[argh(option, default = "std::time::Duration::from_secs(60 * 60).into()")]
+    cors_max_age: humantime::Duration,
+
     /// network host the TCP listener will bind to
     #[argh(option, default = "\"127.0.0.1\".to_owned()")]
     tcp_host: String,
@@ -57,9 +208,109 @@ struct Opts {
     #[argh(option, default = "8002")]
     udp_port: u16,
 
+    /// largest UDP datagram accepted, in bytes. longer ones are dropped
+    #[argh(option, default = "512")]
+    udp_buffer_size: usize,
+
+    /// maximum number of UDP datagrams read at once and persisted together
+    #[argh(option, default = "32")]
+    udp_batch_size: usize,
+
+    /// drop UDP datagrams identical to one received within this time, e.g.
+    /// "5s". disabled if not specified
+    #[argh(option)]
+    udp_dedup_window: Option<humantime::Duration>,
+
+    /// network host the CoAP listener will bind to
+    #[argh(option, default = "\"127.0.0.1\".to_owned()")]
+    coap_host: String,
+
+    /// network port the CoAP listener will bind to, usually 5683. disabled if
+    /// not specified
+    #[argh(option)]
+    coap_port: Option<u16>,
+
+    /// largest CoAP message accepted, in bytes. longer ones are dropped
+    #[argh(option, default = "1152")]
+    coap_buffer_size: usize,
+
+    /// time to wait for storage to persist a status sent over CoAP before
+    /// responding with an error
+    #[argh(option, default = "std::time::Duration::from_secs(10).into()")]
+    coap_timeout: humantime::Duration,
+
     /// read timeout for the TCP listener
     #[argh(option, default = "std::time::Duration::from_secs(30).into()")]
     tcp_read_timeout: humantime::Duration,
+
+    /// close TCP connections that haven't delivered a status for this long,
+    /// even if they're not waiting for data. disabled if not specified
+    #[argh(option)]
+    tcp_idle_timeout: Option<humantime::Duration>,
+
+    /// maximum number of open TCP connections
+    #[argh(option, default = "1024")]
+    tcp_max_connections: usize,
+
+    /// maximum number of open TCP connections from a single IP address.
+    /// unlimited if not specified
+    #[argh(option)]
+    tcp_max_connections_per_ip: Option<usize>,
+
+    /// acknowledge persisted statuses back to TCP clients: "none" (default),
+    /// "each" for every status, or "batch" for every group of statuses
+    /// received together
+    #[argh(option, default = "ingest::AckMode::default()")]
+    tcp_ack: ingest::AckMode,
+
+    /// correct device clocks: shift timestamps of statuses by the clock offset
+    /// observed over each TCP connection once it exceeds this tolerance, e.g.
+    /// "5s". disabled if not specified
+    #[argh(option)]
+    tcp_skew_tolerance: Option<humantime::Duration>,
+
+    /// accept TCP connections compressed as zstd frames, detected from their
+    /// first bytes
+    #[argh(switch)]
+    tcp_zstd: bool,
+
+    /// how many times larger than the compressed payload decompressed HTTP
+    /// bodies and TCP streams may be before they're cut off
+    #[argh(option, default = "100")]
+    decompression_max_ratio: u64,
+
+    /// payload format of TCP connections and UDP datagrams that can't be
+    /// detected from their first bytes: "cbor" (default) or "json"
+    #[argh(option, default = "ingest::PayloadFormat::default()")]
+    ingest_format: ingest::PayloadFormat,
+
+    /// what to do with statuses whose timestamps are further in the future
+    /// than --timestamp-max-future, or older than --timestamp-max-age:
+    /// "reject", "clamp" to the nearest allowed moment, or "flag" as suspect.
+    /// timestamps aren't checked if not specified
+    #[argh(option)]
+    timestamp_policy: Option<ingest::TimestampAction>,
+
+    /// how far ahead of the time of receipt timestamps may be
+    #[argh(option, default = "std::time::Duration::from_secs(5 * 60).into()")]
+    timestamp_max_future: humantime::Duration,
+
+    /// how far behind the time of receipt timestamps may be. unlimited if not
+    /// specified
+    #[argh(option)]
+    timestamp_max_age: Option<humantime::Duration>,
+
+    /// API key as "<tenant-id>:<key>", where the tenant ID is a UUID. may be
+    /// repeated. if any are given, all clients have to authenticate, and only
+    /// see data of their own tenant
+    #[argh(option)]
+    api_key: Vec<auth::ApiKey>,
+
+    /// key that clients of the `/admin` API have to present as a bearer
+    /// token. API keys of tenants don't grant access to it. the `/admin` API
+    /// isn't served if not specified
+    #[argh(option)]
+    admin_key: Option<auth::AdminKey>,
 }
 
 #[tokio::main]
@@ -76,47 +327,265 @@ async fn main() -> eyre::Result<()> {
             .wrap_err_with(|| eyre!("Failed to resolve hostname: {}", host))
     }
 
-    set_up_logging()?;
+    let (log_filter, reload_log_filter) = set_up_logging()?;
 
-    let opts = argh::from_env::<Opts>();
+    let opts = Arc::new(argh::from_env::<Opts>());
     info!(?opts, "Starting server...");
+    // Shared by storage, ingest and HTTP, and changed through `/admin`.
+    let settings = Settings::new(format!("{opts:#?}"), opts.duplicates)
+        .with_log_filter(log_filter, reload_log_filter);
 
     // Initializing storage.
     info!("Initializing storage...");
-    let _storage =
-        storage::init(&opts.storage, opts.duplicates).wrap_err("Failed to initialize storage")?;
-
-    let (status_tx, mut status_rx) = cq::bounded(1024, on_command, on_query);
+    // Storage serves any number of requests at once, and is only locked for
+    // writing when it has to be replaced after a failure.
+    let storage = Arc::new(RwLock::new(open_storage(&opts, &settings).await?));
 
-    tokio::spawn(async move {
-        loop {
-            if let Err(err) = status_rx.next().await {
-                error!(%err, "Failed to process status event");
+    let on_command = {
+        let storage = storage.clone();
+        move |cmd| {
+            let storage = storage.clone();
+            // Shards wait for each other without holding the lock, which
+            // restarts take for writing.
+            storage::shard::synchronize(cmd, |cmd| async move {
+                storage.read().await.handle_command(cmd).await
+            })
+        }
+    };
+    let on_query = {
+        let storage = storage.clone();
+        move |query| {
+            let storage = storage.clone();
+            async move { storage.read().await.handle_query(query).await }
+        }
+    };
+    // Reopens storage after a request handler panicked, since it may have
+    // been left in an inconsistent state. In-memory storage is kept instead,
+    // as reopening it would lose all its data.
+    let restart = {
+        let (opts, settings) = (opts.clone(), settings.clone());
+        let handlers = (on_command.clone(), on_query.clone());
+        move || {
+            let (opts, settings) = (opts.clone(), settings.clone());
+            let (storage, handlers) = (storage.clone(), handlers.clone());
+            async move {
+                if let storage::StorageConfig::InMemory { .. } = opts.storage {
+                    warn!("Keeping in-memory storage after a failure");
+                    return Ok(handlers);
+                }
+                let mut storage = storage.write().await;
+                // Close the old engine first, as e.g. Sled can't be opened twice.
+                let placeholder = storage::StorageConfig::InMemory { config: Default::default() };
+                *storage = storage::StorageService::new(storage::init(
+                    &placeholder,
+                    settings.dupe_strategy().clone(),
+                    None,
+                )?);
+                *storage = open_storage(&opts, &settings).await?;
+                Ok::<_, eyre::Report>(handlers)
             }
         }
-    });
+    };
+    let policy = cq::RestartPolicy {
+        name: "storage",
+        min_backoff: std::time::Duration::from_millis(100),
+        max_backoff: std::time::Duration::from_secs(30),
+    };
+    // All shards share the storage, which is partitioned the same way where
+    // it needs locking, so that shards don't contend: they only keep the
+    // writes of each source in order.
+    let shards = (0..opts.storage_shards.max(1))
+        .map(|_| {
+            let (address, mailbox) =
+                cq::bounded(opts.storage_queue_size, on_command.clone(), on_query.clone());
+            let mailbox = mailbox
+                .with_scheduling(opts.storage_scheduling)
+                .with_overflow(opts.storage_overflow)
+                .with_ordered_commands();
+            tokio::spawn(mailbox.supervise(
+                opts.storage_concurrency,
+                policy.clone(),
+                restart.clone(),
+                |result| match result {
+                    Ok(Some(Err(err))) => error!(%err, "Failed to process storage command"),
+                    Ok(_) => {}
+                    Err(cq::CqrsError::Timeout) => {
+                        warn!("Skipped storage request past its deadline")
+                    }
+                    Err(err) => error!(%err, "Failed to process status event"),
+                },
+            ));
+            address
+        })
+        .collect();
+    let status_tx = storage::StorageHandler::new(shards);
+
+    // Sled needs its size limit enforced, and the Redis cache repopulating
+    // after failures.
+    #[cfg(any(feature = "sled", feature = "redis"))]
+    {
+        #[cfg(feature = "sled")]
+        let maintain = matches!(opts.storage, storage::StorageConfig::Sled { .. });
+        #[cfg(not(feature = "sled"))]
+        let maintain = false;
+        #[cfg(feature = "redis")]
+        let maintain = maintain || opts.redis_cache.is_some();
+        if maintain {
+            storage::spawn_maintenance(status_tx.clone(), opts.storage_maintenance_interval.into());
+        }
+    }
+
+    #[cfg(feature = "archive")]
+    if opts.archive_dir.is_some() {
+        storage::archive::spawn_roller(status_tx.clone(), opts.archive_interval.into());
+    }
 
     // Initializing network listeners.
     let http_addr = lookup_first(opts.host.as_str(), opts.port).await?;
     let tcp_addr = lookup_first(opts.tcp_host.as_str(), opts.tcp_port).await?;
     let udp_addr = lookup_first(opts.udp_host.as_str(), opts.udp_port).await?;
 
-    ingest::listen_tcp(&tcp_addr, opts.tcp_read_timeout.into(), status_tx.clone()).await?;
-    ingest::listen_udp(&udp_addr, status_tx.clone()).await?;
-    http::listen(&http_addr, status_tx.clone()).await?;
+    let publisher = opts.publish.clone().map(|target| {
+        publish::Publisher::spawn(publish::PublisherConfig {
+            target,
+            format: opts.publish_format,
+            buffer: opts.publish_buffer,
+        })
+    });
+    let mut pipeline = ingest::Pipeline::new(status_tx.clone(), publisher).with_settings(settings);
+    if let Some(action) = opts.timestamp_policy {
+        pipeline = pipeline.with_timestamp_policy(ingest::TimestampPolicy {
+            action,
+            max_future: opts.timestamp_max_future.into(),
+            max_age: opts.timestamp_max_age.map(Into::into),
+        });
+    }
+    if !opts.api_key.is_empty() {
+        pipeline = pipeline.with_api_keys(auth::ApiKeys::new(opts.api_key.iter().cloned()));
+    }
+
+    let tcp_cfg = ingest::TcpConfig {
+        read_timeout: opts.tcp_read_timeout.into(),
+        idle_timeout: opts.tcp_idle_timeout.map(Into::into),
+        max_connections: Some(opts.tcp_max_connections),
+        max_connections_per_ip: opts.tcp_max_connections_per_ip,
+        ack: opts.tcp_ack,
+        format: opts.ingest_format,
+        skew_tolerance: opts.tcp_skew_tolerance.map(Into::into),
+        zstd: opts.tcp_zstd,
+        max_decompression_ratio: opts.decompression_max_ratio,
+    };
+    ingest::listen_tcp(&tcp_addr, tcp_cfg, pipeline.clone()).await?;
+    let udp_cfg = ingest::UdpConfig {
+        buffer_size: opts.udp_buffer_size,
+        batch_size: opts.udp_batch_size,
+        format: opts.ingest_format,
+        dedup_window: opts.udp_dedup_window.map(Into::into),
+    };
+    ingest::listen_udp(&udp_addr, udp_cfg, pipeline.clone()).await?;
+    if let Some(coap_port) = opts.coap_port {
+        let coap_addr = lookup_first(opts.coap_host.as_str(), coap_port).await?;
+        let coap_cfg = ingest::coap::CoapConfig {
+            buffer_size: opts.coap_buffer_size,
+            timeout: opts.coap_timeout.into(),
+        };
+        ingest::coap::listen_coap(&coap_addr, coap_cfg, pipeline.clone()).await?;
+    }
+    let cursors = match std::env::var("GEO_CURSOR_SECRET") {
+        Ok(secret) => http::pagination::CursorSecret::new(secret.as_bytes()),
+        Err(_) => http::pagination::CursorSecret::random(),
+    };
+    let cors = (!opts.cors_origin.is_empty()).then(|| http::cors::CorsConfig {
+        origins: opts.cors_origin.clone(),
+        methods: opts.cors_method.clone(),
+        headers: opts.cors_header.clone(),
+        credentials: opts.cors_credentials,
+        max_age: opts.cors_max_age.into(),
+    });
+    let http_cfg = http::HttpConfig {
+        timeout: opts.http_timeout.into(),
+        cursors,
+        cors,
+        max_decompression_ratio: opts.decompression_max_ratio,
+        admin_key: opts.admin_key.clone(),
+    };
+    http::listen(&http_addr, status_tx.clone(), pipeline, http_cfg).await?;
 
     Ok(())
 }
 
-async fn on_command(_cmd: StorageCommand) -> <StorageCommand as Request>::Result {
-    todo!();
+fn default_storage_shards() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// Open the storage engine along with all subsystems layered on top of it.
+async fn open_storage(opts: &Opts, settings: &Settings) -> eyre::Result<storage::StorageService> {
+    let cell_index = opts.cell_precision.map(storage::CellIndex::new).transpose()?;
+    let memory;
+    let cfg = match &opts.storage {
+        // One partition per shard, so that shards don't wait for each other.
+        storage::StorageConfig::InMemory { config } => {
+            let config =
+                storage::MemoryConfig { partitions: opts.storage_shards.max(1), ..config.clone() };
+            memory = storage::StorageConfig::InMemory { config };
+            &memory
+        }
+        cfg => cfg,
+    };
+    let storage = storage::init(cfg, settings.dupe_strategy().clone(), cell_index)
+        .wrap_err("Failed to initialize storage")?;
+    let storage = storage::StorageService::new(storage);
+    #[cfg(feature = "archive")]
+    let storage = match &opts.archive_dir {
+        Some(dir) => {
+            let cfg = storage::archive::ArchiveConfig {
+                dir: dir.clone(),
+                after: opts.archive_after.into(),
+            };
+            let archive =
+                storage::archive::Archive::open(cfg).wrap_err("Failed to open archive")?;
+            #[cfg(feature = "s3")]
+            let archive = match &opts.s3_endpoint {
+                Some(endpoint) => archive.with_sink(s3_sink(opts, endpoint)?),
+                None => archive,
+            };
+            storage.with_archive(archive)
+        }
+        None => storage,
+    };
+    #[cfg(feature = "redis")]
+    let storage = match &opts.redis_cache {
+        Some(cfg) => {
+            // Only latest statuses are served from the cache.
+            let cfg = storage::redis::RedisConfig { history: 0, ..cfg.clone() };
+            let cache = storage::redis::LatestCache::new(&cfg, settings.dupe_strategy().clone());
+            storage.with_cache(cache).await
+        }
+        None => storage,
+    };
+    Ok(storage)
 }
 
-async fn on_query(_query: StorageQuery) -> <StorageQuery as Request>::Result {
-    todo!();
+#[cfg(feature = "s3")]
+fn s3_sink(opts: &Opts, endpoint: &str) -> eyre::Result<storage::export::S3Sink> {
+    let env = |name: &str| std::env::var(name).wrap_err_with(|| eyre!("{} is not set", name));
+    let cfg = storage::export::S3Config {
+        endpoint: endpoint.to_owned(),
+        bucket: opts.s3_bucket.clone(),
+        region: opts.s3_region.clone(),
+        prefix: opts.s3_prefix.clone(),
+        access_key_id: env("AWS_ACCESS_KEY_ID")?,
+        secret_access_key: env("AWS_SECRET_ACCESS_KEY")?,
+        keep_local: opts.s3_keep_local.map(Into::into),
+        expire_after: opts.s3_expire_after.map(Into::into),
+    };
+    storage::export::S3Sink::new(cfg).wrap_err("Failed to configure S3 sink")
 }
 
-fn set_up_logging() -> eyre::Result<()> {
+/// Installs the logging subscriber. Returns its initial filter, and a function
+/// that replaces it with one parsed from new directives.
+fn set_up_logging(
+) -> eyre::Result<(String, impl Fn(&str) -> Result<(), String> + Send + Sync + 'static)> {
     const TIMESTAMP_FORMAT: &[format_description::FormatItem] =
         format_description!("[year]-[month]-[day] [hour]:[minute]:[second].[subsecond digits:3]Z");
 
@@ -128,9 +597,15 @@ fn set_up_logging() -> eyre::Result<()> {
         std::env::set_var("RUST_LOG", "info");
     }
     let filter = EnvFilter::try_from_default_env()?;
+    let current = filter.to_string();
+    let (filter, handle) = reload::Layer::new(filter);
     let output = tracing_subscriber::fmt::layer().with_timer(UtcTime::new(TIMESTAMP_FORMAT));
     let errors = ErrorLayer::default();
     tracing_subscriber::registry().with(filter).with(output).with(errors).init();
 
-    Ok(())
+    let reload = move |directives: &str| {
+        let filter = EnvFilter::try_new(directives).map_err(|err| err.to_string())?;
+        handle.reload(filter).map_err(|err| err.to_string())
+    };
+    Ok((current, reload))
 }
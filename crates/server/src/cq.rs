@@ -1,7 +1,21 @@
-use std::future::Future;
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    future::Future,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use thiserror::Error;
-use tokio::sync::{mpsc, oneshot};
+use tokio::{
+    sync::{oneshot, Notify},
+    task::JoinSet,
+    time::{sleep, timeout_at, Instant},
+};
+use tracing::{error, info, info_span, warn, Instrument};
+
+use crate::metrics;
 
 #[derive(Debug, Error)]
 pub enum CqrsError {
@@ -9,17 +23,23 @@ pub enum CqrsError {
     ChannelClosed,
     #[error("unable to respond to sender")]
     SenderUnavailable,
-}
-
-impl<T> From<mpsc::error::SendError<T>> for CqrsError {
-    fn from(_: mpsc::error::SendError<T>) -> Self {
-        Self::ChannelClosed
-    }
+    #[error("request handler panicked or was cancelled")]
+    HandlerFailed(#[from] tokio::task::JoinError),
+    #[error("request deadline exceeded")]
+    Timeout,
+    #[error("request dropped without a response")]
+    Dropped,
+    #[error("mailbox is full")]
+    Overloaded,
+    #[error("unknown scheduling policy: {name}")]
+    UnknownScheduling { name: String },
+    #[error("unknown overflow policy: {name}")]
+    UnknownOverflow { name: String },
 }
 
 impl From<oneshot::error::RecvError> for CqrsError {
     fn from(_: oneshot::error::RecvError) -> Self {
-        Self::ChannelClosed
+        Self::Dropped
     }
 }
 
@@ -31,37 +51,158 @@ impl Request for () {
     type Result = ();
 }
 
+tokio::task_local! {
+    static REQUEST_ID: RequestId;
+}
+
+/// Identifier of the external request, e.g. an HTTP one, that requests sent
+/// through an [`Address`] are made on behalf of. It's picked up from the task
+/// sending the request, and handlers of the request run within a `request`
+/// span carrying it, so that logs on both sides can be correlated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(Arc<str>);
+
+impl RequestId {
+    pub fn new(id: &str) -> Self {
+        Self(id.into())
+    }
+
+    /// ID of the request the current task works on, if any.
+    pub fn current() -> Option<Self> {
+        REQUEST_ID.try_with(Clone::clone).ok()
+    }
+
+    /// Run `fut` on behalf of this request. Requests sent through addresses
+    /// from within it are tagged with the ID.
+    pub async fn scope<F: Future>(self, fut: F) -> F::Output {
+        REQUEST_ID.scope(self, fut).await
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
 enum Envelope<C: Request, Q: Request> {
-    Command { payload: C, tx: oneshot::Sender<C::Result> },
-    Query { payload: Q, tx: oneshot::Sender<Q::Result> },
+    Command {
+        payload: C,
+        tx: oneshot::Sender<C::Result>,
+        /// Time after which the sender no longer waits for the result.
+        deadline: Option<Instant>,
+        request_id: Option<RequestId>,
+    },
+    /// Command whose sender isn't interested in the result.
+    Notification { payload: C, request_id: Option<RequestId> },
+    Query {
+        payload: Q,
+        tx: oneshot::Sender<Q::Result>,
+        deadline: Option<Instant>,
+        request_id: Option<RequestId>,
+    },
+}
+
+impl<C: Request, Q: Request> Envelope<C, Q> {
+    fn request_id(&self) -> Option<RequestId> {
+        match self {
+            Self::Command { request_id, .. }
+            | Self::Notification { request_id, .. }
+            | Self::Query { request_id, .. } => request_id.clone(),
+        }
+    }
 }
 
+/// Sending side of a mailbox. Commands and queries are queued separately, so
+/// they can be prioritized according to the mailbox's [`Scheduling`].
 #[derive(Debug)]
 pub struct Address<C: Request = (), Q: Request = ()> {
-    tx: mpsc::Sender<Envelope<C, Q>>,
+    commands: Sender<Envelope<C, Q>>,
+    queries: Sender<Envelope<C, Q>>,
 }
 
 impl<C: Request, Q: Request> Address<C, Q> {
     pub async fn command(&self, payload: C) -> Result<C::Result, CqrsError> {
         let (tx, rx) = oneshot::channel();
-        self.tx.send(Envelope::Command { payload, tx }).await?;
+        let request_id = RequestId::current();
+        self.commands.send(Envelope::Command { payload, tx, deadline: None, request_id }).await?;
         let result = rx.await?;
         Ok(result)
     }
 
+    /// Same as [`Address::command`], but gives up with [`CqrsError::Timeout`]
+    /// if the command isn't processed within `timeout`. The mailbox skips the
+    /// command if it's still queued by then.
+    pub async fn command_timeout(
+        &self,
+        payload: C,
+        timeout: Duration,
+    ) -> Result<C::Result, CqrsError> {
+        let deadline = Instant::now() + timeout;
+        let (tx, rx) = oneshot::channel();
+        let request_id = RequestId::current();
+        let envelope = Envelope::Command { payload, tx, deadline: Some(deadline), request_id };
+        self.send_within(envelope, rx, deadline).await
+    }
+
+    /// Enqueue a command without waiting for it to be processed. Its result is
+    /// returned from [`Mailbox::next`] instead.
+    pub async fn notify(&self, payload: C) -> Result<(), CqrsError> {
+        let request_id = RequestId::current();
+        self.commands.send(Envelope::Notification { payload, request_id }).await?;
+        Ok(())
+    }
+
     pub async fn query(&self, payload: Q) -> Result<Q::Result, CqrsError> {
         let (tx, rx) = oneshot::channel();
-        self.tx.send(Envelope::Query { payload, tx }).await?;
+        let request_id = RequestId::current();
+        self.queries.send(Envelope::Query { payload, tx, deadline: None, request_id }).await?;
         let result = rx.await?;
         Ok(result)
     }
+
+    /// Same as [`Address::query`], but gives up with [`CqrsError::Timeout`] if
+    /// the query isn't processed within `timeout`. The mailbox skips the query
+    /// if it's still queued by then.
+    pub async fn query_timeout(
+        &self,
+        payload: Q,
+        timeout: Duration,
+    ) -> Result<Q::Result, CqrsError> {
+        let deadline = Instant::now() + timeout;
+        let (tx, rx) = oneshot::channel();
+        let request_id = RequestId::current();
+        let envelope = Envelope::Query { payload, tx, deadline: Some(deadline), request_id };
+        self.send_within(envelope, rx, deadline).await
+    }
+
+    async fn send_within<T>(
+        &self,
+        envelope: Envelope<C, Q>,
+        rx: oneshot::Receiver<T>,
+        deadline: Instant,
+    ) -> Result<T, CqrsError> {
+        let lane = match envelope {
+            Envelope::Query { .. } => &self.queries,
+            _ => &self.commands,
+        };
+        let request = async {
+            lane.send(envelope).await?;
+            Ok(rx.await?)
+        };
+        timeout_at(deadline, request).await.map_err(|_| CqrsError::Timeout)?
+    }
 }
 
 // Need an explicit impl of [`Clone`] because otherwise the compiler requires
 // the generic parameters to also implement it.
 impl<C: Request, Q: Request> Clone for Address<C, Q> {
     fn clone(&self) -> Self {
-        Self { tx: self.tx.clone() }
+        Self { commands: self.commands.clone(), queries: self.queries.clone() }
     }
 }
 
@@ -75,7 +216,12 @@ where
     QFn: Fn(Q) -> QFut,
     QFut: Future<Output = Q::Result>,
 {
-    rx: mpsc::Receiver<Envelope<C, Q>>,
+    commands: Receiver<Envelope<C, Q>>,
+    queries: Receiver<Envelope<C, Q>>,
+    scheduler: Scheduler,
+    /// Whether commands are handled one at a time, see
+    /// [`Mailbox::with_ordered_commands`].
+    ordered_commands: bool,
     on_command: CFn,
     on_query: QFn,
 }
@@ -89,21 +235,572 @@ where
     QFn: Fn(Q) -> QFut,
     QFut: Future<Output = Q::Result>,
 {
-    pub async fn next(&mut self) -> Result<(), CqrsError> {
-        match self.rx.recv().await {
-            Some(Envelope::Command { payload, tx }) => {
-                let resp = (self.on_command)(payload).await;
-                tx.send(resp).map_err(|_| CqrsError::SenderUnavailable)
-            }
-            Some(Envelope::Query { payload, tx }) => {
-                let resp = (self.on_query)(payload).await;
-                tx.send(resp).map_err(|_| CqrsError::SenderUnavailable)
+    /// Process the next incoming request. Results of notifications are
+    /// returned, since there's no one else to receive them. Requests past their
+    /// deadline are skipped with [`CqrsError::Timeout`].
+    pub async fn next(&mut self) -> Result<Option<C::Result>, CqrsError> {
+        match self.recv(true).await {
+            Some(envelope) => {
+                let request_id = envelope.request_id();
+                traced(request_id, self.dispatch(envelope).run()).await
             }
             None => Err(CqrsError::ChannelClosed),
         }
     }
+
+    /// Set the policy of choosing between waiting commands and queries.
+    pub fn with_scheduling(mut self, scheduling: Scheduling) -> Self {
+        self.scheduler = Scheduler::new(scheduling);
+        self
+    }
+
+    /// Set what happens to new requests when a queue is full.
+    pub fn with_overflow(self, overflow: Overflow) -> Self {
+        self.commands.set_overflow(overflow);
+        self.queries.set_overflow(overflow);
+        self
+    }
+
+    /// Handle commands one at a time, in the order they were sent, while
+    /// queries still run alongside them and each other. Only matters if the
+    /// mailbox runs more than one request handler at a time.
+    pub fn with_ordered_commands(mut self) -> Self {
+        self.ordered_commands = true;
+        self
+    }
+
+    /// Receive the next request from the lane picked by the scheduler, or
+    /// whichever has one first if both are empty. Only queries are received if
+    /// `commands` is false. Returns `None` once all addresses have been
+    /// dropped.
+    async fn recv(&mut self, commands: bool) -> Option<Envelope<C, Q>> {
+        let (first, second) = match (commands, self.scheduler.prefer_queries()) {
+            (false, _) => (&self.queries, None),
+            (true, true) => (&self.queries, Some(&self.commands)),
+            (true, false) => (&self.commands, Some(&self.queries)),
+        };
+        let envelope = match first.try_recv().or_else(|| second?.try_recv()) {
+            Some(envelope) => Some(envelope),
+            None => match second {
+                Some(second) => tokio::select! {
+                    Some(envelope) = first.recv() => Some(envelope),
+                    Some(envelope) = second.recv() => Some(envelope),
+                    else => None,
+                },
+                // Commands may still be queued, so wait for the running one to
+                // finish instead.
+                None => match first.recv().await {
+                    Some(envelope) => Some(envelope),
+                    None => std::future::pending().await,
+                },
+            },
+        };
+        if let Some(envelope) = &envelope {
+            self.scheduler.record(matches!(envelope, Envelope::Query { .. }));
+        }
+        envelope
+    }
+
+    /// Start handling a request. The returned [`Pending`] handler doesn't
+    /// borrow the mailbox, so it can be run independently.
+    fn dispatch(&self, envelope: Envelope<C, Q>) -> Pending<CFut, QFut> {
+        let expired = |deadline: Option<Instant>| deadline.is_some_and(|d| d <= Instant::now());
+        match envelope {
+            Envelope::Command { deadline, .. } | Envelope::Query { deadline, .. }
+                if expired(deadline) =>
+            {
+                Pending::Expired
+            }
+            Envelope::Command { payload, tx, .. } => {
+                Pending::Command((self.on_command)(payload), Some(tx))
+            }
+            Envelope::Notification { payload, .. } => {
+                Pending::Command((self.on_command)(payload), None)
+            }
+            Envelope::Query { payload, tx, .. } => Pending::Query((self.on_query)(payload), tx),
+        }
+    }
+}
+
+/// Run a request handler on behalf of the request it was made for, if any, so
+/// that its logs and the requests it sends in turn carry the request ID.
+async fn traced<F: Future>(request_id: Option<RequestId>, fut: F) -> F::Output {
+    match request_id {
+        Some(request_id) => {
+            let span = info_span!("request", %request_id);
+            request_id.scope(fut).instrument(span).await
+        }
+        None => fut.await,
+    }
+}
+
+/// Request handler future along with the channel to send its result to.
+enum Pending<CFut: Future, QFut: Future> {
+    Command(CFut, Option<oneshot::Sender<CFut::Output>>),
+    Query(QFut, oneshot::Sender<QFut::Output>),
+    /// Request whose sender already gave up on it.
+    Expired,
 }
 
+impl<CFut: Future, QFut: Future> Pending<CFut, QFut> {
+    async fn run(self) -> Result<Option<CFut::Output>, CqrsError> {
+        match self {
+            Self::Command(fut, Some(tx)) => {
+                let resp = fut.await;
+                tx.send(resp).map(|_| None).map_err(|_| CqrsError::SenderUnavailable)
+            }
+            Self::Command(fut, None) => Ok(Some(fut.await)),
+            Self::Query(fut, tx) => {
+                let resp = fut.await;
+                tx.send(resp).map(|_| None).map_err(|_| CqrsError::SenderUnavailable)
+            }
+            Self::Expired => Err(CqrsError::Timeout),
+        }
+    }
+}
+
+impl<C, Q, CFn, CFut, QFn, QFut> Mailbox<C, Q, CFn, CFut, QFn, QFut>
+where
+    C: Request,
+    C::Result: Send + 'static,
+    Q: Request,
+    Q::Result: Send + 'static,
+    CFn: Fn(C) -> CFut,
+    CFut: Future<Output = C::Result> + Send + 'static,
+    QFn: Fn(Q) -> QFut,
+    QFut: Future<Output = Q::Result> + Send + 'static,
+{
+    /// Process incoming requests until all addresses are dropped, running up
+    /// to `concurrency` request handlers at a time. Requests are handled
+    /// strictly one after another if `concurrency` is 1, otherwise they may
+    /// complete out of order.
+    ///
+    /// Outcomes of individual requests, as returned by [`Mailbox::next`], are
+    /// passed to `on_result`. Handler panics are reported as
+    /// [`CqrsError::HandlerFailed`] and don't stop processing.
+    pub async fn run<F>(mut self, concurrency: usize, mut on_result: F)
+    where
+        F: FnMut(Result<Option<C::Result>, CqrsError>),
+    {
+        while let Stopped::Failed = self.serve(concurrency, &mut on_result).await {}
+    }
+
+    /// Same as [`Mailbox::run`], but replaces the request handlers with ones
+    /// created by `restart` whenever a handler panics, e.g. to recreate state
+    /// that may have been left inconsistent. Requests keep queueing up while
+    /// the handlers are being replaced, so addresses stay usable throughout.
+    pub async fn supervise<F, R, RFut, E>(
+        mut self,
+        concurrency: usize,
+        policy: RestartPolicy,
+        mut restart: R,
+        mut on_result: F,
+    ) where
+        F: FnMut(Result<Option<C::Result>, CqrsError>),
+        R: FnMut() -> RFut,
+        RFut: Future<Output = Result<(CFn, QFn), E>>,
+        E: Display,
+    {
+        let restarts = metrics::counter_with(
+            "geo_actor_restarts_total",
+            "Restarts of actors after their request handlers failed.",
+            &[("actor", policy.name)],
+        );
+        let mut backoff = policy.min_backoff;
+        let mut last_restart = Instant::now();
+
+        while let Stopped::Failed = self.serve(concurrency, &mut on_result).await {
+            // Consider the actor recovered if it's been running for a while.
+            if last_restart.elapsed() > policy.max_backoff * 2 {
+                backoff = policy.min_backoff;
+            }
+            loop {
+                warn!(actor = policy.name, ?backoff, "Request handler failed, restarting");
+                sleep(backoff).await;
+                backoff = (backoff * 2).min(policy.max_backoff);
+                restarts.inc();
+                match restart().await {
+                    Ok((on_command, on_query)) => {
+                        self.on_command = on_command;
+                        self.on_query = on_query;
+                        break;
+                    }
+                    Err(err) => error!(actor = policy.name, %err, "Failed to restart"),
+                }
+            }
+            info!(actor = policy.name, "Restarted");
+            last_restart = Instant::now();
+        }
+    }
+
+    /// Process requests until all addresses are dropped or a handler fails.
+    /// All handlers that are still running are awaited before returning.
+    async fn serve<F>(&mut self, concurrency: usize, on_result: &mut F) -> Stopped
+    where
+        F: FnMut(Result<Option<C::Result>, CqrsError>),
+    {
+        // Handlers always run as separate tasks to contain panics.
+        let mut running = JoinSet::new();
+        // Whether a command is being handled, so further ones have to wait.
+        let mut command = false;
+        let (mut failed, mut closed) = (false, false);
+        loop {
+            let accepting = !failed && !closed && running.len() < concurrency.max(1);
+            if !accepting && running.is_empty() {
+                return if closed { Stopped::Closed } else { Stopped::Failed };
+            }
+            tokio::select! {
+                envelope = self.recv(!command), if accepting => match envelope {
+                    Some(envelope) => {
+                        let ordered =
+                            self.ordered_commands && !matches!(envelope, Envelope::Query { .. });
+                        command |= ordered;
+                        let request_id = envelope.request_id();
+                        let pending = traced(request_id, self.dispatch(envelope).run());
+                        running.spawn(async move { (ordered, pending.await) });
+                    }
+                    None => closed = true,
+                },
+                Some(result) = running.join_next(), if !running.is_empty() => {
+                    // A failed handler stops the mailbox from accepting
+                    // requests anyway.
+                    let result = result.map_err(CqrsError::from).and_then(|(ordered, result)| {
+                        command &= !ordered;
+                        result
+                    });
+                    failed |= matches!(result, Err(CqrsError::HandlerFailed(_)));
+                    on_result(result);
+                }
+            }
+        }
+    }
+}
+
+/// Reason for [`Mailbox::serve`] to return.
+enum Stopped {
+    Closed,
+    Failed,
+}
+
+/// Policy of choosing between commands and queries when both are waiting to
+/// be processed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scheduling {
+    /// Always process waiting queries before any commands.
+    QueriesFirst,
+    /// Process up to `queries` queries for every `commands` commands, so
+    /// neither lane can starve the other.
+    Weighted { queries: usize, commands: usize },
+}
+
+impl Default for Scheduling {
+    fn default() -> Self {
+        Self::Weighted { queries: 1, commands: 1 }
+    }
+}
+
+/// Parses "queries-first", "round-robin" or "weighted:<queries>:<commands>".
+impl FromStr for Scheduling {
+    type Err = CqrsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let unknown = || CqrsError::UnknownScheduling { name: s.to_owned() };
+        match s {
+            "queries-first" => Ok(Self::QueriesFirst),
+            "round-robin" => Ok(Self::default()),
+            _ => {
+                let weights = s.strip_prefix("weighted:").ok_or_else(unknown)?;
+                let (queries, commands) = weights.split_once(':').ok_or_else(unknown)?;
+                let queries = queries.parse().map_err(|_| unknown())?;
+                let commands = commands.parse().map_err(|_| unknown())?;
+                match (queries, commands) {
+                    (0, 0) => Err(unknown()),
+                    _ => Ok(Self::Weighted { queries, commands }),
+                }
+            }
+        }
+    }
+}
+
+/// Tracks requests served in the current round of [`Scheduling::Weighted`].
+#[derive(Debug)]
+struct Scheduler {
+    scheduling: Scheduling,
+    queries: usize,
+    commands: usize,
+}
+
+impl Scheduler {
+    fn new(scheduling: Scheduling) -> Self {
+        Self { scheduling, queries: 0, commands: 0 }
+    }
+
+    fn prefer_queries(&mut self) -> bool {
+        match self.scheduling {
+            Scheduling::QueriesFirst => true,
+            Scheduling::Weighted { queries, commands } => {
+                if self.queries >= queries && self.commands >= commands {
+                    (self.queries, self.commands) = (0, 0);
+                }
+                self.queries < queries
+            }
+        }
+    }
+
+    fn record(&mut self, query: bool) {
+        match query {
+            true => self.queries += 1,
+            false => self.commands += 1,
+        }
+    }
+}
+
+/// What to do with new requests when the queue they belong to is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Overflow {
+    /// Wait until there's room in the queue.
+    #[default]
+    Block,
+    /// Discard the oldest queued request to make room. Its sender receives
+    /// [`CqrsError::Dropped`].
+    DropOldest,
+    /// Fail with [`CqrsError::Overloaded`].
+    Reject,
+}
+
+impl FromStr for Overflow {
+    type Err = CqrsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(Self::Block),
+            "drop-oldest" => Ok(Self::DropOldest),
+            "reject" => Ok(Self::Reject),
+            _ => Err(CqrsError::UnknownOverflow { name: s.to_owned() }),
+        }
+    }
+}
+
+/// Bounded queue of one kind of requests, similar to a Tokio `mpsc` channel
+/// but with support for overflow policies and instrumentation.
+struct Lane<T> {
+    state: Mutex<LaneState<T>>,
+    /// Wakes the receiver when an item is queued or the last sender is gone.
+    readable: Notify,
+    /// Wakes senders waiting for room when an item is taken or the receiver is
+    /// gone.
+    writable: Notify,
+    bound: usize,
+    metrics: LaneMetrics,
+}
+
+struct LaneState<T> {
+    queue: VecDeque<T>,
+    overflow: Overflow,
+    senders: usize,
+    receiver: bool,
+}
+
+struct LaneMetrics {
+    depth: metrics::Gauge,
+    depth_max: metrics::Gauge,
+    sends: metrics::Counter,
+    send_wait: metrics::Counter,
+    dropped: metrics::Counter,
+    rejected: metrics::Counter,
+}
+
+impl LaneMetrics {
+    fn new(lane: &str) -> Self {
+        let labels = [("lane", lane)];
+        Self {
+            depth: metrics::gauge_with("geo_mailbox_depth", "Queued requests.", &labels),
+            depth_max: metrics::gauge_with(
+                "geo_mailbox_depth_max",
+                "Highest number of queued requests so far.",
+                &labels,
+            ),
+            sends: metrics::counter_with(
+                "geo_mailbox_sends_total",
+                "Requests added to the queue.",
+                &labels,
+            ),
+            send_wait: metrics::counter_with(
+                "geo_mailbox_send_wait_microseconds_total",
+                "Time spent waiting for room in the queue.",
+                &labels,
+            ),
+            dropped: metrics::counter_with(
+                "geo_mailbox_dropped_total",
+                "Queued requests discarded to make room for new ones.",
+                &labels,
+            ),
+            rejected: metrics::counter_with(
+                "geo_mailbox_rejected_total",
+                "Requests rejected due to a full queue.",
+                &labels,
+            ),
+        }
+    }
+}
+
+fn lane<T>(name: &str, bound: usize) -> (Sender<T>, Receiver<T>) {
+    let lane = Arc::new(Lane {
+        state: Mutex::new(LaneState {
+            queue: VecDeque::new(),
+            overflow: Overflow::default(),
+            senders: 1,
+            receiver: true,
+        }),
+        readable: Notify::new(),
+        writable: Notify::new(),
+        bound: bound.max(1),
+        metrics: LaneMetrics::new(name),
+    });
+    (Sender(lane.clone()), Receiver(lane))
+}
+
+impl<T> Lane<T> {
+    fn state(&self) -> std::sync::MutexGuard<'_, LaneState<T>> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn update_depth(&self, depth: usize) {
+        self.metrics.depth.set(depth as i64);
+        self.metrics.depth_max.set_max(depth as i64);
+    }
+}
+
+struct Sender<T>(Arc<Lane<T>>);
+
+impl<T> std::fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Sender").field("bound", &self.0.bound).finish_non_exhaustive()
+    }
+}
+
+impl<T> Sender<T> {
+    async fn send(&self, item: T) -> Result<(), CqrsError> {
+        let lane = &self.0;
+        let started = Instant::now();
+        loop {
+            let writable = lane.writable.notified();
+            tokio::pin!(writable);
+            writable.as_mut().enable();
+
+            {
+                let mut state = lane.state();
+                if !state.receiver {
+                    return Err(CqrsError::ChannelClosed);
+                }
+                if state.queue.len() >= lane.bound {
+                    match state.overflow {
+                        Overflow::Block => {}
+                        Overflow::DropOldest => {
+                            state.queue.pop_front();
+                            lane.metrics.dropped.inc();
+                        }
+                        Overflow::Reject => {
+                            lane.metrics.rejected.inc();
+                            return Err(CqrsError::Overloaded);
+                        }
+                    }
+                }
+                if state.queue.len() < lane.bound {
+                    state.queue.push_back(item);
+                    lane.update_depth(state.queue.len());
+                    drop(state);
+
+                    lane.readable.notify_one();
+                    lane.metrics.sends.inc();
+                    lane.metrics.send_wait.add(started.elapsed().as_micros() as u64);
+                    return Ok(());
+                }
+            }
+
+            writable.await;
+        }
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.0.state().senders += 1;
+        Self(self.0.clone())
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.0.state();
+        state.senders -= 1;
+        if state.senders == 0 {
+            drop(state);
+            self.0.readable.notify_one();
+        }
+    }
+}
+
+struct Receiver<T>(Arc<Lane<T>>);
+
+impl<T> std::fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Receiver").field("bound", &self.0.bound).finish_non_exhaustive()
+    }
+}
+
+impl<T> Receiver<T> {
+    fn set_overflow(&self, overflow: Overflow) {
+        self.0.state().overflow = overflow;
+    }
+
+    fn try_recv(&self) -> Option<T> {
+        let mut state = self.0.state();
+        let item = state.queue.pop_front()?;
+        self.0.update_depth(state.queue.len());
+        drop(state);
+        self.0.writable.notify_one();
+        Some(item)
+    }
+
+    /// Wait for the next item, or `None` once all senders are gone.
+    async fn recv(&self) -> Option<T> {
+        loop {
+            let readable = self.0.readable.notified();
+            tokio::pin!(readable);
+            readable.as_mut().enable();
+
+            if let Some(item) = self.try_recv() {
+                return Some(item);
+            }
+            if self.0.state().senders == 0 {
+                return None;
+            }
+            readable.await;
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.0.state().receiver = false;
+        self.0.writable.notify_waiters();
+    }
+}
+
+/// Settings of [`Mailbox::supervise`].
+#[derive(Debug, Clone)]
+pub struct RestartPolicy {
+    /// Name of the actor, used in logs and metrics.
+    pub name: &'static str,
+    /// Delay before the first restart, doubled on each consecutive one.
+    pub min_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+/// Create a mailbox and its address. Commands and queries are each buffered up
+/// to `bound` requests, after which the mailbox's [`Overflow`] policy applies.
+#[allow(clippy::type_complexity)]
 pub fn bounded<C, Q, CFn, CFut, QFn, QFut>(
     bound: usize,
     on_command: CFn,
@@ -117,6 +814,328 @@ where
     QFn: Fn(Q) -> QFut,
     QFut: Future<Output = Q::Result>,
 {
-    let (tx, rx) = mpsc::channel(bound);
-    (Address { tx }, Mailbox { rx, on_command, on_query })
+    let (commands_tx, commands) = lane("commands", bound);
+    let (queries_tx, queries) = lane("queries", bound);
+    let scheduler = Scheduler::new(Scheduling::default());
+    (
+        Address { commands: commands_tx, queries: queries_tx },
+        Mailbox { commands, queries, scheduler, ordered_commands: false, on_command, on_query },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use tokio::{sync::Notify, time::timeout};
+
+    use super::{bounded, CqrsError, Overflow, Request, RequestId, RestartPolicy, Scheduling};
+
+    struct Add(u32);
+
+    impl Request for Add {
+        type Result = u32;
+    }
+
+    struct Wait;
+
+    impl Request for Wait {
+        type Result = ();
+    }
+
+    #[tokio::test]
+    async fn slow_query_does_not_block_commands() {
+        let release = Arc::new(Notify::new());
+        let on_command = |Add(n)| async move { n + 1 };
+        let on_query = {
+            let release = release.clone();
+            move |Wait| {
+                let release = release.clone();
+                async move { release.notified().await }
+            }
+        };
+        let (address, mailbox) = bounded(8, on_command, on_query);
+        let notified = Arc::new(Mutex::new(Vec::new()));
+        tokio::spawn({
+            let notified = notified.clone();
+            mailbox.run(2, move |result| notified.lock().unwrap().extend(result.unwrap()))
+        });
+
+        let query = tokio::spawn({
+            let address = address.clone();
+            async move { address.query(Wait).await }
+        });
+        let result = timeout(Duration::from_secs(1), address.command(Add(1))).await;
+        assert_eq!(result.unwrap().unwrap(), 2);
+
+        address.notify(Add(2)).await.unwrap();
+        release.notify_one();
+        query.await.unwrap().unwrap();
+        let notified = async {
+            while notified.lock().unwrap().is_empty() {
+                tokio::task::yield_now().await;
+            }
+            notified.lock().unwrap().clone()
+        };
+        assert_eq!(timeout(Duration::from_secs(1), notified).await.unwrap(), [3]);
+    }
+
+    #[tokio::test]
+    async fn request_id_is_propagated() {
+        struct Current;
+
+        impl Request for Current {
+            type Result = Option<RequestId>;
+        }
+
+        let on_request = |Current| async { RequestId::current() };
+        let (address, mailbox) = bounded(8, on_request, on_request);
+        tokio::spawn(mailbox.run(2, |_| {}));
+
+        let request_id = RequestId::new("3f2c0a1b9d8e7f60");
+        let (command, query) = request_id
+            .clone()
+            .scope(async { (address.command(Current).await, address.query(Current).await) })
+            .await;
+        assert_eq!(command.unwrap(), Some(request_id.clone()));
+        assert_eq!(query.unwrap(), Some(request_id));
+        assert_eq!(address.command(Current).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn ordered_commands() {
+        let release = Arc::new(Notify::new());
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let on_command = {
+            let (release, handled) = (release.clone(), handled.clone());
+            move |Add(n)| {
+                let (release, handled) = (release.clone(), handled.clone());
+                async move {
+                    if n == 0 {
+                        release.notified().await;
+                    }
+                    handled.lock().unwrap().push(n);
+                    n
+                }
+            }
+        };
+        let on_query = |Wait| async {};
+        let (address, mailbox) = bounded(8, on_command, on_query);
+        tokio::spawn(mailbox.with_ordered_commands().run(4, |_| {}));
+
+        address.notify(Add(0)).await.unwrap();
+        address.notify(Add(1)).await.unwrap();
+        // Queries aren't held up by the waiting commands.
+        timeout(Duration::from_secs(1), address.query(Wait)).await.unwrap().unwrap();
+        assert!(handled.lock().unwrap().is_empty());
+
+        release.notify_one();
+        let result = timeout(Duration::from_secs(1), address.command(Add(2))).await;
+        assert_eq!(result.unwrap().unwrap(), 2);
+        assert_eq!(*handled.lock().unwrap(), [0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn expired_requests_are_skipped() {
+        let release = Arc::new(Notify::new());
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let on_command = {
+            let (release, handled) = (release.clone(), handled.clone());
+            move |Add(n)| {
+                let (release, handled) = (release.clone(), handled.clone());
+                async move {
+                    handled.lock().unwrap().push(n);
+                    if n == 0 {
+                        release.notified().await;
+                    }
+                    n
+                }
+            }
+        };
+        let (address, mailbox) = bounded(8, on_command, |Wait| async {});
+        let results = Arc::new(Mutex::new(Vec::new()));
+        tokio::spawn({
+            let results = results.clone();
+            mailbox.run(1, move |result| results.lock().unwrap().push(result.is_ok()))
+        });
+
+        let blocking = tokio::spawn({
+            let address = address.clone();
+            async move { address.command(Add(0)).await }
+        });
+        while handled.lock().unwrap().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        let result = address.command_timeout(Add(1), Duration::from_millis(50)).await;
+        assert!(matches!(result, Err(CqrsError::Timeout)));
+
+        release.notify_one();
+        blocking.await.unwrap().unwrap();
+        assert_eq!(address.command(Add(2)).await.unwrap(), 2);
+        assert_eq!(*handled.lock().unwrap(), [0, 2]);
+        assert_eq!(results.lock().unwrap()[..2], [true, false]);
+    }
+
+    #[tokio::test]
+    async fn supervise_restarts_after_panic() {
+        let on_command = |Add(n)| async move {
+            assert!(n > 0, "invalid request");
+            n
+        };
+        let on_query = |Wait| async {};
+        let (address, mailbox) = bounded(8, on_command, on_query);
+        let restarts = Arc::new(Mutex::new(0));
+        let policy = RestartPolicy {
+            name: "test",
+            min_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(10),
+        };
+        let restart = {
+            let restarts = restarts.clone();
+            move || {
+                *restarts.lock().unwrap() += 1;
+                async move { Ok::<_, String>((on_command, on_query)) }
+            }
+        };
+        tokio::spawn(mailbox.supervise(1, policy, restart, |_| {}));
+
+        assert!(matches!(address.command(Add(0)).await, Err(CqrsError::Dropped)));
+        assert_eq!(address.command(Add(1)).await.unwrap(), 1);
+        assert_eq!(*restarts.lock().unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn queries_first() {
+        let release = Arc::new(Notify::new());
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let on_command = {
+            let (release, order) = (release.clone(), order.clone());
+            move |Add(n)| {
+                let (release, order) = (release.clone(), order.clone());
+                async move {
+                    order.lock().unwrap().push(n);
+                    if n == 0 {
+                        release.notified().await;
+                    }
+                    n
+                }
+            }
+        };
+        let on_query = {
+            let order = order.clone();
+            move |Wait| {
+                order.lock().unwrap().push(u32::MAX);
+                async {}
+            }
+        };
+        let (address, mailbox) = bounded(8, on_command, on_query);
+        let mailbox = mailbox.with_scheduling(Scheduling::QueriesFirst);
+        tokio::spawn(mailbox.run(1, |_| {}));
+
+        // Queue up commands behind a blocked one, then a query.
+        address.notify(Add(0)).await.unwrap();
+        while order.lock().unwrap().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        for n in 1..=3 {
+            address.notify(Add(n)).await.unwrap();
+        }
+        let query = tokio::spawn({
+            let address = address.clone();
+            async move { address.query(Wait).await }
+        });
+        tokio::task::yield_now().await;
+
+        release.notify_one();
+        query.await.unwrap().unwrap();
+        assert_eq!(address.command(Add(4)).await.unwrap(), 4);
+        assert_eq!(*order.lock().unwrap(), [0, u32::MAX, 1, 2, 3, 4]);
+    }
+
+    /// Fill a single-slot mailbox behind a blocked command, then send one more
+    /// command with the given overflow policy. Returns the outcome of the
+    /// queued and of the extra command, and the order commands were handled in.
+    async fn overflow(
+        overflow: Overflow,
+    ) -> (Result<u32, CqrsError>, Result<u32, CqrsError>, Vec<u32>) {
+        let release = Arc::new(Notify::new());
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let on_command = {
+            let (release, handled) = (release.clone(), handled.clone());
+            move |Add(n)| {
+                let (release, handled) = (release.clone(), handled.clone());
+                async move {
+                    handled.lock().unwrap().push(n);
+                    if n == 0 {
+                        release.notified().await;
+                    }
+                    n
+                }
+            }
+        };
+        let (address, mailbox) = bounded(1, on_command, |Wait| async {});
+        tokio::spawn(mailbox.with_overflow(overflow).run(1, |_| {}));
+
+        address.notify(Add(0)).await.unwrap();
+        while handled.lock().unwrap().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        let queued = tokio::spawn({
+            let address = address.clone();
+            async move { address.command(Add(1)).await }
+        });
+        while address.commands.0.state().queue.is_empty() {
+            tokio::task::yield_now().await;
+        }
+        let extra = tokio::spawn({
+            let address = address.clone();
+            async move { address.command(Add(2)).await }
+        });
+        tokio::task::yield_now().await;
+
+        release.notify_one();
+        let queued = timeout(Duration::from_secs(1), queued).await.unwrap().unwrap();
+        let extra = timeout(Duration::from_secs(1), extra).await.unwrap().unwrap();
+        let handled = handled.lock().unwrap().clone();
+        (queued, extra, handled)
+    }
+
+    #[tokio::test]
+    async fn overflow_block() {
+        let (queued, extra, handled) = overflow(Overflow::Block).await;
+        assert_eq!(queued.unwrap(), 1);
+        assert_eq!(extra.unwrap(), 2);
+        assert_eq!(handled, [0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn overflow_drop_oldest() {
+        let (queued, extra, handled) = overflow(Overflow::DropOldest).await;
+        assert!(matches!(queued, Err(CqrsError::Dropped)));
+        assert_eq!(extra.unwrap(), 2);
+        assert_eq!(handled, [0, 2]);
+    }
+
+    #[tokio::test]
+    async fn overflow_reject() {
+        let (queued, extra, handled) = overflow(Overflow::Reject).await;
+        assert_eq!(queued.unwrap(), 1);
+        assert!(matches!(extra, Err(CqrsError::Overloaded)));
+        assert_eq!(handled, [0, 1]);
+    }
+
+    #[test]
+    fn parse_scheduling() {
+        assert_eq!("queries-first".parse::<Scheduling>().unwrap(), Scheduling::QueriesFirst);
+        assert_eq!("round-robin".parse::<Scheduling>().unwrap(), Scheduling::default());
+        assert_eq!(
+            "weighted:3:1".parse::<Scheduling>().unwrap(),
+            Scheduling::Weighted { queries: 3, commands: 1 }
+        );
+        assert!("weighted:0:0".parse::<Scheduling>().is_err());
+        assert!("fifo".parse::<Scheduling>().is_err());
+    }
 }
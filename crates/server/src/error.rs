@@ -1,10 +1,12 @@
 use thiserror::Error;
 
-use crate::{http::HttpError, ingest::IngestError, storage::StorageError};
+use crate::{auth::AuthError, http::HttpError, ingest::IngestError, storage::StorageError};
 
 /// Parent of all server errors.
 #[derive(Debug, Error)]
 pub enum ServerError {
+    #[error("authentication error")]
+    Auth(#[from] AuthError),
     #[error("HTTP server error")]
     Http(#[from] HttpError),
     #[error("ingest server error")]
@@ -1,39 +1,249 @@
 //! The HTTP server providing the public API.
 
-use std::net::SocketAddr;
+mod admin;
+mod bulk;
+pub mod compression;
+pub mod conditional;
+pub mod cors;
+mod export;
+pub mod pagination;
+pub mod request_id;
+pub mod ws;
+
+use std::{
+    net::SocketAddr,
+    ops::{Bound, RangeBounds},
+    time::Duration,
+};
 
 use axum::{
+    async_trait,
+    body::Body,
     extract,
-    http::StatusCode,
-    routing::{get, Router},
+    handler::Handler,
+    http::{header, request::Parts, HeaderMap, StatusCode},
+    middleware,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::{get, post, Router},
     Extension, Json,
 };
-use serde::Deserialize;
-use shared::data::{SourceId, Status};
+use futures_util::{stream, Stream, StreamExt, TryStreamExt};
+use geo_types::{Coord, Rect};
+use serde::{Deserialize, Deserializer, Serialize};
+use shared::data::{
+    timestamp,
+    units::{UnitSystem, WithUnits},
+    SourceId, Status, TenantId,
+};
 use thiserror::Error;
-use tokio::net::TcpListener;
+use time::{Date, Month, OffsetDateTime};
+use tokio::{net::TcpListener, sync::broadcast::error::RecvError};
 use tower_http::trace::TraceLayer;
-use tracing::{error, info};
+use tracing::{debug, error, info, warn};
 
-use crate::storage::{StorageCommand, StorageHandler};
+use self::{
+    conditional::Validators,
+    pagination::{CursorSecret, Page, PageQuery},
+};
+use crate::{
+    auth::AdminKey,
+    cq::CqrsError,
+    ingest::{IngestError, Pipeline},
+    metrics,
+    query::Filter,
+    storage::{
+        aggregate::Aggregate, report::DailyDistance, AggregateStatuses, DistanceReport,
+        GetAllStatuses, GetCellStatuses, GetStatuses, HeatmapQuery, LatestMany, LatestVersion,
+        QueryResult, StorageCommand, StorageError, StorageHandler, StorageQuery, StorageStats,
+    },
+};
 
 #[derive(Debug, Error)]
 pub enum HttpError {
     #[error("internal HTTP server error")]
     Internal(#[from] std::io::Error),
+    #[error("invalid CORS origin: {origin}")]
+    InvalidOrigin { origin: String },
+    #[error("CORS credentials can't be allowed for any origin")]
+    CorsCredentials,
 }
 
 pub type Result<T> = std::result::Result<T, HttpError>;
 
+/// Settings of [`listen`].
+#[derive(Clone)]
+pub struct HttpConfig {
+    /// How long to wait for storage before failing a request.
+    pub timeout: Duration,
+    /// Secret that pagination cursors of list endpoints are signed with.
+    pub cursors: CursorSecret,
+    /// Cross-origin requests are refused by browsers if `None`.
+    pub cors: Option<cors::CorsConfig>,
+    /// How many times larger than the compressed body a decompressed one may
+    /// be, see [`compression::decompress`].
+    pub max_decompression_ratio: u64,
+    /// Key of [`Admin`] clients. The `/admin` endpoints aren't served if
+    /// `None`.
+    pub admin_key: Option<AdminKey>,
+}
+
+/// Storage access shared by request handlers.
+#[derive(Clone)]
+struct StorageClient {
+    handler: StorageHandler,
+    /// How long to wait for storage before giving up on a request.
+    timeout: Duration,
+}
+
+/// Tenant of the client making a request. If API keys are configured, clients
+/// have to present one in an `Authorization: Bearer <key>` header, and get
+/// `401 Unauthorized` otherwise.
+struct Tenant(TenantId);
+
+#[async_trait]
+impl<S: Send + Sync> extract::FromRequestParts<S> for Tenant {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &S,
+    ) -> std::result::Result<Self, Self::Rejection> {
+        let Some(pipeline) = parts.extensions.get::<Pipeline>() else {
+            error!("Ingest pipeline is missing from request extensions");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        };
+        match pipeline.authenticate("http", bearer(parts)) {
+            Ok(tenant_id) => Ok(Self(tenant_id)),
+            Err(err) => {
+                debug!(%err, "Rejected unauthenticated request");
+                Err(StatusCode::UNAUTHORIZED)
+            }
+        }
+    }
+}
+
+/// Key presented in an `Authorization: Bearer <key>` header, if any.
+fn bearer(parts: &Parts) -> Option<&str> {
+    let value = parts.headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    value.strip_prefix("Bearer ")
+}
+
+/// Client allowed to use the `/admin` endpoints, which aren't scoped to a
+/// single tenant. Clients have to present the [`AdminKey`] in an
+/// `Authorization: Bearer <key>` header, and get `401 Unauthorized` otherwise.
+/// API keys of tenants aren't accepted.
+struct Admin;
+
+#[async_trait]
+impl<S: Send + Sync> extract::FromRequestParts<S> for Admin {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &S,
+    ) -> std::result::Result<Self, Self::Rejection> {
+        let Some(admin_key) = parts.extensions.get::<AdminKey>() else {
+            error!("Admin key is missing from request extensions");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        };
+        match admin_key.verify(bearer(parts)) {
+            Ok(()) => Ok(Self),
+            Err(err) => {
+                warn!(%err, "Rejected admin request");
+                Err(StatusCode::UNAUTHORIZED)
+            }
+        }
+    }
+}
+
+/// Unit system that endpoints returning statuses convert measurements to, as
+/// requested with `?units=metric|nautical|imperial`. Statuses are returned in
+/// the plain [`Status`] representation, with SI values, if not specified.
+#[derive(Debug, Deserialize)]
+struct UnitsQuery {
+    units: Option<UnitSystem>,
+}
+
+/// A [`Status`] in a response, with measurements in the requested units.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum StatusView {
+    Plain(Status),
+    WithUnits(WithUnits),
+}
+
+impl StatusView {
+    fn new(status: Status, units: Option<UnitSystem>) -> Self {
+        match units {
+            Some(units) => Self::WithUnits(WithUnits(status, units)),
+            None => Self::Plain(status),
+        }
+    }
+
+    fn many(statuses: Vec<Status>, units: Option<UnitSystem>) -> Vec<Self> {
+        statuses.into_iter().map(|status| Self::new(status, units)).collect()
+    }
+}
+
 /// Bind to the specified network address and start serving HTTP requests.
-#[tracing::instrument(skip(handler))]
-pub async fn listen(addr: &SocketAddr, handler: StorageHandler) -> Result<()> {
+/// Requests that storage doesn't respond to within the configured timeout
+/// fail with `504 Gateway Timeout`, and ones rejected by a full storage queue
+/// with `503 Service Unavailable`. All endpoints other than `/`, `/metrics` and
+/// `/admin` ones are scoped to the [`Tenant`] of the client. `/admin` ones are
+/// only served if an admin key is configured.
+///
+/// Each request is assigned an ID, see [`request_id::propagate`], and error
+/// responses come with problem details bodies. Statuses may be submitted
+/// compressed, see [`compression::decompress`], or pushed over a WebSocket,
+/// see [`ws`].
+#[tracing::instrument(skip(handler, pipeline, cfg))]
+pub async fn listen(
+    addr: &SocketAddr,
+    handler: StorageHandler,
+    pipeline: Pipeline,
+    cfg: HttpConfig,
+) -> Result<()> {
+    let HttpConfig { timeout, cursors, cors, max_decompression_ratio, admin_key } = cfg;
+    let cors = cors.as_ref().map(cors::CorsConfig::layer).transpose()?;
+    let decompress =
+        middleware::from_fn_with_state(max_decompression_ratio, compression::decompress);
     // Routes are listed from least specific to most specific.
     let app = Router::new()
         .route("/", get(hello))
-        .route("/status", get(latest_status).post(submit_status))
-        .layer(Extension(handler))
-        .layer(TraceLayer::new_for_http());
+        .route("/metrics", get(metrics))
+        .route("/stats", get(stats))
+        .route("/status", get(latest_status).post(submit_status.layer(decompress.clone())))
+        .route("/status/stream", post(stream_statuses.layer(decompress.clone())))
+        .route("/ws/ingest", get(ws_ingest))
+        .route("/status/:source_id/history", get(status_history))
+        .route("/statuses", get(all_statuses).post(submit_statuses.layer(decompress)))
+        .route("/sources", get(list_sources))
+        .route("/status/:source_id/export", get(export_history))
+        .route("/sources/:source_id/aggregate", get(aggregate_history))
+        .route("/status/:source_id/watch", get(watch_status))
+        .route("/reports/distance", get(distance_report))
+        .route("/query/latest", post(query_latest))
+        .route("/query/cell/:cell", get(query_cell))
+        .route("/query/heatmap", get(query_heatmap));
+    let app = match admin_key {
+        Some(key) => app.nest("/admin", admin::routes(key)),
+        None => app,
+    };
+    let app = app
+        .layer(Extension(StorageClient { handler, timeout }))
+        .layer(Extension(pipeline))
+        .layer(Extension(cursors));
+    // Answer preflight requests before they reach any handler.
+    let app = match cors {
+        Some(cors) => app.layer(cors),
+        None => app,
+    };
+    let app = app
+        .layer(TraceLayer::new_for_http().make_span_with(request_id::make_span))
+        .layer(middleware::from_fn(request_id::propagate));
 
     info!("Starting HTTP server at http://{}:{}...", addr.ip(), addr.port());
 
@@ -56,29 +266,639 @@ async fn hello(query: Option<extract::Query<HelloQuery>>) -> String {
     }
 }
 
-#[tracing::instrument(skip(handler))]
+async fn metrics() -> String {
+    metrics::render()
+}
+
+#[tracing::instrument(skip(storage, pipeline))]
 async fn submit_status(
-    extract::Extension(handler): extract::Extension<StorageHandler>,
+    Tenant(tenant_id): Tenant,
+    extract::Extension(storage): extract::Extension<StorageClient>,
+    extract::Extension(pipeline): extract::Extension<Pipeline>,
     extract::Json(status): extract::Json<Status>,
 ) -> StatusCode {
-    match handler.command(StorageCommand::PersistStatus(status)).await {
+    let status = Status { received_at: Some(OffsetDateTime::now_utc()), tenant_id, ..status };
+    match pipeline.accept_within(status, storage.timeout).await {
         Ok(_) => StatusCode::OK,
-        Err(err) => {
+        Err(err) => write_error(err),
+    }
+}
+
+/// Persist a JSON array of statuses, all or nothing. Responds with the number
+/// of statuses accepted, and of those rejected for implausible timestamps.
+#[tracing::instrument(skip(storage, pipeline, statuses), fields(count = statuses.len()))]
+async fn submit_statuses(
+    Tenant(tenant_id): Tenant,
+    extract::Extension(storage): extract::Extension<StorageClient>,
+    extract::Extension(pipeline): extract::Extension<Pipeline>,
+    extract::Json(statuses): extract::Json<Vec<Status>>,
+) -> std::result::Result<Json<bulk::Progress>, StatusCode> {
+    let received_at = Some(OffsetDateTime::now_utc());
+    let count = statuses.len() as u64;
+    let statuses = statuses.into_iter().map(|s| Status { received_at, tenant_id, ..s }).collect();
+    let rejected =
+        pipeline.accept_batch_within(statuses, storage.timeout).await.map_err(write_error)? as u64;
+    let progress = bulk::Progress { accepted: count - rejected, rejected, done: true, error: None };
+    Ok(Json(progress))
+}
+
+/// Response status of a failed write.
+fn write_error(err: IngestError) -> StatusCode {
+    match err {
+        IngestError::Internal(CqrsError::Timeout) => {
+            error!("Timed out writing status update");
+            StatusCode::GATEWAY_TIMEOUT
+        }
+        IngestError::Internal(CqrsError::Overloaded) => {
+            warn!("Rejected status update, storage is overloaded");
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+        IngestError::InvalidTimestamp { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+        err => {
             error!(%err, "Failed to write status update");
             StatusCode::INTERNAL_SERVER_ERROR
         }
     }
 }
 
+/// Persist a long stream of newline-delimited JSON or CBOR sequence records,
+/// reporting progress along the way. See [`bulk`] for details.
+#[tracing::instrument(skip(storage, pipeline, headers, body))]
+async fn stream_statuses(
+    Tenant(tenant_id): Tenant,
+    extract::Extension(storage): extract::Extension<StorageClient>,
+    extract::Extension(pipeline): extract::Extension<Pipeline>,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    let format = bulk::format(&headers);
+    let progress = bulk::ingest(body, format, tenant_id, pipeline, storage.timeout);
+    ([(header::CONTENT_TYPE, bulk::CONTENT_TYPE)], progress).into_response()
+}
+
+#[derive(Debug, Deserialize)]
+struct WsIngestQuery {
+    /// Whether to acknowledge every status.
+    #[serde(default)]
+    ack: bool,
+}
+
+/// Upgrade to a WebSocket that devices push statuses over. See [`ws`] for
+/// details.
+#[tracing::instrument(skip(storage, pipeline, upgrade))]
+async fn ws_ingest(
+    Tenant(tenant_id): Tenant,
+    extract::Extension(storage): extract::Extension<StorageClient>,
+    extract::Extension(pipeline): extract::Extension<Pipeline>,
+    extract::Query(WsIngestQuery { ack }): extract::Query<WsIngestQuery>,
+    upgrade: extract::WebSocketUpgrade,
+) -> Response {
+    let session = ws::Session { tenant_id, pipeline, timeout: storage.timeout, ack };
+    upgrade.on_upgrade(move |socket| ws::ingest(socket, session))
+}
+
 #[derive(Debug, Deserialize)]
 struct LatestStatusQuery {
     source_id: SourceId,
 }
 
-#[tracing::instrument]
+/// Latest status of a source, with an `ETag` and `Last-Modified` to make
+/// conditional requests with. If the client already has it, responds with
+/// `304 Not Modified` after merely looking up its version.
+#[tracing::instrument(skip(storage, headers))]
 async fn latest_status(
+    Tenant(tenant_id): Tenant,
+    extract::Extension(storage): extract::Extension<StorageClient>,
     extract::Query(query): extract::Query<LatestStatusQuery>,
-) -> std::result::Result<Json<Status>, StatusCode> {
-    // query.source_id
-    todo!()
+    extract::Query(UnitsQuery { units }): extract::Query<UnitsQuery>,
+    headers: HeaderMap,
+) -> std::result::Result<Response, StatusCode> {
+    let conditional = headers.contains_key(header::IF_NONE_MATCH)
+        || headers.contains_key(header::IF_MODIFIED_SINCE);
+    if conditional {
+        let get = StorageQuery::LatestVersion(tenant_id, query.source_id);
+        let version = fetch(&storage, get, QueryResult::into_version).await?;
+        let validators = Validators::new(version.ok_or(StatusCode::NOT_FOUND)?, units);
+        if validators.is_fresh(&headers) {
+            return Ok(validators.not_modified());
+        }
+    }
+    let get = StorageQuery::Latest(tenant_id, query.source_id);
+    let status =
+        fetch(&storage, get, QueryResult::into_latest).await?.ok_or(StatusCode::NOT_FOUND)?;
+    // Taken from the status itself, which may be newer than the version.
+    let validators = Validators::new(LatestVersion::of(&status), units);
+    Ok((validators.headers(), Json(StatusView::new(status, units))).into_response())
+}
+
+/// Time range of a history query, as inclusive UNIX timestamps or RFC 3339
+/// strings. Unbounded on either side if not specified.
+#[derive(Debug, Deserialize)]
+struct HistoryQuery {
+    #[serde(default, with = "timestamp::option")]
+    from: Option<OffsetDateTime>,
+    #[serde(default, with = "timestamp::option")]
+    to: Option<OffsetDateTime>,
+    /// Further narrows the statuses down by when the server received them.
+    /// Statuses stored without a receive time never match.
+    #[serde(default, with = "timestamp::option")]
+    received_from: Option<OffsetDateTime>,
+    #[serde(default, with = "timestamp::option")]
+    received_to: Option<OffsetDateTime>,
+    /// Filter expression, see [`crate::query`].
+    filter: Option<Filter>,
+}
+
+impl HistoryQuery {
+    fn timestamps(&self) -> (Bound<OffsetDateTime>, Bound<OffsetDateTime>) {
+        (bound(self.from), bound(self.to))
+    }
+
+    /// Whether a status matches the receive time range, if one is given.
+    fn matches_received(&self, status: &Status) -> bool {
+        if self.received_from.is_none() && self.received_to.is_none() {
+            return true;
+        }
+        let received = (bound(self.received_from), bound(self.received_to));
+        status.received_at.is_some_and(|ts| received.contains(&ts))
+    }
+}
+
+fn bound(ts: Option<OffsetDateTime>) -> Bound<OffsetDateTime> {
+    ts.map_or(Bound::Unbounded, Bound::Included)
+}
+
+/// A page of the history of a source, in chronological order. See
+/// [`pagination`] for walking all of it.
+#[tracing::instrument(skip(storage, cursors))]
+async fn status_history(
+    Tenant(tenant_id): Tenant,
+    extract::Extension(storage): extract::Extension<StorageClient>,
+    extract::Extension(cursors): extract::Extension<CursorSecret>,
+    extract::Path(source_id): extract::Path<SourceId>,
+    extract::Query(query): extract::Query<HistoryQuery>,
+    extract::Query(page): extract::Query<PageQuery>,
+    extract::Query(UnitsQuery { units }): extract::Query<UnitsQuery>,
+) -> std::result::Result<Page<StatusView>, StatusCode> {
+    let HistoryQuery { from, to, received_from, received_to, .. } = query;
+    let filter = (tenant_id, source_id, from, to, received_from, received_to, &query.filter);
+    let mut timestamps = query.timestamps();
+    if let Some(nanos) = page.after(&cursors, &filter).map_err(bad_cursor)? {
+        let last = OffsetDateTime::from_unix_timestamp_nanos(nanos)
+            .map_err(|_| bad_cursor(pagination::CursorError::Malformed))?;
+        timestamps.0 = Bound::Excluded(last);
+    }
+    let filter = query.filter.clone();
+    let get =
+        StorageQuery::StreamStatuses(GetStatuses { tenant_id, source_id, timestamps, filter });
+    let statuses: Vec<Status> = fetch(&storage, get, QueryResult::into_stream)
+        .await?
+        .try_filter(|s| std::future::ready(query.matches_received(s)))
+        .take(page.limit() + 1)
+        .try_collect()
+        .await
+        .map_err(|err| {
+            error!(%err, "Failed to read status history");
+            storage_error_status(&err)
+        })?;
+    let key = |s: &Status| s.timestamp.unix_timestamp_nanos();
+    let filter = (tenant_id, source_id, from, to, received_from, received_to, &query.filter);
+    let page = Page::new(statuses, page.limit(), key, &cursors, &filter);
+    Ok(page.map(|statuses| StatusView::many(statuses, units)))
+}
+
+/// Time range of a query over all sources, as inclusive UNIX timestamps or
+/// RFC 3339 strings. Unbounded on either side if not specified.
+#[derive(Debug, Deserialize)]
+struct TimeRangeQuery {
+    #[serde(default, with = "timestamp::option")]
+    from: Option<OffsetDateTime>,
+    #[serde(default, with = "timestamp::option")]
+    to: Option<OffsetDateTime>,
+}
+
+/// A page of the statuses of all sources of the tenant, ordered by timestamp
+/// and then by source ID, e.g. everything received across the fleet in the
+/// last few minutes. Archived statuses aren't included.
+#[tracing::instrument(skip(storage, cursors))]
+async fn all_statuses(
+    Tenant(tenant_id): Tenant,
+    extract::Extension(storage): extract::Extension<StorageClient>,
+    extract::Extension(cursors): extract::Extension<CursorSecret>,
+    extract::Query(TimeRangeQuery { from, to }): extract::Query<TimeRangeQuery>,
+    extract::Query(page): extract::Query<PageQuery>,
+    extract::Query(UnitsQuery { units }): extract::Query<UnitsQuery>,
+) -> std::result::Result<Page<StatusView>, StatusCode> {
+    let filter = ("statuses", tenant_id, from, to);
+    let after = match page.after(&cursors, &filter).map_err(bad_cursor)? {
+        Some((nanos, source_id)) => {
+            let last = OffsetDateTime::from_unix_timestamp_nanos(nanos)
+                .map_err(|_| bad_cursor(pagination::CursorError::Malformed))?;
+            Some((last, source_id))
+        }
+        None => None,
+    };
+    let timestamps = (bound(from), bound(to));
+    let query = GetAllStatuses { tenant_id, timestamps, after, limit: page.limit() + 1 };
+    let statuses = fetch_statuses(&storage, StorageQuery::GetAllStatuses(query)).await?;
+    let key = |s: &Status| (s.timestamp.unix_timestamp_nanos(), s.source_id);
+    let page = Page::new(statuses, page.limit(), key, &cursors, &filter);
+    Ok(page.map(|statuses| StatusView::many(statuses, units)))
+}
+
+#[derive(Debug, Deserialize)]
+struct SourcesQuery {
+    /// Only list sources whose latest status matches this filter expression,
+    /// see [`crate::query`].
+    filter: Option<Filter>,
+}
+
+/// A page of the latest statuses of all sources of the tenant, ordered by
+/// source ID.
+#[tracing::instrument(skip(storage, cursors))]
+async fn list_sources(
+    Tenant(tenant_id): Tenant,
+    extract::Extension(storage): extract::Extension<StorageClient>,
+    extract::Extension(cursors): extract::Extension<CursorSecret>,
+    extract::Query(SourcesQuery { filter }): extract::Query<SourcesQuery>,
+    extract::Query(page): extract::Query<PageQuery>,
+    extract::Query(UnitsQuery { units }): extract::Query<UnitsQuery>,
+) -> std::result::Result<Page<StatusView>, StatusCode> {
+    let cursor_filter = ("sources", tenant_id, &filter);
+    let after: Option<SourceId> = page.after(&cursors, &cursor_filter).map_err(bad_cursor)?;
+    let query = LatestMany { tenant_id, source_ids: None, bbox: None, filter: filter.clone() };
+    let mut statuses = fetch_statuses(&storage, StorageQuery::LatestMany(query)).await?;
+    statuses.sort_unstable_by_key(|s| s.source_id);
+    let start = after.map_or(0, |after| statuses.partition_point(|s| s.source_id <= after));
+    let statuses = statuses.drain(start..).take(page.limit() + 1).collect();
+    let page = Page::new(statuses, page.limit(), |s| s.source_id, &cursors, &cursor_filter);
+    Ok(page.map(|statuses| StatusView::many(statuses, units)))
+}
+
+fn bad_cursor(err: pagination::CursorError) -> StatusCode {
+    warn!(%err, "Rejected pagination cursor");
+    StatusCode::BAD_REQUEST
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportQuery {
+    #[serde(default)]
+    format: export::ExportFormat,
+    /// Longest time between statuses that doesn't split the track, e.g.
+    /// `5m`. Defaults to [`export::DEFAULT_MAX_GAP`].
+    max_gap: Option<String>,
+}
+
+/// Same as the history endpoint, but streamed in the requested
+/// [`export::ExportFormat`] rather than collected into a JSON array, which
+/// suits exporting long time ranges.
+#[tracing::instrument(skip(storage))]
+async fn export_history(
+    Tenant(tenant_id): Tenant,
+    extract::Extension(storage): extract::Extension<StorageClient>,
+    extract::Path(source_id): extract::Path<SourceId>,
+    extract::Query(query): extract::Query<HistoryQuery>,
+    extract::Query(ExportQuery { format, max_gap }): extract::Query<ExportQuery>,
+) -> std::result::Result<Response, StatusCode> {
+    let max_gap = match max_gap {
+        Some(gap) => humantime::parse_duration(&gap)
+            .ok()
+            .and_then(|gap| time::Duration::try_from(gap).ok())
+            .ok_or(StatusCode::BAD_REQUEST)?,
+        None => export::DEFAULT_MAX_GAP,
+    };
+    let (timestamps, filter) = (query.timestamps(), query.filter.clone());
+    let get =
+        StorageQuery::StreamStatuses(GetStatuses { tenant_id, source_id, timestamps, filter });
+    let statuses = fetch(&storage, get, QueryResult::into_stream).await?;
+    let statuses = statuses.filter(move |status| {
+        let matches = status.as_ref().map_or(true, |s| query.matches_received(s));
+        std::future::ready(matches)
+    });
+    let body = export::body(format, source_id, max_gap, statuses);
+    Ok(([(header::CONTENT_TYPE, format.content_type())], body).into_response())
+}
+
+/// Stream statuses of a single source as server-sent events as they arrive,
+/// each one a JSON-encoded [`Status`].
+#[tracing::instrument(skip(pipeline))]
+async fn watch_status(
+    Tenant(tenant_id): Tenant,
+    extract::Extension(pipeline): extract::Extension<Pipeline>,
+    extract::Path(source_id): extract::Path<SourceId>,
+    extract::Query(UnitsQuery { units }): extract::Query<UnitsQuery>,
+) -> Sse<impl Stream<Item = std::result::Result<Event, axum::Error>>> {
+    let statuses = stream::unfold(pipeline.watch(), move |mut rx| async move {
+        loop {
+            match rx.recv().await {
+                Ok(status) if (status.tenant_id, status.source_id) == (tenant_id, source_id) => {
+                    return Some((status, rx))
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(missed)) => warn!(missed, "Watcher fell behind"),
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+    Sse::new(statuses.map(move |status| Event::default().json_data(StatusView::new(status, units))))
+        .keep_alive(KeepAlive::default())
+}
+
+/// Time range and bucket length of an aggregate query.
+#[derive(Debug, Deserialize)]
+struct AggregateQuery {
+    #[serde(default, with = "timestamp::option")]
+    from: Option<OffsetDateTime>,
+    #[serde(default, with = "timestamp::option")]
+    to: Option<OffsetDateTime>,
+    /// Length of the buckets, e.g. `15m` or `1h`.
+    bucket: String,
+    /// Only aggregate statuses matching this filter expression, see
+    /// [`crate::query`].
+    filter: Option<Filter>,
+}
+
+/// Summaries of the history of a source over buckets of the requested
+/// length.
+#[tracing::instrument(skip(storage))]
+async fn aggregate_history(
+    Tenant(tenant_id): Tenant,
+    extract::Extension(storage): extract::Extension<StorageClient>,
+    extract::Path(source_id): extract::Path<SourceId>,
+    extract::Query(query): extract::Query<AggregateQuery>,
+) -> std::result::Result<Json<Vec<Aggregate>>, StatusCode> {
+    let bucket = humantime::parse_duration(&query.bucket).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let timestamps = (bound(query.from), bound(query.to));
+    let query = GetStatuses { tenant_id, source_id, timestamps, filter: query.filter };
+    let query = StorageQuery::Aggregate(AggregateStatuses { query, bucket });
+    fetch(&storage, query, QueryResult::into_aggregates).await.map(Json)
+}
+
+/// Grouping of report rows. Only daily reports are supported for now.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum GroupBy {
+    #[default]
+    Day,
+}
+
+#[derive(Debug, Deserialize)]
+struct DistanceQuery {
+    /// First day of the report, `YYYY-MM-DD`.
+    #[serde(deserialize_with = "deserialize_date")]
+    from: Date,
+    /// Last day of the report, inclusive.
+    #[serde(deserialize_with = "deserialize_date")]
+    to: Date,
+    #[serde(default)]
+    group_by: GroupBy,
+}
+
+/// Parses a `YYYY-MM-DD` calendar date.
+fn deserialize_date<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Date, D::Error> {
+    use serde::de::Error;
+
+    let s = String::deserialize(deserializer)?;
+    let invalid = || D::Error::custom(format!("invalid date: {s}; expected YYYY-MM-DD"));
+    let mut parts = s.splitn(3, '-');
+    let mut next = || parts.next().and_then(|part| part.parse::<u16>().ok());
+    let (year, month, day) = (next(), next(), next());
+    let (Some(year), Some(month), Some(day)) = (year, month, day) else {
+        return Err(invalid());
+    };
+    let month =
+        u8::try_from(month).ok().and_then(|m| Month::try_from(m).ok()).ok_or_else(invalid)?;
+    let day = u8::try_from(day).map_err(|_| invalid())?;
+    Date::from_calendar_date(year.into(), month, day).map_err(|_| invalid())
+}
+
+/// Distance travelled by every source of the tenant per day, in meters, along
+/// with odometer distance and time spent with ignition on, where reported.
+#[tracing::instrument(skip(storage))]
+async fn distance_report(
+    Tenant(tenant_id): Tenant,
+    extract::Extension(storage): extract::Extension<StorageClient>,
+    extract::Query(query): extract::Query<DistanceQuery>,
+) -> std::result::Result<Json<Vec<DailyDistance>>, StatusCode> {
+    let DistanceQuery { from, to, group_by: GroupBy::Day } = query;
+    let query = StorageQuery::DistanceReport(DistanceReport { tenant_id, from, to });
+    fetch(&storage, query, QueryResult::into_distances).await.map(Json)
+}
+
+#[tracing::instrument(skip(storage))]
+async fn stats(
+    Tenant(tenant_id): Tenant,
+    extract::Extension(storage): extract::Extension<StorageClient>,
+) -> std::result::Result<Json<StorageStats>, StatusCode> {
+    fetch(&storage, StorageQuery::Stats(Some(tenant_id)), QueryResult::into_stats).await.map(Json)
+}
+
+/// Request body of the fleet snapshot query. Either `source_ids` or
+/// `all: true` must be specified.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+struct LatestManyQuery {
+    #[serde(default)]
+    source_ids: Vec<SourceId>,
+    #[serde(default)]
+    all: bool,
+    /// Bounding box as [west, south, east, north], same as in GeoJSON, but
+    /// not crossing the antimeridian.
+    bbox: Option<[f64; 4]>,
+    /// Filter expression that latest statuses have to match, see
+    /// [`crate::query`].
+    filter: Option<Filter>,
+}
+
+#[tracing::instrument(skip(storage))]
+async fn query_latest(
+    Tenant(tenant_id): Tenant,
+    extract::Extension(storage): extract::Extension<StorageClient>,
+    extract::Query(UnitsQuery { units }): extract::Query<UnitsQuery>,
+    extract::Json(query): extract::Json<LatestManyQuery>,
+) -> std::result::Result<Json<Vec<StatusView>>, StatusCode> {
+    let source_ids = match (query.all, query.source_ids.is_empty()) {
+        (true, true) => None,
+        (false, false) => Some(query.source_ids),
+        _ => return Err(StatusCode::UNPROCESSABLE_ENTITY),
+    };
+    let bbox = match query.bbox {
+        Some([west, south, east, north]) => {
+            Some(bbox(west, south, east, north).ok_or(StatusCode::UNPROCESSABLE_ENTITY)?)
+        }
+        None => None,
+    };
+    let filter = query.filter;
+    let query = StorageQuery::LatestMany(LatestMany { tenant_id, source_ids, bbox, filter });
+    let statuses = fetch_statuses(&storage, query).await?;
+    Ok(Json(StatusView::many(statuses, units)))
+}
+
+/// Bounding box from its edges, or `None` if they're the wrong way around. A
+/// box with `west > east` would cross the antimeridian, which isn't supported,
+/// and mustn't be turned into its complement instead.
+fn bbox(west: f64, south: f64, east: f64, north: f64) -> Option<Rect<f64>> {
+    (west <= east && south <= north)
+        .then(|| Rect::new(Coord { x: west, y: south }, Coord { x: east, y: north }))
+}
+
+#[tracing::instrument(skip(storage))]
+async fn query_cell(
+    Tenant(tenant_id): Tenant,
+    extract::Extension(storage): extract::Extension<StorageClient>,
+    extract::Path(cell): extract::Path<String>,
+    extract::Query(UnitsQuery { units }): extract::Query<UnitsQuery>,
+) -> std::result::Result<Json<Vec<StatusView>>, StatusCode> {
+    let query = StorageQuery::GetCellStatuses(GetCellStatuses { tenant_id, cell });
+    let statuses = fetch_statuses(&storage, query).await?;
+    Ok(Json(StatusView::many(statuses, units)))
+}
+
+/// Default length of heatmap cells, roughly 1.2km x 0.6km.
+const DEFAULT_HEATMAP_CELLS: usize = 6;
+
+const CBOR: &str = "application/cbor";
+
+#[derive(Debug, Deserialize)]
+struct HeatmapParams {
+    /// Bounding box as `west,south,east,north`, not crossing the antimeridian.
+    #[serde(deserialize_with = "deserialize_bbox")]
+    bbox: Rect<f64>,
+    /// Length of the geohash cells.
+    #[serde(default = "default_heatmap_cells")]
+    cells: usize,
+    #[serde(default, with = "timestamp::option")]
+    from: Option<OffsetDateTime>,
+    #[serde(default, with = "timestamp::option")]
+    to: Option<OffsetDateTime>,
+}
+
+fn default_heatmap_cells() -> usize {
+    DEFAULT_HEATMAP_CELLS
+}
+
+/// Parses a `west,south,east,north` bounding box.
+fn deserialize_bbox<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Rect<f64>, D::Error> {
+    use serde::de::Error;
+
+    let s = String::deserialize(deserializer)?;
+    let invalid = || D::Error::custom(format!("invalid bbox: {s}; expected west,south,east,north"));
+    let edges: Vec<f64> = s
+        .split(',')
+        .map(|edge| edge.trim().parse().ok().filter(|edge: &f64| edge.is_finite()))
+        .collect::<Option<_>>()
+        .ok_or_else(invalid)?;
+    let [west, south, east, north] = edges[..] else {
+        return Err(invalid());
+    };
+    bbox(west, south, east, north).ok_or_else(invalid)
+}
+
+/// Point counts and dwell times over geohash cells within a bounding box, as
+/// JSON or, if the client accepts it, CBOR. Requires the spatial cell index.
+#[tracing::instrument(skip(storage, headers))]
+async fn query_heatmap(
+    Tenant(tenant_id): Tenant,
+    extract::Extension(storage): extract::Extension<StorageClient>,
+    headers: HeaderMap,
+    extract::Query(query): extract::Query<HeatmapParams>,
+) -> std::result::Result<Response, StatusCode> {
+    let HeatmapParams { bbox, cells, from, to } = query;
+    let timestamps = (bound(from), bound(to));
+    let query =
+        StorageQuery::Heatmap(HeatmapQuery { tenant_id, bbox, precision: cells, timestamps });
+    let heatmap = fetch(&storage, query, QueryResult::into_heatmap).await?;
+    let cbor = headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains(CBOR));
+    if !cbor {
+        return Ok(Json(heatmap).into_response());
+    }
+    let mut body = Vec::new();
+    ciborium::into_writer(&heatmap, &mut body).map_err(|err| {
+        error!(%err, "Failed to encode heatmap");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(([(header::CONTENT_TYPE, CBOR)], body).into_response())
+}
+
+/// Reclaim disk space left behind by removed data. Responds with
+/// `501 Not Implemented` if the storage engine doesn't support compaction.
+/// Not subject to the storage timeout, as compacting may take a while.
+#[tracing::instrument(skip(storage))]
+async fn compact_storage(
+    extract::Extension(storage): extract::Extension<StorageClient>,
+) -> StatusCode {
+    match storage.handler.command(StorageCommand::Compact).await {
+        Ok(Ok(())) => StatusCode::NO_CONTENT,
+        Ok(Err(err)) => {
+            error!(%err, "Failed to compact storage");
+            storage_error_status(&err)
+        }
+        Err(CqrsError::Overloaded) => {
+            warn!("Rejected compaction, storage is overloaded");
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+        Err(err) => {
+            error!(%err, "Failed to compact storage");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+async fn fetch_statuses(
+    storage: &StorageClient,
+    query: StorageQuery,
+) -> std::result::Result<Vec<Status>, StatusCode> {
+    fetch(storage, query, QueryResult::into_statuses).await
+}
+
+/// Run a storage query and extract the expected kind of [`QueryResult`].
+async fn fetch<T>(
+    storage: &StorageClient,
+    query: StorageQuery,
+    extract: fn(QueryResult) -> Option<T>,
+) -> std::result::Result<T, StatusCode> {
+    match storage.handler.query_timeout(query, storage.timeout).await {
+        Ok(Ok(result)) => extract(result).ok_or_else(|| {
+            error!("Storage returned an unexpected query result");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }),
+        Ok(Err(err)) => {
+            error!(%err, "Storage query failed");
+            Err(storage_error_status(&err))
+        }
+        Err(CqrsError::Timeout) => {
+            error!("Timed out querying storage");
+            Err(StatusCode::GATEWAY_TIMEOUT)
+        }
+        Err(CqrsError::Overloaded) => {
+            warn!("Rejected query, storage is overloaded");
+            Err(StatusCode::SERVICE_UNAVAILABLE)
+        }
+        Err(err) => {
+            error!(%err, "Failed to query storage");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Maps storage errors caused by invalid requests to matching HTTP status codes.
+fn storage_error_status(err: &StorageError) -> StatusCode {
+    match err {
+        StorageError::InvalidCell { .. }
+        | StorageError::InvalidCellPrecision { .. }
+        | StorageError::InvalidBucket
+        | StorageError::InvalidReportRange => StatusCode::BAD_REQUEST,
+        StorageError::CellIndexDisabled | StorageError::CompactionUnsupported => {
+            StatusCode::NOT_IMPLEMENTED
+        }
+        StorageError::Busy => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
 }
@@ -0,0 +1,124 @@
+//! Endpoints under `/admin` for operators to inspect and change the running
+//! server. They aren't scoped to a tenant, and are only open to [`Admin`]
+//! clients.
+
+use axum::{
+    extract,
+    http::StatusCode,
+    middleware,
+    routing::{delete, get, patch, post, Router},
+    Extension, Json,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use super::{compact_storage, fetch, Admin, StorageClient};
+use crate::{
+    auth::AdminKey,
+    ingest::{Pipeline, Session},
+    settings::{Settings, SettingsError},
+    storage::{DupeStrategy, QueryResult, StorageQuery, StorageStats},
+};
+
+/// Routes to nest under `/admin`, all of which reject clients not presenting
+/// `key`.
+pub fn routes(key: AdminKey) -> Router {
+    Router::new()
+        .route("/config", get(config))
+        .route("/settings", patch(update_settings))
+        .route("/sessions", get(sessions))
+        .route("/sessions/:id", delete(drop_session))
+        .route("/stats", get(stats))
+        .route("/storage/compact", post(compact_storage))
+        .route_layer(middleware::from_extractor::<Admin>())
+        .layer(Extension(key))
+}
+
+/// Effective configuration: the options the server was started with, and
+/// the current values of the settings that can be changed since.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Config {
+    /// Command-line options, in the same form as logged at startup.
+    options: String,
+    duplicates: DupeStrategy,
+    /// `None` if logging can't be reconfigured.
+    log_filter: Option<String>,
+}
+
+impl Config {
+    fn new(settings: &Settings) -> Self {
+        Self {
+            options: settings.config().to_owned(),
+            duplicates: settings.dupe_strategy().get(),
+            log_filter: settings.log_filter(),
+        }
+    }
+}
+
+async fn config(extract::Extension(pipeline): extract::Extension<Pipeline>) -> Json<Config> {
+    Json(Config::new(pipeline.settings()))
+}
+
+/// Settings to change, leaving out ones to keep as they are.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+struct SettingsUpdate {
+    duplicates: Option<DupeStrategy>,
+    /// Filter directives, same as in `RUST_LOG`.
+    log_filter: Option<String>,
+}
+
+/// Apply changed settings, returning the resulting configuration. Fails with
+/// `400 Bad Request` without changing anything if the log filter is invalid,
+/// and with `409 Conflict` if logging can't be reconfigured.
+#[tracing::instrument(skip(pipeline))]
+async fn update_settings(
+    extract::Extension(pipeline): extract::Extension<Pipeline>,
+    Json(update): Json<SettingsUpdate>,
+) -> Result<Json<Config>, StatusCode> {
+    let settings = pipeline.settings();
+    if let Some(directives) = &update.log_filter {
+        settings.set_log_filter(directives).map_err(|err| {
+            warn!(%err, "Rejected log filter");
+            match err {
+                SettingsError::LogFilterFixed => StatusCode::CONFLICT,
+                SettingsError::InvalidLogFilter { .. } => StatusCode::BAD_REQUEST,
+            }
+        })?;
+    }
+    if let Some(duplicates) = update.duplicates {
+        settings.dupe_strategy().set(duplicates);
+    }
+    info!(?update, "Changed runtime settings");
+    Ok(Json(Config::new(settings)))
+}
+
+/// Open TCP ingest connections.
+async fn sessions(
+    extract::Extension(pipeline): extract::Extension<Pipeline>,
+) -> Json<Vec<Session>> {
+    Json(pipeline.settings().connections().list())
+}
+
+/// Close a TCP ingest connection. Its client may well reconnect.
+#[tracing::instrument(skip(pipeline))]
+async fn drop_session(
+    extract::Extension(pipeline): extract::Extension<Pipeline>,
+    extract::Path(id): extract::Path<u64>,
+) -> StatusCode {
+    match pipeline.settings().connections().close(id) {
+        true => {
+            info!("Dropped TCP session");
+            StatusCode::NO_CONTENT
+        }
+        false => StatusCode::NOT_FOUND,
+    }
+}
+
+/// Statistics of all tenants together.
+async fn stats(
+    extract::Extension(storage): extract::Extension<StorageClient>,
+) -> Result<Json<StorageStats>, StatusCode> {
+    fetch(&storage, StorageQuery::Stats(None), QueryResult::into_stats).await.map(Json)
+}
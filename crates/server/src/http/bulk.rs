@@ -0,0 +1,265 @@
+//! Bulk ingest of long status streams, e.g. hours of backlog uploaded by a
+//! gateway over a single connection. The request body is newline-delimited
+//! JSON or a CBOR sequence, and records are decoded and persisted as they
+//! arrive rather than after the whole body has been read.
+//!
+//! The response body reports [`Progress`] as one JSON object per line, at
+//! least every [`PROGRESS_INTERVAL`] while the upload goes on, and once more
+//! at its end. As the response starts before the upload is done, its status is
+//! always `200 OK`, and failures are only reported on the last line. A gateway
+//! that gets cut off can resume past the records counted so far: records are
+//! persisted in batches that are either kept or rolled back as a whole, and
+//! only counted once kept.
+
+use std::time::Duration;
+
+use axum::{
+    body::{Body, BodyDataStream},
+    http::{header, HeaderMap},
+};
+use bytes::BytesMut;
+use futures_util::{stream, StreamExt};
+use serde::Serialize;
+use shared::data::{Status, TenantId};
+use time::OffsetDateTime;
+use tokio::time::{timeout_at, Instant};
+use tokio_util::codec::Decoder;
+use tracing::{debug, error};
+
+use crate::ingest::{IngestError, PayloadFormat, Pipeline, StatusDecoder};
+
+/// How often progress is reported while records keep arriving.
+pub const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+/// Upper limit of records persisted together.
+const BATCH_SIZE: usize = 256;
+
+/// Content type of the progress reports.
+pub const CONTENT_TYPE: &str = "application/x-ndjson";
+
+/// Progress of an upload.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Progress {
+    /// Records persisted so far.
+    pub accepted: u64,
+    /// Records dropped for implausible timestamps.
+    pub rejected: u64,
+    /// Set on the last line, once the whole body has been processed or the
+    /// upload has failed.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub done: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Format of records the body is expected to be in, from its content type.
+/// The format is still detected from the first bytes of the body, this only
+/// decides what to assume when there's no telling.
+pub fn format(headers: &HeaderMap) -> PayloadFormat {
+    let content_type = headers.get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok());
+    match content_type {
+        Some(content_type) if content_type.starts_with("application/cbor") => PayloadFormat::Cbor,
+        _ => PayloadFormat::Json,
+    }
+}
+
+/// An upload in progress.
+struct Upload {
+    chunks: BodyDataStream,
+    buf: BytesMut,
+    decoder: StatusDecoder,
+    /// Records decoded, but not persisted yet.
+    batch: Vec<Status>,
+    tenant_id: TenantId,
+    pipeline: Pipeline,
+    /// How long to wait for storage to persist a batch.
+    timeout: Duration,
+    progress: Progress,
+}
+
+impl Upload {
+    /// Read and persist records until it's time to report progress, or the
+    /// upload is over.
+    async fn advance(&mut self) -> Progress {
+        let deadline = Instant::now() + PROGRESS_INTERVAL;
+        loop {
+            let eof = match timeout_at(deadline, self.chunks.next()).await {
+                Err(_) => return self.progress.clone(),
+                Ok(Some(Ok(bytes))) => {
+                    self.buf.extend_from_slice(&bytes);
+                    false
+                }
+                Ok(Some(Err(err))) => {
+                    let err = std::io::Error::new(std::io::ErrorKind::Other, err);
+                    return self.fail(err.into());
+                }
+                Ok(None) => true,
+            };
+            if let Err(err) = self.decode(eof).await {
+                return self.fail(err);
+            }
+            if eof {
+                self.progress.done = true;
+                return self.progress.clone();
+            }
+            if Instant::now() >= deadline {
+                return self.progress.clone();
+            }
+        }
+    }
+
+    /// Decode all complete records buffered so far and persist them. At the
+    /// end of the body, incomplete ones are malformed.
+    async fn decode(&mut self, eof: bool) -> Result<(), IngestError> {
+        let received_at = Some(OffsetDateTime::now_utc());
+        loop {
+            let record = match eof {
+                true => self.decoder.decode_eof(&mut self.buf),
+                false => self.decoder.decode(&mut self.buf),
+            };
+            match record {
+                Ok(Some(status)) => {
+                    let tenant_id = self.tenant_id;
+                    self.batch.push(Status { received_at, tenant_id, ..status });
+                    if self.batch.len() >= BATCH_SIZE {
+                        self.flush().await?;
+                    }
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    // Records preceding the malformed one are still kept.
+                    self.flush().await?;
+                    return Err(err);
+                }
+            }
+        }
+        self.flush().await
+    }
+
+    async fn flush(&mut self) -> Result<(), IngestError> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let batch = std::mem::take(&mut self.batch);
+        let count = batch.len() as u64;
+        let rejected = self.pipeline.accept_batch_within(batch, self.timeout).await? as u64;
+        self.progress.accepted += count - rejected;
+        self.progress.rejected += rejected;
+        Ok(())
+    }
+
+    fn fail(&mut self, err: IngestError) -> Progress {
+        match err {
+            IngestError::Io(_) | IngestError::Codec(_) => {
+                debug!(%err, "Bulk upload failed");
+            }
+            _ => error!(%err, "Bulk upload failed"),
+        }
+        self.progress.done = true;
+        self.progress.error = Some(err.to_string());
+        self.progress.clone()
+    }
+}
+
+/// Decode and persist records of `body` as they arrive, returning the body of
+/// the response reporting [`Progress`].
+pub fn ingest(
+    body: Body,
+    format: PayloadFormat,
+    tenant_id: TenantId,
+    pipeline: Pipeline,
+    timeout: Duration,
+) -> Body {
+    let upload = Upload {
+        chunks: body.into_data_stream(),
+        buf: BytesMut::new(),
+        decoder: StatusDecoder::Detect(format),
+        batch: Vec::new(),
+        tenant_id,
+        pipeline,
+        timeout,
+        progress: Progress::default(),
+    };
+    let lines = stream::unfold(Some(upload), |upload| async move {
+        let mut upload = upload?;
+        let progress = upload.advance().await;
+        // Progress always serializes to JSON.
+        let mut line = serde_json::to_string(&progress).unwrap_or_default();
+        line.push('\n');
+        Some((Ok::<_, std::convert::Infallible>(line), (!progress.done).then_some(upload)))
+    });
+    Body::from_stream(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use axum::body::Body;
+    use shared::data::TenantId;
+
+    use super::{ingest, Progress};
+    use crate::{
+        cq,
+        ingest::{PayloadFormat, Pipeline},
+        storage::{QueryResult, StorageCommand, StorageHandler},
+    };
+
+    const JSON: &str =
+        r#"{"sourceId":"0aaec05a-0e7d-4fd5-abc0-0ba69e3cfe11","timestamp":1627364719}"#;
+
+    /// Uploads `body`, returning the number of statuses persisted and the
+    /// last progress line.
+    async fn upload(body: String) -> (usize, serde_json::Value) {
+        let persisted = Arc::new(Mutex::new(0));
+        let on_command = {
+            let persisted = persisted.clone();
+            move |cmd| {
+                let persisted = persisted.clone();
+                async move {
+                    if let StorageCommand::PersistBatch(statuses) = cmd {
+                        *persisted.lock().unwrap() += statuses.len();
+                    }
+                    Ok(())
+                }
+            }
+        };
+        let on_query = |_| async { Ok(QueryResult::Latest(None)) };
+        let (address, mailbox) = cq::bounded(8, on_command, on_query);
+        tokio::spawn(mailbox.run(1, |_| {}));
+        let pipeline = Pipeline::new(StorageHandler::from(address), None);
+
+        let progress = ingest(
+            Body::from(body),
+            PayloadFormat::Json,
+            TenantId::DEFAULT,
+            pipeline,
+            Duration::from_secs(1),
+        );
+        let bytes = axum::body::to_bytes(progress, usize::MAX).await.unwrap();
+        let last = String::from_utf8(bytes.to_vec()).unwrap().lines().last().unwrap().to_owned();
+        let count = *persisted.lock().unwrap();
+        (count, serde_json::from_str(&last).unwrap())
+    }
+
+    #[tokio::test]
+    async fn ndjson() {
+        let (persisted, progress) = upload(format!("{JSON}\n\n{JSON}\n{JSON}")).await;
+        assert_eq!(persisted, 3);
+        assert_eq!(progress, serde_json::json!({ "accepted": 3, "rejected": 0, "done": true }));
+
+        let (persisted, progress) = upload(format!("{JSON}\n{JSON}\n{{\"sourceId\"\n")).await;
+        assert_eq!(persisted, 2);
+        assert_eq!(progress["accepted"], 2);
+        assert!(progress["error"].is_string());
+    }
+
+    #[test]
+    fn progress_line() {
+        let progress = Progress { accepted: 1, ..Default::default() };
+        assert_eq!(serde_json::to_string(&progress).unwrap(), r#"{"accepted":1,"rejected":0}"#);
+    }
+}
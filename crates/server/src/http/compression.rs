@@ -0,0 +1,104 @@
+//! Compressed request bodies of the ingest endpoints, see
+//! [`crate::util::compression`].
+
+use std::io;
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use futures_util::TryStreamExt;
+use tokio_util::io::{ReaderStream, StreamReader};
+use tracing::debug;
+
+use crate::util::compression::Compression;
+
+/// Middleware decompressing request bodies sent with a `Content-Encoding` of
+/// `gzip` or `zstd`, as long as they don't expand past the ratio given as
+/// state. Bodies that do are cut off with an error, which fails extracting
+/// them. Other encodings are refused with `415 Unsupported Media Type`.
+///
+/// Limits on the size of bodies apply to the decompressed body.
+pub async fn decompress(State(max_ratio): State<u64>, request: Request, next: Next) -> Response {
+    let Some(encoding) = request.headers().get(header::CONTENT_ENCODING) else {
+        return next.run(request).await;
+    };
+    let compression = match encoding.to_str().map(str::trim) {
+        Ok("identity") => return next.run(request).await,
+        Ok(encoding) => encoding.parse::<Compression>().map_err(|err| err.to_string()),
+        Err(_) => Err("invalid content encoding".to_owned()),
+    };
+    let compression = match compression {
+        Ok(compression) => compression,
+        Err(message) => {
+            debug!(%message, "Rejected request body");
+            let accepted = [(header::ACCEPT_ENCODING, "gzip, zstd")];
+            return (StatusCode::UNSUPPORTED_MEDIA_TYPE, accepted, message).into_response();
+        }
+    };
+
+    let (mut parts, body) = request.into_parts();
+    parts.headers.remove(header::CONTENT_ENCODING);
+    parts.headers.remove(header::CONTENT_LENGTH);
+    let compressed = StreamReader::new(body.into_data_stream().map_err(io::Error::other));
+    let decoder = compression.decoder(compressed, max_ratio);
+    let body = Body::from_stream(ReaderStream::new(decoder));
+    next.run(Request::from_parts(parts, body)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use async_compression::tokio::bufread::GzipEncoder;
+    use axum::{
+        body::{self, Body},
+        http::{header, Request, StatusCode},
+        middleware,
+        routing::post,
+        Router,
+    };
+    use tokio::io::AsyncReadExt;
+    use tower::ServiceExt;
+
+    use super::decompress;
+
+    fn app() -> Router {
+        Router::new()
+            .route("/echo", post(|body: String| async { body }))
+            .layer(middleware::from_fn_with_state(100, decompress))
+    }
+
+    async fn gzip(bytes: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        GzipEncoder::new(bytes).read_to_end(&mut out).await.unwrap();
+        out
+    }
+
+    async fn send(encoding: Option<&str>, body: Vec<u8>) -> (StatusCode, String) {
+        let request = Request::post("/echo");
+        let request = match encoding {
+            Some(encoding) => request.header(header::CONTENT_ENCODING, encoding),
+            None => request,
+        };
+        let response = app().oneshot(request.body(Body::from(body)).unwrap()).await.unwrap();
+        let status = response.status();
+        let body = body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn decompress_body() {
+        let gzipped = gzip(b"hello").await;
+        assert_eq!(send(Some("gzip"), gzipped).await, (StatusCode::OK, "hello".to_owned()));
+        assert_eq!(send(None, b"hello".to_vec()).await, (StatusCode::OK, "hello".to_owned()));
+        assert_eq!(send(Some("br"), b"hello".to_vec()).await.0, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[tokio::test]
+    async fn reject_bomb() {
+        let bomb = gzip(&vec![b'a'; 16 * 1024 * 1024]).await;
+        assert_eq!(send(Some("gzip"), bomb).await.0, StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,137 @@
+//! Conditional requests ([RFC 9110]) of the latest status of a source, so
+//! that clients polling for it only get a body once it has changed.
+//!
+//! [RFC 9110]: https://www.rfc-editor.org/rfc/rfc9110#name-conditional-requests
+
+use axum::{
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use shared::data::units::UnitSystem;
+use time::{
+    format_description::BorrowedFormatItem, macros::format_description, OffsetDateTime,
+    PrimitiveDateTime,
+};
+
+use crate::storage::LatestVersion;
+
+/// IMF-fixdate, the preferred format of dates in HTTP headers.
+const HTTP_DATE: &[BorrowedFormatItem<'_>] = format_description!(
+    "[weekday repr:short], [day] [month repr:short] [year] [hour]:[minute]:[second] GMT"
+);
+
+/// `ETag` and `Last-Modified` of a representation of the latest status.
+#[derive(Debug, Clone)]
+pub struct Validators {
+    etag: String,
+    last_modified: OffsetDateTime,
+}
+
+impl Validators {
+    /// Validators of the latest status at `version`, in the given units,
+    /// which make for a different representation each. The status was last
+    /// modified when it was received, or if it wasn't, at its timestamp.
+    pub fn new(version: LatestVersion, units: Option<UnitSystem>) -> Self {
+        let received = version.received_at.map_or(0, |ts| ts.unix_timestamp_nanos());
+        let units = units.map_or(0, |units| units as u8 + 1);
+        let etag = format!("\"{}.{received}.{units}\"", version.timestamp.unix_timestamp_nanos());
+        let last_modified = version.received_at.unwrap_or(version.timestamp);
+        Self { etag, last_modified }
+    }
+
+    /// Whether the client already has this representation, going by
+    /// `If-None-Match`, or by `If-Modified-Since` if the former isn't sent.
+    /// Malformed headers are ignored.
+    pub fn is_fresh(&self, headers: &HeaderMap) -> bool {
+        if let Some(tags) = headers.get(header::IF_NONE_MATCH) {
+            let Ok(tags) = tags.to_str() else {
+                return false;
+            };
+            // Weak comparison, so validators marked weak by proxies still match.
+            return tags
+                .split(',')
+                .map(|tag| tag.trim())
+                .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == self.etag);
+        }
+        let since = headers
+            .get(header::IF_MODIFIED_SINCE)
+            .and_then(|since| since.to_str().ok())
+            .and_then(parse_http_date);
+        // HTTP dates only have whole seconds.
+        since.is_some_and(|since| self.last_modified.unix_timestamp() <= since.unix_timestamp())
+    }
+
+    /// Response headers carrying the validators.
+    pub fn headers(&self) -> [(header::HeaderName, HeaderValue); 2] {
+        let etag = HeaderValue::from_str(&self.etag).expect("ETags are plain ASCII");
+        let last_modified = format_http_date(self.last_modified);
+        let last_modified = HeaderValue::from_str(&last_modified).expect("dates are plain ASCII");
+        [(header::ETAG, etag), (header::LAST_MODIFIED, last_modified)]
+    }
+
+    /// `304 Not Modified`, with the validators but without a body.
+    pub fn not_modified(&self) -> Response {
+        (StatusCode::NOT_MODIFIED, self.headers()).into_response()
+    }
+}
+
+fn format_http_date(ts: OffsetDateTime) -> String {
+    let utc = ts.to_offset(time::UtcOffset::UTC);
+    // Only fails for years that don't fit into four digits.
+    utc.format(HTTP_DATE).unwrap_or_default()
+}
+
+fn parse_http_date(s: &str) -> Option<OffsetDateTime> {
+    PrimitiveDateTime::parse(s.trim(), HTTP_DATE).ok().map(PrimitiveDateTime::assume_utc)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{header, HeaderMap, HeaderValue};
+    use shared::data::units::UnitSystem;
+    use time::macros::datetime;
+
+    use super::{format_http_date, parse_http_date, Validators};
+    use crate::storage::LatestVersion;
+
+    fn headers(name: header::HeaderName, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn http_dates() {
+        let ts = datetime!(2021-07-27 05:45:19.5 UTC);
+        assert_eq!(format_http_date(ts), "Tue, 27 Jul 2021 05:45:19 GMT");
+        assert_eq!(
+            parse_http_date("Tue, 27 Jul 2021 05:45:19 GMT"),
+            Some(ts.replace_millisecond(0).unwrap())
+        );
+        assert_eq!(parse_http_date("2021-07-27T05:45:19Z"), None);
+    }
+
+    #[test]
+    fn freshness() {
+        let version = LatestVersion {
+            timestamp: datetime!(2021-07-27 05:45:00 UTC),
+            received_at: Some(datetime!(2021-07-27 05:45:19.5 UTC)),
+        };
+        let validators = Validators::new(version, None);
+        let [(_, etag), _] = validators.headers();
+        let etag = etag.to_str().unwrap();
+
+        assert!(!validators.is_fresh(&HeaderMap::new()));
+        assert!(validators.is_fresh(&headers(header::IF_NONE_MATCH, etag)));
+        assert!(validators.is_fresh(&headers(header::IF_NONE_MATCH, &format!("\"x\", W/{etag}"))));
+        assert!(validators.is_fresh(&headers(header::IF_NONE_MATCH, "*")));
+        assert!(!validators.is_fresh(&headers(header::IF_NONE_MATCH, "\"x\"")));
+        let metric = Validators::new(version, Some(UnitSystem::Metric));
+        assert!(!metric.is_fresh(&headers(header::IF_NONE_MATCH, etag)));
+
+        let since = |date| headers(header::IF_MODIFIED_SINCE, date);
+        assert!(validators.is_fresh(&since("Tue, 27 Jul 2021 05:45:19 GMT")));
+        assert!(!validators.is_fresh(&since("Tue, 27 Jul 2021 05:45:18 GMT")));
+        assert!(!validators.is_fresh(&since("yesterday")));
+    }
+}
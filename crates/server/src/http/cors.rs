@@ -0,0 +1,146 @@
+//! Cross-origin resource sharing, letting browser-based dashboards served from
+//! other origins call the API.
+
+use std::time::Duration;
+
+use axum::http::{header, HeaderName, HeaderValue, Method};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+use super::{pagination::NEXT_CURSOR, request_id::REQUEST_ID, HttpError, Result};
+
+/// Methods allowed if none are configured.
+pub const DEFAULT_METHODS: [Method; 3] = [Method::GET, Method::HEAD, Method::POST];
+
+/// Request headers allowed if none are configured. `Last-Event-ID` is sent by
+/// browsers reconnecting to the server-sent event stream of `/watch`, and
+/// `If-None-Match` by dashboards polling the latest status.
+pub const DEFAULT_HEADERS: [HeaderName; 6] = [
+    header::ACCEPT,
+    header::AUTHORIZATION,
+    header::CONTENT_TYPE,
+    header::IF_NONE_MATCH,
+    HeaderName::from_static("last-event-id"),
+    REQUEST_ID,
+];
+
+/// Settings of the CORS layer.
+#[derive(Debug, Clone)]
+pub struct CorsConfig {
+    /// Origins allowed to call the API, e.g. `https://dashboard.example.com`,
+    /// or `*` for any.
+    pub origins: Vec<String>,
+    /// Allowed methods. [`DEFAULT_METHODS`] if empty.
+    pub methods: Vec<Method>,
+    /// Allowed request headers. [`DEFAULT_HEADERS`] if empty.
+    pub headers: Vec<HeaderName>,
+    /// Whether browsers may send credentials, like cookies, along. Can't be
+    /// combined with any origin being allowed.
+    pub credentials: bool,
+    /// How long browsers may cache preflight responses.
+    pub max_age: Duration,
+}
+
+impl CorsConfig {
+    /// Builds the layer answering preflight requests and adding CORS headers
+    /// to responses. Headers that the API responds with, like the pagination
+    /// cursor, the request ID and the `ETag` of the latest status, are always
+    /// exposed.
+    pub fn layer(&self) -> Result<CorsLayer> {
+        let origins = if self.origins.iter().any(|origin| origin == "*") {
+            if self.credentials {
+                return Err(HttpError::CorsCredentials);
+            }
+            AllowOrigin::any()
+        } else {
+            let origins = self
+                .origins
+                .iter()
+                .map(|origin| {
+                    HeaderValue::from_str(origin.trim_end_matches('/'))
+                        .map_err(|_| HttpError::InvalidOrigin { origin: origin.clone() })
+                })
+                .collect::<Result<Vec<_>>>()?;
+            AllowOrigin::list(origins)
+        };
+        let methods = match self.methods.as_slice() {
+            [] => DEFAULT_METHODS.to_vec(),
+            methods => methods.to_vec(),
+        };
+        let headers = match self.headers.as_slice() {
+            [] => DEFAULT_HEADERS.to_vec(),
+            headers => headers.to_vec(),
+        };
+        Ok(CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods(methods)
+            .allow_headers(headers)
+            .allow_credentials(self.credentials)
+            .expose_headers([header::ETAG, NEXT_CURSOR, REQUEST_ID])
+            .max_age(self.max_age))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::{
+        body::Body,
+        http::{header, Method, Request, StatusCode},
+        routing::get,
+        Router,
+    };
+    use tower::ServiceExt;
+
+    use super::CorsConfig;
+    use crate::http::HttpError;
+
+    fn config(origins: &[&str], credentials: bool) -> CorsConfig {
+        CorsConfig {
+            origins: origins.iter().map(|&origin| origin.to_owned()).collect(),
+            methods: Vec::new(),
+            headers: Vec::new(),
+            credentials,
+            max_age: Duration::from_secs(600),
+        }
+    }
+
+    #[tokio::test]
+    async fn preflight() {
+        let cors = config(&["https://dashboard.example.com/"], true).layer().unwrap();
+        let app = Router::new().route("/status/:source_id/watch", get(|| async { "" })).layer(cors);
+        let preflight = |origin: &str| {
+            Request::builder()
+                .method(Method::OPTIONS)
+                .uri("/status/0aaec05a-0e7d-4fd5-abc0-0ba69e3cfe11/watch")
+                .header(header::ORIGIN, origin)
+                .header(header::ACCESS_CONTROL_REQUEST_METHOD, "GET")
+                .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "authorization,last-event-id")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response =
+            app.clone().oneshot(preflight("https://dashboard.example.com")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let headers = response.headers();
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], "https://dashboard.example.com");
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        assert_eq!(headers[header::ACCESS_CONTROL_MAX_AGE], "600");
+        let allowed = headers[header::ACCESS_CONTROL_ALLOW_HEADERS].to_str().unwrap();
+        assert!(allowed.contains("last-event-id"));
+
+        let response = app.oneshot(preflight("https://evil.example.com")).await.unwrap();
+        assert!(response.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+    }
+
+    #[test]
+    fn validate() {
+        assert!(config(&["*"], false).layer().is_ok());
+        assert!(matches!(config(&["*"], true).layer(), Err(HttpError::CorsCredentials)));
+        assert!(matches!(
+            config(&["https://a.example.com\n"], false).layer(),
+            Err(HttpError::InvalidOrigin { .. })
+        ));
+    }
+}
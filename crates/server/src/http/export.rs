@@ -0,0 +1,237 @@
+//! Streaming exports of status history as NDJSON, CSV or GPX. Statuses are
+//! encoded as they're read from storage, so that memory use stays flat
+//! regardless of the length of the exported range.
+
+use std::fmt::Write;
+
+use axum::body::Body;
+use futures_util::{Stream, StreamExt};
+use serde::Deserialize;
+use shared::{
+    data::{SourceId, Status},
+    track,
+};
+use time::{format_description::well_known::Rfc3339, Duration, OffsetDateTime};
+use tracing::error;
+use uom::si::{angle::radian, velocity::meter_per_second};
+
+use crate::storage;
+
+/// Number of statuses encoded into a single chunk of the response body.
+const CHUNK_SIZE: usize = 256;
+
+/// Gap between statuses that splits an exported track unless requested
+/// otherwise.
+pub const DEFAULT_MAX_GAP: Duration = Duration::minutes(10);
+
+/// Encoding of an exported history.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// One JSON-encoded [`Status`] per line.
+    #[default]
+    Ndjson,
+    /// Columns `source_id,timestamp,lon,lat,bearing,speed`, same as read by
+    /// `geo-replay`, with bearing in radians and speed in m/s.
+    Csv,
+    /// A GPX 1.1 track, split into segments wherever positioned statuses are
+    /// further apart than the maximum gap. Statuses without a position are
+    /// left out.
+    Gpx,
+}
+
+impl ExportFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Ndjson => "application/x-ndjson",
+            Self::Csv => "text/csv",
+            Self::Gpx => "application/gpx+xml",
+        }
+    }
+
+    fn header(self, source_id: SourceId) -> String {
+        match self {
+            Self::Ndjson => String::new(),
+            Self::Csv => "source_id,timestamp,lon,lat,bearing,speed\n".to_owned(),
+            Self::Gpx => format!(
+                concat!(
+                    "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+                    "<gpx version=\"1.1\" creator=\"geo-track\" ",
+                    "xmlns=\"http://www.topografix.com/GPX/1/1\">\n",
+                    "<trk><name>{}</name><trkseg>\n",
+                ),
+                source_id
+            ),
+        }
+    }
+
+    fn footer(self) -> &'static str {
+        match self {
+            Self::Ndjson | Self::Csv => "",
+            Self::Gpx => "</trkseg></trk>\n</gpx>\n",
+        }
+    }
+
+    /// Writes `status`, which follows a gap since the previous positioned
+    /// status if `after_gap`.
+    fn write(self, out: &mut String, status: &Status, after_gap: bool) {
+        match self {
+            Self::Ndjson => {
+                // Statuses always serialize to JSON.
+                if let Ok(json) = serde_json::to_string(status) {
+                    out.push_str(&json);
+                    out.push('\n');
+                }
+            }
+            Self::Csv => {
+                let _ = write!(out, "{},", status.source_id);
+                write_seconds(out, status.timestamp);
+                let position = status.position.map(|p| (p.x, p.y));
+                let fields = [
+                    position.map(|(x, _)| x),
+                    position.map(|(_, y)| y),
+                    status.bearing.map(|b| b.get::<radian>()),
+                    status.speed.map(|v| v.get::<meter_per_second>()),
+                ];
+                for field in fields {
+                    out.push(',');
+                    if let Some(value) = field {
+                        let _ = write!(out, "{value}");
+                    }
+                }
+                out.push('\n');
+            }
+            Self::Gpx => {
+                let Some(position) = status.position else {
+                    return;
+                };
+                if after_gap {
+                    out.push_str("</trkseg><trkseg>\n");
+                }
+                let _ = write!(out, "<trkpt lat=\"{}\" lon=\"{}\">", position.y, position.x);
+                if let Ok(time) = status.timestamp.format(&Rfc3339) {
+                    let _ = write!(out, "<time>{time}</time>");
+                }
+                out.push_str("</trkpt>\n");
+            }
+        }
+    }
+}
+
+/// Writes UNIX seconds, with as many fractional digits as needed.
+fn write_seconds(out: &mut String, timestamp: OffsetDateTime) {
+    let nanos = timestamp.unix_timestamp_nanos();
+    let sign = if nanos < 0 { "-" } else { "" };
+    let (secs, frac) = (nanos.unsigned_abs() / 1_000_000_000, nanos.unsigned_abs() % 1_000_000_000);
+    let _ = write!(out, "{sign}{secs}");
+    if frac != 0 {
+        let _ = write!(out, ".{frac:09}");
+        out.truncate(out.trim_end_matches('0').len());
+    }
+}
+
+/// Encodes `statuses` of `source_id` into a response body, splitting tracks
+/// on gaps longer than `max_gap`. If reading from storage fails midway, the
+/// response is cut short.
+pub fn body<S>(format: ExportFormat, source_id: SourceId, max_gap: Duration, statuses: S) -> Body
+where
+    S: Stream<Item = storage::Result<Status>> + Send + 'static,
+{
+    let header = futures_util::stream::once(async move { Ok(format.header(source_id)) });
+    let mut last_positioned: Option<Status> = None;
+    let records = statuses.ready_chunks(CHUNK_SIZE).map(move |chunk| {
+        let mut out = String::new();
+        for status in chunk {
+            match status {
+                Ok(status) => {
+                    let after_gap =
+                        last_positioned.is_some_and(|last| track::is_gap(&last, &status, max_gap));
+                    if status.position.is_some() {
+                        last_positioned = Some(status);
+                    }
+                    format.write(&mut out, &status, after_gap);
+                }
+                Err(err) => {
+                    error!(%err, "Failed to read exported statuses");
+                    return Err(err);
+                }
+            }
+        }
+        Ok(out)
+    });
+    let footer = futures_util::stream::once(async move { Ok(format.footer().to_owned()) });
+    Body::from_stream(header.chain(records).chain(footer))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::stream;
+    use geo_types::Coord;
+    use shared::data::{SourceId, Status, TenantId};
+    use time::macros::datetime;
+    use uom::si::{angle::radian, f64::Angle};
+
+    use super::{body, ExportFormat, DEFAULT_MAX_GAP};
+
+    async fn export(format: ExportFormat, statuses: Vec<Status>) -> String {
+        let source_id = statuses[0].source_id;
+        let statuses = stream::iter(statuses.into_iter().map(Ok));
+        let body = body(format, source_id, DEFAULT_MAX_GAP, statuses);
+        let bytes = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn formats() {
+        let source_id: SourceId =
+            serde_json::from_str("\"0aaec05a-0e7d-4fd5-abc0-0ba69e3cfe11\"").unwrap();
+        let positioned = Status {
+            source_id,
+            timestamp: datetime!(2021-07-27 05:45:19.25 UTC),
+            position: Some(Coord { x: 24.5, y: 59.25 }),
+            bearing: Some(Angle::new::<radian>(1.5)),
+            speed: None,
+            satellites: None,
+            fix: None,
+            odometer: None,
+            ignition: None,
+            received_at: None,
+            suspect_timestamp: false,
+            tenant_id: TenantId::DEFAULT,
+        };
+        let unpositioned = Status {
+            timestamp: datetime!(2021-07-27 05:45:20 UTC),
+            position: None,
+            bearing: None,
+            ..positioned
+        };
+        let statuses = vec![positioned, unpositioned];
+
+        let ndjson = export(ExportFormat::Ndjson, statuses.clone()).await;
+        assert_eq!(ndjson.lines().count(), 2);
+        let decoded: Status = serde_json::from_str(ndjson.lines().next().unwrap()).unwrap();
+        assert_eq!(decoded.position, positioned.position);
+
+        let csv = export(ExportFormat::Csv, statuses.clone()).await;
+        assert_eq!(
+            csv,
+            "source_id,timestamp,lon,lat,bearing,speed\n\
+             0aaec05a-0e7d-4fd5-abc0-0ba69e3cfe11,1627364719.25,24.5,59.25,1.5,\n\
+             0aaec05a-0e7d-4fd5-abc0-0ba69e3cfe11,1627364720,,,,\n"
+        );
+
+        let gpx = export(ExportFormat::Gpx, statuses.clone()).await;
+        assert!(gpx.starts_with("<?xml"));
+        assert!(gpx.contains(
+            "<trkpt lat=\"59.25\" lon=\"24.5\"><time>2021-07-27T05:45:19.25Z</time></trkpt>\n"
+        ));
+        assert_eq!(gpx.matches("<trkpt").count(), 1);
+        assert!(gpx.ends_with("</gpx>\n"));
+
+        let nearby = Status { timestamp: datetime!(2021-07-27 05:50:00 UTC), ..positioned };
+        let later = Status { timestamp: datetime!(2021-07-27 07:00:00 UTC), ..positioned };
+        let gpx = export(ExportFormat::Gpx, [statuses, vec![nearby, later]].concat()).await;
+        assert_eq!(gpx.matches("<trkpt").count(), 3);
+        assert_eq!(gpx.matches("<trkseg>").count(), 2);
+    }
+}
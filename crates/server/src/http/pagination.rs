@@ -0,0 +1,234 @@
+//! Cursor-based pagination of list endpoints.
+//!
+//! Pages are at most [`MAX_PAGE_SIZE`] items long. If there are more items, the
+//! response carries a [`NEXT_CURSOR`] header whose value is to be passed back
+//! as the `cursor` query parameter to get the next page, along with the same
+//! filters as before. Cursors are opaque to clients: they hold the key of the
+//! last returned item and a hash of the filters of the query, signed so that
+//! clients can't forge them.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    sync::Arc,
+};
+
+use axum::{
+    http::{HeaderName, HeaderValue},
+    response::{IntoResponse, Response},
+    Json,
+};
+use hmac::{Hmac, Mac};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::util::hex;
+
+/// Page size used if the client doesn't ask for one.
+pub const DEFAULT_PAGE_SIZE: usize = 100;
+
+/// Largest page size. Larger requested sizes are capped to it.
+pub const MAX_PAGE_SIZE: usize = 1000;
+
+/// Response header holding the cursor of the next page, if there is one.
+pub const NEXT_CURSOR: HeaderName = HeaderName::from_static("next-cursor");
+
+/// Length of the truncated HMAC-SHA256 signature of a cursor.
+const TAG_LEN: usize = 16;
+
+#[derive(Debug, Error)]
+pub enum CursorError {
+    #[error("malformed cursor")]
+    Malformed,
+    #[error("cursor signature mismatch")]
+    Forged,
+    #[error("cursor was issued for a query with different filters")]
+    FilterMismatch,
+}
+
+/// Secret that cursors are signed with. Cursors signed with one secret aren't
+/// accepted with another.
+#[derive(Clone)]
+pub struct CursorSecret(Arc<[u8]>);
+
+impl CursorSecret {
+    pub fn new(secret: &[u8]) -> Self {
+        Self(secret.into())
+    }
+
+    /// A secret unique to the current process, so that cursors are only valid
+    /// until the server restarts. Derived from the randomly seeded keys of the
+    /// standard library's hasher, to avoid depending on a random number
+    /// generator for this alone.
+    pub fn random() -> Self {
+        let state = RandomState::new();
+        let secret: Vec<u8> = (0..4u8)
+            .flat_map(|n| {
+                let mut hasher = state.build_hasher();
+                hasher.write_u8(n);
+                hasher.finish().to_le_bytes()
+            })
+            .collect();
+        Self(secret.into())
+    }
+
+    /// Encodes the key of the last item of a page into a cursor, bound to the
+    /// filters of the query.
+    pub fn encode<K: Serialize, F: Serialize>(&self, last: &K, filter: &F) -> String {
+        let mut payload = Vec::new();
+        // Encoding plain keys into memory can't fail.
+        let _ = ciborium::into_writer(&(last, filter_hash(filter)), &mut payload);
+        let tag = self.mac(&payload).finalize().into_bytes();
+        payload.extend_from_slice(&tag[..TAG_LEN]);
+        hex::encode(&payload)
+    }
+
+    /// Decodes the key of the last item of the previous page out of a cursor,
+    /// checking that it was issued for a query with the same filters.
+    pub fn decode<K: DeserializeOwned, F: Serialize>(
+        &self,
+        cursor: &str,
+        filter: &F,
+    ) -> Result<K, CursorError> {
+        let bytes = hex::decode(cursor).ok_or(CursorError::Malformed)?;
+        let split = bytes.len().checked_sub(TAG_LEN).ok_or(CursorError::Malformed)?;
+        let (payload, tag) = bytes.split_at(split);
+        self.mac(payload).verify_truncated_left(tag).map_err(|_| CursorError::Forged)?;
+        let (last, hash): (K, u64) =
+            ciborium::from_reader(payload).map_err(|_| CursorError::Malformed)?;
+        if hash != filter_hash(filter) {
+            return Err(CursorError::FilterMismatch);
+        }
+        Ok(last)
+    }
+
+    fn mac(&self, payload: &[u8]) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.0).expect("HMAC accepts keys of any length");
+        mac.update(payload);
+        mac
+    }
+}
+
+fn filter_hash<F: Serialize>(filter: &F) -> u64 {
+    let mut encoded = Vec::new();
+    let _ = ciborium::into_writer(filter, &mut encoded);
+    let digest = Sha256::digest(&encoded);
+    let mut hash = [0; 8];
+    hash.copy_from_slice(&digest[..8]);
+    u64::from_le_bytes(hash)
+}
+
+/// Pagination query parameters shared by all list endpoints.
+#[derive(Debug, Default, Deserialize)]
+pub struct PageQuery {
+    /// Number of items per page, [`DEFAULT_PAGE_SIZE`] if not specified.
+    limit: Option<usize>,
+    /// Cursor from the [`NEXT_CURSOR`] header of the previous page.
+    cursor: Option<String>,
+}
+
+impl PageQuery {
+    /// Requested page size, within `1..=MAX_PAGE_SIZE`.
+    pub fn limit(&self) -> usize {
+        self.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE)
+    }
+
+    /// Key of the last item of the previous page, or `None` for the first page.
+    pub fn after<K: DeserializeOwned, F: Serialize>(
+        &self,
+        secret: &CursorSecret,
+        filter: &F,
+    ) -> Result<Option<K>, CursorError> {
+        self.cursor.as_deref().map(|cursor| secret.decode(cursor, filter)).transpose()
+    }
+}
+
+/// A single page of a listing, serialized as a plain JSON array.
+#[derive(Debug)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// Cursor of the next page, if there is one.
+    pub next: Option<String>,
+}
+
+impl<T> Page<T> {
+    /// Makes a page out of up to `limit + 1` items following the previous
+    /// page, where the extra item only indicates that there's a next page.
+    pub fn new<K, F>(
+        mut items: Vec<T>,
+        limit: usize,
+        key: impl Fn(&T) -> K,
+        secret: &CursorSecret,
+        filter: &F,
+    ) -> Self
+    where
+        K: Serialize,
+        F: Serialize,
+    {
+        let mut next = None;
+        if items.len() > limit {
+            items.truncate(limit);
+            next = items.last().map(|last| secret.encode(&key(last), filter));
+        }
+        Self { items, next }
+    }
+
+    pub fn map<U>(self, f: impl FnOnce(Vec<T>) -> Vec<U>) -> Page<U> {
+        Page { items: f(self.items), next: self.next }
+    }
+}
+
+impl<T: Serialize> IntoResponse for Page<T> {
+    fn into_response(self) -> Response {
+        let mut response = Json(self.items).into_response();
+        if let Some(cursor) = self.next.and_then(|next| HeaderValue::from_str(&next).ok()) {
+            response.headers_mut().insert(NEXT_CURSOR, cursor);
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CursorError, CursorSecret, Page};
+
+    #[test]
+    fn cursors() {
+        let secret = CursorSecret::new(b"secret");
+        let filter = ("source", Some(10));
+        let cursor = secret.encode(&42i128, &filter);
+        assert_eq!(secret.decode::<i128, _>(&cursor, &filter).unwrap(), 42);
+
+        let other = ("source", Some(11));
+        assert!(matches!(
+            secret.decode::<i128, _>(&cursor, &other),
+            Err(CursorError::FilterMismatch)
+        ));
+        assert!(matches!(
+            CursorSecret::new(b"other").decode::<i128, _>(&cursor, &filter),
+            Err(CursorError::Forged)
+        ));
+        let mut tampered = cursor.clone().into_bytes();
+        tampered[0] = if tampered[0] == b'0' { b'1' } else { b'0' };
+        let tampered = String::from_utf8(tampered).unwrap();
+        assert!(secret.decode::<i128, _>(&tampered, &filter).is_err());
+        assert!(matches!(secret.decode::<i128, _>("zz", &filter), Err(CursorError::Malformed)));
+
+        // Random secrets differ from one another.
+        assert!(CursorSecret::random().decode::<i128, _>(&cursor, &filter).is_err());
+    }
+
+    #[test]
+    fn pages() {
+        let secret = CursorSecret::random();
+        let page = Page::new(vec![1, 2, 3], 2, |n| *n, &secret, &());
+        assert_eq!(page.items, [1, 2]);
+        let next = page.next.unwrap();
+        assert_eq!(secret.decode::<i32, _>(&next, &()).unwrap(), 2);
+
+        let last = Page::new(vec![3], 2, |n| *n, &secret, &());
+        assert_eq!((last.items, last.next), (vec![3], None));
+    }
+}
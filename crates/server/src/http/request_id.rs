@@ -0,0 +1,201 @@
+//! Request IDs, for correlating logs of a single request across the HTTP
+//! server and storage, and problem details ([RFC 9457]) bodies of error
+//! responses, which carry the ID for clients to refer to.
+//!
+//! [RFC 9457]: https://www.rfc-editor.org/rfc/rfc9457
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+};
+
+use axum::{
+    body::{self, Body},
+    extract::Request,
+    http::{header, HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use serde::Serialize;
+use tracing::{field, info_span, Span};
+
+use crate::{cq::RequestId, util::hex};
+
+/// Header holding the ID of a request, in both requests and responses.
+pub const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longest request ID accepted from clients.
+const MAX_LEN: usize = 128;
+
+/// Largest plain text error body to carry over into problem details.
+const MAX_DETAIL_LEN: usize = 4096;
+
+const PROBLEM_JSON: &str = "application/problem+json";
+
+/// Middleware assigning an ID to each request, or adopting the one sent by the
+/// client in an `X-Request-Id` header if it's at most [`MAX_LEN`] printable
+/// ASCII characters long. The ID is stored in request extensions, requests to
+/// storage are tagged with it, and it's echoed in the response.
+///
+/// Error responses without a body, or with a plain text one, are turned into
+/// problem details, the text becoming their `detail`.
+pub async fn propagate(mut request: Request, next: Next) -> Response {
+    let request_id = request.headers().get(REQUEST_ID).and_then(adopt).unwrap_or_else(generate);
+    request.extensions_mut().insert(request_id.clone());
+    let response = request_id.clone().scope(next.run(request)).await;
+
+    let mut response = problem(response, &request_id).await;
+    if let Ok(value) = HeaderValue::from_str(request_id.as_str()) {
+        response.headers_mut().insert(REQUEST_ID, value);
+    }
+    response
+}
+
+/// Span of an HTTP request, including the ID assigned by [`propagate`].
+pub fn make_span<B>(request: &axum::http::Request<B>) -> Span {
+    let request_id = request.extensions().get::<RequestId>().map(field::display);
+    info_span!(
+        "request",
+        method = %request.method(),
+        uri = %request.uri(),
+        version = ?request.version(),
+        request_id,
+    )
+}
+
+fn adopt(value: &HeaderValue) -> Option<RequestId> {
+    let id = value.to_str().ok()?;
+    let valid = (1..=MAX_LEN).contains(&id.len()) && id.bytes().all(|b| b.is_ascii_graphic());
+    valid.then(|| RequestId::new(id))
+}
+
+/// A new, unpredictable request ID. Hashes a counter with the randomly seeded
+/// hasher of the standard library, which yields unique IDs without depending
+/// on a random number generator.
+fn generate() -> RequestId {
+    static STATE: OnceLock<RandomState> = OnceLock::new();
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = STATE.get_or_init(RandomState::new).build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    RequestId::new(&hex::encode(&hasher.finish().to_be_bytes()))
+}
+
+/// Problem details of an error response. The `type` member is left out, which
+/// means `about:blank`: the status code says it all.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Problem<'a> {
+    title: &'a str,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+    request_id: &'a str,
+}
+
+async fn problem(response: Response, request_id: &RequestId) -> Response {
+    let status = response.status();
+    let plain = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .map_or(true, |content_type| content_type.as_bytes().starts_with(b"text/plain"));
+    if !(status.is_client_error() || status.is_server_error()) || !plain {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let detail = body::to_bytes(body, MAX_DETAIL_LEN)
+        .await
+        .ok()
+        .map(|text| String::from_utf8_lossy(&text).trim().to_owned())
+        .filter(|text| !text.is_empty());
+    let problem = Problem {
+        title: status.canonical_reason().unwrap_or("Error"),
+        status: status.as_u16(),
+        detail,
+        request_id: request_id.as_str(),
+    };
+    // Serializing plain strings and numbers can't fail.
+    let body = serde_json::to_vec(&problem).unwrap_or_default();
+    parts.headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON));
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{self, Body},
+        http::{header, Request, StatusCode},
+        middleware,
+        routing::get,
+        Router,
+    };
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    use super::{propagate, REQUEST_ID};
+    use crate::cq::RequestId;
+
+    fn app() -> Router {
+        Router::new()
+            .route("/id", get(|| async { RequestId::current().unwrap().to_string() }))
+            .route("/missing", get(|| async { StatusCode::NOT_FOUND }))
+            .route("/invalid", get(|| async { (StatusCode::BAD_REQUEST, "invalid cell") }))
+            .layer(middleware::from_fn(propagate))
+    }
+
+    fn get_with_id(uri: &str, request_id: Option<&str>) -> Request<Body> {
+        let request = Request::builder().uri(uri);
+        let request = match request_id {
+            Some(request_id) => request.header(REQUEST_ID, request_id),
+            None => request,
+        };
+        request.body(Body::empty()).unwrap()
+    }
+
+    async fn body(response: axum::response::Response) -> String {
+        let body = body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn propagation() {
+        let response = app().oneshot(get_with_id("/id", Some("client-42"))).await.unwrap();
+        assert_eq!(response.headers()[REQUEST_ID], "client-42");
+        assert_eq!(body(response).await, "client-42");
+
+        for request_id in [None, Some(""), Some("with spaces"), Some(&*"a".repeat(129))] {
+            let response = app().oneshot(get_with_id("/id", request_id)).await.unwrap();
+            let echoed = response.headers()[REQUEST_ID].to_str().unwrap().to_owned();
+            assert_eq!(echoed.len(), 16);
+            assert_eq!(body(response).await, echoed);
+        }
+
+        let first = app().oneshot(get_with_id("/id", None)).await.unwrap();
+        let second = app().oneshot(get_with_id("/id", None)).await.unwrap();
+        assert_ne!(first.headers()[REQUEST_ID], second.headers()[REQUEST_ID]);
+    }
+
+    #[tokio::test]
+    async fn problems() {
+        let response = app().oneshot(get_with_id("/missing", Some("client-42"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/problem+json");
+        let problem: Value = serde_json::from_str(&body(response).await).unwrap();
+        assert_eq!(
+            problem,
+            json!({ "title": "Not Found", "status": 404, "requestId": "client-42" })
+        );
+
+        let response = app().oneshot(get_with_id("/invalid", Some("client-42"))).await.unwrap();
+        let problem: Value = serde_json::from_str(&body(response).await).unwrap();
+        assert_eq!(problem["detail"], "invalid cell");
+
+        let response = app().oneshot(get_with_id("/id", None)).await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/plain; charset=utf-8");
+    }
+}
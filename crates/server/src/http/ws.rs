@@ -0,0 +1,141 @@
+//! Ingest over a WebSocket, for devices behind NATs or proxies that only let
+//! outbound WebSocket connections through. Once upgraded, devices send one
+//! status per message, CBOR-encoded in binary messages or JSON-encoded in
+//! text messages.
+//!
+//! With `?ack=true`, every message is answered with a CBOR-encoded [`Ack`] in
+//! a binary message, the same as over TCP with [`AckMode::Each`]. The first
+//! status that can't be decoded or persisted fails the connection: it's
+//! acknowledged with `ok: false` if acks are on, and the socket is closed.
+//!
+//! [`AckMode::Each`]: crate::ingest::AckMode::Each
+
+use std::time::Duration;
+
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket};
+use shared::data::{Ack, Status, TenantId};
+use time::OffsetDateTime;
+use tracing::{debug, warn};
+
+use crate::{
+    ingest::{IngestError, PayloadFormat, Pipeline, Result},
+    metrics,
+};
+
+/// Settings of a single WebSocket ingest session.
+#[derive(Clone)]
+pub struct Session {
+    pub tenant_id: TenantId,
+    pub pipeline: Pipeline,
+    /// How long to wait for storage to persist a status.
+    pub timeout: Duration,
+    /// Whether to acknowledge every message.
+    pub ack: bool,
+}
+
+/// Persist statuses arriving over `socket` until the client closes it, or a
+/// status fails.
+pub async fn ingest(mut socket: WebSocket, session: Session) {
+    let open = metrics::gauge("geo_ws_connections", "Open WebSocket ingest connections.");
+    open.add(1);
+    let mut seq = 0;
+    let failure = loop {
+        let message = match socket.recv().await {
+            Some(Ok(message)) => message,
+            Some(Err(err)) => {
+                debug!(%err, "WebSocket ingest connection failed");
+                break None;
+            }
+            None => break None,
+        };
+        let Some(status) = decode(message) else {
+            continue;
+        };
+        let result = persist(status, &session, &mut seq).await;
+        if session.ack {
+            let ack = Ack { ok: result.is_ok(), seq, count: 1 };
+            if let Err(err) = send_ack(&mut socket, ack).await {
+                debug!(%err, "Failed to acknowledge status");
+                break None;
+            }
+        }
+        if let Err(err) = result {
+            break Some(err);
+        }
+    };
+    if let Some(err) = failure {
+        let code = match err {
+            IngestError::Internal(_) | IngestError::Storage(_) => {
+                warn!(%err, "Failed to write status update");
+                close_code::AGAIN
+            }
+            _ => {
+                debug!(%err, "Rejected status update");
+                close_code::INVALID
+            }
+        };
+        let reason = err.to_string().chars().take(120).collect::<String>().into();
+        let _ = socket.send(Message::Close(Some(CloseFrame { code, reason }))).await;
+    }
+    open.add(-1);
+}
+
+/// Status carried by a message, or `None` if it's a control message.
+fn decode(message: Message) -> Option<Result<Status>> {
+    match message {
+        Message::Binary(bytes) => Some(PayloadFormat::Cbor.decode(&bytes)),
+        Message::Text(text) => Some(PayloadFormat::Json.decode(text.as_bytes())),
+        Message::Ping(_) | Message::Pong(_) | Message::Close(_) => None,
+    }
+}
+
+/// Persist a status, incrementing `seq` unless that fails.
+async fn persist(status: Result<Status>, session: &Session, seq: &mut u64) -> Result<()> {
+    let status = Status {
+        received_at: Some(OffsetDateTime::now_utc()),
+        tenant_id: session.tenant_id,
+        ..status?
+    };
+    match session.pipeline.accept_within(status, session.timeout).await {
+        Ok(()) => *seq += 1,
+        // Resending a rejected status wouldn't help, so it's acknowledged
+        // as if it were persisted.
+        Err(err @ IngestError::InvalidTimestamp { .. }) => {
+            debug!(%err, "Rejected status");
+            *seq += 1;
+        }
+        Err(err) => return Err(err),
+    }
+    Ok(())
+}
+
+async fn send_ack(socket: &mut WebSocket, ack: Ack) -> Result<()> {
+    let mut bytes = Vec::new();
+    ciborium::ser::into_writer(&ack, &mut bytes)?;
+    socket.send(Message::Binary(bytes)).await.map_err(std::io::Error::other)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::extract::ws::Message;
+    use shared::data::SourceId;
+
+    use super::decode;
+
+    #[test]
+    fn decode_messages() {
+        let json = r#"{"sourceId":"0aaec05a-0e7d-4fd5-abc0-0ba69e3cfe11","timestamp":1627364719}"#;
+        let status = decode(Message::Text(json.to_owned())).unwrap().unwrap();
+        let source_id = "0aaec05a-0e7d-4fd5-abc0-0ba69e3cfe11".parse::<SourceId>().unwrap();
+        assert_eq!(status.source_id, source_id);
+
+        let mut cbor = Vec::new();
+        ciborium::ser::into_writer(&status, &mut cbor).unwrap();
+        let decoded = decode(Message::Binary(cbor)).unwrap().unwrap();
+        assert_eq!((decoded.source_id, decoded.timestamp), (source_id, status.timestamp));
+
+        assert!(decode(Message::Binary(b"nope".to_vec())).unwrap().is_err());
+        assert!(decode(Message::Ping(Vec::new())).is_none());
+    }
+}
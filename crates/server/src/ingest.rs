@@ -1,34 +1,83 @@
 //! Listeners for incoming status updates arriving directly from monitored
 //! devices. In reality these would come in all kinds of proprietary (mostly
 //! binary) formats depending on the manufacturer, but we're using CBOR for
-//! demonstration purposes.
+//! demonstration purposes. Gateways that can't produce CBOR may send
+//! newline-delimited JSON instead, and devices using [`shared::client`] may
+//! wrap their payloads into checksummed frames. These are told apart by the
+//! first bytes of each connection or datagram (see [`PayloadFormat`]).
 //!
 //! Both TCP and UDP listeners are provided. The UDP listener only supports one
 //! status update per datagram, while the TCP listener can decode a stream of
-//! one or more payloads.
+//! one or more payloads, and optionally acknowledges them (see [`AckMode`]).
+//! Devices speaking CoAP are served by a separate listener (see [`coap`]), and
+//! ones that can only reach out over WebSockets by the HTTP server (see
+//! [`crate::http::ws`]).
 
-use std::{net::SocketAddr, time::Duration};
+pub mod coap;
 
-use futures_util::stream::StreamExt;
-use shared::data::Status;
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet, VecDeque},
+    hash::{Hash, Hasher},
+    io::Cursor,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use bytes::{Buf, BytesMut};
+use futures_util::{stream::StreamExt, FutureExt};
+use serde::Serialize;
+use shared::{
+    client::{self, Frame},
+    codec::{self, Cbor, Codec, Json},
+    data::{timestamp, Ack, SourceId, Status, TenantId},
+};
 use thiserror::Error;
+use time::OffsetDateTime;
 use tokio::{
-    net::{TcpListener, TcpStream, UdpSocket},
-    time::timeout,
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream, UdpSocket,
+    },
+    sync::broadcast,
+    time::{timeout, MissedTickBehavior},
+};
+use tokio_util::{
+    codec::{Decoder, FramedRead},
+    sync::CancellationToken,
 };
-use tokio_util::codec::FramedRead;
 use tracing::{debug, error, info, warn};
+use uom::si::length::meter;
 
 use crate::{
+    auth::{ApiKeys, AuthError},
     cq::CqrsError,
+    metrics,
+    publish::Publisher,
+    settings::Settings,
     storage::{StorageCommand, StorageError, StorageHandler},
-    util::cbor::CborDecoder,
+    util::{
+        codec::{CodecDecoder, DecodeError},
+        compression::Compression,
+    },
 };
 
 #[derive(Debug, Error)]
 pub enum IngestError {
+    #[error("authentication failed")]
+    Auth(#[from] AuthError),
     #[error("packet deserialization error")]
-    Deserialize(#[from] ciborium::de::Error<std::io::Error>),
+    Codec(#[from] codec::Error),
+    #[error("framed packet error")]
+    Frame(#[from] client::Error),
+    #[error("packet serialization error")]
+    Serialize(#[from] ciborium::ser::Error<std::io::Error>),
     #[error("internal communication error")]
     Internal(#[from] CqrsError),
     #[error("IO error")]
@@ -37,33 +86,744 @@ pub enum IngestError {
     Storage(#[from] StorageError),
     #[error("timeout elapsed")]
     Timeout(#[from] tokio::time::error::Elapsed),
+    #[error("unknown acknowledgment mode: {name}")]
+    UnknownAckMode { name: String },
+    #[error("unknown payload format: {name}")]
+    UnknownPayloadFormat { name: String },
+    #[error("unknown timestamp policy: {name}")]
+    UnknownTimestampAction { name: String },
+    #[error("implausible timestamp: {timestamp}")]
+    InvalidTimestamp { timestamp: OffsetDateTime },
+}
+
+impl From<DecodeError> for IngestError {
+    fn from(err: DecodeError) -> Self {
+        match err {
+            DecodeError::Codec(err) => Self::Codec(err),
+            DecodeError::Io(err) => Self::Io(err),
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, IngestError>;
 
+/// Encoding of incoming [`Status`] packets.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PayloadFormat {
+    #[default]
+    Cbor,
+    /// One JSON object per line (or per datagram).
+    Json,
+    /// Frames built by [`shared::client`], carrying CBOR or postcard.
+    Framed,
+}
+
+impl PayloadFormat {
+    /// CBOR self-described data tag (55799), which may precede any CBOR value.
+    const CBOR_MAGIC: [u8; 3] = [0xd9, 0xd9, 0xf7];
+
+    /// Guess the format from the first bytes of a payload. Returns `None` if
+    /// more bytes are needed, or `Some(default)` if there's no telling.
+    pub fn detect(bytes: &[u8], default: Self) -> Option<Self> {
+        let first = *bytes.iter().find(|b| !b.is_ascii_whitespace())?;
+        let format = match first {
+            b'{' => Self::Json,
+            client::FRAME_MAGIC => Self::Framed,
+            // Definite or indefinite length map.
+            0xa0..=0xbf => Self::Cbor,
+            _ if bytes.starts_with(&Self::CBOR_MAGIC) => Self::Cbor,
+            _ if Self::CBOR_MAGIC.starts_with(bytes) => return None,
+            _ => default,
+        };
+        Some(format)
+    }
+
+    /// Decode a single status from a complete payload.
+    pub fn decode(self, bytes: &[u8]) -> Result<Status> {
+        match self {
+            Self::Cbor => Ok(Cbor.decode_one(bytes)?),
+            Self::Json => Ok(Json.decode_one(bytes)?),
+            Self::Framed => {
+                decode_frame(client::decode_frame(bytes)?.ok_or(client::Error::Truncated)?)
+            }
+        }
+    }
+}
+
+fn decode_frame(frame: Frame<'_>) -> Result<Status> {
+    Ok(frame.encoding.codec().decode_one(frame.payload)?)
+}
+
+impl FromStr for PayloadFormat {
+    type Err = IngestError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "cbor" => Ok(Self::Cbor),
+            "json" => Ok(Self::Json),
+            _ => Err(IngestError::UnknownPayloadFormat { name: s.to_owned() }),
+        }
+    }
+}
+
+/// Decodes a stream of statuses in a format detected from its first bytes.
+pub(crate) enum StatusDecoder {
+    Detect(PayloadFormat),
+    Cbor(CodecDecoder<Cbor>),
+    Json(CodecDecoder<Json>),
+    Framed,
+}
+
+impl StatusDecoder {
+    /// Pick the actual decoder once there are enough bytes to detect the
+    /// format. Returns `false` if there aren't yet.
+    fn detect(&mut self, src: &[u8], eof: bool) -> bool {
+        if let Self::Detect(default) = *self {
+            let format = match PayloadFormat::detect(src, default) {
+                Some(format) => format,
+                None if eof => default,
+                None => return false,
+            };
+            *self = match format {
+                PayloadFormat::Cbor => Self::Cbor(CodecDecoder::default()),
+                PayloadFormat::Json => Self::Json(CodecDecoder::default()),
+                PayloadFormat::Framed => Self::Framed,
+            };
+        }
+        true
+    }
+}
+
+impl Decoder for StatusDecoder {
+    type Item = Status;
+    type Error = IngestError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+        if !self.detect(src, false) {
+            return Ok(None);
+        }
+        match self {
+            Self::Detect(_) => Ok(None),
+            Self::Cbor(decoder) => Ok(decoder.decode(src)?),
+            Self::Json(decoder) => Ok(decoder.decode(src)?),
+            Self::Framed => {
+                let Some(frame) = client::decode_frame(src)? else { return Ok(None) };
+                let len = frame.len;
+                let status = decode_frame(frame);
+                src.advance(len);
+                status.map(Some)
+            }
+        }
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+        if !self.detect(src, true) {
+            return Ok(None);
+        }
+        match self {
+            Self::Detect(_) => Ok(None),
+            Self::Cbor(decoder) => Ok(decoder.decode_eof(src)?),
+            Self::Json(decoder) => Ok(decoder.decode_eof(src)?),
+            Self::Framed => match self.decode(src)? {
+                None if !src.is_empty() => Err(client::Error::Truncated.into()),
+                status => Ok(status),
+            },
+        }
+    }
+}
+
+/// Number of statuses buffered for each watcher of a [`Pipeline`].
+const WATCH_BUFFER: usize = 1024;
+/// Upper limit of statuses acknowledged together in [`AckMode::Batch`].
+const MAX_ACK_BATCH: usize = 256;
+/// Shortest interval between sweeps for idle TCP connections.
+const REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Whether and how often the TCP listener reports back to devices which of
+/// their statuses have been persisted, by writing a CBOR-encoded [`Ack`] to the
+/// connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AckMode {
+    /// Never write anything back.
+    #[default]
+    None,
+    /// Acknowledge every status separately.
+    Each,
+    /// Acknowledge all statuses that have been received together at once.
+    Batch,
+}
+
+impl FromStr for AckMode {
+    type Err = IngestError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Self::None),
+            "each" => Ok(Self::Each),
+            "batch" => Ok(Self::Batch),
+            _ => Err(IngestError::UnknownAckMode { name: s.to_owned() }),
+        }
+    }
+}
+
+/// What to do with statuses whose timestamps fall outside of a
+/// [`TimestampPolicy`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampAction {
+    /// Drop the status.
+    Reject,
+    /// Move the timestamp to the nearest plausible moment.
+    Clamp,
+    /// Store the status as is, with [`Status::suspect_timestamp`] set.
+    Flag,
+}
+
+impl TimestampAction {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Reject => "reject",
+            Self::Clamp => "clamp",
+            Self::Flag => "flag",
+        }
+    }
+}
+
+impl FromStr for TimestampAction {
+    type Err = IngestError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "reject" => Ok(Self::Reject),
+            "clamp" => Ok(Self::Clamp),
+            "flag" => Ok(Self::Flag),
+            _ => Err(IngestError::UnknownTimestampAction { name: s.to_owned() }),
+        }
+    }
+}
+
+/// Range of plausible timestamps of incoming statuses, relative to when
+/// they're received.
+#[derive(Debug, Clone, Copy)]
+pub struct TimestampPolicy {
+    pub action: TimestampAction,
+    /// How far ahead of the receive time timestamps may be.
+    pub max_future: Duration,
+    /// How far behind the receive time timestamps may be. Unlimited if `None`.
+    pub max_age: Option<Duration>,
+}
+
+/// A [`TimestampPolicy`] along with counters of statuses it's been applied to.
+struct TimestampCheck {
+    policy: TimestampPolicy,
+    future: metrics::Counter,
+    stale: metrics::Counter,
+}
+
+impl TimestampCheck {
+    fn new(policy: TimestampPolicy) -> Self {
+        let counter = |reason| {
+            metrics::counter_with(
+                "geo_timestamp_violations_total",
+                "Statuses with implausible timestamps, by policy applied.",
+                &[("action", policy.action.as_str()), ("reason", reason)],
+            )
+        };
+        Self { policy, future: counter("future"), stale: counter("stale") }
+    }
+
+    fn apply(&self, mut status: Status) -> Result<Status> {
+        let now = status.received_at.unwrap_or_else(OffsetDateTime::now_utc);
+        let latest = now + self.policy.max_future;
+        let earliest = self.policy.max_age.map(|age| now - age);
+        let bound = match earliest {
+            _ if status.timestamp > latest => {
+                self.future.inc();
+                latest
+            }
+            Some(earliest) if status.timestamp < earliest => {
+                self.stale.inc();
+                earliest
+            }
+            _ => return Ok(status),
+        };
+
+        match self.policy.action {
+            TimestampAction::Reject => {
+                return Err(IngestError::InvalidTimestamp { timestamp: status.timestamp });
+            }
+            // Keeps the sub-second part of the bound, as storage does.
+            TimestampAction::Clamp => status.timestamp = bound,
+            TimestampAction::Flag => status.suspect_timestamp = true,
+        }
+        Ok(status)
+    }
+}
+
+/// Discards odometer readings that can't be right, being negative or not finite,
+/// rather than rejecting the whole status.
+fn sanitize(mut status: Status) -> Status {
+    let odometer = status.odometer.map(|d| d.get::<meter>());
+    if odometer.is_some_and(|d| !d.is_finite() || d < 0.) {
+        metrics::counter(
+            "geo_invalid_odometer_total",
+            "Odometer readings discarded for being negative or not finite.",
+        )
+        .inc();
+        status.odometer = None;
+    }
+    status
+}
+
+/// Common path of all incoming [`Status`] packets regardless of the transport
+/// they arrived over: validates timestamps (see [`TimestampPolicy`]) and
+/// odometer readings, persists statuses, then fans them out to the optional
+/// [`Publisher`] and to local watchers (see [`Pipeline::watch`]).
+#[derive(Clone)]
+pub struct Pipeline {
+    handler: StorageHandler,
+    publisher: Option<Publisher>,
+    watchers: broadcast::Sender<Status>,
+    timestamps: Option<Arc<TimestampCheck>>,
+    api_keys: Arc<ApiKeys>,
+    settings: Settings,
+}
+
+impl Pipeline {
+    pub fn new(handler: StorageHandler, publisher: Option<Publisher>) -> Self {
+        let (watchers, _) = broadcast::channel(WATCH_BUFFER);
+        Self {
+            handler,
+            publisher,
+            watchers,
+            timestamps: None,
+            api_keys: Default::default(),
+            settings: Default::default(),
+        }
+    }
+
+    /// Share runtime settings with the listeners and HTTP handlers that this
+    /// pipeline is passed to. TCP listeners register their connections there.
+    pub fn with_settings(mut self, settings: Settings) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// Require clients of all transports to authenticate with one of the
+    /// given keys, and scope their statuses to the tenant of the key.
+    pub fn with_api_keys(mut self, keys: ApiKeys) -> Self {
+        self.api_keys = Arc::new(keys);
+        self
+    }
+
+    /// Whether clients have to present an API key.
+    pub fn requires_auth(&self) -> bool {
+        self.api_keys.is_enabled()
+    }
+
+    /// Resolve the tenant of a client presenting `key` over `transport`.
+    /// Always succeeds with [`TenantId::DEFAULT`] if no API keys are
+    /// configured.
+    pub fn authenticate(&self, transport: &str, key: Option<&str>) -> Result<TenantId> {
+        self.api_keys.authenticate(key).map_err(|err| {
+            metrics::counter_with(
+                "geo_unauthorized_total",
+                "Requests, connections and datagrams rejected for missing or unknown API keys.",
+                &[("transport", transport)],
+            )
+            .inc();
+            err.into()
+        })
+    }
+
+    /// Apply the given policy to statuses before persisting them. Rejected
+    /// ones fail with [`IngestError::InvalidTimestamp`].
+    pub fn with_timestamp_policy(mut self, policy: TimestampPolicy) -> Self {
+        self.timestamps = Some(Arc::new(TimestampCheck::new(policy)));
+        self
+    }
+
+    /// Subscribe to statuses as they're accepted. Watchers that fall behind
+    /// by more than a fixed number of statuses miss the oldest ones.
+    pub fn watch(&self) -> broadcast::Receiver<Status> {
+        self.watchers.subscribe()
+    }
+
+    /// Persist a single status and, once stored, publish it.
+    pub async fn accept(&self, status: Status) -> Result<()> {
+        let status = self.check(status)?;
+        self.handler.command(StorageCommand::PersistStatus(status)).await??;
+        self.publish(&status);
+        Ok(())
+    }
+
+    /// Same as [`Pipeline::accept`], but fails if the status isn't persisted
+    /// within `timeout`.
+    pub async fn accept_within(&self, status: Status, timeout: Duration) -> Result<()> {
+        let status = self.check(status)?;
+        let cmd = StorageCommand::PersistStatus(status);
+        self.handler.command_timeout(cmd, timeout).await??;
+        self.publish(&status);
+        Ok(())
+    }
+
+    /// Persist several statuses at once, all or nothing, and once stored,
+    /// publish them. Fails if they aren't persisted within `timeout`, and with
+    /// [`StorageError::BatchRolledBack`] if storage fails midway. Statuses
+    /// rejected for their timestamps are skipped, and their number is
+    /// returned.
+    pub async fn accept_batch_within(
+        &self,
+        statuses: Vec<Status>,
+        timeout: Duration,
+    ) -> Result<usize> {
+        let count = statuses.len();
+        let statuses: Vec<_> = statuses.into_iter().filter_map(|s| self.check(s).ok()).collect();
+        let rejected = count - statuses.len();
+        if statuses.is_empty() {
+            return Ok(rejected);
+        }
+        let cmd = StorageCommand::PersistBatch(statuses.clone());
+        self.handler.command_timeout(cmd, timeout).await??;
+        for status in &statuses {
+            self.publish(status);
+        }
+        Ok(rejected)
+    }
+
+    /// Queue a single status for storage without waiting for it to be
+    /// persisted, and publish it right away. Storage errors are only logged.
+    pub async fn submit(&self, status: Status) -> Result<()> {
+        let status = self.check(status)?;
+        self.handler.notify(StorageCommand::PersistStatus(status)).await?;
+        self.publish(&status);
+        Ok(())
+    }
+
+    /// Same as [`Pipeline::submit`], but for several statuses at once.
+    /// Statuses rejected for their timestamps are skipped.
+    pub async fn submit_batch(&self, statuses: Vec<Status>) -> Result<()> {
+        let statuses: Vec<_> = statuses.into_iter().filter_map(|s| self.check(s).ok()).collect();
+        if statuses.is_empty() {
+            return Ok(());
+        }
+        for status in &statuses {
+            self.publish(status);
+        }
+        self.handler.notify(StorageCommand::PersistStatuses(statuses)).await?;
+        Ok(())
+    }
+
+    fn check(&self, status: Status) -> Result<Status> {
+        let status = sanitize(status);
+        match &self.timestamps {
+            Some(check) => check.apply(status),
+            None => Ok(status),
+        }
+    }
+
+    fn publish(&self, status: &Status) {
+        if let Some(publisher) = &self.publisher {
+            publisher.publish(status);
+        }
+        // Fails only if nobody is watching.
+        let _ = self.watchers.send(*status);
+    }
+}
+
+/// Settings of [`listen_tcp`].
+#[derive(Debug, Clone)]
+pub struct TcpConfig {
+    /// How long to wait for the next status before closing a connection.
+    pub read_timeout: Duration,
+    /// How long a connection may go without delivering a status before it's
+    /// closed by a periodic sweep. Unlike `read_timeout`, this also catches
+    /// connections stuck elsewhere, e.g. in writing acknowledgments to a client
+    /// that doesn't read them.
+    pub idle_timeout: Option<Duration>,
+    /// Maximum number of open connections. New ones are closed right away.
+    pub max_connections: Option<usize>,
+    /// Maximum number of open connections from a single IP address.
+    pub max_connections_per_ip: Option<usize>,
+    pub ack: AckMode,
+    /// Payload format of connections it can't be detected for.
+    pub format: PayloadFormat,
+    /// Shift device timestamps by the clock offset observed over each
+    /// connection once it exceeds this tolerance (see [`ClockSkew`]).
+    /// Disabled if `None`.
+    pub skew_tolerance: Option<Duration>,
+    /// Accept connections compressed as a stream of zstd frames, detected
+    /// from its magic number after any authentication line.
+    pub zstd: bool,
+    /// How many times more bytes of statuses than compressed ones a
+    /// connection may deliver before it's closed.
+    pub max_decompression_ratio: u64,
+}
+
+/// Estimate of how far the clock of a device is off, from the statuses it has
+/// sent over a connection so far. Network latency only ever delays statuses,
+/// so the smallest difference between receive and device time seen is the
+/// closest to the actual offset.
+struct ClockSkew {
+    tolerance: Duration,
+    offset: Option<time::Duration>,
+}
+
+impl ClockSkew {
+    fn new(tolerance: Duration) -> Self {
+        Self { tolerance, offset: None }
+    }
+
+    /// Update the estimate with a received status.
+    fn observe(&mut self, status: &Status) {
+        let Some(received_at) = status.received_at else { return };
+        let observed = received_at - status.timestamp;
+        self.offset = Some(self.offset.map_or(observed, |offset| offset.min(observed)));
+    }
+
+    /// Shift the timestamp of a status by the estimated offset if it's beyond
+    /// tolerance, sub-second part included.
+    fn correct(&self, status: &mut Status) {
+        match self.offset {
+            Some(offset) if offset.unsigned_abs() > self.tolerance => status.timestamp += offset,
+            _ => {}
+        }
+    }
+}
+
+/// Open TCP connections, used for enforcing limits, reaping idle ones, and
+/// listing them through the admin API.
+pub(crate) struct Connections {
+    state: Mutex<ConnectionsState>,
+    open: metrics::Gauge,
+    rejected_total: metrics::Counter,
+    rejected_per_ip: metrics::Counter,
+    reaped: metrics::Counter,
+}
+
+#[derive(Default)]
+struct ConnectionsState {
+    next_id: u64,
+    per_ip: HashMap<IpAddr, usize>,
+    open: HashMap<u64, Arc<Connection>>,
+}
+
+/// Tracking state of a single connection.
+struct Connection {
+    remote_addr: SocketAddr,
+    connected_at: OffsetDateTime,
+    /// Set once the client has authenticated, if it has to.
+    tenant_id: OnceLock<TenantId>,
+    last_active: Mutex<Instant>,
+    /// Statuses persisted so far.
+    persisted: AtomicU64,
+    /// Cancelled to close the connection.
+    close: CancellationToken,
+}
+
+impl Connection {
+    /// Record that statuses up to `seq` were persisted.
+    fn touch(&self, seq: u64) {
+        *self.last_active.lock().unwrap_or_else(|e| e.into_inner()) = Instant::now();
+        self.persisted.store(seq, Ordering::Relaxed);
+    }
+
+    fn idle_for(&self) -> Duration {
+        self.last_active.lock().unwrap_or_else(|e| e.into_inner()).elapsed()
+    }
+}
+
+/// Unregisters a connection once dropped.
+struct ConnectionGuard {
+    connections: Arc<Connections>,
+    id: u64,
+    ip: IpAddr,
+    connection: Arc<Connection>,
+}
+
+/// An open TCP connection, as listed by the admin API.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Session {
+    pub id: u64,
+    pub remote_addr: SocketAddr,
+    /// Unknown until the client authenticates, if API keys are configured.
+    pub tenant_id: Option<TenantId>,
+    #[serde(with = "timestamp")]
+    pub connected_at: OffsetDateTime,
+    /// Seconds since the last persisted status, or since connecting.
+    pub idle_secs: f64,
+    /// Statuses persisted over the connection so far.
+    pub statuses: u64,
+}
+
+impl Connections {
+    pub(crate) fn new() -> Self {
+        let rejected = |reason| {
+            metrics::counter_with(
+                "geo_tcp_connections_rejected_total",
+                "TCP connections closed right away due to connection limits.",
+                &[("reason", reason)],
+            )
+        };
+        Self {
+            state: Default::default(),
+            open: metrics::gauge("geo_tcp_connections", "Open TCP connections."),
+            rejected_total: rejected("max_connections"),
+            rejected_per_ip: rejected("max_connections_per_ip"),
+            reaped: metrics::counter(
+                "geo_tcp_connections_reaped_total",
+                "TCP connections closed for being idle.",
+            ),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, ConnectionsState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Register a new connection, unless that would exceed the limits.
+    fn open(self: &Arc<Self>, remote_addr: SocketAddr, cfg: &TcpConfig) -> Option<ConnectionGuard> {
+        let ip = remote_addr.ip();
+        let mut state = self.state();
+        if cfg.max_connections.is_some_and(|max| state.open.len() >= max) {
+            self.rejected_total.inc();
+            return None;
+        }
+        let from_ip = state.per_ip.get(&ip).copied().unwrap_or_default();
+        if cfg.max_connections_per_ip.is_some_and(|max| from_ip >= max) {
+            self.rejected_per_ip.inc();
+            return None;
+        }
+
+        let id = state.next_id;
+        state.next_id += 1;
+        *state.per_ip.entry(ip).or_default() += 1;
+        let connection = Arc::new(Connection {
+            remote_addr,
+            connected_at: OffsetDateTime::now_utc(),
+            tenant_id: OnceLock::new(),
+            last_active: Mutex::new(Instant::now()),
+            persisted: AtomicU64::new(0),
+            close: CancellationToken::new(),
+        });
+        state.open.insert(id, connection.clone());
+        self.open.set(state.open.len() as i64);
+        Some(ConnectionGuard { connections: self.clone(), id, ip, connection })
+    }
+
+    /// Close connections that have been idle for longer than `idle_timeout`.
+    fn reap(&self, idle_timeout: Duration) {
+        for connection in self.state().open.values() {
+            if !connection.close.is_cancelled() && connection.idle_for() > idle_timeout {
+                connection.close.cancel();
+                self.reaped.inc();
+            }
+        }
+    }
+
+    /// Open connections, ordered by when they were opened.
+    pub(crate) fn list(&self) -> Vec<Session> {
+        let mut sessions: Vec<_> = self
+            .state()
+            .open
+            .iter()
+            .map(|(&id, connection)| Session {
+                id,
+                remote_addr: connection.remote_addr,
+                tenant_id: connection.tenant_id.get().copied(),
+                connected_at: connection.connected_at,
+                idle_secs: connection.idle_for().as_secs_f64(),
+                statuses: connection.persisted.load(Ordering::Relaxed),
+            })
+            .collect();
+        sessions.sort_unstable_by_key(|s| s.id);
+        sessions
+    }
+
+    /// Close the connection with the given ID. Returns whether it was open.
+    pub(crate) fn close(&self, id: u64) -> bool {
+        match self.state().open.get(&id) {
+            Some(connection) => {
+                connection.close.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut state = self.connections.state();
+        state.open.remove(&self.id);
+        if let Some(count) = state.per_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                state.per_ip.remove(&self.ip);
+            }
+        }
+        self.connections.open.set(state.open.len() as i64);
+    }
+}
+
 /// Bind to the specified network address and start listening for incoming
 /// [`Status`] packets over TCP. Incoming packets are decoded and forwarded for
-/// storage and further processing.
-#[tracing::instrument(skip(handler))]
-pub async fn listen_tcp(
-    addr: &SocketAddr,
-    read_timeout: Duration,
-    handler: StorageHandler,
-) -> Result<()> {
+/// storage and further processing. The payload format is detected for each
+/// connection, falling back to `cfg.format`, after decompressing it if it's
+/// zstd compressed and `cfg.zstd` is enabled.
+#[tracing::instrument(skip(pipeline))]
+pub async fn listen_tcp(addr: &SocketAddr, cfg: TcpConfig, pipeline: Pipeline) -> Result<()> {
     info!("Starting TCP listener at http://{}:{}...", addr.ip(), addr.port());
 
     let listener = TcpListener::bind(addr).await?;
+    let connections = pipeline.settings().connections().clone();
 
+    if let Some(idle_timeout) = cfg.idle_timeout {
+        let connections = Arc::downgrade(&connections);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval((idle_timeout / 4).max(REAP_INTERVAL));
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                match connections.upgrade() {
+                    Some(connections) => connections.reap(idle_timeout),
+                    None => break,
+                }
+            }
+        });
+    }
+
+    let cfg = Arc::new(cfg);
     tokio::spawn(async move {
         loop {
             match listener.accept().await {
                 Ok((socket, remote_addr)) => {
+                    let Some(guard) = connections.open(remote_addr, &cfg) else {
+                        debug!(%remote_addr, "connection limit reached, closing connection");
+                        continue;
+                    };
                     debug!(%remote_addr, "new incoming connection established");
-                    let handler = handler.clone();
+                    let (cfg, pipeline) = (cfg.clone(), pipeline.clone());
                     tokio::spawn(async move {
-                        match process_status_stream(socket, read_timeout, remote_addr, handler)
-                            .await
-                        {
+                        let connection = &guard.connection;
+                        let result = tokio::select! {
+                            result = process_status_stream(
+                                socket,
+                                &cfg,
+                                remote_addr,
+                                pipeline,
+                                connection,
+                            ) => result,
+                            _ = connection.close.cancelled() => {
+                                debug!(%remote_addr, "closing idle or dropped connection");
+                                Ok(())
+                            }
+                        };
+                        match result {
                             Ok(()) => {
                                 debug!("connection closed");
                             }
@@ -83,15 +843,115 @@ pub async fn listen_tcp(
     Ok(())
 }
 
-#[tracing::instrument(skip(handler))]
+#[tracing::instrument(skip(cfg, pipeline, connection))]
 async fn process_status_stream(
     stream: TcpStream,
-    read_timeout: Duration,
+    cfg: &TcpConfig,
     remote_addr: SocketAddr,
-    handler: StorageHandler,
+    pipeline: Pipeline,
+    connection: &Connection,
+) -> Result<()> {
+    let (mut reader, mut writer) = stream.into_split();
+    let (tenant_id, mut rest) = match pipeline.requires_auth() {
+        true => authenticate(&mut reader, cfg, &pipeline).await?,
+        false => (TenantId::DEFAULT, BytesMut::new()),
+    };
+    let _ = connection.tenant_id.set(tenant_id);
+    if cfg.zstd {
+        while rest.len() < Compression::ZSTD_MAGIC.len() {
+            if timeout(cfg.read_timeout, reader.read_buf(&mut rest)).await?? == 0 {
+                break;
+            }
+        }
+    }
+    let compressed = cfg.zstd && rest.starts_with(&Compression::ZSTD_MAGIC);
+    let reader = AsyncReadExt::chain(Cursor::new(rest), reader);
+    let reader: Pin<Box<dyn AsyncRead + Send>> = match compressed {
+        true => Compression::Zstd.decoder(reader, cfg.max_decompression_ratio),
+        false => Box::pin(reader),
+    };
+    let mut reader = FramedRead::new(reader, StatusDecoder::Detect(cfg.format));
+    let mut skew = cfg.skew_tolerance.map(ClockSkew::new);
+    let mut seq = 0;
+    while let Some(frame) = timeout(cfg.read_timeout, reader.next()).await? {
+        let received_at = OffsetDateTime::now_utc();
+        let mut frames = vec![frame];
+        if cfg.ack == AckMode::Batch || skew.is_some() {
+            // Gather whatever else has already arrived without waiting for more.
+            while frames.len() < MAX_ACK_BATCH {
+                match reader.next().now_or_never() {
+                    Some(Some(frame)) => frames.push(frame),
+                    _ => break,
+                }
+            }
+        }
+
+        for status in frames.iter_mut().flatten() {
+            status.received_at = Some(received_at);
+            status.tenant_id = tenant_id;
+        }
+        if let Some(skew) = &mut skew {
+            // Statuses arriving together are likely a backlog, whose latest
+            // entry tells the most about the device clock.
+            frames.iter().flatten().for_each(|status| skew.observe(status));
+            frames.iter_mut().flatten().for_each(|status| skew.correct(status));
+        }
+
+        let batch_size = if cfg.ack == AckMode::Batch { frames.len() } else { 1 };
+        let mut frames = frames.into_iter().peekable();
+        while frames.peek().is_some() {
+            let batch = frames.by_ref().take(batch_size).collect::<Vec<_>>();
+            let count = batch.len() as u32;
+            let result = persist_batch(batch, remote_addr, &pipeline, &mut seq).await;
+            if result.is_ok() {
+                connection.touch(seq);
+            }
+            if cfg.ack != AckMode::None {
+                write_ack(&mut writer, Ack { ok: result.is_ok(), seq, count }).await?;
+            }
+            result?;
+        }
+    }
+    Ok(())
+}
+
+/// Read the authentication line a connection has to start with when API keys
+/// are configured. Returns the tenant of the key along with any bytes read past
+/// the line.
+async fn authenticate(
+    reader: &mut OwnedReadHalf,
+    cfg: &TcpConfig,
+    pipeline: &Pipeline,
+) -> Result<(TenantId, BytesMut)> {
+    let mut buf = BytesMut::with_capacity(client::MAX_AUTH_LEN);
+    loop {
+        let line = match client::decode_auth(&buf) {
+            Ok(None) => {
+                if timeout(cfg.read_timeout, reader.read_buf(&mut buf)).await?? == 0 {
+                    return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+                }
+                continue;
+            }
+            Ok(Some(line)) => Some(line),
+            // Treated the same as a missing key.
+            Err(_) => None,
+        };
+        let tenant_id = pipeline.authenticate("tcp", line.map(|(key, _)| key))?;
+        let len = line.map_or(0, |(_, len)| len);
+        buf.advance(len);
+        return Ok((tenant_id, buf));
+    }
+}
+
+/// Persist decoded statuses in order, stopping at the first failure. `seq` is
+/// incremented for each persisted status.
+async fn persist_batch(
+    batch: Vec<Result<Status>>,
+    remote_addr: SocketAddr,
+    pipeline: &Pipeline,
+    seq: &mut u64,
 ) -> Result<()> {
-    let mut reader = FramedRead::new(stream, CborDecoder::<Status>::default());
-    while let Some(frame) = timeout(read_timeout, reader.next()).await? {
+    for frame in batch {
         let status = frame?;
         debug!(
             %remote_addr,
@@ -100,54 +960,395 @@ async fn process_status_stream(
             "received status: {:?}",
             status
         );
-        handler.command(StorageCommand::PersistStatus(status)).await??;
+        match pipeline.accept(status).await {
+            Ok(()) => *seq += 1,
+            // Resending a rejected status wouldn't help, so it's acknowledged
+            // as if it were persisted.
+            Err(err @ IngestError::InvalidTimestamp { .. }) => {
+                debug!(%remote_addr, %err, "rejected status");
+                *seq += 1;
+            }
+            Err(err) => return Err(err),
+        }
     }
     Ok(())
 }
 
+async fn write_ack(writer: &mut OwnedWriteHalf, ack: Ack) -> Result<()> {
+    let mut bytes = Vec::new();
+    ciborium::ser::into_writer(&ack, &mut bytes)?;
+    writer.write_all(&bytes).await?;
+    Ok(())
+}
+
+/// Settings of [`listen_udp`].
+#[derive(Debug, Clone)]
+pub struct UdpConfig {
+    /// Largest datagram accepted, in bytes. Longer ones are dropped.
+    pub buffer_size: usize,
+    /// Maximum number of datagrams read at once and persisted together.
+    pub batch_size: usize,
+    /// Payload format of datagrams it can't be detected for.
+    pub format: PayloadFormat,
+    /// How long to remember received datagrams for, dropping identical ones
+    /// arriving in the meantime. Disabled if `None`.
+    pub dedup_window: Option<Duration>,
+}
+
+/// Short-lived memory of received datagrams, used for dropping retransmissions
+/// before they reach storage.
+struct Dedup {
+    window: Duration,
+    seen: HashSet<DedupKey>,
+    /// Keys in the order they were first seen, for expiring them.
+    expiry: VecDeque<(Instant, DedupKey)>,
+}
+
+type DedupKey = (TenantId, SourceId, OffsetDateTime, u64);
+
+impl Dedup {
+    fn new(window: Duration) -> Self {
+        Self { window, seen: HashSet::new(), expiry: VecDeque::new() }
+    }
+
+    /// Record a datagram, returning `false` if it's been seen within the window.
+    fn insert(&mut self, status: &Status, payload: &[u8], now: Instant) -> bool {
+        while let Some((seen_at, key)) = self.expiry.front() {
+            if now.duration_since(*seen_at) < self.window {
+                break;
+            }
+            self.seen.remove(key);
+            self.expiry.pop_front();
+        }
+
+        let mut hasher = DefaultHasher::new();
+        payload.hash(&mut hasher);
+        let key = (status.tenant_id, status.source_id, status.timestamp, hasher.finish());
+        if !self.seen.insert(key) {
+            return false;
+        }
+        self.expiry.push_back((now, key));
+        true
+    }
+}
+
 /// Bind to the specified network address and start listening for incoming
 /// [`Status`] packets over UDP. Incoming packets are decoded and forwarded for
-/// storage and further processing.
-#[tracing::instrument(skip(handler))]
-pub async fn listen_udp(addr: &SocketAddr, handler: StorageHandler) -> Result<()> {
+/// storage and further processing. The payload format is detected for each
+/// datagram, falling back to `cfg.format`.
+#[tracing::instrument(skip(pipeline))]
+pub async fn listen_udp(addr: &SocketAddr, cfg: UdpConfig, pipeline: Pipeline) -> Result<()> {
     info!("Starting UDP listener at http://{}:{}...", addr.ip(), addr.port());
 
     let socket = UdpSocket::bind(addr).await?;
-    // A valid `Status` with all fields specified is CBOR-encoded into ~100
-    // bytes, so this buffer should be sufficient to store a single instance
-    // while also not blowing the stack.
-    let mut buf = [0; 128];
+    // One extra byte makes it possible to tell truncated datagrams apart from
+    // ones that fill the buffer exactly.
+    let mut buf = vec![0; cfg.buffer_size + 1];
+    let truncated = metrics::counter(
+        "geo_udp_truncated_total",
+        "UDP datagrams dropped for exceeding the receive buffer.",
+    );
+    let duplicates =
+        metrics::counter("geo_udp_duplicates_total", "Retransmitted UDP datagrams dropped.");
+    let mut dedup = cfg.dedup_window.map(Dedup::new);
 
     tokio::spawn(async move {
         loop {
-            match socket.recv_from(&mut buf).await {
-                Ok((len, remote_addr)) => {
-                    match ciborium::de::from_reader::<Status, _>(&buf[0..len]) {
-                        Ok(status) => {
-                            debug!(
-                                %remote_addr,
-                                source_id = %status.source_id,
-                                timestamp = %status.timestamp,
-                                "received status: {:?}",
-                                status
-                            );
-                            if let Err(err) =
-                                handler.command(StorageCommand::PersistStatus(status)).await
-                            {
-                                error!(%err, "failed to handle incoming status");
-                            }
-                        }
+            if let Err(err) = socket.readable().await {
+                debug!(%err, "failed to wait for datagrams");
+                continue;
+            }
+
+            let mut batch = Vec::new();
+            while batch.len() < cfg.batch_size.max(1) {
+                let (len, remote_addr) = match socket.try_recv_from(&mut buf) {
+                    Ok(received) => received,
+                    Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => break,
+                    Err(err) => {
+                        debug!(%err, "failed to read datagram");
+                        break;
+                    }
+                };
+                if len > cfg.buffer_size {
+                    debug!(%remote_addr, "dropping datagram exceeding the receive buffer");
+                    truncated.inc();
+                    continue;
+                }
+
+                let mut payload = &buf[0..len];
+                let mut tenant_id = TenantId::DEFAULT;
+                if pipeline.requires_auth() {
+                    let line = client::decode_auth(payload).ok().flatten();
+                    match pipeline.authenticate("udp", line.map(|(key, _)| key)) {
+                        Ok(tenant) => tenant_id = tenant,
                         Err(err) => {
-                            debug!(%remote_addr, %err, "failed to deserialize status");
+                            debug!(%remote_addr, %err, "dropping unauthenticated datagram");
+                            continue;
                         }
                     }
+                    payload = &payload[line.map_or(0, |(_, len)| len)..];
                 }
-                Err(err) => {
-                    debug!(%err, "failed to read datagram");
+
+                let format = PayloadFormat::detect(payload, cfg.format).unwrap_or(cfg.format);
+                let received_at = Some(OffsetDateTime::now_utc());
+                let status = format.decode(payload);
+                match status.map(|status| Status { received_at, tenant_id, ..status }) {
+                    Ok(status)
+                        if dedup.as_mut().is_some_and(|dedup| {
+                            !dedup.insert(&status, payload, Instant::now())
+                        }) =>
+                    {
+                        debug!(%remote_addr, "dropping duplicate datagram");
+                        duplicates.inc();
+                    }
+                    Ok(status) => {
+                        debug!(
+                            %remote_addr,
+                            source_id = %status.source_id,
+                            timestamp = %status.timestamp,
+                            "received status: {:?}",
+                            status
+                        );
+                        batch.push(status);
+                    }
+                    Err(err) => {
+                        debug!(%remote_addr, %err, "failed to deserialize status");
+                    }
                 }
             }
+
+            let result = match batch.len() {
+                0 => Ok(()),
+                1 => pipeline.submit(batch[0]).await,
+                _ => pipeline.submit_batch(batch).await,
+            };
+            match result {
+                Ok(()) => {}
+                Err(err @ IngestError::InvalidTimestamp { .. }) => debug!(%err, "rejected status"),
+                Err(err) => error!(%err, "failed to handle incoming status"),
+            }
         }
     });
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use tokio_util::codec::Decoder;
+
+    use std::{
+        net::{IpAddr, Ipv4Addr, SocketAddr},
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use shared::{
+        client::{Encoder, Encoding, MAX_FRAME_LEN},
+        data::{SourceId, Status, TenantId},
+    };
+    use time::OffsetDateTime;
+    use uom::si::{f64::Length, length::meter};
+
+    use super::{
+        sanitize, AckMode, ClockSkew, Connections, Dedup, IngestError, PayloadFormat,
+        StatusDecoder, TcpConfig, TimestampAction, TimestampCheck, TimestampPolicy,
+    };
+
+    const JSON: &str =
+        r#"{"sourceId":"0aaec05a-0e7d-4fd5-abc0-0ba69e3cfe11","timestamp":1627364719}"#;
+
+    #[test]
+    fn detect_format() {
+        let detect = |bytes: &[u8]| PayloadFormat::detect(bytes, PayloadFormat::Cbor);
+        assert_eq!(detect(b" \n{\"sourceId\""), Some(PayloadFormat::Json));
+        assert_eq!(detect(&[0xa2, 0x68]), Some(PayloadFormat::Cbor));
+        assert_eq!(detect(&[0xd9, 0xd9, 0xf7, 0xa2]), Some(PayloadFormat::Cbor));
+        assert_eq!(detect(&[0xd9]), None);
+        assert_eq!(detect(b"G\x80\x00"), Some(PayloadFormat::Framed));
+        assert_eq!(detect(b"  "), None);
+        assert_eq!(PayloadFormat::detect(b"[", PayloadFormat::Json), Some(PayloadFormat::Json));
+    }
+
+    #[test]
+    fn decode_json_stream() {
+        let mut decoder = StatusDecoder::Detect(PayloadFormat::Cbor);
+        let mut src = BytesMut::from(&b"\n"[..]);
+        assert!(decoder.decode(&mut src).unwrap().is_none());
+
+        src.extend_from_slice(format!("{JSON}\n\n{}", &JSON[..20]).as_bytes());
+        assert!(decoder.decode(&mut src).unwrap().is_some());
+        assert!(decoder.decode(&mut src).unwrap().is_none());
+
+        // Objects are complete without a trailing newline.
+        src.extend_from_slice(JSON[20..].as_bytes());
+        assert!(decoder.decode(&mut src).unwrap().is_some());
+        assert!(decoder.decode_eof(&mut src).unwrap().is_none());
+
+        // Whitespace may follow any token, so a line ending doesn't complete one.
+        src.extend_from_slice(format!("{}\n", &JSON[..12]).as_bytes());
+        assert!(decoder.decode(&mut src).unwrap().is_none());
+        assert!(decoder.decode_eof(&mut src).is_err());
+    }
+
+    #[test]
+    fn decode_framed_stream() {
+        let status: Status = serde_json::from_str(JSON).unwrap();
+        let mut src = BytesMut::new();
+        for encoder in [Encoder::new(Encoding::Cbor).with_crc(), Encoder::new(Encoding::Postcard)] {
+            let mut buf = [0; MAX_FRAME_LEN];
+            let len = encoder.encode(&status, &mut buf).unwrap();
+            src.extend_from_slice(&buf[..len]);
+        }
+        let partial = src.split_off(src.len() - 3);
+
+        let mut decoder = StatusDecoder::Detect(PayloadFormat::Cbor);
+        let decoded = decoder.decode(&mut src).unwrap().unwrap();
+        assert_eq!(decoded.source_id, status.source_id);
+        assert!(decoder.decode(&mut src).unwrap().is_none());
+        assert!(decoder.decode_eof(&mut src).is_err());
+
+        src.unsplit(partial);
+        assert!(decoder.decode(&mut src).unwrap().is_some());
+        assert!(decoder.decode_eof(&mut src).unwrap().is_none());
+    }
+
+    #[test]
+    fn clock_skew() {
+        let mut skew = ClockSkew::new(Duration::from_secs(2));
+        let mut status: Status = serde_json::from_str(JSON).unwrap();
+        let device_time = status.timestamp;
+        let mut receive = |delay: i64, latency: i64| {
+            let timestamp = device_time + time::Duration::seconds(delay);
+            let received_at = timestamp + time::Duration::seconds(100 + latency);
+            status = Status { timestamp, received_at: Some(received_at), ..status };
+            skew.observe(&status);
+            skew.correct(&mut status);
+            status.timestamp - timestamp
+        };
+
+        // The first status is assumed to have no latency at all.
+        assert_eq!(receive(0, 3), time::Duration::seconds(103));
+        assert_eq!(receive(10, 0), time::Duration::seconds(100));
+        assert_eq!(receive(20, 1), time::Duration::seconds(100));
+
+        let mut skew = ClockSkew::new(Duration::from_secs(2));
+        status.received_at = Some(status.timestamp + time::Duration::seconds(1));
+        let timestamp = status.timestamp;
+        skew.observe(&status);
+        skew.correct(&mut status);
+        assert_eq!(status.timestamp, timestamp);
+
+        let mut skew = ClockSkew::new(Duration::from_secs(2));
+        let offset = time::Duration::milliseconds(2500);
+        status.received_at = Some(timestamp + offset);
+        skew.observe(&status);
+        skew.correct(&mut status);
+        assert_eq!(status.timestamp, timestamp + offset);
+    }
+
+    #[test]
+    fn timestamp_policy() {
+        let check = |action, offset: i64| {
+            let policy = TimestampPolicy {
+                action,
+                max_future: Duration::from_secs(60),
+                max_age: Some(Duration::from_secs(3600)),
+            };
+            let mut status: Status = serde_json::from_str(JSON).unwrap();
+            status.received_at = Some(status.timestamp + time::Duration::milliseconds(250));
+            status.timestamp += time::Duration::seconds(offset);
+            TimestampCheck::new(policy)
+                .apply(status)
+                .map(|s| (s.timestamp - s.received_at.unwrap(), s.suspect_timestamp))
+        };
+        let seconds = time::Duration::seconds_f64;
+
+        assert_eq!(check(TimestampAction::Reject, 60).unwrap(), (seconds(59.75), false));
+        assert_eq!(check(TimestampAction::Reject, -3599).unwrap(), (seconds(-3599.25), false));
+        assert!(matches!(
+            check(TimestampAction::Reject, 61),
+            Err(IngestError::InvalidTimestamp { .. })
+        ));
+        // Clamped to the bounds, sub-second part included.
+        assert_eq!(check(TimestampAction::Clamp, 1000).unwrap(), (seconds(60.0), false));
+        assert_eq!(check(TimestampAction::Clamp, -5000).unwrap(), (seconds(-3600.0), false));
+        assert_eq!(check(TimestampAction::Flag, -5000).unwrap(), (seconds(-5000.25), true));
+    }
+
+    #[test]
+    fn sanitize_odometer() {
+        let status: Status = serde_json::from_str(JSON).unwrap();
+        let odometer = |meters| {
+            let status = Status { odometer: Some(Length::new::<meter>(meters)), ..status };
+            sanitize(status).odometer.map(|d| d.get::<meter>())
+        };
+        assert_eq!(odometer(0.), Some(0.));
+        assert_eq!(odometer(1_234.5), Some(1_234.5));
+        assert_eq!(odometer(-1.), None);
+        assert_eq!(odometer(f64::NAN), None);
+        assert_eq!(odometer(f64::INFINITY), None);
+    }
+
+    #[test]
+    fn connection_limits() {
+        let cfg = TcpConfig {
+            read_timeout: Duration::from_secs(1),
+            idle_timeout: None,
+            max_connections: Some(3),
+            max_connections_per_ip: Some(2),
+            ack: AckMode::None,
+            format: PayloadFormat::Cbor,
+            skew_tolerance: None,
+            zstd: false,
+            max_decompression_ratio: 100,
+        };
+        let connections = Arc::new(Connections::new());
+        let a = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 40000);
+        let b = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 40000);
+
+        let first = connections.open(a, &cfg).unwrap();
+        let _second = connections.open(a, &cfg).unwrap();
+        assert!(connections.open(a, &cfg).is_none());
+        let third = connections.open(b, &cfg).unwrap();
+        assert!(connections.open(b, &cfg).is_none());
+
+        let ids: Vec<_> = connections.list().iter().map(|s| s.id).collect();
+        assert_eq!(ids, [0, 1, 2]);
+        assert_eq!(connections.list()[2].remote_addr, b);
+        assert!(connections.close(2));
+        assert!(third.connection.close.is_cancelled());
+        assert!(!connections.close(3));
+
+        drop(first);
+        assert!(connections.open(a, &cfg).is_some());
+    }
+
+    #[test]
+    fn dedup_window() {
+        let status = Status {
+            source_id: serde_json::from_str::<SourceId>("\"00000000-0000-0000-0000-000000000001\"")
+                .unwrap(),
+            timestamp: OffsetDateTime::UNIX_EPOCH,
+            position: None,
+            bearing: None,
+            speed: None,
+            satellites: None,
+            fix: None,
+            odometer: None,
+            ignition: None,
+            received_at: None,
+            suspect_timestamp: false,
+            tenant_id: TenantId::DEFAULT,
+        };
+        let mut dedup = Dedup::new(Duration::from_secs(5));
+        let now = Instant::now();
+
+        assert!(dedup.insert(&status, b"a", now));
+        assert!(!dedup.insert(&status, b"a", now + Duration::from_secs(4)));
+        // Same source and timestamp, but different contents.
+        assert!(dedup.insert(&status, b"b", now + Duration::from_secs(4)));
+        assert!(dedup.insert(&status, b"a", now + Duration::from_secs(5)));
+    }
+}
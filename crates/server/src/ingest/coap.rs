@@ -0,0 +1,680 @@
+//! CoAP ([RFC 7252]) listener for constrained devices, like NB-IoT trackers,
+//! that speak it over UDP rather than sending raw datagrams. Devices `POST`
+//! CBOR-encoded statuses to `/status`.
+//!
+//! Confirmable requests are acknowledged once their status has been persisted,
+//! with the response piggybacked on the acknowledgment. Retransmissions are
+//! recognized by their message ID and answered with the same response without
+//! being processed again. When API keys are configured, requests have to carry
+//! one as a `key=<key>` query parameter.
+//!
+//! [RFC 7252]: https://www.rfc-editor.org/rfc/rfc7252
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use shared::{
+    codec::{Cbor, Codec},
+    data::Status,
+};
+use thiserror::Error;
+use time::OffsetDateTime;
+use tokio::net::UdpSocket;
+use tracing::{debug, info};
+
+use super::{IngestError, Pipeline, Result};
+use crate::{cq::CqrsError, metrics};
+
+/// Protocol version, the only one there is.
+const VERSION: u8 = 1;
+/// Separates options from the payload.
+const PAYLOAD_MARKER: u8 = 0xff;
+/// Longest token allowed.
+const MAX_TOKEN_LEN: usize = 8;
+/// How long a message ID identifies the same exchange. Retransmissions can't
+/// arrive later than this.
+const EXCHANGE_LIFETIME: Duration = Duration::from_secs(247);
+/// Upper limit of exchanges remembered for answering retransmissions.
+const MAX_EXCHANGES: usize = 65_536;
+
+const OPTION_URI_HOST: u16 = 3;
+const OPTION_URI_PORT: u16 = 7;
+const OPTION_URI_PATH: u16 = 11;
+const OPTION_CONTENT_FORMAT: u16 = 12;
+const OPTION_URI_QUERY: u16 = 15;
+const OPTION_ACCEPT: u16 = 17;
+
+/// `application/cbor` in the CoAP content-format registry.
+const CONTENT_FORMAT_CBOR: u16 = 60;
+
+/// The only resource there is.
+const STATUS_PATH: &str = "status";
+/// Query parameter carrying the API key.
+const KEY_PARAM: &str = "key=";
+
+/// Malformed CoAP messages.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum CoapError {
+    #[error("message truncated")]
+    Truncated,
+    #[error("unsupported version: {version}")]
+    Version { version: u8 },
+    #[error("token too long: {len} bytes")]
+    TokenLength { len: usize },
+    #[error("invalid option encoding")]
+    InvalidOption,
+    #[error("payload marker without payload")]
+    EmptyPayload,
+}
+
+/// Settings of [`listen_coap`].
+#[derive(Debug, Clone)]
+pub struct CoapConfig {
+    /// Largest datagram accepted, in bytes. Longer ones are dropped.
+    pub buffer_size: usize,
+    /// How long to wait for a status to be persisted before responding with an
+    /// error.
+    pub timeout: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageType {
+    Confirmable,
+    NonConfirmable,
+    Acknowledgement,
+    Reset,
+}
+
+impl MessageType {
+    fn from_bits(bits: u8) -> Self {
+        match bits & 0b11 {
+            0 => Self::Confirmable,
+            1 => Self::NonConfirmable,
+            2 => Self::Acknowledgement,
+            _ => Self::Reset,
+        }
+    }
+
+    fn bits(self) -> u8 {
+        match self {
+            Self::Confirmable => 0,
+            Self::NonConfirmable => 1,
+            Self::Acknowledgement => 2,
+            Self::Reset => 3,
+        }
+    }
+}
+
+/// Request method or response code, as a class and a detail, e.g. `4.04`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Code(u8);
+
+impl Code {
+    pub const EMPTY: Self = Self::new(0, 0);
+    pub const POST: Self = Self::new(0, 2);
+    pub const CHANGED: Self = Self::new(2, 4);
+    pub const BAD_REQUEST: Self = Self::new(4, 0);
+    pub const UNAUTHORIZED: Self = Self::new(4, 1);
+    pub const BAD_OPTION: Self = Self::new(4, 2);
+    pub const NOT_FOUND: Self = Self::new(4, 4);
+    pub const METHOD_NOT_ALLOWED: Self = Self::new(4, 5);
+    pub const UNSUPPORTED_CONTENT_FORMAT: Self = Self::new(4, 15);
+    pub const INTERNAL_SERVER_ERROR: Self = Self::new(5, 0);
+    pub const SERVICE_UNAVAILABLE: Self = Self::new(5, 3);
+    pub const GATEWAY_TIMEOUT: Self = Self::new(5, 4);
+
+    pub const fn new(class: u8, detail: u8) -> Self {
+        Self(class << 5 | detail & 0x1f)
+    }
+
+    pub fn class(self) -> u8 {
+        self.0 >> 5
+    }
+
+    pub fn detail(self) -> u8 {
+        self.0 & 0x1f
+    }
+
+    /// Whether this is a request method, as opposed to an empty message or a
+    /// response.
+    pub fn is_request(self) -> bool {
+        self.class() == 0 && self != Self::EMPTY
+    }
+}
+
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{:02}", self.class(), self.detail())
+    }
+}
+
+/// A CoAP message borrowing from the datagram it was decoded from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message<'a> {
+    pub ty: MessageType,
+    pub code: Code,
+    pub message_id: u16,
+    pub token: &'a [u8],
+    /// Options in ascending order of their numbers.
+    pub options: Vec<(u16, &'a [u8])>,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Message<'a> {
+    pub fn decode(bytes: &'a [u8]) -> std::result::Result<Self, CoapError> {
+        let &[first, code, id_high, id_low, ref rest @ ..] = bytes else {
+            return Err(CoapError::Truncated);
+        };
+        let version = first >> 6;
+        if version != VERSION {
+            return Err(CoapError::Version { version });
+        }
+        let token_len = (first & 0x0f) as usize;
+        if token_len > MAX_TOKEN_LEN {
+            return Err(CoapError::TokenLength { len: token_len });
+        }
+        let message_id = u16::from_be_bytes([id_high, id_low]);
+        let token = rest.get(..token_len).ok_or(CoapError::Truncated)?;
+
+        let mut rest = &rest[token_len..];
+        let mut options = Vec::new();
+        let mut number = 0u16;
+        let mut payload: &[u8] = &[];
+        while let Some((&byte, tail)) = rest.split_first() {
+            if byte == PAYLOAD_MARKER {
+                if tail.is_empty() {
+                    return Err(CoapError::EmptyPayload);
+                }
+                payload = tail;
+                break;
+            }
+            let (delta, tail) = decode_nibble(byte >> 4, tail)?;
+            let (len, tail) = decode_nibble(byte & 0x0f, tail)?;
+            number = number.checked_add(delta).ok_or(CoapError::InvalidOption)?;
+            let value = tail.get(..len as usize).ok_or(CoapError::Truncated)?;
+            options.push((number, value));
+            rest = &tail[len as usize..];
+        }
+
+        Ok(Self {
+            ty: MessageType::from_bits(first >> 4),
+            code: Code(code),
+            message_id,
+            token,
+            options,
+            payload,
+        })
+    }
+
+    /// Append the encoded message to `buf`. Options have to be sorted by
+    /// their numbers.
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        debug_assert!(self.token.len() <= MAX_TOKEN_LEN);
+        buf.push(VERSION << 6 | self.ty.bits() << 4 | self.token.len() as u8);
+        buf.push(self.code.0);
+        buf.extend_from_slice(&self.message_id.to_be_bytes());
+        buf.extend_from_slice(self.token);
+
+        let mut number = 0;
+        for &(option, value) in &self.options {
+            let (delta, delta_ext) = encode_nibble(option - number);
+            let (len, len_ext) = encode_nibble(value.len() as u16);
+            buf.push(delta << 4 | len);
+            buf.extend_from_slice(&delta_ext);
+            buf.extend_from_slice(&len_ext);
+            buf.extend_from_slice(value);
+            number = option;
+        }
+
+        if !self.payload.is_empty() {
+            buf.push(PAYLOAD_MARKER);
+            buf.extend_from_slice(self.payload);
+        }
+    }
+
+    /// Values of all options with the given number.
+    fn option_values(&self, number: u16) -> impl Iterator<Item = &'a [u8]> + '_ {
+        self.options.iter().filter(move |(n, _)| *n == number).map(|(_, value)| *value)
+    }
+}
+
+/// Decode the option delta or length held in 4 bits, which may be extended by
+/// up to two more bytes.
+fn decode_nibble(nibble: u8, bytes: &[u8]) -> std::result::Result<(u16, &[u8]), CoapError> {
+    match (nibble, bytes) {
+        (0..=12, _) => Ok((nibble as u16, bytes)),
+        (13, [ext, rest @ ..]) => Ok((*ext as u16 + 13, rest)),
+        (14, [a, b, rest @ ..]) => {
+            let value = u16::from_be_bytes([*a, *b]).checked_add(269);
+            Ok((value.ok_or(CoapError::InvalidOption)?, rest))
+        }
+        (13 | 14, _) => Err(CoapError::Truncated),
+        _ => Err(CoapError::InvalidOption),
+    }
+}
+
+fn encode_nibble(value: u16) -> (u8, Vec<u8>) {
+    match value {
+        0..=12 => (value as u8, Vec::new()),
+        13..=268 => (13, vec![(value - 13) as u8]),
+        _ => (14, (value - 269).to_be_bytes().to_vec()),
+    }
+}
+
+/// Decode an unsigned integer option value, which is sent without leading
+/// zero bytes.
+fn decode_uint(value: &[u8]) -> Option<u16> {
+    match value {
+        [] => Some(0),
+        [a] => Some(*a as u16),
+        [a, b] => Some(u16::from_be_bytes([*a, *b])),
+        _ => None,
+    }
+}
+
+/// Response to a request: a code, and a diagnostic message for errors.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Reply {
+    code: Code,
+    diagnostic: &'static str,
+}
+
+impl Reply {
+    const fn error(code: Code, diagnostic: &'static str) -> Self {
+        Self { code, diagnostic }
+    }
+}
+
+/// Check a request against the single resource there is, returning the status
+/// it carries, or the error to respond with.
+fn parse_request(request: &Message<'_>, pipeline: &Pipeline) -> std::result::Result<Status, Reply> {
+    // Unrecognized options with odd numbers are critical, and mustn't be
+    // ignored.
+    let known = [
+        OPTION_URI_HOST,
+        OPTION_URI_PORT,
+        OPTION_URI_PATH,
+        OPTION_CONTENT_FORMAT,
+        OPTION_URI_QUERY,
+        OPTION_ACCEPT,
+    ];
+    if request.options.iter().any(|(n, _)| n % 2 == 1 && !known.contains(n)) {
+        return Err(Reply::error(Code::BAD_OPTION, "unsupported critical option"));
+    }
+
+    let path = request.option_values(OPTION_URI_PATH).collect::<Vec<_>>();
+    if path != [STATUS_PATH.as_bytes()] {
+        return Err(Reply::error(Code::NOT_FOUND, "no such resource"));
+    }
+    if request.code != Code::POST {
+        return Err(Reply::error(Code::METHOD_NOT_ALLOWED, "only POST is supported"));
+    }
+    match request.option_values(OPTION_CONTENT_FORMAT).next().map(decode_uint) {
+        None | Some(Some(CONTENT_FORMAT_CBOR)) => {}
+        Some(_) => {
+            return Err(Reply::error(Code::UNSUPPORTED_CONTENT_FORMAT, "expected CBOR"));
+        }
+    }
+
+    let key = request
+        .option_values(OPTION_URI_QUERY)
+        .filter_map(|value| std::str::from_utf8(value).ok())
+        .find_map(|param| param.strip_prefix(KEY_PARAM));
+    let tenant_id = pipeline
+        .authenticate("coap", key)
+        .map_err(|_| Reply::error(Code::UNAUTHORIZED, "missing or unknown API key"))?;
+
+    let status = Cbor
+        .decode_one(request.payload)
+        .map_err(|_| Reply::error(Code::BAD_REQUEST, "invalid status payload"))?;
+    Ok(Status { tenant_id, received_at: Some(OffsetDateTime::now_utc()), ..status })
+}
+
+/// Response to a failure to accept a parsed status.
+fn error_reply(err: &IngestError) -> Reply {
+    match err {
+        IngestError::InvalidTimestamp { .. } => {
+            Reply::error(Code::BAD_REQUEST, "implausible timestamp")
+        }
+        IngestError::Internal(CqrsError::Timeout) | IngestError::Timeout(_) => {
+            Reply::error(Code::GATEWAY_TIMEOUT, "storage timed out")
+        }
+        IngestError::Internal(CqrsError::Overloaded) => {
+            Reply::error(Code::SERVICE_UNAVAILABLE, "storage overloaded")
+        }
+        _ => Reply::error(Code::INTERNAL_SERVER_ERROR, "failed to persist status"),
+    }
+}
+
+/// Key of an exchange: requests from the same endpoint with the same message
+/// ID are retransmissions of each other.
+type ExchangeKey = (SocketAddr, u16);
+
+/// Responses to recent requests, for answering retransmissions without
+/// processing them again.
+struct Exchanges {
+    /// Encoded responses, or `None` while the request is still being processed.
+    responses: HashMap<ExchangeKey, Option<Vec<u8>>>,
+    /// Keys in the order they were first seen, for expiring them.
+    expiry: VecDeque<(Instant, ExchangeKey)>,
+}
+
+/// Whether a request has been seen before, according to [`Exchanges`].
+#[derive(Debug, PartialEq, Eq)]
+enum Exchange {
+    New,
+    /// Still being processed. The response is sent once it's done.
+    Pending,
+    Done(Vec<u8>),
+}
+
+impl Exchanges {
+    fn new() -> Self {
+        Self { responses: HashMap::new(), expiry: VecDeque::new() }
+    }
+
+    /// Record a request, returning what's known about it from before.
+    fn begin(&mut self, key: ExchangeKey, now: Instant) -> Exchange {
+        while let Some((seen_at, expired)) = self.expiry.front() {
+            if now.duration_since(*seen_at) < EXCHANGE_LIFETIME && self.expiry.len() < MAX_EXCHANGES
+            {
+                break;
+            }
+            self.responses.remove(expired);
+            self.expiry.pop_front();
+        }
+
+        match self.responses.get(&key) {
+            Some(Some(response)) => Exchange::Done(response.clone()),
+            Some(None) => Exchange::Pending,
+            None => {
+                self.responses.insert(key, None);
+                self.expiry.push_back((now, key));
+                Exchange::New
+            }
+        }
+    }
+
+    /// Remember the response to a request, if it hasn't expired yet.
+    fn complete(&mut self, key: ExchangeKey, response: &[u8]) {
+        if let Some(entry) = self.responses.get_mut(&key) {
+            *entry = Some(response.to_vec());
+        }
+    }
+}
+
+/// State shared by the tasks processing requests.
+struct Listener {
+    socket: UdpSocket,
+    cfg: CoapConfig,
+    pipeline: Pipeline,
+    exchanges: Mutex<Exchanges>,
+    /// IDs of non-confirmable responses, which aren't tied to the request.
+    next_message_id: AtomicU16,
+    retransmissions: metrics::Counter,
+}
+
+impl Listener {
+    fn exchanges(&self) -> std::sync::MutexGuard<'_, Exchanges> {
+        self.exchanges.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Encode the response to a request of type `ty`, with the given message ID
+    /// and token. Confirmable requests are answered with a piggybacked
+    /// acknowledgment.
+    fn response(&self, ty: MessageType, message_id: u16, token: &[u8], reply: &Reply) -> Vec<u8> {
+        let (ty, message_id) = match ty {
+            MessageType::Confirmable => (MessageType::Acknowledgement, message_id),
+            _ => {
+                (MessageType::NonConfirmable, self.next_message_id.fetch_add(1, Ordering::Relaxed))
+            }
+        };
+        let mut buf = Vec::new();
+        Message {
+            ty,
+            code: reply.code,
+            message_id,
+            token,
+            options: Vec::new(),
+            payload: reply.diagnostic.as_bytes(),
+        }
+        .encode(&mut buf);
+        buf
+    }
+
+    async fn send(&self, bytes: &[u8], remote_addr: SocketAddr) {
+        if let Err(err) = self.socket.send_to(bytes, remote_addr).await {
+            debug!(%remote_addr, %err, "failed to send CoAP response");
+        }
+    }
+
+    /// Handle a single datagram. Requests carrying a status are persisted in a
+    /// separate task, so that slow storage doesn't hold up other devices.
+    async fn receive(self: &Arc<Self>, datagram: &[u8], remote_addr: SocketAddr) {
+        let message = match Message::decode(datagram) {
+            Ok(message) => message,
+            Err(err) => {
+                debug!(%remote_addr, %err, "failed to decode CoAP message");
+                // Malformed confirmable messages are rejected with a reset, as
+                // long as their message ID can be made out.
+                if let [first, _, a, b, ..] = *datagram {
+                    if MessageType::from_bits(first >> 4) == MessageType::Confirmable {
+                        self.send(&reset(u16::from_be_bytes([a, b])), remote_addr).await;
+                    }
+                }
+                return;
+            }
+        };
+
+        match (message.ty, message.code) {
+            // This listener never sends confirmable messages, so there's
+            // nothing to be acknowledged or reset.
+            (MessageType::Acknowledgement | MessageType::Reset, _) => return,
+            // Empty confirmable messages are pings, answered with a reset.
+            (MessageType::Confirmable, code) if !code.is_request() => {
+                self.send(&reset(message.message_id), remote_addr).await;
+                return;
+            }
+            (MessageType::NonConfirmable, code) if !code.is_request() => return,
+            _ => {}
+        }
+
+        let key = (remote_addr, message.message_id);
+        let exchange = self.exchanges().begin(key, Instant::now());
+        match exchange {
+            Exchange::New => {}
+            Exchange::Pending => {
+                self.retransmissions.inc();
+                return;
+            }
+            Exchange::Done(response) => {
+                self.retransmissions.inc();
+                self.send(&response, remote_addr).await;
+                return;
+            }
+        }
+
+        let status = match parse_request(&message, &self.pipeline) {
+            Ok(status) => status,
+            Err(reply) => {
+                debug!(%remote_addr, code = %reply.code, "rejected CoAP request");
+                let response = self.response(message.ty, message.message_id, message.token, &reply);
+                self.exchanges().complete(key, &response);
+                self.send(&response, remote_addr).await;
+                return;
+            }
+        };
+        debug!(
+            %remote_addr,
+            source_id = %status.source_id,
+            timestamp = %status.timestamp,
+            "received status: {:?}",
+            status
+        );
+
+        let (ty, message_id, token) = (message.ty, message.message_id, message.token.to_vec());
+        let listener = self.clone();
+        tokio::spawn(async move {
+            let reply = match listener.pipeline.accept_within(status, listener.cfg.timeout).await {
+                Ok(()) => Reply { code: Code::CHANGED, diagnostic: "" },
+                Err(err) => {
+                    debug!(%remote_addr, %err, "failed to accept status");
+                    error_reply(&err)
+                }
+            };
+            let response = listener.response(ty, message_id, &token, &reply);
+            listener.exchanges().complete(key, &response);
+            listener.send(&response, remote_addr).await;
+        });
+    }
+}
+
+/// Encode an empty reset message.
+fn reset(message_id: u16) -> Vec<u8> {
+    let mut buf = Vec::new();
+    Message {
+        ty: MessageType::Reset,
+        code: Code::EMPTY,
+        message_id,
+        token: &[],
+        options: Vec::new(),
+        payload: &[],
+    }
+    .encode(&mut buf);
+    buf
+}
+
+/// Bind to the specified network address and start listening for CoAP
+/// requests carrying [`Status`] packets. Decoded statuses are forwarded for
+/// storage and further processing.
+#[tracing::instrument(skip(pipeline))]
+pub async fn listen_coap(addr: &SocketAddr, cfg: CoapConfig, pipeline: Pipeline) -> Result<()> {
+    info!("Starting CoAP listener at coap://{}:{}...", addr.ip(), addr.port());
+
+    let socket = UdpSocket::bind(addr).await?;
+    // One extra byte makes it possible to tell truncated datagrams apart from
+    // ones that fill the buffer exactly.
+    let mut buf = vec![0; cfg.buffer_size + 1];
+    let truncated = metrics::counter(
+        "geo_coap_truncated_total",
+        "CoAP datagrams dropped for exceeding the receive buffer.",
+    );
+    let listener = Arc::new(Listener {
+        socket,
+        cfg,
+        pipeline,
+        exchanges: Mutex::new(Exchanges::new()),
+        next_message_id: AtomicU16::new(rand_message_id()),
+        retransmissions: metrics::counter(
+            "geo_coap_retransmissions_total",
+            "Retransmitted CoAP requests answered without processing them again.",
+        ),
+    });
+
+    tokio::spawn(async move {
+        loop {
+            let (len, remote_addr) = match listener.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(err) => {
+                    debug!(%err, "failed to read datagram");
+                    continue;
+                }
+            };
+            if len > listener.cfg.buffer_size {
+                debug!(%remote_addr, "dropping datagram exceeding the receive buffer");
+                truncated.inc();
+                continue;
+            }
+            listener.receive(&buf[..len], remote_addr).await;
+        }
+    });
+
+    Ok(())
+}
+
+/// Starting point of message IDs, which should be hard to guess.
+fn rand_message_id() -> u16 {
+    use std::{
+        collections::hash_map::RandomState,
+        hash::{BuildHasher, Hasher},
+    };
+    RandomState::new().build_hasher().finish() as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        time::Instant,
+    };
+
+    use super::{
+        decode_uint, CoapError, Code, Exchange, Exchanges, Message, MessageType, EXCHANGE_LIFETIME,
+        OPTION_CONTENT_FORMAT, OPTION_URI_PATH, OPTION_URI_QUERY,
+    };
+
+    #[test]
+    fn message_roundtrip() {
+        let query = [b'k'; 20];
+        let message = Message {
+            ty: MessageType::Confirmable,
+            code: Code::POST,
+            message_id: 0x1234,
+            token: &[1, 2, 3],
+            options: vec![
+                (OPTION_URI_PATH, b"status".as_slice()),
+                (OPTION_CONTENT_FORMAT, [60].as_slice()),
+                (OPTION_URI_QUERY, query.as_slice()),
+                (300, [].as_slice()),
+            ],
+            payload: &[0xa1, 0x01, 0x02],
+        };
+        let mut buf = Vec::new();
+        message.encode(&mut buf);
+        assert_eq!(buf[..4], [0x43, 0x02, 0x12, 0x34]);
+        assert_eq!(Message::decode(&buf).unwrap(), message);
+        assert_eq!(decode_uint(&[0, 60]), Some(60));
+    }
+
+    #[test]
+    fn decode_errors() {
+        assert_eq!(Message::decode(&[0x40, 0x02, 0x00]), Err(CoapError::Truncated));
+        assert_eq!(Message::decode(&[0x80, 0x02, 0, 0]), Err(CoapError::Version { version: 2 }));
+        assert_eq!(Message::decode(&[0x49, 0x02, 0, 0]), Err(CoapError::TokenLength { len: 9 }));
+        assert_eq!(Message::decode(&[0x42, 0x02, 0, 0, 1]), Err(CoapError::Truncated));
+        assert_eq!(Message::decode(&[0x40, 0x02, 0, 0, 0xff]), Err(CoapError::EmptyPayload));
+        assert_eq!(Message::decode(&[0x40, 0x02, 0, 0, 0xf0]), Err(CoapError::InvalidOption));
+        assert_eq!(Message::decode(&[0x40, 0x02, 0, 0, 0xb6, b's']), Err(CoapError::Truncated));
+    }
+
+    #[test]
+    fn code_display() {
+        assert_eq!(Code::NOT_FOUND.to_string(), "4.04");
+        assert_eq!(Code::UNSUPPORTED_CONTENT_FORMAT.to_string(), "4.15");
+        assert!(Code::POST.is_request());
+        assert!(!Code::EMPTY.is_request());
+        assert!(!Code::CHANGED.is_request());
+    }
+
+    #[test]
+    fn exchanges() {
+        let mut exchanges = Exchanges::new();
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 5683));
+        let now = Instant::now();
+
+        assert_eq!(exchanges.begin((addr, 1), now), Exchange::New);
+        assert_eq!(exchanges.begin((addr, 1), now), Exchange::Pending);
+        assert_eq!(exchanges.begin((addr, 2), now), Exchange::New);
+        exchanges.complete((addr, 1), b"ack");
+        assert_eq!(exchanges.begin((addr, 1), now), Exchange::Done(b"ack".to_vec()));
+        assert_eq!(exchanges.begin((addr, 1), now + EXCHANGE_LIFETIME), Exchange::New);
+    }
+}
@@ -4,9 +4,16 @@
 //! This crate contains the different components of the `geo-track` backend
 //! service.
 
+pub mod auth;
 pub mod cq;
 pub mod error;
 pub mod http;
 pub mod ingest;
+pub mod metrics;
+pub mod publish;
+pub mod query;
+#[cfg(feature = "tools")]
+pub mod sender;
+pub mod settings;
 pub mod storage;
 pub mod util;
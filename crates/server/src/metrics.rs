@@ -0,0 +1,128 @@
+//! Process-wide counters and gauges, exported in the Prometheus text format.
+//!
+//! Metrics are registered lazily on first use and live for the duration of the
+//! process, so handles can be obtained wherever they're needed without
+//! threading a registry through the code.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Counter,
+    Gauge,
+}
+
+struct Family {
+    help: &'static str,
+    kind: Kind,
+    series: BTreeMap<String, Arc<AtomicI64>>,
+}
+
+fn registry() -> &'static Mutex<BTreeMap<&'static str, Family>> {
+    static REGISTRY: OnceLock<Mutex<BTreeMap<&'static str, Family>>> = OnceLock::new();
+    REGISTRY.get_or_init(Default::default)
+}
+
+fn register(
+    name: &'static str,
+    help: &'static str,
+    kind: Kind,
+    labels: &[(&str, &str)],
+) -> Arc<AtomicI64> {
+    let labels = labels.iter().map(|(k, v)| format!("{k}=\"{v}\"")).collect::<Vec<_>>().join(",");
+    let mut registry = registry().lock().unwrap_or_else(|e| e.into_inner());
+    let family =
+        registry.entry(name).or_insert_with(|| Family { help, kind, series: BTreeMap::new() });
+    debug_assert_eq!(family.kind, kind, "metric {name} registered with different kinds");
+    family.series.entry(labels).or_default().clone()
+}
+
+/// Monotonically increasing value.
+#[derive(Debug, Clone)]
+pub struct Counter(Arc<AtomicI64>);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n as i64, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed) as u64
+    }
+}
+
+/// Value that can go up and down.
+#[derive(Debug, Clone)]
+pub struct Gauge(Arc<AtomicI64>);
+
+impl Gauge {
+    pub fn set(&self, value: i64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    pub fn add(&self, n: i64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Raise the gauge to `value` if it's currently lower.
+    pub fn set_max(&self, value: i64) {
+        self.0.fetch_max(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Get or register a counter.
+pub fn counter(name: &'static str, help: &'static str) -> Counter {
+    counter_with(name, help, &[])
+}
+
+/// Get or register a counter with a set of labels.
+pub fn counter_with(name: &'static str, help: &'static str, labels: &[(&str, &str)]) -> Counter {
+    Counter(register(name, help, Kind::Counter, labels))
+}
+
+/// Get or register a gauge.
+pub fn gauge(name: &'static str, help: &'static str) -> Gauge {
+    gauge_with(name, help, &[])
+}
+
+/// Get or register a gauge with a set of labels.
+pub fn gauge_with(name: &'static str, help: &'static str, labels: &[(&str, &str)]) -> Gauge {
+    Gauge(register(name, help, Kind::Gauge, labels))
+}
+
+/// Render all registered metrics in the Prometheus text exposition format.
+pub fn render() -> String {
+    let registry = registry().lock().unwrap_or_else(|e| e.into_inner());
+    let mut out = String::new();
+    for (name, family) in registry.iter() {
+        let kind = match family.kind {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+        };
+        let _ = writeln!(out, "# HELP {name} {}", family.help);
+        let _ = writeln!(out, "# TYPE {name} {kind}");
+        for (labels, value) in &family.series {
+            let value = value.load(Ordering::Relaxed);
+            let _ = match labels.is_empty() {
+                true => writeln!(out, "{name} {value}"),
+                false => writeln!(out, "{name}{{{labels}}} {value}"),
+            };
+        }
+    }
+    out
+}
@@ -0,0 +1,485 @@
+//! Fan-out of accepted [`Status`] packets to a message broker, so that other
+//! services can consume the raw stream without polling the HTTP API.
+//!
+//! Publishing never blocks ingestion: statuses are put into a bounded
+//! in-memory buffer that is drained by a background task. If the broker is
+//! unreachable, statuses accumulate in the buffer until it fills up, at which
+//! point the oldest ones are dropped.
+//!
+//! Both supported brokers are spoken to directly using minimal native clients:
+//!
+//! - NATS: core protocol publishing to a single subject. Every batch is
+//!   confirmed with a `PING`/`PONG` round trip.
+//! - Kafka: `Produce` (v3) requests to a single topic partition, with
+//!   `acks=1`. The configured broker must be the partition leader, which is
+//!   always the case for single-node deployments.
+
+use std::{
+    collections::VecDeque,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use shared::{
+    client::crc32c,
+    codec::{self, Cbor, Codec, Json},
+    data::Status,
+};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    sync::Notify,
+};
+use tracing::{debug, info, warn};
+
+use crate::metrics::{self, Counter, Gauge};
+
+/// Maximum number of statuses sent to the broker in one go.
+const BATCH_SIZE: usize = 256;
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Error)]
+pub enum PublishError {
+    #[error("IO error")]
+    Io(#[from] std::io::Error),
+    #[error("failed to encode status")]
+    Codec(#[from] codec::Error),
+    #[error("unknown publish target: {target}")]
+    UnknownTarget { target: String },
+    #[error("unknown publish format: {name}")]
+    UnknownFormat { name: String },
+    #[error("broker error: {message}")]
+    Broker { message: String },
+}
+
+pub type Result<T> = std::result::Result<T, PublishError>;
+
+/// Broker and destination that statuses are published to.
+#[derive(Debug, Clone)]
+pub enum PublishTarget {
+    /// Specified as `nats://host:port/subject`.
+    Nats { addr: String, subject: String },
+    /// Specified as `kafka://host:port/topic[:partition]`.
+    Kafka { addr: String, topic: String, partition: i32 },
+}
+
+impl FromStr for PublishTarget {
+    type Err = PublishError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let unknown = || PublishError::UnknownTarget { target: s.to_owned() };
+        let (scheme, rest) = s.split_once("://").ok_or_else(unknown)?;
+        let (addr, dest) = rest.split_once('/').ok_or_else(unknown)?;
+        if addr.is_empty() || dest.is_empty() {
+            return Err(unknown());
+        }
+
+        let target = match scheme {
+            "nats" => Self::Nats { addr: addr.to_owned(), subject: dest.to_owned() },
+            "kafka" => {
+                let (topic, partition) = match dest.rsplit_once(':') {
+                    Some((topic, p)) => (topic, p.parse().map_err(|_| unknown())?),
+                    None => (dest, 0),
+                };
+                Self::Kafka { addr: addr.to_owned(), topic: topic.to_owned(), partition }
+            }
+            _ => return Err(unknown()),
+        };
+        Ok(target)
+    }
+}
+
+/// Encoding of published statuses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PublishFormat {
+    Cbor,
+    Json,
+}
+
+impl FromStr for PublishFormat {
+    type Err = PublishError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "cbor" => Ok(Self::Cbor),
+            "json" => Ok(Self::Json),
+            _ => Err(PublishError::UnknownFormat { name: s.to_owned() }),
+        }
+    }
+}
+
+impl PublishFormat {
+    fn codec(self) -> &'static dyn Codec {
+        match self {
+            Self::Cbor => &Cbor,
+            Self::Json => &Json,
+        }
+    }
+
+    fn encode(self, status: &Status) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        self.codec().encode(status, &mut bytes)?;
+        Ok(bytes)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PublisherConfig {
+    pub target: PublishTarget,
+    pub format: PublishFormat,
+    /// Maximum number of statuses held in memory while the broker is slow or
+    /// unavailable.
+    pub buffer: usize,
+}
+
+struct Shared {
+    buffer: Mutex<VecDeque<Vec<u8>>>,
+    notify: Notify,
+    capacity: usize,
+    format: PublishFormat,
+    sent: Counter,
+    failed: Counter,
+    dropped: Counter,
+    buffered: Gauge,
+}
+
+/// Handle for publishing statuses. Cheap to clone.
+#[derive(Clone)]
+pub struct Publisher {
+    shared: Arc<Shared>,
+}
+
+impl Publisher {
+    /// Start a background task delivering published statuses to the broker.
+    pub fn spawn(cfg: PublisherConfig) -> Self {
+        let shared = Arc::new(Shared {
+            buffer: Mutex::new(VecDeque::new()),
+            notify: Notify::new(),
+            capacity: cfg.buffer.max(1),
+            format: cfg.format,
+            sent: metrics::counter("geo_publish_sent_total", "Statuses delivered to the broker"),
+            failed: metrics::counter(
+                "geo_publish_failed_total",
+                "Failed attempts to deliver a batch of statuses",
+            ),
+            dropped: metrics::counter(
+                "geo_publish_dropped_total",
+                "Statuses dropped due to publish buffer overflow",
+            ),
+            buffered: metrics::gauge("geo_publish_buffered", "Statuses waiting to be published"),
+        });
+
+        tokio::spawn(deliver(cfg.target, shared.clone()));
+        Self { shared }
+    }
+
+    /// Enqueue a status for publishing. Never blocks; if the buffer is full,
+    /// the oldest buffered status is dropped.
+    pub fn publish(&self, status: &Status) {
+        let payload = match self.shared.format.encode(status) {
+            Ok(payload) => payload,
+            Err(err) => {
+                warn!(%err, "Failed to encode status for publishing");
+                return;
+            }
+        };
+
+        let mut buffer = self.shared.buffer.lock().unwrap_or_else(|e| e.into_inner());
+        if buffer.len() >= self.shared.capacity {
+            buffer.pop_front();
+            self.shared.dropped.inc();
+        }
+        buffer.push_back(payload);
+        self.shared.buffered.set(buffer.len() as i64);
+        drop(buffer);
+
+        self.shared.notify.notify_one();
+    }
+}
+
+async fn deliver(target: PublishTarget, shared: Arc<Shared>) {
+    let mut transport: Option<Box<dyn Transport>> = None;
+    let mut backoff = Duration::from_millis(100);
+
+    loop {
+        let batch: Vec<Vec<u8>> = {
+            let mut buffer = shared.buffer.lock().unwrap_or_else(|e| e.into_inner());
+            let len = buffer.len().min(BATCH_SIZE);
+            buffer.drain(..len).collect()
+        };
+        if batch.is_empty() {
+            shared.notify.notified().await;
+            continue;
+        }
+
+        let result = match transport.as_mut() {
+            Some(t) => t.send(&batch).await,
+            None => match connect(&target).await {
+                Ok(mut t) => {
+                    info!(?target, "Connected to publish target");
+                    let result = t.send(&batch).await;
+                    transport = Some(t);
+                    result
+                }
+                Err(err) => Err(err),
+            },
+        };
+
+        match result {
+            Ok(()) => {
+                shared.sent.add(batch.len() as u64);
+                backoff = Duration::from_millis(100);
+            }
+            Err(err) => {
+                warn!(%err, ?backoff, "Failed to publish statuses, retrying");
+                shared.failed.inc();
+                transport = None;
+
+                // Putting the batch back in front, dropping the oldest entries
+                // if newer statuses have filled up the buffer in the meantime.
+                {
+                    let mut buffer = shared.buffer.lock().unwrap_or_else(|e| e.into_inner());
+                    for payload in batch.into_iter().rev() {
+                        buffer.push_front(payload);
+                    }
+                    while buffer.len() > shared.capacity {
+                        buffer.pop_front();
+                        shared.dropped.inc();
+                    }
+                }
+
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+        let len = shared.buffer.lock().unwrap_or_else(|e| e.into_inner()).len();
+        shared.buffered.set(len as i64);
+    }
+}
+
+async fn connect(target: &PublishTarget) -> Result<Box<dyn Transport>> {
+    match target {
+        PublishTarget::Nats { addr, subject } => {
+            Ok(Box::new(Nats::connect(addr, subject.clone()).await?))
+        }
+        PublishTarget::Kafka { addr, topic, partition } => {
+            Ok(Box::new(Kafka::connect(addr, topic.clone(), *partition).await?))
+        }
+    }
+}
+
+#[async_trait]
+trait Transport: Send {
+    /// Deliver a batch of payloads, returning only once the broker has
+    /// confirmed receiving them.
+    async fn send(&mut self, payloads: &[Vec<u8>]) -> Result<()>;
+}
+
+struct Nats {
+    stream: BufReader<TcpStream>,
+    subject: String,
+}
+
+impl Nats {
+    async fn connect(addr: &str, subject: String) -> Result<Self> {
+        let mut stream = BufReader::new(TcpStream::connect(addr).await?);
+        // The server greets every client with an `INFO` message.
+        let mut line = String::new();
+        stream.read_line(&mut line).await?;
+        if !line.starts_with("INFO") {
+            return Err(PublishError::Broker { message: line.trim_end().to_owned() });
+        }
+        stream
+            .get_mut()
+            .write_all(b"CONNECT {\"verbose\":false,\"pedantic\":false,\"name\":\"geo-track\"}\r\n")
+            .await?;
+        Ok(Self { stream, subject })
+    }
+}
+
+#[async_trait]
+impl Transport for Nats {
+    async fn send(&mut self, payloads: &[Vec<u8>]) -> Result<()> {
+        let mut buf = Vec::new();
+        for payload in payloads {
+            buf.extend_from_slice(format!("PUB {} {}\r\n", self.subject, payload.len()).as_bytes());
+            buf.extend_from_slice(payload);
+            buf.extend_from_slice(b"\r\n");
+        }
+        buf.extend_from_slice(b"PING\r\n");
+        self.stream.get_mut().write_all(&buf).await?;
+
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.stream.read_line(&mut line).await? == 0 {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
+            match line.trim_end() {
+                "PONG" => return Ok(()),
+                "PING" => self.stream.get_mut().write_all(b"PONG\r\n").await?,
+                "+OK" => {}
+                msg if msg.starts_with("INFO") => debug!(msg, "NATS server info update"),
+                msg => return Err(PublishError::Broker { message: msg.to_owned() }),
+            }
+        }
+    }
+}
+
+struct Kafka {
+    stream: TcpStream,
+    topic: String,
+    partition: i32,
+    correlation_id: i32,
+}
+
+impl Kafka {
+    const CLIENT_ID: &'static str = "geo-track";
+    const PRODUCE_KEY: i16 = 0;
+    const PRODUCE_VERSION: i16 = 3;
+    const TIMEOUT_MS: i32 = 10_000;
+
+    async fn connect(addr: &str, topic: String, partition: i32) -> Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+        Ok(Self { stream, topic, partition, correlation_id: 0 })
+    }
+
+    /// Encode payloads as a v2 record batch.
+    fn record_batch(payloads: &[Vec<u8>]) -> Vec<u8> {
+        let now = time::OffsetDateTime::now_utc();
+        let timestamp = (now.unix_timestamp_nanos() / 1_000_000) as i64;
+
+        let mut records = Vec::new();
+        for (offset, payload) in payloads.iter().enumerate() {
+            let mut record = vec![0]; // attributes
+            put_varint(&mut record, 0); // timestamp delta
+            put_varint(&mut record, offset as i64);
+            put_varint(&mut record, -1); // null key
+            put_varint(&mut record, payload.len() as i64);
+            record.extend_from_slice(payload);
+            put_varint(&mut record, 0); // no headers
+
+            put_varint(&mut records, record.len() as i64);
+            records.extend_from_slice(&record);
+        }
+
+        // Everything covered by the CRC, starting with `attributes`.
+        let mut tail = Vec::new();
+        tail.extend_from_slice(&0i16.to_be_bytes()); // attributes
+        tail.extend_from_slice(&(payloads.len() as i32 - 1).to_be_bytes()); // last offset delta
+        tail.extend_from_slice(&timestamp.to_be_bytes()); // base timestamp
+        tail.extend_from_slice(&timestamp.to_be_bytes()); // max timestamp
+        tail.extend_from_slice(&(-1i64).to_be_bytes()); // producer id
+        tail.extend_from_slice(&(-1i16).to_be_bytes()); // producer epoch
+        tail.extend_from_slice(&(-1i32).to_be_bytes()); // base sequence
+        tail.extend_from_slice(&(payloads.len() as i32).to_be_bytes());
+        tail.extend_from_slice(&records);
+
+        let mut batch = Vec::with_capacity(tail.len() + 21);
+        batch.extend_from_slice(&0i64.to_be_bytes()); // base offset
+        batch.extend_from_slice(&(tail.len() as i32 + 9).to_be_bytes()); // batch length
+        batch.extend_from_slice(&(-1i32).to_be_bytes()); // partition leader epoch
+        batch.push(2); // magic
+        batch.extend_from_slice(&crc32c(&tail).to_be_bytes());
+        batch.extend_from_slice(&tail);
+        batch
+    }
+}
+
+#[async_trait]
+impl Transport for Kafka {
+    async fn send(&mut self, payloads: &[Vec<u8>]) -> Result<()> {
+        self.correlation_id = self.correlation_id.wrapping_add(1);
+        let batch = Self::record_batch(payloads);
+
+        let mut req = Vec::new();
+        req.extend_from_slice(&Self::PRODUCE_KEY.to_be_bytes());
+        req.extend_from_slice(&Self::PRODUCE_VERSION.to_be_bytes());
+        req.extend_from_slice(&self.correlation_id.to_be_bytes());
+        put_string(&mut req, Self::CLIENT_ID);
+        req.extend_from_slice(&(-1i16).to_be_bytes()); // null transactional id
+        req.extend_from_slice(&1i16.to_be_bytes()); // acks
+        req.extend_from_slice(&Self::TIMEOUT_MS.to_be_bytes());
+        req.extend_from_slice(&1i32.to_be_bytes()); // topic count
+        put_string(&mut req, &self.topic);
+        req.extend_from_slice(&1i32.to_be_bytes()); // partition count
+        req.extend_from_slice(&self.partition.to_be_bytes());
+        req.extend_from_slice(&(batch.len() as i32).to_be_bytes());
+        req.extend_from_slice(&batch);
+
+        self.stream.write_all(&(req.len() as i32).to_be_bytes()).await?;
+        self.stream.write_all(&req).await?;
+
+        let len = self.stream.read_i32().await?;
+        let mut resp = vec![0; len.max(0) as usize];
+        self.stream.read_exact(&mut resp).await?;
+
+        // correlation id (4), topic count (4), topic name (2 + n),
+        // partition count (4), partition index (4), then the error code.
+        let error_code = resp
+            .get(8..10)
+            .map(|b| i16::from_be_bytes([b[0], b[1]]) as usize)
+            .and_then(|name_len| resp.get(18 + name_len..20 + name_len))
+            .map(|b| i16::from_be_bytes([b[0], b[1]]))
+            .ok_or_else(|| PublishError::Broker { message: "malformed produce response".into() })?;
+        match error_code {
+            0 => Ok(()),
+            code => Err(PublishError::Broker { message: format!("Kafka error code {code}") }),
+        }
+    }
+}
+
+fn put_string(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as i16).to_be_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+/// Zigzag-encoded variable length integer, as used in Kafka records.
+fn put_varint(buf: &mut Vec<u8>, value: i64) {
+    let mut v = ((value << 1) ^ (value >> 63)) as u64;
+    while v >= 0x80 {
+        buf.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{crc32c, put_varint, PublishTarget};
+
+    #[test]
+    fn crc32c_check_value() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+    }
+
+    #[test]
+    fn zigzag_varints() {
+        let encode = |v| {
+            let mut buf = Vec::new();
+            put_varint(&mut buf, v);
+            buf
+        };
+        assert_eq!(encode(0), [0x00]);
+        assert_eq!(encode(-1), [0x01]);
+        assert_eq!(encode(1), [0x02]);
+        assert_eq!(encode(300), [0xd8, 0x04]);
+    }
+
+    #[test]
+    fn parse_targets() {
+        assert!(matches!(
+            "nats://127.0.0.1:4222/geo.status".parse(),
+            Ok(PublishTarget::Nats { addr, subject }) if addr == "127.0.0.1:4222" && subject == "geo.status"
+        ));
+        assert!(matches!(
+            "kafka://localhost:9092/statuses:3".parse(),
+            Ok(PublishTarget::Kafka { topic, partition: 3, .. }) if topic == "statuses"
+        ));
+        assert!("kafka://localhost:9092".parse::<PublishTarget>().is_err());
+        assert!("amqp://localhost/q".parse::<PublishTarget>().is_err());
+    }
+}
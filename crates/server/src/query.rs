@@ -0,0 +1,564 @@
+//! A small expression language for filtering statuses in queries, e.g.
+//! `speed > 30 && within(24.5, 59.3, 24.9, 59.5) && has(position)`.
+//!
+//! Expressions are made of:
+//! - comparisons of a field with a number, using `<`, `<=`, `>`, `>=`, `==` or
+//!   `!=`. Fields are `speed` (m/s), `bearing` (radians), `lon`, `lat`,
+//!   `timestamp` and `received_at` (UNIX seconds). Statuses lacking the field
+//!   never match a comparison;
+//! - `has(field)`, matching statuses that have the field set, where field is
+//!   one of `position`, `speed`, `bearing` or `received_at`;
+//! - `within(west, south, east, north)`, matching statuses positioned within a
+//!   bounding box, borders included;
+//! - `suspect_timestamp`, matching statuses with a suspect timestamp;
+//! - `!`, `&&` and `||`, in order of decreasing precedence, and parentheses.
+//!
+//! Filters are parsed into a [`Filter`] and applied by storage while scanning
+//! statuses, so that non-matching ones are never sent to clients.
+
+use std::{fmt, str::FromStr};
+
+use geo_types::{Coord, Rect};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use shared::data::Status;
+use thiserror::Error;
+use uom::si::{angle::radian, velocity::meter_per_second};
+
+/// Deepest nesting of parentheses and negations, to bound the recursion of
+/// the parser.
+const MAX_DEPTH: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum FilterError {
+    #[error("unexpected {found} at offset {offset}, expected {expected}")]
+    Unexpected { offset: usize, found: String, expected: &'static str },
+    #[error("unknown field: {name}")]
+    UnknownField { name: String },
+    #[error("filter is nested too deeply; at most {MAX_DEPTH} levels are supported")]
+    TooDeep,
+}
+
+/// Numeric fields of a [`Status`] that can be compared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Speed,
+    Bearing,
+    Lon,
+    Lat,
+    Timestamp,
+    ReceivedAt,
+}
+
+impl Field {
+    fn value(self, status: &Status) -> Option<f64> {
+        match self {
+            Self::Speed => status.speed.map(|v| v.get::<meter_per_second>()),
+            Self::Bearing => status.bearing.map(|b| b.get::<radian>()),
+            Self::Lon => status.position.map(|p| p.x),
+            Self::Lat => status.position.map(|p| p.y),
+            Self::Timestamp => Some(seconds(status.timestamp)),
+            Self::ReceivedAt => status.received_at.map(seconds),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Speed => "speed",
+            Self::Bearing => "bearing",
+            Self::Lon => "lon",
+            Self::Lat => "lat",
+            Self::Timestamp => "timestamp",
+            Self::ReceivedAt => "received_at",
+        }
+    }
+}
+
+impl FromStr for Field {
+    type Err = FilterError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let field = match s {
+            "speed" => Self::Speed,
+            "bearing" => Self::Bearing,
+            "lon" => Self::Lon,
+            "lat" => Self::Lat,
+            "timestamp" => Self::Timestamp,
+            "received_at" => Self::ReceivedAt,
+            _ => return Err(FilterError::UnknownField { name: s.to_owned() }),
+        };
+        Ok(field)
+    }
+}
+
+fn seconds(ts: time::OffsetDateTime) -> f64 {
+    ts.unix_timestamp_nanos() as f64 / 1e9
+}
+
+/// Optional fields of a [`Status`] that `has()` checks for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Presence {
+    Position,
+    Speed,
+    Bearing,
+    ReceivedAt,
+}
+
+impl Presence {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Position => "position",
+            Self::Speed => "speed",
+            Self::Bearing => "bearing",
+            Self::ReceivedAt => "received_at",
+        }
+    }
+}
+
+impl FromStr for Presence {
+    type Err = FilterError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let field = match s {
+            "position" => Self::Position,
+            "speed" => Self::Speed,
+            "bearing" => Self::Bearing,
+            "received_at" => Self::ReceivedAt,
+            _ => return Err(FilterError::UnknownField { name: s.to_owned() }),
+        };
+        Ok(field)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+}
+
+impl Comparison {
+    fn apply(self, lhs: f64, rhs: f64) -> bool {
+        match self {
+            Self::Lt => lhs < rhs,
+            Self::Le => lhs <= rhs,
+            Self::Gt => lhs > rhs,
+            Self::Ge => lhs >= rhs,
+            Self::Eq => lhs == rhs,
+            Self::Ne => lhs != rhs,
+        }
+    }
+
+    fn symbol(self) -> &'static str {
+        match self {
+            Self::Lt => "<",
+            Self::Le => "<=",
+            Self::Gt => ">",
+            Self::Ge => ">=",
+            Self::Eq => "==",
+            Self::Ne => "!=",
+        }
+    }
+}
+
+/// A parsed filter expression. (De)serialized as its textual form.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    Compare(Field, Comparison, f64),
+    Has(Presence),
+    Within(Rect<f64>),
+    SuspectTimestamp,
+    Not(Box<Filter>),
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+}
+
+impl Filter {
+    pub fn matches(&self, status: &Status) -> bool {
+        match self {
+            Self::Compare(field, cmp, value) => {
+                field.value(status).is_some_and(|lhs| cmp.apply(lhs, *value))
+            }
+            Self::Has(Presence::Position) => status.position.is_some(),
+            Self::Has(Presence::Speed) => status.speed.is_some(),
+            Self::Has(Presence::Bearing) => status.bearing.is_some(),
+            Self::Has(Presence::ReceivedAt) => status.received_at.is_some(),
+            Self::Within(bbox) => status.position.is_some_and(|p| {
+                let (min, max) = (bbox.min(), bbox.max());
+                (min.x..=max.x).contains(&p.x) && (min.y..=max.y).contains(&p.y)
+            }),
+            Self::SuspectTimestamp => status.suspect_timestamp,
+            Self::Not(filter) => !filter.matches(status),
+            Self::And(lhs, rhs) => lhs.matches(status) && rhs.matches(status),
+            Self::Or(lhs, rhs) => lhs.matches(status) || rhs.matches(status),
+        }
+    }
+}
+
+/// Whether `status` matches `filter`, if there is one.
+pub fn matches(filter: Option<&Filter>, status: &Status) -> bool {
+    filter.map_or(true, |filter| filter.matches(status))
+}
+
+impl FromStr for Filter {
+    type Err = FilterError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser { tokens: tokenize(s)?, pos: 0, depth: 0 };
+        let filter = parser.or()?;
+        parser.expect_end()?;
+        Ok(filter)
+    }
+}
+
+impl fmt::Display for Filter {
+    /// Prints the filter fully parenthesized, such that it parses back into
+    /// the same [`Filter`].
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Compare(field, cmp, value) => {
+                write!(f, "{} {} {value}", field.name(), cmp.symbol())
+            }
+            Self::Has(field) => write!(f, "has({})", field.name()),
+            Self::Within(bbox) => {
+                let (min, max) = (bbox.min(), bbox.max());
+                write!(f, "within({}, {}, {}, {})", min.x, min.y, max.x, max.y)
+            }
+            Self::SuspectTimestamp => f.write_str("suspect_timestamp"),
+            Self::Not(filter) => write!(f, "!({filter})"),
+            Self::And(lhs, rhs) => write!(f, "({lhs}) && ({rhs})"),
+            Self::Or(lhs, rhs) => write!(f, "({lhs}) || ({rhs})"),
+        }
+    }
+}
+
+impl Serialize for Filter {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Filter {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(f64),
+    Compare(Comparison),
+    Not,
+    And,
+    Or,
+    Open,
+    Close,
+    Comma,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ident(name) => write!(f, "`{name}`"),
+            Self::Number(value) => write!(f, "`{value}`"),
+            Self::Compare(cmp) => write!(f, "`{}`", cmp.symbol()),
+            Self::Not => f.write_str("`!`"),
+            Self::And => f.write_str("`&&`"),
+            Self::Or => f.write_str("`||`"),
+            Self::Open => f.write_str("`(`"),
+            Self::Close => f.write_str("`)`"),
+            Self::Comma => f.write_str("`,`"),
+        }
+    }
+}
+
+/// Splits a filter into tokens along with their byte offsets.
+fn tokenize(s: &str) -> Result<Vec<(usize, Token)>, FilterError> {
+    let mut tokens = Vec::new();
+    let bytes = s.as_bytes();
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let start = pos;
+        let rest = &s[pos..];
+        let two = rest.get(..2);
+        let token = match bytes[pos] {
+            b if b.is_ascii_whitespace() => {
+                pos += 1;
+                continue;
+            }
+            _ if two == Some("&&") => Token::And,
+            _ if two == Some("||") => Token::Or,
+            _ if two == Some("<=") => Token::Compare(Comparison::Le),
+            _ if two == Some(">=") => Token::Compare(Comparison::Ge),
+            _ if two == Some("==") => Token::Compare(Comparison::Eq),
+            _ if two == Some("!=") => Token::Compare(Comparison::Ne),
+            b'<' => Token::Compare(Comparison::Lt),
+            b'>' => Token::Compare(Comparison::Gt),
+            b'!' => Token::Not,
+            b'(' => Token::Open,
+            b')' => Token::Close,
+            b',' => Token::Comma,
+            b if b.is_ascii_digit() || b == b'-' || b == b'.' => {
+                let len = rest[1..]
+                    .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+                    .map_or(rest.len(), |len| len + 1);
+                let number = &rest[..len];
+                pos += len;
+                let value = number.parse().map_err(|_| FilterError::Unexpected {
+                    offset: start,
+                    found: format!("`{number}`"),
+                    expected: "a number",
+                })?;
+                tokens.push((start, Token::Number(value)));
+                continue;
+            }
+            b if b.is_ascii_alphabetic() || b == b'_' => {
+                let len = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                pos += len;
+                tokens.push((start, Token::Ident(rest[..len].to_owned())));
+                continue;
+            }
+            _ => {
+                let found = rest.chars().next().unwrap_or_default();
+                return Err(FilterError::Unexpected {
+                    offset: start,
+                    found: format!("`{found}`"),
+                    expected: "a field, number, operator or parenthesis",
+                });
+            }
+        };
+        pos += match token {
+            Token::And | Token::Or => 2,
+            Token::Compare(Comparison::Lt | Comparison::Gt) => 1,
+            Token::Compare(_) => 2,
+            _ => 1,
+        };
+        tokens.push((start, token));
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent parser over tokens, one function per precedence level.
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, token)| token)
+    }
+
+    fn next(&mut self, expected: &'static str) -> Result<Token, FilterError> {
+        match self.tokens.get(self.pos) {
+            Some((_, token)) => {
+                self.pos += 1;
+                Ok(token.clone())
+            }
+            None => Err(self.unexpected(expected)),
+        }
+    }
+
+    /// Error about the current token, or the end of the filter.
+    fn unexpected(&self, expected: &'static str) -> FilterError {
+        let (offset, found) = match self.tokens.get(self.pos) {
+            Some((offset, token)) => (*offset, token.to_string()),
+            None => (self.tokens.last().map_or(0, |(offset, _)| offset + 1), "end".to_owned()),
+        };
+        FilterError::Unexpected { offset, found, expected }
+    }
+
+    fn expect(&mut self, token: Token, expected: &'static str) -> Result<(), FilterError> {
+        if self.peek() != Some(&token) {
+            return Err(self.unexpected(expected));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn expect_end(&self) -> Result<(), FilterError> {
+        match self.peek() {
+            Some(_) => Err(self.unexpected("`&&`, `||` or end")),
+            None => Ok(()),
+        }
+    }
+
+    fn or(&mut self) -> Result<Filter, FilterError> {
+        let mut filter = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            filter = Filter::Or(Box::new(filter), Box::new(self.and()?));
+        }
+        Ok(filter)
+    }
+
+    fn and(&mut self) -> Result<Filter, FilterError> {
+        let mut filter = self.unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.pos += 1;
+            filter = Filter::And(Box::new(filter), Box::new(self.unary()?));
+        }
+        Ok(filter)
+    }
+
+    fn unary(&mut self) -> Result<Filter, FilterError> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(FilterError::TooDeep);
+        }
+        let filter = match self.next("an expression")? {
+            Token::Not => Filter::Not(Box::new(self.unary()?)),
+            Token::Open => {
+                let filter = self.or()?;
+                self.expect(Token::Close, "`)`")?;
+                filter
+            }
+            Token::Ident(name) => self.atom(name)?,
+            _ => {
+                self.pos -= 1;
+                return Err(self.unexpected("an expression"));
+            }
+        };
+        self.depth -= 1;
+        Ok(filter)
+    }
+
+    /// An expression starting with an identifier.
+    fn atom(&mut self, name: String) -> Result<Filter, FilterError> {
+        match name.as_str() {
+            "has" => {
+                self.expect(Token::Open, "`(`")?;
+                let field = self.ident()?.parse()?;
+                self.expect(Token::Close, "`)`")?;
+                Ok(Filter::Has(field))
+            }
+            "within" => {
+                self.expect(Token::Open, "`(`")?;
+                let west = self.number()?;
+                let mut edges = [0.; 3];
+                for edge in &mut edges {
+                    self.expect(Token::Comma, "`,`")?;
+                    *edge = self.number()?;
+                }
+                self.expect(Token::Close, "`)`")?;
+                let [south, east, north] = edges;
+                Ok(Filter::Within(Rect::new(
+                    Coord { x: west, y: south },
+                    Coord { x: east, y: north },
+                )))
+            }
+            "suspect_timestamp" => Ok(Filter::SuspectTimestamp),
+            _ => {
+                let field = name.parse()?;
+                let cmp = match self.next("a comparison")? {
+                    Token::Compare(cmp) => cmp,
+                    _ => {
+                        self.pos -= 1;
+                        return Err(self.unexpected("a comparison"));
+                    }
+                };
+                Ok(Filter::Compare(field, cmp, self.number()?))
+            }
+        }
+    }
+
+    fn ident(&mut self) -> Result<String, FilterError> {
+        match self.next("a field")? {
+            Token::Ident(name) => Ok(name),
+            _ => {
+                self.pos -= 1;
+                Err(self.unexpected("a field"))
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<f64, FilterError> {
+        match self.next("a number")? {
+            Token::Number(value) => Ok(value),
+            _ => {
+                self.pos -= 1;
+                Err(self.unexpected("a number"))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use geo_types::Coord;
+    use shared::data::{SourceId, Status, TenantId};
+    use time::macros::datetime;
+    use uom::si::{f64::Velocity, velocity::meter_per_second};
+
+    use super::{Filter, FilterError};
+
+    fn status(speed: Option<f64>, position: Option<(f64, f64)>) -> Status {
+        Status {
+            source_id: serde_json::from_str::<SourceId>("\"0aaec05a-0e7d-4fd5-abc0-0ba69e3cfe11\"")
+                .unwrap(),
+            timestamp: datetime!(2021-07-27 05:45:19 UTC),
+            position: position.map(|(x, y)| Coord { x, y }),
+            bearing: None,
+            speed: speed.map(Velocity::new::<meter_per_second>),
+            satellites: None,
+            fix: None,
+            odometer: None,
+            ignition: None,
+            received_at: None,
+            suspect_timestamp: false,
+            tenant_id: TenantId::DEFAULT,
+        }
+    }
+
+    #[test]
+    fn evaluate() {
+        let filter: Filter =
+            "speed > 30 && within(24.5, 59.3, 24.9, 59.5) && has(position)".parse().unwrap();
+        assert!(filter.matches(&status(Some(31.), Some((24.7, 59.4)))));
+        assert!(!filter.matches(&status(Some(30.), Some((24.7, 59.4)))));
+        assert!(!filter.matches(&status(Some(31.), Some((25.7, 59.4)))));
+        assert!(!filter.matches(&status(None, Some((24.7, 59.4)))));
+
+        let filter: Filter =
+            "!has(speed) || speed <= 1.5 && timestamp >= 1627364719".parse().unwrap();
+        assert!(filter.matches(&status(None, None)));
+        assert!(filter.matches(&status(Some(1.5), None)));
+        assert!(!filter.matches(&status(Some(2.), None)));
+
+        let filter: Filter = "(lat < -10 || lat > 10) && !suspect_timestamp".parse().unwrap();
+        assert!(filter.matches(&status(None, Some((0., -10.5)))));
+        assert!(!filter.matches(&status(None, Some((0., 5.)))));
+        assert!(!filter.matches(&status(None, None)));
+    }
+
+    #[test]
+    fn round_trip() {
+        for filter in ["speed > 30 && !(has(bearing) || lon != -1.5)", "within(1, 2, 3, 4)"] {
+            let parsed: Filter = filter.parse().unwrap();
+            assert_eq!(parsed.to_string().parse::<Filter>().unwrap(), parsed);
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let err = |s: &str| s.parse::<Filter>().unwrap_err();
+        assert_eq!(
+            err("speed >"),
+            FilterError::Unexpected { offset: 7, found: "end".to_owned(), expected: "a number" }
+        );
+        assert_eq!(err("altitude > 1"), FilterError::UnknownField { name: "altitude".to_owned() });
+        assert_eq!(err("has(lat)"), FilterError::UnknownField { name: "lat".to_owned() });
+        assert!(matches!(err("speed > 1 speed"), FilterError::Unexpected { offset: 10, .. }));
+        assert!(matches!(err("speed > 1 & lat < 2"), FilterError::Unexpected { offset: 10, .. }));
+        assert!(matches!(err("(speed > 1"), FilterError::Unexpected { .. }));
+        assert!(matches!(err("within(1, 2, 3)"), FilterError::Unexpected { .. }));
+        assert_eq!(err(&"!".repeat(100)), FilterError::TooDeep);
+    }
+}
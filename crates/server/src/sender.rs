@@ -0,0 +1,149 @@
+//! Client side of the ingest protocols, used by tools that feed statuses into
+//! a running server.
+//!
+//! Statuses are sent CBOR-encoded over TCP and UDP, and as JSON over HTTP.
+//! Acknowledgments sent back by the TCP listener are not read. If an API key is
+//! given, it's sent in the form each transport expects.
+
+use std::{fmt::Display, str::FromStr};
+
+use client::{Client, ClientError};
+use shared::{
+    client as device,
+    codec::{self, Cbor, Codec},
+    data::Status,
+};
+use thiserror::Error;
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpStream, UdpSocket},
+};
+
+#[derive(Debug, Error)]
+pub enum SenderError {
+    #[error("unknown transport: {name}")]
+    UnknownTransport { name: String },
+    #[error("IO error")]
+    Io(#[from] std::io::Error),
+    #[error("packet serialization error")]
+    Serialize(#[from] codec::Error),
+    #[error("HTTP client error")]
+    Http(#[from] ClientError),
+    #[error("invalid API key")]
+    InvalidApiKey,
+}
+
+pub type Result<T> = std::result::Result<T, SenderError>;
+
+/// Protocol to send statuses over.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Transport {
+    #[default]
+    Tcp,
+    Udp,
+    Http,
+}
+
+impl Transport {
+    /// Address the server listens on for this transport by default.
+    pub fn default_target(self) -> &'static str {
+        match self {
+            Self::Tcp => "127.0.0.1:8001",
+            Self::Udp => "127.0.0.1:8002",
+            Self::Http => "127.0.0.1:8000",
+        }
+    }
+}
+
+impl FromStr for Transport {
+    type Err = SenderError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "tcp" => Ok(Self::Tcp),
+            "udp" => Ok(Self::Udp),
+            "http" => Ok(Self::Http),
+            _ => Err(SenderError::UnknownTransport { name: s.to_owned() }),
+        }
+    }
+}
+
+impl Display for Transport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Tcp => "tcp",
+            Self::Udp => "udp",
+            Self::Http => "http",
+        })
+    }
+}
+
+/// Connection to a server's ingest endpoint.
+pub enum Sender {
+    Tcp(TcpStream),
+    Udp {
+        socket: UdpSocket,
+        /// Authentication line preceding every datagram, if any.
+        auth: Vec<u8>,
+    },
+    Http(Client),
+}
+
+impl Sender {
+    /// Connect to a server listening at `target` (`host:port`), authenticating
+    /// with `api_key` if given.
+    pub async fn connect(
+        transport: Transport,
+        target: &str,
+        api_key: Option<&str>,
+    ) -> Result<Self> {
+        let auth = match api_key {
+            Some(key) => {
+                let mut line = vec![0; device::MAX_AUTH_LEN];
+                let len =
+                    device::encode_auth(key, &mut line).map_err(|_| SenderError::InvalidApiKey)?;
+                line.truncate(len);
+                line
+            }
+            None => Vec::new(),
+        };
+        match transport {
+            Transport::Tcp => {
+                let mut stream = TcpStream::connect(target).await?;
+                stream.set_nodelay(true)?;
+                stream.write_all(&auth).await?;
+                Ok(Self::Tcp(stream))
+            }
+            Transport::Udp => {
+                let socket = UdpSocket::bind("0.0.0.0:0").await?;
+                socket.connect(target).await?;
+                Ok(Self::Udp { socket, auth })
+            }
+            Transport::Http => {
+                let client = Client::new(&format!("http://{target}"))?;
+                Ok(Self::Http(match api_key {
+                    Some(key) => client.with_api_key(key),
+                    None => client,
+                }))
+            }
+        }
+    }
+
+    /// Send a single status. Over HTTP, waits for the server to persist it.
+    pub async fn send(&mut self, status: &Status) -> Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.write_all(&encode(status)?).await?,
+            Self::Udp { socket, auth } => {
+                socket.send(&[auth.as_slice(), &encode(status)?].concat()).await?;
+            }
+            Self::Http(client) => client.submit(status).await?,
+        }
+        Ok(())
+    }
+}
+
+fn encode(status: &Status) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    Cbor.encode(status, &mut bytes)?;
+    Ok(bytes)
+}
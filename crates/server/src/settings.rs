@@ -0,0 +1,166 @@
+//! Registry of runtime settings, shared between the HTTP API, ingest listeners
+//! and storage. Settings that can be changed while the server is running are
+//! read from here on every use, so that changes made through the `/admin`
+//! endpoints apply right away without a restart.
+
+use std::sync::{Arc, Mutex, RwLock};
+
+use thiserror::Error;
+
+use crate::{ingest::Connections, storage::DupeStrategy};
+
+#[derive(Debug, Error)]
+pub enum SettingsError {
+    #[error("log filter can't be changed at runtime")]
+    LogFilterFixed,
+    #[error("invalid log filter: {message}")]
+    InvalidLogFilter { message: String },
+}
+
+pub type Result<T> = std::result::Result<T, SettingsError>;
+
+/// A value shared by everything holding a clone of it, which all see changes
+/// made through any of them.
+#[derive(Debug, Default)]
+pub struct Setting<T>(Arc<RwLock<T>>);
+
+impl<T: Copy> Setting<T> {
+    pub fn new(value: T) -> Self {
+        Self(Arc::new(RwLock::new(value)))
+    }
+
+    pub fn get(&self) -> T {
+        *self.0.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn set(&self, value: T) {
+        *self.0.write().unwrap_or_else(|e| e.into_inner()) = value;
+    }
+}
+
+// Not derived, as that would require `T: Clone`.
+impl<T> Clone for Setting<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T: Copy> From<T> for Setting<T> {
+    fn from(value: T) -> Self {
+        Self::new(value)
+    }
+}
+
+/// Replaces the filter of the logging subscriber.
+type Reload = dyn Fn(&str) -> std::result::Result<(), String> + Send + Sync;
+
+/// Filter of log events, e.g. `info,server::ingest=debug`, along with a way
+/// to replace it in the logging subscriber.
+struct LogFilter {
+    current: Mutex<String>,
+    reload: Box<Reload>,
+}
+
+/// Runtime settings and state of the server, as inspected and changed through
+/// the `/admin` endpoints.
+#[derive(Clone)]
+pub struct Settings {
+    /// Configuration the server was started with, in a human readable form.
+    config: Arc<str>,
+    dupe_strategy: Setting<DupeStrategy>,
+    log_filter: Option<Arc<LogFilter>>,
+    /// Open TCP ingest connections.
+    connections: Arc<Connections>,
+}
+
+impl Settings {
+    pub fn new(config: impl Into<Arc<str>>, dupe_strategy: DupeStrategy) -> Self {
+        Self {
+            config: config.into(),
+            dupe_strategy: Setting::new(dupe_strategy),
+            log_filter: None,
+            connections: Arc::new(Connections::new()),
+        }
+    }
+
+    /// Allow changing the log filter, initially `current`, by calling
+    /// `reload` with new filter directives. It fails with a description of
+    /// the problem if the directives are invalid.
+    pub fn with_log_filter(
+        mut self,
+        current: impl Into<String>,
+        reload: impl Fn(&str) -> std::result::Result<(), String> + Send + Sync + 'static,
+    ) -> Self {
+        let filter = LogFilter { current: Mutex::new(current.into()), reload: Box::new(reload) };
+        self.log_filter = Some(Arc::new(filter));
+        self
+    }
+
+    pub fn config(&self) -> &str {
+        &self.config
+    }
+
+    /// Strategy for duplicate statuses, which storage engines given a clone
+    /// of it follow.
+    pub fn dupe_strategy(&self) -> &Setting<DupeStrategy> {
+        &self.dupe_strategy
+    }
+
+    /// Current log filter, if it can be changed.
+    pub fn log_filter(&self) -> Option<String> {
+        let filter = self.log_filter.as_ref()?;
+        Some(filter.current.lock().unwrap_or_else(|e| e.into_inner()).clone())
+    }
+
+    pub fn set_log_filter(&self, directives: &str) -> Result<()> {
+        let filter = self.log_filter.as_ref().ok_or(SettingsError::LogFilterFixed)?;
+        let mut current = filter.current.lock().unwrap_or_else(|e| e.into_inner());
+        (filter.reload)(directives)
+            .map_err(|message| SettingsError::InvalidLogFilter { message })?;
+        directives.clone_into(&mut current);
+        Ok(())
+    }
+
+    pub(crate) fn connections(&self) -> &Arc<Connections> {
+        &self.connections
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self::new("", DupeStrategy::Merge)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Setting, Settings, SettingsError};
+    use crate::storage::DupeStrategy;
+
+    #[test]
+    fn shared_setting() {
+        let setting = Setting::new(DupeStrategy::Merge);
+        let clone = setting.clone();
+        clone.set(DupeStrategy::Drop);
+        assert_eq!(setting.get(), DupeStrategy::Drop);
+    }
+
+    #[test]
+    fn log_filter() {
+        let settings = Settings::default();
+        assert_eq!(settings.log_filter(), None);
+        assert!(matches!(settings.set_log_filter("debug"), Err(SettingsError::LogFilterFixed)));
+
+        let settings = settings.with_log_filter("info", |directives| match directives {
+            "debug" => Ok(()),
+            _ => Err("unknown level".to_owned()),
+        });
+        settings.clone().set_log_filter("debug").unwrap();
+        assert_eq!(settings.log_filter().as_deref(), Some("debug"));
+        assert!(matches!(
+            settings.set_log_filter("loud"),
+            Err(SettingsError::InvalidLogFilter { .. })
+        ));
+        assert_eq!(settings.log_filter().as_deref(), Some("debug"));
+    }
+}
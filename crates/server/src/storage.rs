@@ -1,7 +1,18 @@
 //! This module houses the [`Storage`] trait that describes the interface of
 //! supported persistence engines, as well as its implementations.
 
+pub mod aggregate;
+#[cfg(feature = "archive")]
+pub mod archive;
+pub mod codec;
+#[cfg(feature = "s3")]
+pub mod export;
+pub mod heatmap;
 mod memory;
+#[cfg(feature = "redis")]
+pub mod redis;
+pub mod report;
+pub mod shard;
 #[cfg(feature = "sled")]
 mod sled;
 
@@ -9,24 +20,84 @@ use std::{
     fmt::Debug,
     ops::{Bound, RangeBounds},
     str::FromStr,
+    time::Duration,
 };
 
 use async_trait::async_trait;
-use shared::data::{SourceId, Status};
+use futures_util::stream::{self, BoxStream, StreamExt};
+use geo_types::Rect;
+use serde::{Deserialize, Serialize};
+use shared::data::{SourceId, Status, TenantId};
 use thiserror::Error;
-use time::OffsetDateTime;
+use time::{Date, OffsetDateTime};
 
 use crate::{
-    cq::{Address, Request},
-    storage::memory::MemoryStorage,
+    cq::Request,
+    query::{self, Filter},
+    settings::Setting,
+    storage::{
+        aggregate::Aggregate,
+        heatmap::Heatmap,
+        memory::MemoryStorage,
+        report::{DailyDistance, Day, DistanceCache},
+    },
+    util::geohash,
 };
 
 /// Storage errors.
 #[derive(Debug, Error)]
 pub enum StorageError {
+    #[cfg(feature = "archive")]
+    #[error("Parquet error")]
+    Parquet(#[from] ::parquet::errors::ParquetError),
+    #[error("archival is disabled")]
+    ArchiveDisabled,
+    #[error("corrupt archive file: {}", path.display())]
+    CorruptArchive { path: std::path::PathBuf },
+    #[error("Redis error: {message}")]
+    Redis { message: String },
     #[cfg(feature = "sled")]
     #[error("Sled error")]
     Sled(#[from] ::sled::Error),
+    #[error("failed to encode or decode stored status")]
+    Codec(#[from] shared::codec::Error),
+    #[error("corrupt stored status")]
+    CorruptStatus,
+    #[error("IO error")]
+    Io(#[from] std::io::Error),
+    #[error("invalid S3 endpoint: {endpoint}; must be an http:// URL")]
+    InvalidEndpoint { endpoint: String },
+    #[cfg(feature = "s3")]
+    #[error("S3 request error")]
+    S3Request(#[from] hyper_util::client::legacy::Error),
+    #[cfg(feature = "s3")]
+    #[error("S3 response error")]
+    S3Response(#[from] hyper::Error),
+    #[cfg(feature = "s3")]
+    #[error("invalid S3 request")]
+    S3InvalidRequest(#[from] hyper::http::Error),
+    #[error("S3 request failed with status {status}: {message}")]
+    S3Status { status: u16, message: String },
+    #[error("invalid geohash cell: {cell}")]
+    InvalidCell { cell: String },
+    #[error("invalid cell index precision: {precision}; must be between 1 and 12")]
+    InvalidCellPrecision { precision: usize },
+    #[error("spatial cell index is disabled")]
+    CellIndexDisabled,
+    #[error("storage is in use by open result streams")]
+    Busy,
+    #[error("storage engine doesn't support compaction")]
+    CompactionUnsupported,
+    #[error("storage engine doesn't support atomic batches")]
+    AtomicBatchUnsupported,
+    #[error("batch of {count} statuses rolled back")]
+    BatchRolledBack { count: usize, source: Box<StorageError> },
+    #[error("invalid aggregation bucket; must be positive")]
+    InvalidBucket,
+    #[error("invalid report range; must span between 1 and {} days", report::MAX_DAYS)]
+    InvalidReportRange,
+    #[error("invalid storage option: {option}")]
+    InvalidStorageOption { option: String },
     #[error("storage type not compiled: {name}; recompile with corresponding --features flag")]
     StorageNotCompiled { name: String },
     #[error("unknown duplicate strategy: {name}")]
@@ -37,18 +108,172 @@ pub enum StorageError {
 
 pub type Result<T> = std::result::Result<T, StorageError>;
 
+/// Statuses produced one at a time, independently of the storage engine they
+/// come from.
+pub type StatusStream = BoxStream<'static, Result<Status>>;
+
+/// Position of a status among all statuses of a tenant, which are ordered by
+/// timestamp first and by source second.
+pub type TimeKey = (OffsetDateTime, SourceId);
+
 /// This trait describes the operations that all supported storage engines must
 /// support in order to be used in this project.
+///
+/// All data is scoped to tenants: sources with the same [`SourceId`] but
+/// different [`TenantId`]s are unrelated, and queries only ever return data of
+/// the tenant they're made for. Queries that take an optional tenant cover all
+/// tenants if it's `None`, which is meant for internal housekeeping only.
+///
+/// All operations take a shared reference, so that an engine can serve any
+/// number of reads and writes at the same time. Engines take care of their own
+/// synchronization.
 #[async_trait]
 pub trait Storage {
-    /// Save a single [`Status`] packet.
-    async fn persist_status(&mut self, status: Status) -> Result<()>;
+    /// Save a single [`Status`] packet under its `tenant_id`.
+    async fn persist_status(&self, status: Status) -> Result<()>;
+
+    /// Save several [`Status`] packets in order, all or nothing. If any of
+    /// them can't be saved, none of them are, and the whole batch fails with
+    /// [`StorageError::BatchRolledBack`]. Readers never see part of a batch.
+    ///
+    /// Engines that can't guarantee that fail with
+    /// [`StorageError::AtomicBatchUnsupported`] without saving anything.
+    async fn persist_batch_atomic(&self, _statuses: Vec<Status>) -> Result<()> {
+        Err(StorageError::AtomicBatchUnsupported)
+    }
 
     /// Get a range of [`Status`] packets for a given [`SourceId`] in a given
     /// time range.
-    async fn get_statuses<R>(&self, source_id: SourceId, timestamps: R) -> Result<Vec<Status>>
+    async fn get_statuses<R>(
+        &self,
+        tenant_id: TenantId,
+        source_id: SourceId,
+        timestamps: R,
+    ) -> Result<Vec<Status>>
+    where
+        R: RangeBounds<OffsetDateTime> + Send + Debug;
+
+    /// Same as [`Storage::get_statuses`], but produces statuses one at a time,
+    /// so that memory use doesn't grow with the size of the range. Engines
+    /// holding all data in memory anyway collect the statuses first.
+    async fn stream_statuses<R>(
+        &self,
+        tenant_id: TenantId,
+        source_id: SourceId,
+        timestamps: R,
+    ) -> Result<StatusStream>
+    where
+        R: RangeBounds<OffsetDateTime> + Send + Debug,
+    {
+        let statuses = self.get_statuses(tenant_id, source_id, timestamps).await?;
+        Ok(stream::iter(statuses.into_iter().map(Ok)).boxed())
+    }
+
+    /// Summarize [`Status`] packets of a given [`SourceId`] in a given time
+    /// range over consecutive buckets of a given length.
+    async fn aggregate<R>(
+        &self,
+        tenant_id: TenantId,
+        source_id: SourceId,
+        timestamps: R,
+        bucket: Duration,
+    ) -> Result<Vec<Aggregate>>
+    where
+        R: RangeBounds<OffsetDateTime> + Send + Debug,
+    {
+        let statuses = self.stream_statuses(tenant_id, source_id, timestamps).await?;
+        aggregate::aggregate(statuses, bucket).await
+    }
+
+    /// Remove all [`Status`] packets of a given [`SourceId`] in a given time
+    /// range. Returns the number of removed packets.
+    async fn remove_statuses<R>(
+        &self,
+        tenant_id: TenantId,
+        source_id: SourceId,
+        timestamps: R,
+    ) -> Result<usize>
     where
         R: RangeBounds<OffsetDateTime> + Send + Debug;
+
+    /// Get [`Status`] packets of all sources in a given time range, ordered
+    /// by [`TimeKey`], starting past `after` if given, and at most `limit` of
+    /// them. Engines keeping a time-ordered index look them up in it, while
+    /// others go through every known source.
+    async fn get_all_statuses<R>(
+        &self,
+        tenant_id: TenantId,
+        timestamps: R,
+        after: Option<TimeKey>,
+        limit: usize,
+    ) -> Result<Vec<Status>>
+    where
+        R: RangeBounds<OffsetDateTime> + Send + Debug,
+    {
+        let range = (timestamps.start_bound().cloned(), timestamps.end_bound().cloned());
+        let mut statuses = Vec::new();
+        for latest in self.latest_many(Some(tenant_id), None).await? {
+            let found = self.get_statuses(tenant_id, latest.source_id, range).await?;
+            statuses.extend(
+                found.into_iter().filter(|s| after.is_none_or(|after| time_key(s) > after)),
+            );
+        }
+        statuses.sort_unstable_by_key(time_key);
+        statuses.truncate(limit);
+        Ok(statuses)
+    }
+
+    /// Get the most recent [`Status`] packet for each of the given
+    /// [`SourceId`]s, or for every known source if `source_ids` is `None`.
+    /// Sources that have no stored statuses are omitted from the result.
+    async fn latest_many(
+        &self,
+        tenant_id: Option<TenantId>,
+        source_ids: Option<&[SourceId]>,
+    ) -> Result<Vec<Status>>;
+
+    /// Get the [`LatestVersion`] of a given [`SourceId`], which is cheaper
+    /// than getting its latest [`Status`] packet for engines that can look it
+    /// up without decoding the whole status.
+    async fn latest_version(
+        &self,
+        tenant_id: TenantId,
+        source_id: SourceId,
+    ) -> Result<Option<LatestVersion>> {
+        let latest = self.latest_many(Some(tenant_id), Some(&[source_id])).await?;
+        Ok(latest.first().map(LatestVersion::of))
+    }
+
+    /// Get all [`Status`] packets whose position lies within a given geohash
+    /// cell. Requires the spatial cell index to be enabled.
+    async fn get_cell_statuses(&self, tenant_id: TenantId, cell: &str) -> Result<Vec<Status>>;
+
+    /// Get the number of known sources and stored [`Status`] packets.
+    async fn stats(&self, tenant_id: Option<TenantId>) -> Result<StorageStats>;
+}
+
+/// Version of the latest status of a source, which changes whenever a newer
+/// status is stored, as well as when a duplicate is merged into the latest
+/// one and its receive time with it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatestVersion {
+    pub timestamp: OffsetDateTime,
+    pub received_at: Option<OffsetDateTime>,
+}
+
+impl LatestVersion {
+    pub fn of(status: &Status) -> Self {
+        Self { timestamp: status.timestamp, received_at: status.received_at }
+    }
+}
+
+/// Summary of the data held by a storage engine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct StorageStats {
+    /// Number of sources with at least one stored status.
+    pub sources: usize,
+    /// Total number of stored statuses.
+    pub statuses: usize,
 }
 
 /// Lists all supported storage backends along with their corresponding
@@ -56,13 +281,20 @@ pub trait Storage {
 #[derive(Debug)]
 pub enum StorageConfig {
     /// In-memory storage. Not persisted between service restarts.
-    InMemory,
+    InMemory {
+        /// Limits on the amount of stored statuses.
+        config: memory::MemoryConfig,
+    },
     /// Persistent storage backed by the Sled database engine.
     #[cfg(feature = "sled")]
     Sled {
-        /// Path to the directory where Sled stores its data.
+        /// Location of the database and tuning of the engine.
         config: sled::SledConfig,
     },
+    /// Storage of the latest statuses and a capped window of recent history in
+    /// Redis.
+    #[cfg(feature = "redis")]
+    Redis { config: redis::RedisConfig },
 }
 
 impl FromStr for StorageConfig {
@@ -70,15 +302,18 @@ impl FromStr for StorageConfig {
 
     fn from_str(s: &str) -> Result<Self> {
         let storage = match s {
-            "memory" => Self::InMemory,
+            _ if s == "memory" || s.starts_with("memory:") => {
+                let config = match s.split_once(':') {
+                    Some((_, config)) => config.parse()?,
+                    None => memory::MemoryConfig::default(),
+                };
+                Self::InMemory { config }
+            }
             #[cfg(feature = "sled")]
             _ if s == "sled" || s.starts_with("sled:") => {
-                let config = if let Some(db_dir) =
-                    s.split_once(':').map(|(_, path)| std::path::PathBuf::from(path))
-                {
-                    sled::SledConfig { db_dir }
-                } else {
-                    sled::SledConfig::default()
+                let config = match s.split_once(':') {
+                    Some((_, config)) => config.parse()?,
+                    None => sled::SledConfig::default(),
                 };
                 Self::Sled { config }
             }
@@ -86,6 +321,18 @@ impl FromStr for StorageConfig {
             _ if s == "sled" || s.starts_with("sled:") => {
                 return Err(StorageError::StorageNotCompiled { name: s.to_owned() });
             }
+            #[cfg(feature = "redis")]
+            _ if s == "redis" || s.starts_with("redis:") => {
+                let config = match s.split_once(':') {
+                    Some((_, config)) => config.parse()?,
+                    None => redis::RedisConfig::default(),
+                };
+                Self::Redis { config }
+            }
+            #[cfg(not(feature = "redis"))]
+            _ if s == "redis" || s.starts_with("redis:") => {
+                return Err(StorageError::StorageNotCompiled { name: s.to_owned() });
+            }
             _ => return Err(StorageError::UnknownStorageType { name: s.to_owned() }),
         };
         Ok(storage)
@@ -93,8 +340,9 @@ impl FromStr for StorageConfig {
 }
 
 /// Strategy to use when multiple [`Status`] packets arrive with the same pair
-/// of `source_id` + `timestamp`.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// of `source_id` + `timestamp`. Named the same in JSON as when parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum DupeStrategy {
     /// Discard newly received packets, keeping the original one.
     Drop,
@@ -119,6 +367,45 @@ impl FromStr for DupeStrategy {
     }
 }
 
+/// Configuration of the optional spatial index that buckets positions into
+/// geohash cells of a fixed length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CellIndex {
+    precision: usize,
+}
+
+impl CellIndex {
+    pub fn new(precision: usize) -> Result<Self> {
+        if !(1..=geohash::MAX_PRECISION).contains(&precision) {
+            return Err(StorageError::InvalidCellPrecision { precision });
+        }
+        Ok(Self { precision })
+    }
+
+    /// Length of the geohash cells stored in the index.
+    pub fn precision(&self) -> usize {
+        self.precision
+    }
+
+    /// Cell that the given [`Status`] belongs to, if it has a position.
+    pub fn cell(&self, status: &Status) -> Option<String> {
+        status.position.map(|p| geohash::encode(p, self.precision))
+    }
+
+    /// Splits a query cell into the prefix to scan the index for and, if the
+    /// cell is more precise than the index, the full cell to filter by.
+    pub fn scan_prefix<'a>(&self, cell: &'a str) -> Result<(&'a str, Option<&'a str>)> {
+        if !geohash::is_valid(cell) {
+            return Err(StorageError::InvalidCell { cell: cell.to_owned() });
+        }
+        if cell.len() > self.precision {
+            Ok((&cell[..self.precision], Some(cell)))
+        } else {
+            Ok((cell, None))
+        }
+    }
+}
+
 /// A concrete instance of one of the supported storage engines.
 pub enum StorageEngine {
     #[doc(hidden)]
@@ -126,47 +413,633 @@ pub enum StorageEngine {
     #[doc(hidden)]
     #[cfg(feature = "sled")]
     Sled(sled::SledStorage),
+    #[doc(hidden)]
+    #[cfg(feature = "redis")]
+    Redis(redis::RedisStorage),
 }
 
 #[async_trait]
 impl Storage for StorageEngine {
-    async fn persist_status(&mut self, status: Status) -> Result<()> {
+    async fn persist_status(&self, status: Status) -> Result<()> {
         match self {
             Self::InMemory(s) => s.persist_status(status).await,
             #[cfg(feature = "sled")]
             Self::Sled(s) => s.persist_status(status).await,
+            #[cfg(feature = "redis")]
+            Self::Redis(s) => s.persist_status(status).await,
         }
     }
 
-    async fn get_statuses<R>(&self, source_id: SourceId, timestamps: R) -> Result<Vec<Status>>
+    async fn persist_batch_atomic(&self, statuses: Vec<Status>) -> Result<()> {
+        match self {
+            Self::InMemory(s) => s.persist_batch_atomic(statuses).await,
+            #[cfg(feature = "sled")]
+            Self::Sled(s) => s.persist_batch_atomic(statuses).await,
+            #[cfg(feature = "redis")]
+            Self::Redis(s) => s.persist_batch_atomic(statuses).await,
+        }
+    }
+
+    async fn get_statuses<R>(
+        &self,
+        tenant_id: TenantId,
+        source_id: SourceId,
+        timestamps: R,
+    ) -> Result<Vec<Status>>
     where
         R: RangeBounds<OffsetDateTime> + Send + Debug,
     {
         match self {
-            Self::InMemory(s) => s.get_statuses(source_id, timestamps).await,
+            Self::InMemory(s) => s.get_statuses(tenant_id, source_id, timestamps).await,
             #[cfg(feature = "sled")]
-            Self::Sled(s) => s.get_statuses(source_id, timestamps).await,
+            Self::Sled(s) => s.get_statuses(tenant_id, source_id, timestamps).await,
+            #[cfg(feature = "redis")]
+            Self::Redis(s) => s.get_statuses(tenant_id, source_id, timestamps).await,
         }
     }
+
+    async fn stream_statuses<R>(
+        &self,
+        tenant_id: TenantId,
+        source_id: SourceId,
+        timestamps: R,
+    ) -> Result<StatusStream>
+    where
+        R: RangeBounds<OffsetDateTime> + Send + Debug,
+    {
+        match self {
+            Self::InMemory(s) => s.stream_statuses(tenant_id, source_id, timestamps).await,
+            #[cfg(feature = "sled")]
+            Self::Sled(s) => s.stream_statuses(tenant_id, source_id, timestamps).await,
+            #[cfg(feature = "redis")]
+            Self::Redis(s) => s.stream_statuses(tenant_id, source_id, timestamps).await,
+        }
+    }
+
+    async fn aggregate<R>(
+        &self,
+        tenant_id: TenantId,
+        source_id: SourceId,
+        timestamps: R,
+        bucket: Duration,
+    ) -> Result<Vec<Aggregate>>
+    where
+        R: RangeBounds<OffsetDateTime> + Send + Debug,
+    {
+        match self {
+            Self::InMemory(s) => s.aggregate(tenant_id, source_id, timestamps, bucket).await,
+            #[cfg(feature = "sled")]
+            Self::Sled(s) => s.aggregate(tenant_id, source_id, timestamps, bucket).await,
+            #[cfg(feature = "redis")]
+            Self::Redis(s) => s.aggregate(tenant_id, source_id, timestamps, bucket).await,
+        }
+    }
+
+    async fn remove_statuses<R>(
+        &self,
+        tenant_id: TenantId,
+        source_id: SourceId,
+        timestamps: R,
+    ) -> Result<usize>
+    where
+        R: RangeBounds<OffsetDateTime> + Send + Debug,
+    {
+        match self {
+            Self::InMemory(s) => s.remove_statuses(tenant_id, source_id, timestamps).await,
+            #[cfg(feature = "sled")]
+            Self::Sled(s) => s.remove_statuses(tenant_id, source_id, timestamps).await,
+            #[cfg(feature = "redis")]
+            Self::Redis(s) => s.remove_statuses(tenant_id, source_id, timestamps).await,
+        }
+    }
+
+    async fn get_all_statuses<R>(
+        &self,
+        tenant_id: TenantId,
+        timestamps: R,
+        after: Option<TimeKey>,
+        limit: usize,
+    ) -> Result<Vec<Status>>
+    where
+        R: RangeBounds<OffsetDateTime> + Send + Debug,
+    {
+        match self {
+            Self::InMemory(s) => s.get_all_statuses(tenant_id, timestamps, after, limit).await,
+            #[cfg(feature = "sled")]
+            Self::Sled(s) => s.get_all_statuses(tenant_id, timestamps, after, limit).await,
+            #[cfg(feature = "redis")]
+            Self::Redis(s) => s.get_all_statuses(tenant_id, timestamps, after, limit).await,
+        }
+    }
+
+    async fn latest_many(
+        &self,
+        tenant_id: Option<TenantId>,
+        source_ids: Option<&[SourceId]>,
+    ) -> Result<Vec<Status>> {
+        match self {
+            Self::InMemory(s) => s.latest_many(tenant_id, source_ids).await,
+            #[cfg(feature = "sled")]
+            Self::Sled(s) => s.latest_many(tenant_id, source_ids).await,
+            #[cfg(feature = "redis")]
+            Self::Redis(s) => s.latest_many(tenant_id, source_ids).await,
+        }
+    }
+
+    async fn latest_version(
+        &self,
+        tenant_id: TenantId,
+        source_id: SourceId,
+    ) -> Result<Option<LatestVersion>> {
+        match self {
+            Self::InMemory(s) => s.latest_version(tenant_id, source_id).await,
+            #[cfg(feature = "sled")]
+            Self::Sled(s) => s.latest_version(tenant_id, source_id).await,
+            #[cfg(feature = "redis")]
+            Self::Redis(s) => s.latest_version(tenant_id, source_id).await,
+        }
+    }
+
+    async fn get_cell_statuses(&self, tenant_id: TenantId, cell: &str) -> Result<Vec<Status>> {
+        match self {
+            Self::InMemory(s) => s.get_cell_statuses(tenant_id, cell).await,
+            #[cfg(feature = "sled")]
+            Self::Sled(s) => s.get_cell_statuses(tenant_id, cell).await,
+            #[cfg(feature = "redis")]
+            Self::Redis(s) => s.get_cell_statuses(tenant_id, cell).await,
+        }
+    }
+
+    async fn stats(&self, tenant_id: Option<TenantId>) -> Result<StorageStats> {
+        match self {
+            Self::InMemory(s) => s.stats(tenant_id).await,
+            #[cfg(feature = "sled")]
+            Self::Sled(s) => s.stats(tenant_id).await,
+            #[cfg(feature = "redis")]
+            Self::Redis(s) => s.stats(tenant_id).await,
+        }
+    }
+}
+
+impl StorageEngine {
+    /// Report the size of the engine's data and enforce its size limits, if
+    /// it has any. Returns the number of statuses pruned to stay within them.
+    pub async fn maintain(&self) -> Result<usize> {
+        match self {
+            #[cfg(feature = "sled")]
+            Self::Sled(s) => s.maintain().await,
+            _ => Ok(0),
+        }
+    }
+
+    /// Reclaim disk space left behind by removed data.
+    pub async fn compact(&self) -> Result<()> {
+        match self {
+            #[cfg(feature = "sled")]
+            Self::Sled(s) => s.compact().await,
+            _ => Err(StorageError::CompactionUnsupported),
+        }
+    }
+}
+
+/// A [`StorageEngine`] together with the optional subsystems layered on top of
+/// it. Serves [`StorageCommand`]s and [`StorageQuery`]s, any number of them at
+/// the same time.
+pub struct StorageService {
+    engine: StorageEngine,
+    #[cfg(feature = "archive")]
+    archive: Option<std::sync::Arc<archive::Archive>>,
+    #[cfg(feature = "redis")]
+    cache: Option<redis::LatestCache>,
+    distances: DistanceCache,
+}
+
+impl StorageService {
+    pub fn new(engine: StorageEngine) -> Self {
+        Self {
+            engine,
+            #[cfg(feature = "archive")]
+            archive: None,
+            #[cfg(feature = "redis")]
+            cache: None,
+            distances: DistanceCache::default(),
+        }
+    }
+
+    /// Serve latest status queries from the given [`redis::LatestCache`],
+    /// populating it from the storage engine first.
+    #[cfg(feature = "redis")]
+    pub async fn with_cache(mut self, cache: redis::LatestCache) -> Self {
+        cache.refresh(&self.engine).await;
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Move old statuses into the given [`archive::Archive`] on
+    /// [`StorageCommand::RollArchive`], and include archived data in history
+    /// queries.
+    #[cfg(feature = "archive")]
+    pub fn with_archive(mut self, archive: archive::Archive) -> Self {
+        self.archive = Some(std::sync::Arc::new(archive));
+        self
+    }
+
+    /// Execute a [`StorageCommand`].
+    pub async fn handle_command(&self, cmd: StorageCommand) -> Result<()> {
+        match cmd {
+            StorageCommand::PersistStatus(status) => self.persist_status(status).await,
+            StorageCommand::PersistStatuses(statuses) => {
+                for status in statuses {
+                    self.persist_status(status).await?;
+                }
+                Ok(())
+            }
+            StorageCommand::PersistBatch(statuses) => self.persist_batch(statuses).await,
+            // Shards wait for each other in `shard::synchronize` beforehand.
+            StorageCommand::PersistBatchAcross(statuses, _gate) => {
+                self.persist_batch(statuses).await
+            }
+            StorageCommand::Barrier(_) => Ok(()),
+            StorageCommand::RollArchive => self.roll_archive().await,
+            StorageCommand::Maintain => self.maintain().await,
+            StorageCommand::Compact => self.engine.compact().await,
+        }
+    }
+
+    async fn maintain(&self) -> Result<()> {
+        let pruned = self.engine.maintain().await?;
+        if pruned > 0 {
+            tracing::info!(pruned, "Pruned oldest statuses to stay within the size limit");
+            self.distances.clear();
+            // Latest statuses may have been pruned as well.
+            #[cfg(feature = "redis")]
+            if let Some(cache) = &self.cache {
+                cache.invalidate().await;
+            }
+        }
+        // Repopulate the cache if it's stale, which writes leave to this.
+        #[cfg(feature = "redis")]
+        if let Some(cache) = &self.cache {
+            cache.refresh(&self.engine).await;
+        }
+        Ok(())
+    }
+
+    async fn persist_status(&self, status: Status) -> Result<()> {
+        self.engine.persist_status(status).await?;
+        self.distances.invalidate(&status);
+        #[cfg(feature = "redis")]
+        if let Some(cache) = &self.cache {
+            cache.persist_status(status).await;
+        }
+        Ok(())
+    }
+
+    async fn persist_batch(&self, statuses: Vec<Status>) -> Result<()> {
+        match self.engine.persist_batch_atomic(statuses.clone()).await {
+            Ok(()) => {}
+            // Nothing has been saved, so it's safe to fall back to saving
+            // statuses one at a time.
+            Err(StorageError::AtomicBatchUnsupported) => {
+                for status in statuses {
+                    self.persist_status(status).await?;
+                }
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        }
+        for status in &statuses {
+            self.distances.invalidate(status);
+        }
+        #[cfg(feature = "redis")]
+        if let Some(cache) = &self.cache {
+            for status in statuses {
+                cache.persist_status(status).await;
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "archive")]
+    async fn roll_archive(&self) -> Result<()> {
+        let archive = self.archive.as_ref().ok_or(StorageError::ArchiveDisabled)?;
+        let archived = archive.roll(&self.engine).await?;
+        if archived > 0 {
+            tracing::info!(archived, "Archived old statuses");
+            // Latest statuses may have been archived as well.
+            #[cfg(feature = "redis")]
+            if let Some(cache) = &self.cache {
+                cache.invalidate().await;
+                cache.refresh(&self.engine).await;
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "archive"))]
+    async fn roll_archive(&self) -> Result<()> {
+        Err(StorageError::ArchiveDisabled)
+    }
+
+    /// Execute a [`StorageQuery`].
+    pub async fn handle_query(&self, query: StorageQuery) -> Result<QueryResult> {
+        match query {
+            StorageQuery::GetStatuses(GetStatuses { tenant_id, source_id, timestamps, filter }) => {
+                let statuses = self.engine.get_statuses(tenant_id, source_id, timestamps).await?;
+                #[cfg(feature = "archive")]
+                let statuses = match &self.archive {
+                    Some(archive) => {
+                        archive.merge(tenant_id, source_id, &timestamps, statuses).await?
+                    }
+                    None => statuses,
+                };
+                let statuses =
+                    statuses.into_iter().filter(|s| query::matches(filter.as_ref(), s)).collect();
+                Ok(QueryResult::Statuses(statuses))
+            }
+            StorageQuery::StreamStatuses(query) => {
+                self.stream_statuses(query).await.map(QueryResult::Stream)
+            }
+            StorageQuery::GetAllStatuses(GetAllStatuses {
+                tenant_id,
+                timestamps,
+                after,
+                limit,
+            }) => {
+                let statuses =
+                    self.engine.get_all_statuses(tenant_id, timestamps, after, limit).await?;
+                Ok(QueryResult::Statuses(statuses))
+            }
+            StorageQuery::Aggregate(AggregateStatuses { query, bucket }) => {
+                self.aggregate(query, bucket).await.map(QueryResult::Aggregates)
+            }
+            StorageQuery::DistanceReport(report) => {
+                self.distance_report(report).await.map(QueryResult::Distances)
+            }
+            StorageQuery::Latest(tenant_id, source_id) => {
+                let mut statuses = self.latest_many(tenant_id, Some(&[source_id])).await?;
+                Ok(QueryResult::Latest(statuses.pop()))
+            }
+            StorageQuery::LatestVersion(tenant_id, source_id) => {
+                self.latest_version(tenant_id, source_id).await.map(QueryResult::Version)
+            }
+            StorageQuery::LatestMany(LatestMany { tenant_id, source_ids, bbox, filter }) => {
+                let mut statuses = self.latest_many(tenant_id, source_ids.as_deref()).await?;
+                if let Some(bbox) = bbox {
+                    statuses.retain(|s| s.position.is_some_and(|p| contains(&bbox, p)));
+                }
+                statuses.retain(|s| query::matches(filter.as_ref(), s));
+                Ok(QueryResult::Statuses(statuses))
+            }
+            StorageQuery::GetCellStatuses(GetCellStatuses { tenant_id, cell }) => {
+                self.engine.get_cell_statuses(tenant_id, &cell).await.map(QueryResult::Statuses)
+            }
+            StorageQuery::Heatmap(query) => self.heatmap(query).await.map(QueryResult::Heatmap),
+            StorageQuery::Stats(tenant_id) => {
+                self.engine.stats(tenant_id).await.map(QueryResult::Stats)
+            }
+        }
+    }
+
+    /// Statuses of a single source, archived ones included.
+    async fn stream_statuses(&self, query: GetStatuses) -> Result<StatusStream> {
+        let GetStatuses { tenant_id, source_id, timestamps, filter } = query;
+        let statuses = self.engine.stream_statuses(tenant_id, source_id, timestamps).await?;
+        #[cfg(feature = "archive")]
+        let statuses = match &self.archive {
+            Some(archive) => {
+                let archived = archive.stream(tenant_id, source_id, timestamps).await?;
+                archive::merge_streams(archived, statuses)
+            }
+            None => statuses,
+        };
+        let Some(filter) = filter else {
+            return Ok(statuses);
+        };
+        let matching = move |status: &Result<Status>| {
+            let matches = status.as_ref().map_or(true, |s| filter.matches(s));
+            std::future::ready(matches)
+        };
+        Ok(statuses.filter(matching).boxed())
+    }
+
+    /// Aggregates of a single source, archived statuses included.
+    async fn aggregate(&self, query: GetStatuses, bucket: Duration) -> Result<Vec<Aggregate>> {
+        // Archived statuses have to be merged in and filters applied before
+        // aggregating.
+        #[cfg(feature = "archive")]
+        let merge = self.archive.is_some();
+        #[cfg(not(feature = "archive"))]
+        let merge = false;
+        if merge || query.filter.is_some() {
+            let statuses = self.stream_statuses(query).await?;
+            return aggregate::aggregate(statuses, bucket).await;
+        }
+        let GetStatuses { tenant_id, source_id, timestamps, .. } = query;
+        self.engine.aggregate(tenant_id, source_id, timestamps, bucket).await
+    }
+
+    /// Daily distances of every known source of a tenant. Days before the
+    /// current UTC day are cached.
+    async fn distance_report(&self, report: DistanceReport) -> Result<Vec<DailyDistance>> {
+        let DistanceReport { tenant_id, from, to } = report;
+        if !(1..=report::MAX_DAYS).contains(&((to - from).whole_days() + 1)) {
+            return Err(StorageError::InvalidReportRange);
+        }
+        let today = OffsetDateTime::now_utc().date();
+        let sources = self.latest_many(tenant_id, None).await?;
+        let mut rows = Vec::new();
+        for source_id in sources.into_iter().map(|s| s.source_id) {
+            let mut date = from;
+            loop {
+                let key = (tenant_id, source_id, date);
+                let day = match self.distances.get(key) {
+                    Some(day) => day,
+                    None => {
+                        let day = self.daily_distance(tenant_id, source_id, date).await?;
+                        if date < today {
+                            self.distances.insert(key, day);
+                        }
+                        day
+                    }
+                };
+                if day.fixes > 0 {
+                    rows.push(DailyDistance { source_id, date, day });
+                }
+                match date.next_day() {
+                    Some(next) if next <= to => date = next,
+                    _ => break,
+                }
+            }
+        }
+        Ok(rows)
+    }
+
+    /// Distance travelled by a source, the number of its statuses and its
+    /// utilization within a single UTC day.
+    async fn daily_distance(
+        &self,
+        tenant_id: TenantId,
+        source_id: SourceId,
+        date: Date,
+    ) -> Result<Day> {
+        let start = Bound::Included(date.midnight().assume_utc());
+        let end = date
+            .next_day()
+            .map_or(Bound::Unbounded, |next| Bound::Excluded(next.midnight().assume_utc()));
+        let query = GetStatuses { tenant_id, source_id, timestamps: (start, end), filter: None };
+        let aggregates = self.aggregate(query, report::DAY).await?;
+        Ok(aggregates.first().map(Day::from).unwrap_or_default())
+    }
+
+    /// Heatmap of statuses within a bounding box, looked up in the spatial
+    /// index. Archived statuses aren't indexed and thus aren't included.
+    async fn heatmap(&self, query: HeatmapQuery) -> Result<Heatmap> {
+        let HeatmapQuery { tenant_id, bbox, precision, timestamps } = query;
+        if !(1..=geohash::MAX_PRECISION).contains(&precision) {
+            return Err(StorageError::InvalidCellPrecision { precision });
+        }
+        // There are at most 32 cells of length 1.
+        let scan = (1..=precision)
+            .rev()
+            .find_map(|p| geohash::covering(&bbox, p, heatmap::MAX_SCAN_CELLS))
+            .unwrap_or_default();
+        let mut statuses = Vec::new();
+        for cell in scan {
+            let found = self.engine.get_cell_statuses(tenant_id, &cell).await?;
+            statuses.extend(found.into_iter().filter(|s| {
+                timestamps.contains(&s.timestamp) && s.position.is_some_and(|p| contains(&bbox, p))
+            }));
+        }
+        Ok(Heatmap::new(precision, statuses))
+    }
+
+    /// Version of the latest status of a source, served from the cache if
+    /// possible.
+    async fn latest_version(
+        &self,
+        tenant_id: TenantId,
+        source_id: SourceId,
+    ) -> Result<Option<LatestVersion>> {
+        #[cfg(feature = "redis")]
+        if let Some(statuses) = match &self.cache {
+            Some(cache) => cache.latest_many(tenant_id, Some(&[source_id])).await,
+            None => None,
+        } {
+            return Ok(statuses.first().map(LatestVersion::of));
+        }
+        self.engine.latest_version(tenant_id, source_id).await
+    }
+
+    /// Latest statuses, served from the cache if possible.
+    async fn latest_many(
+        &self,
+        tenant_id: TenantId,
+        source_ids: Option<&[SourceId]>,
+    ) -> Result<Vec<Status>> {
+        #[cfg(feature = "redis")]
+        if let Some(statuses) = match &self.cache {
+            Some(cache) => cache.latest_many(tenant_id, source_ids).await,
+            None => None,
+        } {
+            return Ok(statuses);
+        }
+        self.engine.latest_many(Some(tenant_id), source_ids).await
+    }
+}
+
+/// Position of a status in the order of [`Storage::get_all_statuses`].
+fn time_key(status: &Status) -> TimeKey {
+    (status.timestamp, status.source_id)
+}
+
+/// Checks whether a status is positioned within `cell`. Statuses always match
+/// if `cell` is `None`.
+fn within_cell(status: &Status, cell: Option<&str>) -> bool {
+    match (cell, status.position) {
+        (Some(cell), Some(p)) => geohash::encode(p, cell.len()) == cell,
+        (Some(_), None) => false,
+        (None, _) => true,
+    }
+}
+
+/// Checks whether a point lies within a bounding box, borders included.
+fn contains(bbox: &Rect<f64>, point: geo_types::Coord<f64>) -> bool {
+    let (min, max) = (bbox.min(), bbox.max());
+    (min.x..=max.x).contains(&point.x) && (min.y..=max.y).contains(&point.y)
+}
+
+/// Periodically send [`StorageCommand::Maintain`] to the storage actor, which
+/// also repopulates a stale [`redis::LatestCache`].
+pub fn spawn_maintenance(handler: StorageHandler, interval: Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            match handler.command(StorageCommand::Maintain).await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => tracing::error!(%err, "Failed to maintain storage"),
+                Err(err) => {
+                    tracing::info!(%err, "Storage is shut down, stopping maintenance");
+                    break;
+                }
+            }
+        }
+    });
 }
 
 /// Initialize an instance of a storage engine based on the provided
 /// [`StorageConfig`] and return it.
 #[tracing::instrument]
-pub fn init(cfg: &StorageConfig, dupe_strategy: DupeStrategy) -> Result<StorageEngine> {
+pub fn init(
+    cfg: &StorageConfig,
+    dupe_strategy: Setting<DupeStrategy>,
+    cell_index: Option<CellIndex>,
+) -> Result<StorageEngine> {
     match cfg {
-        StorageConfig::InMemory => Ok(StorageEngine::InMemory(MemoryStorage::new(dupe_strategy))),
+        StorageConfig::InMemory { config } => {
+            Ok(StorageEngine::InMemory(MemoryStorage::new(config, dupe_strategy, cell_index)))
+        }
         #[cfg(feature = "sled")]
         StorageConfig::Sled { config } => {
-            sled::SledStorage::new(config, dupe_strategy).map(StorageEngine::Sled)
+            sled::SledStorage::new(config, dupe_strategy, cell_index).map(StorageEngine::Sled)
+        }
+        #[cfg(feature = "redis")]
+        StorageConfig::Redis { config } => {
+            if cell_index.is_some() {
+                tracing::warn!("Spatial cell index is not supported by Redis storage");
+            }
+            Ok(StorageEngine::Redis(redis::RedisStorage::new(config, dupe_strategy)))
         }
     }
 }
 
-pub type StorageHandler = Address<StorageCommand, StorageQuery>;
+pub use self::{memory::MemoryConfig, shard::StorageHandler};
 
 pub enum StorageCommand {
     PersistStatus(Status),
+    /// Persist several statuses in order, stopping at the first failure.
+    /// Statuses persisted before that are kept.
+    PersistStatuses(Vec<Status>),
+    /// Persist several statuses in order, all or nothing, see
+    /// [`Storage::persist_batch_atomic`]. Engines without transactions, i.e.
+    /// Redis, persist them like [`StorageCommand::PersistStatuses`] instead.
+    PersistBatch(Vec<Status>),
+    /// Same as [`StorageCommand::PersistBatch`], for a batch spanning several
+    /// shards. Waits until all other shards involved are held back by a
+    /// [`StorageCommand::Barrier`], and releases them once it's done. Sent by
+    /// [`StorageHandler`] only, and waited for by [`shard::synchronize`].
+    PersistBatchAcross(Vec<Status>, shard::Gate),
+    /// Hold a shard back while another one persists a batch spanning both.
+    /// Sent by [`StorageHandler`] only, and waited for by
+    /// [`shard::synchronize`].
+    Barrier(shard::Barrier),
+    /// Move statuses past the retention period into the archive.
+    RollArchive,
+    /// Report storage size and enforce its size limits.
+    Maintain,
+    /// Reclaim disk space left behind by removed data.
+    Compact,
 }
 
 impl Request for StorageCommand {
@@ -175,15 +1048,188 @@ impl Request for StorageCommand {
 
 pub enum StorageQuery {
     GetStatuses(GetStatuses),
+    /// Same as [`StorageQuery::GetStatuses`], but as a [`StatusStream`].
+    StreamStatuses(GetStatuses),
+    GetAllStatuses(GetAllStatuses),
+    Aggregate(AggregateStatuses),
+    DistanceReport(DistanceReport),
+    /// Latest [`Status`] of a single source.
+    Latest(TenantId, SourceId),
+    /// [`LatestVersion`] of a single source.
+    LatestVersion(TenantId, SourceId),
+    LatestMany(LatestMany),
+    GetCellStatuses(GetCellStatuses),
+    Heatmap(HeatmapQuery),
+    /// Statistics of a single tenant, or of all of them if `None`.
+    Stats(Option<TenantId>),
 }
 
 impl Request for StorageQuery {
-    //TODO
-    type Result = Result<()>;
+    type Result = Result<QueryResult>;
+}
+
+/// Data returned in response to a [`StorageQuery`].
+pub enum QueryResult {
+    /// Response to [`StorageQuery::GetStatuses`],
+    /// [`StorageQuery::GetAllStatuses`], [`StorageQuery::LatestMany`] and
+    /// [`StorageQuery::GetCellStatuses`].
+    Statuses(Vec<Status>),
+    /// Response to [`StorageQuery::StreamStatuses`].
+    Stream(StatusStream),
+    /// Response to [`StorageQuery::Aggregate`].
+    Aggregates(Vec<Aggregate>),
+    /// Response to [`StorageQuery::DistanceReport`].
+    Distances(Vec<DailyDistance>),
+    /// Response to [`StorageQuery::Heatmap`].
+    Heatmap(Heatmap),
+    /// Response to [`StorageQuery::Latest`].
+    Latest(Option<Status>),
+    /// Response to [`StorageQuery::LatestVersion`].
+    Version(Option<LatestVersion>),
+    /// Response to [`StorageQuery::Stats`].
+    Stats(StorageStats),
+}
+
+impl QueryResult {
+    pub fn into_statuses(self) -> Option<Vec<Status>> {
+        match self {
+            Self::Statuses(statuses) => Some(statuses),
+            _ => None,
+        }
+    }
+
+    pub fn into_stream(self) -> Option<StatusStream> {
+        match self {
+            Self::Stream(statuses) => Some(statuses),
+            _ => None,
+        }
+    }
+
+    pub fn into_aggregates(self) -> Option<Vec<Aggregate>> {
+        match self {
+            Self::Aggregates(aggregates) => Some(aggregates),
+            _ => None,
+        }
+    }
+
+    pub fn into_distances(self) -> Option<Vec<DailyDistance>> {
+        match self {
+            Self::Distances(distances) => Some(distances),
+            _ => None,
+        }
+    }
+
+    pub fn into_heatmap(self) -> Option<Heatmap> {
+        match self {
+            Self::Heatmap(heatmap) => Some(heatmap),
+            _ => None,
+        }
+    }
+
+    pub fn into_latest(self) -> Option<Option<Status>> {
+        match self {
+            Self::Latest(status) => Some(status),
+            _ => None,
+        }
+    }
+
+    pub fn into_version(self) -> Option<Option<LatestVersion>> {
+        match self {
+            Self::Version(version) => Some(version),
+            _ => None,
+        }
+    }
+
+    pub fn into_stats(self) -> Option<StorageStats> {
+        match self {
+            Self::Stats(stats) => Some(stats),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct GetStatuses {
-    source_id: SourceId,
-    timestamps: (Bound<OffsetDateTime>, Bound<OffsetDateTime>),
+    pub tenant_id: TenantId,
+    pub source_id: SourceId,
+    pub timestamps: (Bound<OffsetDateTime>, Bound<OffsetDateTime>),
+    /// Only return statuses matching this filter.
+    pub filter: Option<Filter>,
+}
+
+/// Statuses of all sources of a tenant within a time range, in the order of
+/// [`Storage::get_all_statuses`]. Archived statuses aren't included.
+#[derive(Debug, Clone)]
+pub struct GetAllStatuses {
+    pub tenant_id: TenantId,
+    pub timestamps: (Bound<OffsetDateTime>, Bound<OffsetDateTime>),
+    /// Last status of the previous page, if any.
+    pub after: Option<TimeKey>,
+    pub limit: usize,
+}
+
+/// Summaries of statuses over consecutive buckets of a given length.
+#[derive(Debug, Clone)]
+pub struct AggregateStatuses {
+    pub query: GetStatuses,
+    pub bucket: Duration,
+}
+
+/// Distance travelled by every source of a tenant per UTC day, for days from
+/// `from` to `to` inclusive. Days without statuses are left out.
+#[derive(Debug, Clone)]
+pub struct DistanceReport {
+    pub tenant_id: TenantId,
+    pub from: Date,
+    pub to: Date,
+}
+
+/// Latest [`Status`] for a set of sources, optionally restricted to those
+/// whose last known position lies within a bounding box.
+#[derive(Debug, Clone)]
+pub struct LatestMany {
+    pub tenant_id: TenantId,
+    /// Sources to look up. `None` selects all known sources of the tenant.
+    pub source_ids: Option<Vec<SourceId>>,
+    pub bbox: Option<Rect<f64>>,
+    /// Only return latest statuses matching this filter.
+    pub filter: Option<Filter>,
+}
+
+/// All statuses positioned within a geohash cell. Cells shorter than the
+/// index precision match all of their sub-cells.
+#[derive(Debug, Clone)]
+pub struct GetCellStatuses {
+    pub tenant_id: TenantId,
+    pub cell: String,
+}
+
+/// Point counts and dwell times within a bounding box over geohash cells of
+/// length `precision`.
+#[derive(Debug, Clone)]
+pub struct HeatmapQuery {
+    pub tenant_id: TenantId,
+    pub bbox: Rect<f64>,
+    pub precision: usize,
+    pub timestamps: (Bound<OffsetDateTime>, Bound<OffsetDateTime>),
+}
+
+/// Status of a source numbered `source`, with nothing but its timestamp set.
+/// Shared by the tests of the storage engines.
+#[cfg(test)]
+pub(crate) fn test_status(source: u8, timestamp: OffsetDateTime) -> Status {
+    Status {
+        source_id: SourceId::from_uuid(uuid::Uuid::from_u128(source.into())),
+        timestamp,
+        position: None,
+        bearing: None,
+        speed: None,
+        satellites: None,
+        fix: None,
+        odometer: None,
+        ignition: None,
+        received_at: None,
+        suspect_timestamp: false,
+        tenant_id: TenantId::DEFAULT,
+    }
 }
@@ -0,0 +1,310 @@
+//! Summaries of the history of a source over consecutive time buckets, e.g.
+//! to draw a speed chart without downloading every single status.
+
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use geo_types::Coord;
+use serde::Serialize;
+use shared::data::timestamp;
+use time::OffsetDateTime;
+use uom::si::{length::meter, velocity::meter_per_second};
+
+use crate::{
+    storage::{self, StatusStream, StorageError},
+    util::geodesy,
+};
+
+/// Summary of the statuses within a single bucket.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Aggregate {
+    /// Start of the bucket. Buckets are aligned to the UNIX epoch.
+    #[serde(with = "timestamp")]
+    pub start: OffsetDateTime,
+    /// Number of statuses in the bucket.
+    pub count: usize,
+    /// Average reported speed in m/s. `None` if no status reported one.
+    pub avg_speed: Option<f64>,
+    /// Highest reported speed in m/s.
+    pub max_speed: Option<f64>,
+    /// Distance in meters between consecutive positions, as measured by an
+    /// [`Odometer`]. Each leg counts towards the bucket it ends in.
+    pub distance: f64,
+    /// Distance in meters between consecutive odometer readings, counted the
+    /// same way as `distance`. Readings going backwards, e.g. after the device
+    /// has been replaced, count as zero. `None` if no status reported one.
+    pub odometer_distance: Option<f64>,
+    /// Seconds the ignition was on, assuming the state last reported lasted
+    /// until the next status. Each interval counts towards the bucket it ends
+    /// in. `None` if the state wasn't known within the bucket.
+    pub ignition_time: Option<f64>,
+}
+
+/// Fastest plausible movement between two fixes in m/s, about 360 km/h. Legs
+/// implying faster movement are caused by positioning errors.
+const MAX_SPEED: f64 = 100.;
+/// Number of consecutive implausible fixes after which the last accepted one
+/// is assumed to have been the outlier.
+const MAX_REJECTED: usize = 3;
+
+/// Sums up distances between consecutive positions, rejecting outliers: fixes
+/// that would imply moving faster than [`MAX_SPEED`] since the last accepted
+/// one.
+#[derive(Debug, Default)]
+pub struct Odometer {
+    last: Option<(Coord<f64>, OffsetDateTime)>,
+    rejected: usize,
+}
+
+impl Odometer {
+    /// Distance travelled since the previous accepted fix, or zero if this one
+    /// is rejected or the first.
+    pub fn advance(&mut self, position: Coord<f64>, timestamp: OffsetDateTime) -> f64 {
+        let Some((last, last_timestamp)) = self.last else {
+            self.last = Some((position, timestamp));
+            return 0.;
+        };
+        let distance = geodesy::distance(last, position);
+        let elapsed = (timestamp - last_timestamp).as_seconds_f64().abs();
+        if distance > MAX_SPEED * elapsed {
+            self.rejected += 1;
+            if self.rejected >= MAX_REJECTED {
+                // Start over from here.
+                self.last = Some((position, timestamp));
+                self.rejected = 0;
+            }
+            return 0.;
+        }
+        self.last = Some((position, timestamp));
+        self.rejected = 0;
+        distance
+    }
+}
+
+/// Running totals of a bucket.
+struct Bucket {
+    index: i128,
+    count: usize,
+    speeds: usize,
+    speed_sum: f64,
+    max_speed: Option<f64>,
+    distance: f64,
+    odometer_distance: Option<f64>,
+    ignition_time: Option<f64>,
+}
+
+impl Bucket {
+    fn new(index: i128) -> Self {
+        Self {
+            index,
+            count: 0,
+            speeds: 0,
+            speed_sum: 0.,
+            max_speed: None,
+            distance: 0.,
+            odometer_distance: None,
+            ignition_time: None,
+        }
+    }
+
+    fn finish(self, length: i128) -> storage::Result<Aggregate> {
+        let start = OffsetDateTime::from_unix_timestamp_nanos(self.index * length)
+            .map_err(|_| StorageError::InvalidBucket)?;
+        Ok(Aggregate {
+            start,
+            count: self.count,
+            avg_speed: (self.speeds > 0).then(|| self.speed_sum / self.speeds as f64),
+            max_speed: self.max_speed,
+            distance: self.distance,
+            odometer_distance: self.odometer_distance,
+            ignition_time: self.ignition_time,
+        })
+    }
+}
+
+/// Adds `value` to an optional total, which is then known.
+fn add(total: &mut Option<f64>, value: f64) {
+    *total = Some(total.unwrap_or_default() + value);
+}
+
+/// Summarizes `statuses`, ordered by timestamp, over buckets of the given
+/// length. Buckets without any statuses are left out.
+pub async fn aggregate(
+    mut statuses: StatusStream,
+    bucket: Duration,
+) -> storage::Result<Vec<Aggregate>> {
+    let length = i128::try_from(bucket.as_nanos()).map_err(|_| StorageError::InvalidBucket)?;
+    if length == 0 {
+        return Err(StorageError::InvalidBucket);
+    }
+
+    let mut aggregates = Vec::new();
+    let mut current: Option<Bucket> = None;
+    let mut odometer = Odometer::default();
+    let mut last_reading: Option<f64> = None;
+    // Last known ignition state, and since when it's been accounted for.
+    let mut ignition: Option<(bool, OffsetDateTime)> = None;
+    while let Some(status) = statuses.next().await {
+        let status = status?;
+        let index = status.timestamp.unix_timestamp_nanos().div_euclid(length);
+        let bucket = match current.take() {
+            Some(bucket) if bucket.index == index => bucket,
+            Some(bucket) => {
+                aggregates.push(bucket.finish(length)?);
+                Bucket::new(index)
+            }
+            None => Bucket::new(index),
+        };
+        let bucket = current.insert(bucket);
+
+        bucket.count += 1;
+        if let Some(speed) = status.speed.map(|v| v.get::<meter_per_second>()) {
+            bucket.speeds += 1;
+            bucket.speed_sum += speed;
+            bucket.max_speed = Some(bucket.max_speed.map_or(speed, |max| max.max(speed)));
+        }
+        if let Some(position) = status.position {
+            bucket.distance += odometer.advance(position, status.timestamp);
+        }
+        if let Some(reading) = status.odometer.map(|d| d.get::<meter>()) {
+            let leg = last_reading.map_or(0., |last| (reading - last).max(0.));
+            add(&mut bucket.odometer_distance, leg);
+            last_reading = Some(reading);
+        }
+        if let Some((on, since)) = ignition {
+            let elapsed = (status.timestamp - since).as_seconds_f64();
+            add(&mut bucket.ignition_time, if on { elapsed } else { 0. });
+        }
+        if let Some(on) = status.ignition.or(ignition.map(|(on, _)| on)) {
+            add(&mut bucket.ignition_time, 0.);
+            ignition = Some((on, status.timestamp));
+        }
+    }
+    if let Some(bucket) = current {
+        aggregates.push(bucket.finish(length)?);
+    }
+    Ok(aggregates)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use float_eq::assert_float_eq;
+    use futures_util::{stream, StreamExt};
+    use geo_types::Coord;
+    use shared::data::{SourceId, Status, TenantId};
+    use time::macros::datetime;
+    use uom::si::{
+        f64::{Length, Velocity},
+        length::meter,
+        velocity::meter_per_second,
+    };
+
+    use super::{aggregate, Odometer};
+
+    #[tokio::test]
+    async fn buckets() {
+        let source_id: SourceId =
+            serde_json::from_str("\"0aaec05a-0e7d-4fd5-abc0-0ba69e3cfe11\"").unwrap();
+        let status = |minutes: i64, speed: Option<f64>, lat: Option<f64>| Status {
+            source_id,
+            timestamp: datetime!(2021-07-27 05:00 UTC) + time::Duration::minutes(minutes),
+            position: lat.map(|y| Coord { x: 0., y }),
+            bearing: None,
+            speed: speed.map(Velocity::new::<meter_per_second>),
+            satellites: None,
+            fix: None,
+            odometer: None,
+            ignition: None,
+            received_at: None,
+            suspect_timestamp: false,
+            tenant_id: TenantId::DEFAULT,
+        };
+        let statuses = [
+            status(10, Some(2.), Some(0.)),
+            status(20, Some(4.), Some(0.01)),
+            status(30, None, None),
+            // Nothing in the next hour.
+            status(130, Some(1.), Some(0.02)),
+        ];
+
+        let statuses = stream::iter(statuses.map(Ok)).boxed();
+        let aggregates = aggregate(statuses, Duration::from_secs(3600)).await.unwrap();
+        assert_eq!(aggregates.len(), 2);
+        let (first, second) = (aggregates[0], aggregates[1]);
+        assert_eq!(first.start, datetime!(2021-07-27 05:00 UTC));
+        assert_eq!((first.count, first.avg_speed, first.max_speed), (3, Some(3.), Some(4.)));
+        assert_float_eq!(first.distance, 1_112., abs <= 1.);
+        assert_eq!(second.start, datetime!(2021-07-27 07:00 UTC));
+        assert_eq!((second.count, second.avg_speed), (1, Some(1.)));
+        assert_float_eq!(second.distance, 1_112., abs <= 1.);
+
+        let empty = stream::iter([]).boxed();
+        assert!(aggregate(empty, Duration::ZERO).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn odometer_and_ignition() {
+        let source_id: SourceId =
+            serde_json::from_str("\"0aaec05a-0e7d-4fd5-abc0-0ba69e3cfe11\"").unwrap();
+        let status = |minutes: i64, odometer: Option<f64>, ignition: Option<bool>| Status {
+            source_id,
+            timestamp: datetime!(2021-07-27 05:00 UTC) + time::Duration::minutes(minutes),
+            position: None,
+            bearing: None,
+            speed: None,
+            satellites: None,
+            fix: None,
+            odometer: odometer.map(Length::new::<meter>),
+            ignition,
+            received_at: None,
+            suspect_timestamp: false,
+            tenant_id: TenantId::DEFAULT,
+        };
+        let statuses = [
+            status(0, None, None),
+            status(10, Some(1_000.), Some(true)),
+            status(20, Some(1_500.), None),
+            status(30, Some(2_000.), Some(false)),
+            // Carries over into the next hour: 15 minutes on, 500 m.
+            status(45, None, Some(true)),
+            status(60, Some(2_500.), None),
+            // Odometer replaced.
+            status(70, Some(10.), Some(false)),
+        ];
+
+        let statuses = stream::iter(statuses.map(Ok)).boxed();
+        let aggregates = aggregate(statuses, Duration::from_secs(3600)).await.unwrap();
+        let (first, second) = (aggregates[0], aggregates[1]);
+        assert_eq!(first.odometer_distance, Some(1_000.));
+        assert_eq!(first.ignition_time, Some(1_200.));
+        assert_eq!(second.odometer_distance, Some(500.));
+        assert_eq!(second.ignition_time, Some(1_500.));
+
+        let statuses = stream::iter([Ok(status(0, None, None))]).boxed();
+        let aggregates = aggregate(statuses, Duration::from_secs(3600)).await.unwrap();
+        assert_eq!((aggregates[0].odometer_distance, aggregates[0].ignition_time), (None, None));
+    }
+
+    #[test]
+    fn odometer() {
+        let at = |secs| datetime!(2021-07-27 05:00 UTC) + time::Duration::seconds(secs);
+        let north = |lat| Coord { x: 0., y: lat };
+        let mut odometer = Odometer::default();
+        assert_eq!(odometer.advance(north(0.), at(0)), 0.);
+        // About 111 m in 10 s.
+        assert_float_eq!(odometer.advance(north(0.001), at(10)), 111.2, abs <= 0.1);
+        // A jump of 111 km is rejected, the next leg starts from the last accepted fix.
+        assert_eq!(odometer.advance(north(1.), at(20)), 0.);
+        assert_float_eq!(odometer.advance(north(0.002), at(30)), 111.2, abs <= 0.1);
+
+        // Fixes consistently far from the last accepted one replace it.
+        for secs in [40, 41] {
+            assert_eq!(odometer.advance(north(2.), at(secs)), 0.);
+        }
+        assert_eq!(odometer.advance(north(2.), at(42)), 0.);
+        assert_float_eq!(odometer.advance(north(2.001), at(52)), 111.2, abs <= 0.1);
+    }
+}
@@ -0,0 +1,693 @@
+//! Archival of old [`Status`] packets into Parquet files on local disk.
+//!
+//! Statuses older than the configured retention period are periodically moved
+//! out of the primary storage engine into files partitioned by source and UTC
+//! day, laid out as `<archive_dir>/<source_id>/<YYYY-MM-DD>.parquet`. Sources
+//! of tenants other than the default one are kept apart in
+//! `<archive_dir>/tenants/<tenant_id>/<source_id>/`. History queries
+//! transparently merge archived and live data.
+
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet},
+    fmt::Debug,
+    fs::{self, File},
+    ops::RangeBounds,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use futures_util::{stream, StreamExt};
+use geo_types::Coord;
+use parquet::{
+    basic::{Compression, Encoding},
+    data_type::{
+        BoolType, ByteArray, ByteArrayType, DoubleType, FixedLenByteArray, FixedLenByteArrayType,
+        Int32Type, Int64Type,
+    },
+    file::{
+        properties::WriterProperties,
+        reader::{ChunkReader, FileReader, SerializedFileReader},
+        writer::SerializedFileWriter,
+    },
+    record::Field,
+    schema::{
+        parser::parse_message_type,
+        types::{ColumnPath, Type},
+    },
+};
+use shared::data::{FixType, SourceId, Status, TenantId};
+use time::{Date, Month, OffsetDateTime};
+use tracing::{error, info};
+use uom::si::{
+    angle::radian,
+    f64::{Angle, Length, Velocity},
+    length::meter,
+    velocity::meter_per_second,
+};
+
+#[cfg(feature = "s3")]
+use crate::storage::export::S3Sink;
+use crate::storage::{
+    self, codec, StatusStream, Storage, StorageCommand, StorageError, StorageHandler,
+};
+
+const SCHEMA: &str = "
+message status {
+    required fixed_len_byte_array(16) source_id (UUID);
+    required int64 timestamp;
+    optional int32 lon_e7;
+    optional int32 lat_e7;
+    optional double bearing;
+    optional double speed;
+    optional int64 received_at;
+    optional boolean suspect_timestamp;
+    optional int32 timestamp_nanos;
+    optional int32 satellites;
+    optional binary fix (UTF8);
+    optional double odometer;
+    optional boolean ignition;
+}
+";
+
+/// Subdirectory holding sources of tenants other than the default one.
+const TENANTS_DIR: &str = "tenants";
+
+/// Columns of consecutive values that usually differ by little, written with
+/// delta encoding. Coordinates are scaled to integers for the purpose.
+const DELTA_COLUMNS: [&str; 5] =
+    ["timestamp", "timestamp_nanos", "lon_e7", "lat_e7", "received_at"];
+
+#[derive(Debug, Clone)]
+pub struct ArchiveConfig {
+    /// Directory to store Parquet files in.
+    pub dir: PathBuf,
+    /// Statuses older than this are moved into the archive. Always rounded up
+    /// to whole UTC days.
+    pub after: Duration,
+}
+
+pub struct Archive {
+    cfg: ArchiveConfig,
+    schema: Arc<Type>,
+    #[cfg(feature = "s3")]
+    sink: Option<S3Sink>,
+}
+
+impl Archive {
+    pub fn open(cfg: ArchiveConfig) -> storage::Result<Self> {
+        fs::create_dir_all(&cfg.dir)?;
+        let schema = Arc::new(parse_message_type(SCHEMA)?);
+        Ok(Self {
+            cfg,
+            schema,
+            #[cfg(feature = "s3")]
+            sink: None,
+        })
+    }
+
+    /// Upload archive files into an S3-compatible bucket, and fetch them from
+    /// there when they're no longer available locally.
+    #[cfg(feature = "s3")]
+    pub fn with_sink(mut self, sink: S3Sink) -> Self {
+        self.sink = Some(sink);
+        self
+    }
+
+    /// Move statuses older than the retention period from `storage` into the
+    /// archive. Returns the number of archived statuses.
+    #[tracing::instrument(skip(self, storage))]
+    pub async fn roll<S: Storage + Send + Sync>(&self, storage: &S) -> storage::Result<usize> {
+        let cutoff = (OffsetDateTime::now_utc() - self.cfg.after).date();
+        let cutoff = cutoff.midnight().assume_utc();
+
+        let mut archived = 0;
+        let mut written = BTreeSet::new();
+        for source in storage.latest_many(None, None).await? {
+            let (tenant_id, source_id) = (source.tenant_id, source.source_id);
+            let statuses = storage.get_statuses(tenant_id, source_id, ..cutoff).await?;
+            if statuses.is_empty() {
+                continue;
+            }
+
+            let mut days: BTreeMap<Date, Vec<Status>> = BTreeMap::new();
+            for status in statuses {
+                days.entry(utc_date(status.timestamp)).or_default().push(status);
+            }
+            for (day, statuses) in days {
+                written.insert(self.write_partition(tenant_id, source_id, day, statuses)?);
+            }
+
+            archived += storage.remove_statuses(tenant_id, source_id, ..cutoff).await?;
+        }
+
+        #[cfg(feature = "s3")]
+        if let Some(sink) = &self.sink {
+            self.sync(sink, &written).await?;
+        }
+
+        Ok(archived)
+    }
+
+    /// Upload partitions that were just written or are missing from the
+    /// bucket, then apply the configured lifecycle rules.
+    #[cfg(feature = "s3")]
+    #[tracing::instrument(skip_all)]
+    async fn sync(&self, sink: &S3Sink, written: &BTreeSet<PathBuf>) -> storage::Result<()> {
+        let mut remote: BTreeSet<String> = sink.list(&sink.key("")).await?.into_iter().collect();
+        let today = OffsetDateTime::now_utc().date();
+        let expired = |after: Option<Duration>, day: Date| {
+            after.is_some_and(|after| day < (OffsetDateTime::now_utc() - after).date())
+        };
+
+        for (source, source_dir) in self.source_dirs()? {
+            for entry in fs::read_dir(&source_dir)? {
+                let path = entry?.path();
+                let (Some(day), Some(file)) =
+                    (partition_date(&path), path.file_name().and_then(|n| n.to_str()))
+                else {
+                    continue;
+                };
+
+                let key = sink.key(&format!("{source}/{file}"));
+                if written.contains(&path) || !remote.contains(&key) {
+                    sink.put(&key, fs::read(&path)?.into()).await?;
+                    remote.insert(key);
+                }
+                if day < today && expired(sink.config().keep_local, day) {
+                    fs::remove_file(&path)?;
+                }
+            }
+        }
+
+        for key in &remote {
+            let expire = key.rsplit('/').next().and_then(|file| partition_date(Path::new(file)));
+            if expire.is_some_and(|day| expired(sink.config().expire_after, day)) {
+                sink.delete(key).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Combine archived statuses of a given source with `live` ones fetched
+    /// for the same time range from the primary storage. Live statuses take
+    /// precedence over archived ones with the same timestamp.
+    pub async fn merge<R>(
+        &self,
+        tenant_id: TenantId,
+        source_id: SourceId,
+        timestamps: &R,
+        live: Vec<Status>,
+    ) -> storage::Result<Vec<Status>>
+    where
+        R: RangeBounds<OffsetDateTime> + Debug,
+    {
+        let mut archived = Vec::new();
+        for partition in self.partitions(tenant_id, source_id, timestamps).await? {
+            archived.extend(self.load(&partition, tenant_id, source_id).await?);
+        }
+
+        let mut merged: BTreeMap<_, _> = archived
+            .into_iter()
+            .filter(|s| timestamps.contains(&s.timestamp))
+            .map(|s| (s.timestamp, s))
+            .collect();
+        merged.extend(live.into_iter().map(|s| (s.timestamp, s)));
+
+        Ok(merged.into_values().collect())
+    }
+
+    /// Archived statuses of a given source in a given time range, ordered by
+    /// timestamp. Only a single partition is loaded into memory at a time.
+    pub async fn stream<R>(
+        self: &Arc<Self>,
+        tenant_id: TenantId,
+        source_id: SourceId,
+        timestamps: R,
+    ) -> storage::Result<StatusStream>
+    where
+        R: RangeBounds<OffsetDateTime> + Send + Sync + Debug + 'static,
+    {
+        let partitions = self.partitions(tenant_id, source_id, &timestamps).await?;
+        let archive = self.clone();
+        let timestamps = Arc::new(timestamps);
+        let statuses = stream::iter(partitions).then(move |partition| {
+            let (archive, timestamps) = (archive.clone(), timestamps.clone());
+            async move {
+                let mut statuses = archive.load(&partition, tenant_id, source_id).await?;
+                statuses.retain(|s| timestamps.contains(&s.timestamp));
+                statuses.sort_by_key(|s| s.timestamp);
+                Ok(statuses)
+            }
+        });
+        let statuses = statuses.flat_map(|partition| match partition {
+            Ok(statuses) => stream::iter(statuses.into_iter().map(Ok).collect::<Vec<_>>()),
+            Err(err) => stream::iter(vec![Err(err)]),
+        });
+        Ok(statuses.boxed())
+    }
+
+    /// Partitions of a given source overlapping a given time range, ordered
+    /// by day. Local partitions take precedence over uploaded ones of the same
+    /// day.
+    async fn partitions<R>(
+        &self,
+        tenant_id: TenantId,
+        source_id: SourceId,
+        timestamps: &R,
+    ) -> storage::Result<Vec<Partition>>
+    where
+        R: RangeBounds<OffsetDateTime>,
+    {
+        let mut partitions = BTreeMap::new();
+
+        let dir = self.cfg.dir.join(relative_dir(tenant_id, source_id));
+        if dir.is_dir() {
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                if let Some(day) = partition_date(&path) {
+                    partitions.insert(day, Partition::Local(path));
+                }
+            }
+        }
+
+        #[cfg(feature = "s3")]
+        if let Some(sink) = &self.sink {
+            let prefix = sink.key(&format!("{}/", relative_dir(tenant_id, source_id)));
+            for key in sink.list(&prefix).await? {
+                if let Some(day) = partition_date(Path::new(&key)) {
+                    partitions.entry(day).or_insert(Partition::Remote(key));
+                }
+            }
+        }
+
+        partitions.retain(|&day, _| overlaps(timestamps, day));
+        Ok(partitions.into_values().collect())
+    }
+
+    /// Read all statuses of a partition.
+    async fn load(
+        &self,
+        partition: &Partition,
+        tenant_id: TenantId,
+        source_id: SourceId,
+    ) -> storage::Result<Vec<Status>> {
+        match partition {
+            Partition::Local(path) => read_partition(File::open(path)?, tenant_id, source_id, path),
+            #[cfg(feature = "s3")]
+            Partition::Remote(key) => {
+                let sink = self.sink.as_ref().ok_or(StorageError::ArchiveDisabled)?;
+                match sink.get(key).await? {
+                    Some(bytes) => read_partition(bytes, tenant_id, source_id, Path::new(key)),
+                    None => Ok(Vec::new()),
+                }
+            }
+        }
+    }
+
+    /// Directories of all archived sources, along with their paths relative
+    /// to the archive directory.
+    #[cfg(feature = "s3")]
+    fn source_dirs(&self) -> storage::Result<Vec<(String, PathBuf)>> {
+        let subdirs = |dir: &Path| -> storage::Result<Vec<(String, PathBuf)>> {
+            let mut subdirs = Vec::new();
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
+                    if path.is_dir() {
+                        subdirs.push((name.to_owned(), path));
+                    }
+                }
+            }
+            Ok(subdirs)
+        };
+
+        let mut dirs = Vec::new();
+        for (name, path) in subdirs(&self.cfg.dir)? {
+            if name != TENANTS_DIR {
+                dirs.push((name, path));
+                continue;
+            }
+            for (tenant, tenant_dir) in subdirs(&path)? {
+                for (source, source_dir) in subdirs(&tenant_dir)? {
+                    dirs.push((format!("{TENANTS_DIR}/{tenant}/{source}"), source_dir));
+                }
+            }
+        }
+        Ok(dirs)
+    }
+
+    /// Write statuses of a single day into a partition file, merging them with
+    /// the file's contents if it already exists. Returns the file's path.
+    fn write_partition(
+        &self,
+        tenant_id: TenantId,
+        source_id: SourceId,
+        day: Date,
+        statuses: Vec<Status>,
+    ) -> storage::Result<PathBuf> {
+        let dir = self.cfg.dir.join(relative_dir(tenant_id, source_id));
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(format!("{day}.parquet"));
+
+        let mut merged = BTreeMap::new();
+        if path.exists() {
+            let existing = read_partition(File::open(&path)?, tenant_id, source_id, &path)?;
+            merged.extend(existing.into_iter().map(|s| (s.timestamp, s)));
+        }
+        merged.extend(statuses.into_iter().map(|s| (s.timestamp, s)));
+        let statuses: Vec<Status> = merged.into_values().collect();
+
+        // Writing into a temporary file first so that a crash never leaves a
+        // partially written partition behind.
+        let tmp_path = path.with_extension("parquet.tmp");
+        let props = DELTA_COLUMNS
+            .into_iter()
+            .map(ColumnPath::from)
+            .fold(WriterProperties::builder(), |props, col| {
+                props
+                    .set_column_dictionary_enabled(col.clone(), false)
+                    .set_column_encoding(col, Encoding::DELTA_BINARY_PACKED)
+            })
+            .set_compression(Compression::UNCOMPRESSED);
+        let props = Arc::new(props.build());
+        let mut writer =
+            SerializedFileWriter::new(File::create(&tmp_path)?, self.schema.clone(), props)?;
+        let mut row_group = writer.next_row_group()?;
+
+        let source_ids: Vec<FixedLenByteArray> = statuses
+            .iter()
+            .map(|s| FixedLenByteArray::from(s.source_id.to_key().to_vec()))
+            .collect();
+        let timestamps: Vec<i64> = statuses.iter().map(|s| s.timestamp.unix_timestamp()).collect();
+        let coords: [Vec<Option<i32>>; 2] = [
+            statuses.iter().map(|s| s.position.map(|p| codec::scale_coord(p.x))).collect(),
+            statuses.iter().map(|s| s.position.map(|p| codec::scale_coord(p.y))).collect(),
+        ];
+        let optionals: [Vec<Option<f64>>; 2] = [
+            statuses.iter().map(|s| s.bearing.map(|b| b.get::<radian>())).collect(),
+            statuses.iter().map(|s| s.speed.map(|v| v.get::<meter_per_second>())).collect(),
+        ];
+
+        if let Some(mut col) = row_group.next_column()? {
+            col.typed::<FixedLenByteArrayType>().write_batch(&source_ids, None, None)?;
+            col.close()?;
+        }
+        if let Some(mut col) = row_group.next_column()? {
+            col.typed::<Int64Type>().write_batch(&timestamps, None, None)?;
+            col.close()?;
+        }
+        for values in &coords {
+            if let Some(mut col) = row_group.next_column()? {
+                let defs: Vec<i16> = values.iter().map(|v| i16::from(v.is_some())).collect();
+                let values: Vec<i32> = values.iter().flatten().copied().collect();
+                col.typed::<Int32Type>().write_batch(&values, Some(&defs), None)?;
+                col.close()?;
+            }
+        }
+        for values in &optionals {
+            if let Some(mut col) = row_group.next_column()? {
+                let defs: Vec<i16> = values.iter().map(|v| i16::from(v.is_some())).collect();
+                let values: Vec<f64> = values.iter().flatten().copied().collect();
+                col.typed::<DoubleType>().write_batch(&values, Some(&defs), None)?;
+                col.close()?;
+            }
+        }
+        if let Some(mut col) = row_group.next_column()? {
+            let defs: Vec<i16> =
+                statuses.iter().map(|s| i16::from(s.received_at.is_some())).collect();
+            let values: Vec<i64> =
+                statuses.iter().filter_map(|s| Some(s.received_at?.unix_timestamp())).collect();
+            col.typed::<Int64Type>().write_batch(&values, Some(&defs), None)?;
+            col.close()?;
+        }
+        if let Some(mut col) = row_group.next_column()? {
+            let values: Vec<bool> = statuses.iter().map(|s| s.suspect_timestamp).collect();
+            col.typed::<BoolType>().write_batch(&values, Some(&vec![1; values.len()]), None)?;
+            col.close()?;
+        }
+        if let Some(mut col) = row_group.next_column()? {
+            let values: Vec<i32> =
+                statuses.iter().map(|s| s.timestamp.nanosecond() as i32).collect();
+            col.typed::<Int32Type>().write_batch(&values, Some(&vec![1; values.len()]), None)?;
+            col.close()?;
+        }
+        if let Some(mut col) = row_group.next_column()? {
+            let defs: Vec<i16> =
+                statuses.iter().map(|s| i16::from(s.satellites.is_some())).collect();
+            let values: Vec<i32> =
+                statuses.iter().filter_map(|s| Some(s.satellites?.into())).collect();
+            col.typed::<Int32Type>().write_batch(&values, Some(&defs), None)?;
+            col.close()?;
+        }
+        if let Some(mut col) = row_group.next_column()? {
+            let defs: Vec<i16> = statuses.iter().map(|s| i16::from(s.fix.is_some())).collect();
+            let values: Vec<ByteArray> =
+                statuses.iter().filter_map(|s| Some(s.fix?.as_str().into())).collect();
+            col.typed::<ByteArrayType>().write_batch(&values, Some(&defs), None)?;
+            col.close()?;
+        }
+        if let Some(mut col) = row_group.next_column()? {
+            let defs: Vec<i16> = statuses.iter().map(|s| i16::from(s.odometer.is_some())).collect();
+            let values: Vec<f64> =
+                statuses.iter().filter_map(|s| Some(s.odometer?.get::<meter>())).collect();
+            col.typed::<DoubleType>().write_batch(&values, Some(&defs), None)?;
+            col.close()?;
+        }
+        if let Some(mut col) = row_group.next_column()? {
+            let defs: Vec<i16> = statuses.iter().map(|s| i16::from(s.ignition.is_some())).collect();
+            let values: Vec<bool> = statuses.iter().filter_map(|s| s.ignition).collect();
+            col.typed::<BoolType>().write_batch(&values, Some(&defs), None)?;
+            col.close()?;
+        }
+
+        row_group.close()?;
+        writer.close()?;
+        fs::rename(tmp_path, &path)?;
+
+        Ok(path)
+    }
+}
+
+/// Directory of a source relative to the archive directory, also used as the
+/// object key prefix of its partitions.
+/// A single day of archived statuses of a source, stored either locally or in
+/// the bucket of the S3 sink.
+enum Partition {
+    Local(PathBuf),
+    #[cfg(feature = "s3")]
+    Remote(String),
+}
+
+fn relative_dir(tenant_id: TenantId, source_id: SourceId) -> String {
+    match tenant_id.is_default() {
+        true => source_id.to_string(),
+        false => format!("{TENANTS_DIR}/{tenant_id}/{source_id}"),
+    }
+}
+
+/// Read all statuses from a partition. `path` is only used in error messages.
+fn read_partition<R: ChunkReader + 'static>(
+    reader: R,
+    tenant_id: TenantId,
+    source_id: SourceId,
+    path: &Path,
+) -> storage::Result<Vec<Status>> {
+    let reader = SerializedFileReader::new(reader)?;
+    let mut statuses = Vec::new();
+
+    for row in reader.get_row_iter(None)? {
+        let row = row?;
+        let (mut timestamp, mut received_at, mut suspect_timestamp) = (None, None, false);
+        let mut nanos = 0;
+        let (mut lon, mut lat, mut bearing, mut speed) = (None, None, None, None);
+        let (mut satellites, mut fix, mut odometer, mut ignition) = (None, None, None, None);
+        for (name, field) in row.get_column_iter() {
+            match (name.as_str(), field) {
+                ("timestamp", Field::Long(ts)) => timestamp = Some(*ts),
+                ("lon_e7", Field::Int(v)) => lon = Some(codec::unscale_coord(*v)),
+                ("lat_e7", Field::Int(v)) => lat = Some(codec::unscale_coord(*v)),
+                // Written before coordinates were scaled to integers.
+                ("lon", Field::Double(v)) => lon = Some(*v),
+                ("lat", Field::Double(v)) => lat = Some(*v),
+                ("bearing", Field::Double(v)) => bearing = Some(Angle::new::<radian>(*v)),
+                ("speed", Field::Double(v)) => speed = Some(Velocity::new::<meter_per_second>(*v)),
+                // Missing from partitions written before it was recorded.
+                ("received_at", Field::Long(ts)) => {
+                    received_at = OffsetDateTime::from_unix_timestamp(*ts).ok();
+                }
+                ("suspect_timestamp", Field::Bool(v)) => suspect_timestamp = *v,
+                ("timestamp_nanos", Field::Int(v)) => nanos = *v as u32,
+                ("satellites", Field::Int(v)) => satellites = u8::try_from(*v).ok(),
+                ("fix", Field::Str(v)) => fix = v.parse::<FixType>().ok(),
+                ("odometer", Field::Double(v)) => odometer = Some(Length::new::<meter>(*v)),
+                ("ignition", Field::Bool(v)) => ignition = Some(*v),
+                _ => {}
+            }
+        }
+
+        let timestamp = timestamp
+            .and_then(|ts| OffsetDateTime::from_unix_timestamp(ts).ok())
+            .and_then(|ts| ts.replace_nanosecond(nanos).ok())
+            .ok_or_else(|| StorageError::CorruptArchive { path: path.to_owned() })?;
+
+        statuses.push(Status {
+            source_id,
+            timestamp,
+            position: lon.zip(lat).map(|(x, y)| Coord { x, y }),
+            bearing,
+            speed,
+            satellites,
+            fix,
+            odometer,
+            ignition,
+            received_at,
+            suspect_timestamp,
+            tenant_id,
+        });
+    }
+
+    Ok(statuses)
+}
+
+/// Merges two streams of statuses ordered by timestamp, with `live` statuses
+/// taking precedence over `archived` ones with the same timestamp, as in
+/// [`Archive::merge`].
+pub fn merge_streams(archived: StatusStream, live: StatusStream) -> StatusStream {
+    let state = (archived.fuse(), live.fuse(), None, None);
+    let merged = stream::unfold(state, |(mut archived, mut live, mut a, mut l)| async move {
+        if a.is_none() {
+            a = archived.next().await;
+        }
+        if l.is_none() {
+            l = live.next().await;
+        }
+        let next = match (a.take(), l.take()) {
+            (None, None) => return None,
+            (Some(Err(err)), head) => {
+                l = head;
+                Err(err)
+            }
+            (head, Some(Err(err))) => {
+                a = head;
+                Err(err)
+            }
+            (Some(Ok(status)), None) | (None, Some(Ok(status))) => Ok(status),
+            (Some(Ok(old)), Some(Ok(new))) => match old.timestamp.cmp(&new.timestamp) {
+                Ordering::Less => {
+                    l = Some(Ok(new));
+                    Ok(old)
+                }
+                Ordering::Equal => Ok(new),
+                Ordering::Greater => {
+                    a = Some(Ok(old));
+                    Ok(new)
+                }
+            },
+        };
+        Some((next, (archived, live, a, l)))
+    });
+    merged.boxed()
+}
+
+/// Periodically send [`StorageCommand::RollArchive`] to the storage actor.
+pub fn spawn_roller(handler: StorageHandler, interval: Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            match handler.command(StorageCommand::RollArchive).await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => error!(%err, "Failed to archive old statuses"),
+                Err(err) => {
+                    info!(%err, "Storage is shut down, stopping archival");
+                    break;
+                }
+            }
+        }
+    });
+}
+
+fn utc_date(ts: OffsetDateTime) -> Date {
+    ts.to_offset(time::UtcOffset::UTC).date()
+}
+
+/// Parses the date of a partition from its `YYYY-MM-DD.parquet` file name.
+fn partition_date(path: &Path) -> Option<Date> {
+    if path.extension()? != "parquet" {
+        return None;
+    }
+    let mut parts = path.file_stem()?.to_str()?.splitn(3, '-');
+    let year = parts.next()?.parse().ok()?;
+    let month = Month::try_from(parts.next()?.parse::<u8>().ok()?).ok()?;
+    let day = parts.next()?.parse().ok()?;
+    Date::from_calendar_date(year, month, day).ok()
+}
+
+/// Checks whether a time range overlaps with a given UTC day.
+fn overlaps<R: RangeBounds<OffsetDateTime>>(range: &R, day: Date) -> bool {
+    use std::ops::Bound;
+
+    let start = day.midnight().assume_utc();
+    let next = day.next_day().map(|d| d.midnight().assume_utc());
+    let starts_before_end = match (range.start_bound(), next) {
+        (Bound::Included(ts) | Bound::Excluded(ts), Some(next)) => *ts < next,
+        _ => true,
+    };
+    let ends_after_start = match range.end_bound() {
+        Bound::Included(ts) => *ts >= start,
+        Bound::Excluded(ts) => *ts > start,
+        Bound::Unbounded => true,
+    };
+    starts_before_end && ends_after_start
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{stream, StreamExt};
+    use shared::data::{SourceId, Status, TenantId};
+    use time::macros::datetime;
+
+    use super::merge_streams;
+    use crate::storage::{StatusStream, StorageError};
+
+    #[tokio::test]
+    async fn merge_ordered_streams() {
+        let source_id: SourceId =
+            serde_json::from_str("\"0aaec05a-0e7d-4fd5-abc0-0ba69e3cfe11\"").unwrap();
+        let status = |secs, suspect_timestamp| Status {
+            source_id,
+            timestamp: datetime!(2021-07-27 00:00 UTC) + time::Duration::seconds(secs),
+            position: None,
+            bearing: None,
+            speed: None,
+            satellites: None,
+            fix: None,
+            odometer: None,
+            ignition: None,
+            received_at: None,
+            suspect_timestamp,
+            tenant_id: TenantId::DEFAULT,
+        };
+        let stream = |statuses: Vec<Status>| -> StatusStream {
+            stream::iter(statuses.into_iter().map(Ok)).boxed()
+        };
+
+        let archived = stream(vec![status(1, true), status(3, true), status(4, true)]);
+        let live = stream(vec![status(2, false), status(3, false), status(5, false)]);
+        let merged: Vec<_> = merge_streams(archived, live).map(Result::unwrap).collect().await;
+        let summary: Vec<_> = merged
+            .iter()
+            .map(|s| {
+                ((s.timestamp - status(0, false).timestamp).whole_seconds(), s.suspect_timestamp)
+            })
+            .collect();
+        assert_eq!(summary, [(1, true), (2, false), (3, false), (4, true), (5, false)]);
+
+        let failing = stream::iter([Ok(status(1, true)), Err(StorageError::CorruptStatus)]).boxed();
+        let merged: Vec<_> = merge_streams(failing, stream(vec![status(2, false)])).collect().await;
+        assert!(matches!(merged[..], [Ok(_), Err(StorageError::CorruptStatus), Ok(_)]));
+    }
+}
@@ -0,0 +1,343 @@
+//! Compact at-rest encoding of [`Status`] packets, several times smaller than
+//! CBOR.
+//!
+//! Fields are written without names, as varints. Coordinates are scaled to
+//! integers in units of 10⁻⁷ degrees (about 1 cm), bearing to 10⁻⁵ radians and
+//! speed to mm/s. `received_at` is stored as a delta from `timestamp`, which is
+//! usually just a few seconds. The tenant and source are left out, as storage
+//! keys already hold them. Satellite count (`sats`) and fix type take a byte
+//! each, the odometer is stored in whole meters, and ignition state is a flag
+//! of its own. A record is laid out as:
+//!
+//! ```text
+//! +-----+-------+-----------+----------+---------+-------+-------------+------+-----+----------+
+//! | tag | flags | timestamp | position | bearing | speed | received_at | sats | fix | odometer |
+//! | 1   |       | s, ns     | lon, lat |         |       | Δs, ns      | 1    | 1   | m        |
+//! +-----+-------+-----------+----------+---------+-------+-------------+------+-----+----------+
+//! ```
+//!
+//! with optional fields only present if flagged. Flags are a varint as well,
+//! and take a single byte unless odometer or ignition are set. Values written
+//! as CBOR before this encoding was introduced are still decoded transparently.
+
+use geo_types::Coord;
+use shared::{
+    codec::{Cbor, Codec},
+    data::{FixType, SourceId, Status, TenantId},
+};
+use time::OffsetDateTime;
+use uom::si::{
+    angle::radian,
+    f64::{Angle, Length, Velocity},
+    length::meter,
+    velocity::meter_per_second,
+};
+
+use crate::storage::{self, LatestVersion, StorageError};
+
+/// First byte of compact records. CBOR-encoded statuses start with a map
+/// header, `0xa0..=0xbf`, instead.
+const TAG: u8 = 0x01;
+
+const HAS_POSITION: u64 = 1 << 0;
+const HAS_BEARING: u64 = 1 << 1;
+const HAS_SPEED: u64 = 1 << 2;
+const HAS_RECEIVED_AT: u64 = 1 << 3;
+const SUSPECT_TIMESTAMP: u64 = 1 << 4;
+const HAS_SATELLITES: u64 = 1 << 5;
+const HAS_FIX: u64 = 1 << 6;
+const HAS_ODOMETER: u64 = 1 << 7;
+const HAS_IGNITION: u64 = 1 << 8;
+const IGNITION_ON: u64 = 1 << 9;
+
+/// Coordinates are stored in units of 10⁻⁷ degrees.
+pub const COORD_SCALE: f64 = 1e7;
+const BEARING_SCALE: f64 = 1e5;
+const SPEED_SCALE: f64 = 1e3;
+
+/// Scales a coordinate to an integer in units of 10⁻⁷ degrees, which always
+/// fits into `i32` for valid longitudes and latitudes.
+pub fn scale_coord(value: f64) -> i32 {
+    (value * COORD_SCALE).round() as i32
+}
+
+pub fn unscale_coord(value: i32) -> f64 {
+    f64::from(value) / COORD_SCALE
+}
+
+/// Encodes a status into a compact record.
+pub fn encode(status: &Status) -> Vec<u8> {
+    let flags = [
+        (status.position.is_some(), HAS_POSITION),
+        (status.bearing.is_some(), HAS_BEARING),
+        (status.speed.is_some(), HAS_SPEED),
+        (status.received_at.is_some(), HAS_RECEIVED_AT),
+        (status.suspect_timestamp, SUSPECT_TIMESTAMP),
+        (status.satellites.is_some(), HAS_SATELLITES),
+        (status.fix.is_some(), HAS_FIX),
+        (status.odometer.is_some(), HAS_ODOMETER),
+        (status.ignition.is_some(), HAS_IGNITION),
+        (status.ignition == Some(true), IGNITION_ON),
+    ]
+    .into_iter()
+    .filter(|(set, _)| *set)
+    .fold(0, |flags, (_, flag)| flags | flag);
+
+    let mut buf = Vec::with_capacity(32);
+    buf.push(TAG);
+    put_unsigned(&mut buf, flags);
+    put_signed(&mut buf, status.timestamp.unix_timestamp());
+    put_unsigned(&mut buf, status.timestamp.nanosecond().into());
+    if let Some(position) = status.position {
+        put_signed(&mut buf, scale_coord(position.x).into());
+        put_signed(&mut buf, scale_coord(position.y).into());
+    }
+    if let Some(bearing) = status.bearing {
+        put_signed(&mut buf, (bearing.get::<radian>() * BEARING_SCALE).round() as i64);
+    }
+    if let Some(speed) = status.speed {
+        put_signed(&mut buf, (speed.get::<meter_per_second>() * SPEED_SCALE).round() as i64);
+    }
+    if let Some(received_at) = status.received_at {
+        let delta = received_at.unix_timestamp().wrapping_sub(status.timestamp.unix_timestamp());
+        put_signed(&mut buf, delta);
+        put_unsigned(&mut buf, received_at.nanosecond().into());
+    }
+    if let Some(satellites) = status.satellites {
+        buf.push(satellites);
+    }
+    if let Some(fix) = status.fix {
+        // Index into `FixType::ALL`, which only ever grows at the end.
+        buf.push(FixType::ALL.iter().position(|&f| f == fix).unwrap_or_default() as u8);
+    }
+    if let Some(odometer) = status.odometer {
+        // Implausible readings are discarded on ingest.
+        put_unsigned(&mut buf, odometer.get::<meter>().round() as u64);
+    }
+    buf
+}
+
+/// Decodes a stored status of the given tenant and source, either a compact
+/// record or a CBOR-encoded one.
+pub fn decode(tenant_id: TenantId, source_id: SourceId, bytes: &[u8]) -> storage::Result<Status> {
+    let Some((&TAG, rest)) = bytes.split_first() else {
+        let status = Cbor.decode_one(bytes)?;
+        return Ok(Status { tenant_id, ..status });
+    };
+    decode_record(tenant_id, source_id, rest).ok_or(StorageError::CorruptStatus)
+}
+
+/// Decodes only the [`LatestVersion`] of a stored status, skipping over the
+/// fields in between.
+pub fn decode_version(bytes: &[u8]) -> storage::Result<LatestVersion> {
+    let Some((&TAG, rest)) = bytes.split_first() else {
+        let status: Status = Cbor.decode_one(bytes)?;
+        return Ok(LatestVersion::of(&status));
+    };
+    decode_version_record(rest).ok_or(StorageError::CorruptStatus)
+}
+
+fn decode_version_record(bytes: &[u8]) -> Option<LatestVersion> {
+    let mut r = Reader { bytes };
+    let flags = r.unsigned()?;
+    let timestamp = r.timestamp(0)?;
+    // Position takes two varints, bearing and speed one each.
+    let skipped: usize = [(HAS_POSITION, 2), (HAS_BEARING, 1), (HAS_SPEED, 1)]
+        .into_iter()
+        .filter(|(flag, _)| flags & flag != 0)
+        .map(|(_, n)| n)
+        .sum();
+    for _ in 0..skipped {
+        r.unsigned()?;
+    }
+    let received_at = match flags & HAS_RECEIVED_AT {
+        0 => None,
+        _ => Some(r.timestamp(timestamp.unix_timestamp())?),
+    };
+    Some(LatestVersion { timestamp, received_at })
+}
+
+fn decode_record(tenant_id: TenantId, source_id: SourceId, bytes: &[u8]) -> Option<Status> {
+    let mut r = Reader { bytes };
+    let flags = r.unsigned()?;
+    let timestamp = r.timestamp(0)?;
+    let position = match flags & HAS_POSITION {
+        0 => None,
+        _ => Some(Coord { x: r.coord()?, y: r.coord()? }),
+    };
+    let bearing = match flags & HAS_BEARING {
+        0 => None,
+        _ => Some(Angle::new::<radian>(r.signed()? as f64 / BEARING_SCALE)),
+    };
+    let speed = match flags & HAS_SPEED {
+        0 => None,
+        _ => Some(Velocity::new::<meter_per_second>(r.signed()? as f64 / SPEED_SCALE)),
+    };
+    let received_at = match flags & HAS_RECEIVED_AT {
+        0 => None,
+        _ => Some(r.timestamp(timestamp.unix_timestamp())?),
+    };
+    let satellites = match flags & HAS_SATELLITES {
+        0 => None,
+        _ => Some(r.byte()?),
+    };
+    let fix = match flags & HAS_FIX {
+        0 => None,
+        _ => Some(*FixType::ALL.get(usize::from(r.byte()?))?),
+    };
+    let odometer = match flags & HAS_ODOMETER {
+        0 => None,
+        _ => Some(Length::new::<meter>(r.unsigned()? as f64)),
+    };
+    let ignition = (flags & HAS_IGNITION != 0).then_some(flags & IGNITION_ON != 0);
+    if !r.bytes.is_empty() {
+        return None;
+    }
+
+    Some(Status {
+        source_id,
+        timestamp,
+        position,
+        bearing,
+        speed,
+        satellites,
+        fix,
+        odometer,
+        ignition,
+        received_at,
+        suspect_timestamp: flags & SUSPECT_TIMESTAMP != 0,
+        tenant_id,
+    })
+}
+
+/// Writes an LEB128 varint.
+fn put_unsigned(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// Writes a zigzag-encoded varint, so that small negative values stay short.
+fn put_signed(buf: &mut Vec<u8>, value: i64) {
+    put_unsigned(buf, ((value << 1) ^ (value >> 63)) as u64);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn byte(&mut self) -> Option<u8> {
+        let (&byte, rest) = self.bytes.split_first()?;
+        self.bytes = rest;
+        Some(byte)
+    }
+
+    fn unsigned(&mut self) -> Option<u64> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    fn signed(&mut self) -> Option<i64> {
+        let value = self.unsigned()?;
+        Some((value >> 1) as i64 ^ -((value & 1) as i64))
+    }
+
+    fn coord(&mut self) -> Option<f64> {
+        Some(unscale_coord(self.signed()?.try_into().ok()?))
+    }
+
+    /// Reads a timestamp as seconds relative to `base`, and nanoseconds.
+    fn timestamp(&mut self, base: i64) -> Option<OffsetDateTime> {
+        let seconds = base.wrapping_add(self.signed()?);
+        let nanos = self.unsigned()?.try_into().ok()?;
+        OffsetDateTime::from_unix_timestamp(seconds).ok()?.replace_nanosecond(nanos).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use geo_types::Coord;
+    use shared::data::{FixType, SourceId, Status, TenantId};
+    use time::macros::datetime;
+    use uom::si::{
+        angle::radian,
+        f64::{Angle, Length, Velocity},
+        length::meter,
+        velocity::meter_per_second,
+    };
+
+    use super::{decode, decode_version, encode};
+    use crate::storage::LatestVersion;
+
+    #[test]
+    fn round_trip() {
+        let source_id: SourceId =
+            serde_json::from_str("\"0aaec05a-0e7d-4fd5-abc0-0ba69e3cfe11\"").unwrap();
+        let tenant_id: TenantId = "11111111-1111-1111-1111-111111111111".parse().unwrap();
+        let full = Status {
+            source_id,
+            timestamp: datetime!(2021-07-27 05:45:19.25 UTC),
+            position: Some(Coord { x: 24.745_278, y: -59.437_222 }),
+            bearing: Some(Angle::new::<radian>(1.234)),
+            speed: Some(Velocity::new::<meter_per_second>(15.)),
+            satellites: Some(11),
+            fix: Some(FixType::Dgps),
+            odometer: Some(Length::new::<meter>(123_456_789.)),
+            ignition: Some(true),
+            received_at: Some(datetime!(2021-07-27 05:45:18.5 UTC)),
+            suspect_timestamp: true,
+            tenant_id,
+        };
+        let minimal = Status {
+            timestamp: datetime!(1969-12-31 23:59:59 UTC),
+            position: None,
+            bearing: None,
+            speed: None,
+            satellites: None,
+            fix: None,
+            odometer: None,
+            ignition: None,
+            received_at: None,
+            suspect_timestamp: false,
+            ..full
+        };
+
+        let parked = Status { ignition: Some(false), ..minimal };
+
+        for status in [full, minimal, parked] {
+            let encoded = encode(&status);
+            let decoded = decode(tenant_id, source_id, &encoded).unwrap();
+            assert_eq!(decoded.source_id, status.source_id);
+            assert_eq!(decoded.tenant_id, status.tenant_id);
+            assert_eq!(decoded.timestamp, status.timestamp);
+            assert_eq!(decoded.position, status.position);
+            assert_eq!(decoded.bearing, status.bearing);
+            assert_eq!(decoded.speed, status.speed);
+            assert_eq!(decoded.received_at, status.received_at);
+            assert_eq!(decoded.satellites, status.satellites);
+            assert_eq!(decoded.fix, status.fix);
+            assert_eq!(decoded.odometer, status.odometer);
+            assert_eq!(decoded.ignition, status.ignition);
+            assert_eq!(decoded.suspect_timestamp, status.suspect_timestamp);
+            assert!(decode(tenant_id, source_id, &encoded[..encoded.len() - 1]).is_err());
+            assert_eq!(decode_version(&encoded).unwrap(), LatestVersion::of(&status));
+        }
+
+        let mut cbor = Vec::new();
+        ciborium::ser::into_writer(&full, &mut cbor).unwrap();
+        assert!(encode(&full).len() * 3 < cbor.len());
+        let decoded = decode(tenant_id, source_id, &cbor).unwrap();
+        assert_eq!(decoded.position, full.position);
+        assert_eq!(decode_version(&cbor).unwrap(), LatestVersion::of(&decoded));
+    }
+}
@@ -0,0 +1,248 @@
+//! Offloading of archived data into S3-compatible object storage.
+//!
+//! Requests are signed with AWS Signature Version 4 and sent using path-style
+//! URLs (`<endpoint>/<bucket>/<key>`), which are supported by AWS S3 as well as
+//! self-hosted alternatives like MinIO or Garage. Only plain HTTP endpoints
+//! are supported; TLS is expected to be terminated by a local proxy or tunnel.
+
+use std::{fmt::Debug, time::Duration};
+
+use bytes::Bytes;
+use hmac::{Hmac, Mac};
+use http_body_util::{BodyExt, Full};
+use hyper::{Method, Request, Uri};
+use hyper_util::{
+    client::legacy::{connect::HttpConnector, Client},
+    rt::TokioExecutor,
+};
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+
+use crate::{
+    storage::{self, StorageError},
+    util::hex,
+};
+
+/// Connection and lifecycle settings of an S3-compatible bucket.
+#[derive(Clone)]
+pub struct S3Config {
+    /// Base URL of the service, e.g. `http://127.0.0.1:9000`.
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    /// Prepended to all object keys.
+    pub prefix: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    /// Uploaded archive files older than this are removed from local disk.
+    /// Kept indefinitely if `None`.
+    pub keep_local: Option<Duration>,
+    /// Uploaded archive files older than this are removed from the bucket.
+    /// Kept indefinitely if `None`.
+    pub expire_after: Option<Duration>,
+}
+
+// Implemented manually to keep the secret key out of logs.
+impl Debug for S3Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("S3Config")
+            .field("endpoint", &self.endpoint)
+            .field("bucket", &self.bucket)
+            .field("region", &self.region)
+            .field("prefix", &self.prefix)
+            .field("access_key_id", &self.access_key_id)
+            .field("keep_local", &self.keep_local)
+            .field("expire_after", &self.expire_after)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Client for a single S3 bucket.
+pub struct S3Sink {
+    cfg: S3Config,
+    host: String,
+    client: Client<HttpConnector, Full<Bytes>>,
+}
+
+impl S3Sink {
+    pub fn new(mut cfg: S3Config) -> storage::Result<Self> {
+        let uri: Uri = cfg
+            .endpoint
+            .parse()
+            .map_err(|_| StorageError::InvalidEndpoint { endpoint: cfg.endpoint.clone() })?;
+        let host = match (uri.scheme_str(), uri.authority()) {
+            (Some("http"), Some(authority)) => authority.to_string(),
+            _ => return Err(StorageError::InvalidEndpoint { endpoint: cfg.endpoint }),
+        };
+
+        cfg.endpoint = cfg.endpoint.trim_end_matches('/').to_owned();
+        if !cfg.prefix.is_empty() && !cfg.prefix.ends_with('/') {
+            cfg.prefix.push('/');
+        }
+
+        let client = Client::builder(TokioExecutor::new()).build_http();
+        Ok(Self { cfg, host, client })
+    }
+
+    pub fn config(&self) -> &S3Config {
+        &self.cfg
+    }
+
+    /// Full object key for a key relative to the configured prefix.
+    pub fn key(&self, relative: &str) -> String {
+        format!("{}{}", self.cfg.prefix, relative)
+    }
+
+    pub async fn put(&self, key: &str, body: Bytes) -> storage::Result<()> {
+        self.send(Method::PUT, key, &[], body).await.map(|_| ())
+    }
+
+    /// Fetch an object, returning `None` if it doesn't exist.
+    pub async fn get(&self, key: &str) -> storage::Result<Option<Bytes>> {
+        match self.send(Method::GET, key, &[], Bytes::new()).await {
+            Ok(body) => Ok(Some(body)),
+            Err(StorageError::S3Status { status: 404, .. }) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub async fn delete(&self, key: &str) -> storage::Result<()> {
+        self.send(Method::DELETE, key, &[], Bytes::new()).await.map(|_| ())
+    }
+
+    /// List keys of all objects starting with `prefix`.
+    pub async fn list(&self, prefix: &str) -> storage::Result<Vec<String>> {
+        let mut keys = Vec::new();
+        let mut token: Option<String> = None;
+
+        loop {
+            let mut query = vec![("list-type", "2".to_owned()), ("prefix", prefix.to_owned())];
+            if let Some(token) = token.take() {
+                query.push(("continuation-token", token));
+            }
+            let body = self.send(Method::GET, "", &query, Bytes::new()).await?;
+            let body = String::from_utf8_lossy(&body);
+
+            keys.extend(xml_values(&body, "Key").into_iter().map(unescape_xml));
+            match xml_values(&body, "NextContinuationToken").first() {
+                Some(next) => token = Some(unescape_xml(next)),
+                None => break,
+            }
+        }
+
+        Ok(keys)
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        key: &str,
+        query: &[(&str, String)],
+        body: Bytes,
+    ) -> storage::Result<Bytes> {
+        let path = if key.is_empty() {
+            format!("/{}", uri_encode(&self.cfg.bucket, false))
+        } else {
+            format!("/{}/{}", uri_encode(&self.cfg.bucket, false), uri_encode(key, true))
+        };
+        let mut query: Vec<String> = query
+            .iter()
+            .map(|(k, v)| format!("{}={}", uri_encode(k, false), uri_encode(v, false)))
+            .collect();
+        query.sort();
+        let query = query.join("&");
+
+        let now = OffsetDateTime::now_utc();
+        let date = format!("{:04}{:02}{:02}", now.year(), u8::from(now.month()), now.day());
+        let timestamp = format!("{date}T{:02}{:02}{:02}Z", now.hour(), now.minute(), now.second());
+        let payload_hash = hex::encode(&Sha256::digest(&body));
+
+        let canonical_request = format!(
+            "{method}\n{path}\n{query}\nhost:{}\nx-amz-content-sha256:{payload_hash}\nx-amz-date:{timestamp}\n\n{SIGNED_HEADERS}\n{payload_hash}",
+            self.host
+        );
+        let scope = format!("{date}/{}/s3/aws4_request", self.cfg.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{timestamp}\n{scope}\n{}",
+            hex::encode(&Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let secret = format!("AWS4{}", self.cfg.secret_access_key);
+        let signing_key = [date.as_str(), self.cfg.region.as_str(), "s3", "aws4_request"]
+            .iter()
+            .fold(secret.into_bytes(), |key, part| hmac(&key, part.as_bytes()));
+        let signature = hex::encode(&hmac(&signing_key, string_to_sign.as_bytes()));
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={SIGNED_HEADERS}, Signature={signature}",
+            self.cfg.access_key_id
+        );
+
+        let uri = match query.is_empty() {
+            true => format!("{}{path}", self.cfg.endpoint),
+            false => format!("{}{path}?{query}", self.cfg.endpoint),
+        };
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("host", &self.host)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", timestamp)
+            .header("authorization", authorization)
+            .body(Full::new(body))?;
+
+        let response = self.client.request(request).await?;
+        let status = response.status();
+        let body = response.into_body().collect().await?.to_bytes();
+        if status.is_success() {
+            Ok(body)
+        } else {
+            Err(StorageError::S3Status {
+                status: status.as_u16(),
+                message: String::from_utf8_lossy(&body).into_owned(),
+            })
+        }
+    }
+}
+
+const SIGNED_HEADERS: &str = "host;x-amz-content-sha256;x-amz-date";
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Percent-encodes everything except unreserved characters, as required by
+/// SigV4. Slashes are kept as is if `keep_slash` is set.
+fn uri_encode(s: &str, keep_slash: bool) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(b as char)
+            }
+            b'/' if keep_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{b:02X}")),
+        }
+    }
+    encoded
+}
+
+/// Extracts contents of all `<tag>...</tag>` elements. The responses we parse
+/// are simple enough for this to not warrant a full XML parser.
+fn xml_values<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+    let open = format!("<{tag}>");
+    let close = format!("</{tag}>");
+    xml.split(open.as_str())
+        .skip(1)
+        .filter_map(|s| s.split_once(close.as_str()).map(|(v, _)| v))
+        .collect()
+}
+
+fn unescape_xml(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
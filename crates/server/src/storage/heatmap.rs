@@ -0,0 +1,117 @@
+//! Point counts and dwell times over geohash cells, for drawing heat layers
+//! without downloading every single status.
+
+use std::{collections::BTreeMap, time::Duration};
+
+use serde::Serialize;
+use shared::data::Status;
+
+use crate::util::geohash;
+
+/// Longest gap between consecutive statuses of a source that still counts as
+/// time spent within a cell. Longer gaps mean the source went elsewhere or
+/// wasn't reporting.
+pub const MAX_DWELL_GAP: Duration = Duration::from_secs(300);
+
+/// Most index cells to scan for a single heatmap. The finest cells covering
+/// the requested area in at most this many are used.
+pub const MAX_SCAN_CELLS: usize = 64;
+
+/// Statistics of non-empty cells as parallel columns, which is considerably
+/// more compact than a list of objects.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Heatmap {
+    /// Length of the geohash cells.
+    pub precision: usize,
+    /// Geohash cells in ascending order.
+    pub cells: Vec<String>,
+    /// Number of statuses positioned within each cell.
+    pub counts: Vec<usize>,
+    /// Seconds spent within each cell, summed over all sources.
+    pub dwell: Vec<f64>,
+}
+
+impl Heatmap {
+    /// Computes a heatmap out of positioned statuses of any number of sources,
+    /// in any order. Statuses without a position are ignored.
+    ///
+    /// Time between two consecutive statuses of a source counts as dwell time
+    /// if both lie within the same cell, up to [`MAX_DWELL_GAP`].
+    pub fn new(precision: usize, mut statuses: Vec<Status>) -> Self {
+        statuses.retain(|s| s.position.is_some());
+        statuses.sort_unstable_by_key(|s| (s.source_id, s.timestamp));
+
+        let mut cells = BTreeMap::<String, (usize, f64)>::new();
+        let mut previous: Option<(&Status, String)> = None;
+        for status in &statuses {
+            let Some(position) = status.position else { continue };
+            let cell = geohash::encode(position, precision);
+            let entry = cells.entry(cell.clone()).or_default();
+            entry.0 += 1;
+            if let Some((last, last_cell)) = &previous {
+                if last.source_id == status.source_id && *last_cell == cell {
+                    let gap = (status.timestamp - last.timestamp).as_seconds_f64();
+                    entry.1 += gap.min(MAX_DWELL_GAP.as_secs_f64());
+                }
+            }
+            previous = Some((status, cell));
+        }
+
+        let mut heatmap = Self { precision, ..Self::default() };
+        for (cell, (count, dwell)) in cells {
+            heatmap.cells.push(cell);
+            heatmap.counts.push(count);
+            heatmap.dwell.push(dwell);
+        }
+        heatmap
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use geo_types::Coord;
+    use shared::data::{SourceId, Status, TenantId};
+    use time::macros::datetime;
+
+    use super::Heatmap;
+
+    #[test]
+    fn counts_and_dwell() {
+        let source_id =
+            |id: &str| -> SourceId { serde_json::from_str(&format!("\"{id}\"")).unwrap() };
+        let (first, second) = (
+            source_id("0aaec05a-0e7d-4fd5-abc0-0ba69e3cfe11"),
+            source_id("1aaec05a-0e7d-4fd5-abc0-0ba69e3cfe11"),
+        );
+        let status = |source_id, minutes: i64, x: f64| Status {
+            source_id,
+            timestamp: datetime!(2021-07-27 05:00 UTC) + time::Duration::minutes(minutes),
+            position: Some(Coord { x, y: 42.6 }),
+            bearing: None,
+            speed: None,
+            satellites: None,
+            fix: None,
+            odometer: None,
+            ignition: None,
+            received_at: None,
+            suspect_timestamp: false,
+            tenant_id: TenantId::DEFAULT,
+        };
+        let statuses = vec![
+            status(first, 1, -5.6),
+            status(second, 0, -5.6),
+            status(first, 0, -5.6),
+            // Another cell.
+            status(first, 2, -5.63),
+            // Back in the first cell, but after a long gap.
+            status(first, 30, -5.6),
+            Status { position: None, ..status(second, 3, -5.6) },
+        ];
+
+        let heatmap = Heatmap::new(4, statuses);
+        assert_eq!(heatmap.cells, ["ezef", "ezs4"]);
+        assert_eq!(heatmap.counts, [1, 4]);
+        assert_eq!(heatmap.dwell, [0., 60.]);
+    }
+}
@@ -1,54 +1,328 @@
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::{BTreeMap, BTreeSet, HashMap},
     fmt::Debug,
-    ops::RangeBounds,
+    ops::{Bound, RangeBounds},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    time::Duration,
 };
 
 use async_trait::async_trait;
-use shared::data::{SourceId, Status};
+use shared::data::{SourceId, Status, TenantId};
 use time::OffsetDateTime;
 
-use crate::storage::{self, DupeStrategy, Storage};
+use crate::{
+    metrics,
+    settings::Setting,
+    storage::{
+        self, shard, CellIndex, DupeStrategy, LatestVersion, Storage, StorageError, StorageStats,
+        TimeKey,
+    },
+};
+
+/// Limits on the amount of statuses kept in memory. Once a limit is exceeded,
+/// the oldest statuses are evicted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryConfig {
+    /// Maximum number of statuses kept per source.
+    pub max_per_source: Option<usize>,
+    /// Maximum number of statuses kept across all sources.
+    pub max_total: Option<usize>,
+    /// Statuses with timestamps older than this are evicted.
+    pub max_age: Option<Duration>,
+    /// Number of partitions sources are spread over, each locked on its own.
+    /// Not parsed, but set to the number of storage shards, so that every
+    /// shard writes to a partition of its own.
+    pub partitions: usize,
+}
+
+impl Default for MemoryConfig {
+    fn default() -> Self {
+        Self { max_per_source: None, max_total: None, max_age: None, partitions: 1 }
+    }
+}
+
+/// Parses a comma-separated list of `key=value` limits, e.g.
+/// `max_per_source=10000,max_total=1000000,max_age=24h`.
+impl FromStr for MemoryConfig {
+    type Err = StorageError;
+
+    fn from_str(s: &str) -> storage::Result<Self> {
+        let mut cfg = Self::default();
+        for option in s.split(',').filter(|o| !o.is_empty()) {
+            let invalid = || StorageError::InvalidStorageOption { option: option.to_owned() };
+            match option.split_once('=').ok_or_else(invalid)? {
+                ("max_per_source", n) => {
+                    cfg.max_per_source = Some(n.parse().map_err(|_| invalid())?)
+                }
+                ("max_total", n) => cfg.max_total = Some(n.parse().map_err(|_| invalid())?),
+                ("max_age", age) => {
+                    cfg.max_age = Some(humantime::parse_duration(age).map_err(|_| invalid())?)
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(cfg)
+    }
+}
+
+/// Sources are identified by their tenant along with their own ID.
+type SourceKey = (TenantId, SourceId);
+
+/// Stored statuses along with their indexes, which have to be updated
+/// together.
+#[derive(Default)]
+struct Data {
+    statuses: HashMap<SourceKey, BTreeMap<OffsetDateTime, Status>>,
+    /// Spatial index mapping geohash cells to the statuses positioned within.
+    cells: BTreeMap<String, BTreeSet<(SourceKey, OffsetDateTime)>>,
+    /// All stored statuses ordered by timestamp, used for eviction and for
+    /// looking up statuses of all sources by time.
+    by_age: BTreeSet<(OffsetDateTime, SourceKey)>,
+}
+
+impl Data {
+    /// Removes a single status along with its index entries.
+    fn remove(
+        &mut self,
+        cell_index: Option<CellIndex>,
+        source: SourceKey,
+        ts: OffsetDateTime,
+    ) -> Option<Status> {
+        let statuses = self.statuses.get_mut(&source)?;
+        let status = statuses.remove(&ts)?;
+        if statuses.is_empty() {
+            self.statuses.remove(&source);
+        }
+        self.by_age.remove(&(ts, source));
 
+        if let Some(cell) = cell_index.and_then(|index| index.cell(&status)) {
+            if let Some(keys) = self.cells.get_mut(&cell) {
+                keys.remove(&(source, ts));
+                if keys.is_empty() {
+                    self.cells.remove(&cell);
+                }
+            }
+        }
+
+        Some(status)
+    }
+}
+
+/// Storage holding all statuses in memory, partitioned by source the same way
+/// as storage shards are. Queries run concurrently, while writes are applied
+/// one at a time per partition.
 pub struct MemoryStorage {
-    statuses: HashMap<SourceId, BTreeMap<OffsetDateTime, Status>>,
-    dupe_strategy: DupeStrategy,
+    partitions: Box<[RwLock<Data>]>,
+    /// Number of statuses stored across all partitions.
+    len: AtomicUsize,
+    cfg: MemoryConfig,
+    dupe_strategy: Setting<DupeStrategy>,
+    cell_index: Option<CellIndex>,
+    stored: metrics::Gauge,
 }
 
 impl MemoryStorage {
-    pub fn new(dupe_strategy: DupeStrategy) -> Self {
-        Self { statuses: Default::default(), dupe_strategy }
+    pub fn new(
+        cfg: &MemoryConfig,
+        dupe_strategy: impl Into<Setting<DupeStrategy>>,
+        cell_index: Option<CellIndex>,
+    ) -> Self {
+        Self {
+            partitions: (0..cfg.partitions.max(1)).map(|_| Default::default()).collect(),
+            len: AtomicUsize::new(0),
+            cfg: cfg.clone(),
+            dupe_strategy: dupe_strategy.into(),
+            cell_index,
+            stored: metrics::gauge("geo_memory_statuses", "Statuses held in memory storage."),
+        }
     }
-}
 
-#[async_trait]
-impl Storage for MemoryStorage {
-    async fn persist_status(&mut self, status: Status) -> storage::Result<()> {
-        match self.dupe_strategy {
+    // A panic while holding the lock can't leave the data inconsistent in a
+    // way that would make it unsafe to keep using it.
+    fn read(&self, partition: usize) -> RwLockReadGuard<'_, Data> {
+        self.partitions[partition].read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self, partition: usize) -> RwLockWriteGuard<'_, Data> {
+        self.partitions[partition].write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn partition(&self, tenant_id: TenantId, source_id: SourceId) -> usize {
+        shard::shard_of(tenant_id, source_id, self.partitions.len())
+    }
+
+    /// Apply `f` to a partition, keeping count of the statuses stored.
+    fn update<T>(&self, partition: usize, f: impl FnOnce(&mut Data) -> T) -> T {
+        let mut data = self.write(partition);
+        let before = data.by_age.len();
+        let result = f(&mut data);
+        self.count(before, data.by_age.len());
+        result
+    }
+
+    fn count(&self, before: usize, after: usize) {
+        let len = match after >= before {
+            true => self.len.fetch_add(after - before, Ordering::Relaxed) + (after - before),
+            false => self.len.fetch_sub(before - after, Ordering::Relaxed) - (before - after),
+        };
+        self.stored.set(len as i64);
+    }
+
+    /// Evicts the oldest statuses of a source until it's within its limit.
+    fn evict_source(&self, data: &mut Data, source: SourceKey) {
+        let Some(max) = self.cfg.max_per_source else { return };
+        let excess = data.statuses.get(&source).map_or(0, |s| s.len().saturating_sub(max));
+        for _ in 0..excess {
+            let oldest = data.statuses.get(&source).and_then(|s| s.first_key_value());
+            if let Some((&ts, _)) = oldest {
+                data.remove(self.cell_index, source, ts);
+            }
+        }
+        evicted("max_per_source", excess);
+    }
+
+    /// Evicts the oldest statuses across all partitions until the total and
+    /// age limits are satisfied. Only one partition is locked at a time, so
+    /// the oldest status found may be gone by the time it's removed.
+    fn evict(&self) {
+        if let Some(max) = self.cfg.max_total {
+            let mut excess = 0;
+            // Claiming each eviction first keeps concurrent writers from
+            // evicting more than necessary.
+            while self
+                .len
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| (n > max).then(|| n - 1))
+                .is_ok()
+            {
+                let oldest = (0..self.partitions.len())
+                    .filter_map(|p| Some((*self.read(p).by_age.first()?, p)))
+                    .min();
+                let removed = oldest.map(|((ts, source), p)| {
+                    self.write(p).remove(self.cell_index, source, ts).is_some()
+                });
+                match removed {
+                    Some(true) => excess += 1,
+                    // Someone else got to it first, or there's nothing left.
+                    Some(false) | None => {
+                        self.len.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                if removed.is_none() {
+                    break;
+                }
+            }
+            evicted("max_total", excess);
+        }
+
+        if let Some(max_age) = self.cfg.max_age {
+            let cutoff = OffsetDateTime::now_utc() - max_age;
+            let mut expired = 0;
+            for p in 0..self.partitions.len() {
+                if self.read(p).by_age.first().is_none_or(|(ts, _)| *ts >= cutoff) {
+                    continue;
+                }
+                self.update(p, |data| {
+                    while let Some(&(ts, source)) =
+                        data.by_age.first().filter(|(ts, _)| *ts < cutoff)
+                    {
+                        data.remove(self.cell_index, source, ts);
+                        expired += 1;
+                    }
+                });
+            }
+            evicted("max_age", expired);
+        }
+
+        self.stored.set(self.len.load(Ordering::Relaxed) as i64);
+    }
+
+    /// Stores a single status along with its index entries, and evicts the
+    /// oldest ones of its source if that exceeds their limit.
+    fn insert(&self, data: &mut Data, status: Status) {
+        let source = (status.tenant_id, status.source_id);
+        let statuses = data.statuses.entry(source).or_default();
+        let existing = statuses.get(&status.timestamp).copied();
+        data.by_age.insert((status.timestamp, source));
+
+        match self.dupe_strategy.get() {
             DupeStrategy::Drop => {
-                self.statuses
-                    .entry(status.source_id)
-                    .or_default()
-                    .entry(status.timestamp)
-                    .or_insert(status);
+                statuses.entry(status.timestamp).or_insert(status);
             }
             DupeStrategy::Merge => {
-                self.statuses
-                    .entry(status.source_id)
-                    .or_default()
+                statuses
                     .entry(status.timestamp)
                     .and_modify(|s| *s = s.merge(&status))
                     .or_insert(status);
             }
             DupeStrategy::Overwrite => {
-                self.statuses.entry(status.source_id).or_default().insert(status.timestamp, status);
+                statuses.insert(status.timestamp, status);
+            }
+        }
+
+        if let Some(index) = self.cell_index {
+            let key = (source, status.timestamp);
+            let old_cell = existing.and_then(|s| index.cell(&s));
+            let new_cell = index.cell(&statuses[&status.timestamp]);
+            if old_cell != new_cell {
+                if let Some(cell) = old_cell.and_then(|c| data.cells.get_mut(&c)) {
+                    cell.remove(&key);
+                }
+                if let Some(cell) = new_cell {
+                    data.cells.entry(cell).or_default().insert(key);
+                }
             }
         }
+
+        self.evict_source(data, source);
+    }
+}
+
+fn evicted(reason: &str, n: usize) {
+    if n > 0 {
+        metrics::counter_with(
+            "geo_memory_evicted_total",
+            "Statuses evicted from memory storage due to configured limits.",
+            &[("reason", reason)],
+        )
+        .add(n as u64);
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn persist_status(&self, status: Status) -> storage::Result<()> {
+        let partition = self.partition(status.tenant_id, status.source_id);
+        self.update(partition, |data| self.insert(data, status));
+        self.evict();
+        Ok(())
+    }
+
+    /// Inserting can't fail, so all it takes is holding the locks of all
+    /// partitions involved for the whole batch, taken in order to not
+    /// deadlock with other batches.
+    async fn persist_batch_atomic(&self, statuses: Vec<Status>) -> storage::Result<()> {
+        let partitions: BTreeSet<usize> =
+            statuses.iter().map(|s| self.partition(s.tenant_id, s.source_id)).collect();
+        let mut locked: BTreeMap<usize, _> =
+            partitions.into_iter().map(|p| (p, self.write(p))).collect();
+        let before: usize = locked.values().map(|data| data.by_age.len()).sum();
+        for status in statuses {
+            let data = locked.get_mut(&self.partition(status.tenant_id, status.source_id));
+            self.insert(data.expect("partition is locked"), status);
+        }
+        self.count(before, locked.values().map(|data| data.by_age.len()).sum());
+        drop(locked);
+        self.evict();
         Ok(())
     }
 
     async fn get_statuses<R>(
         &self,
+        tenant_id: TenantId,
         source_id: SourceId,
         timestamps: R,
     ) -> storage::Result<Vec<Status>>
@@ -56,10 +330,314 @@ impl Storage for MemoryStorage {
         R: RangeBounds<OffsetDateTime> + Send + Debug,
     {
         let range = self
+            .read(self.partition(tenant_id, source_id))
             .statuses
-            .get(&source_id)
+            .get(&(tenant_id, source_id))
             .map(|m| m.range(timestamps).map(|(_, v)| v).copied().collect())
             .unwrap_or_default();
         Ok(range)
     }
+
+    async fn remove_statuses<R>(
+        &self,
+        tenant_id: TenantId,
+        source_id: SourceId,
+        timestamps: R,
+    ) -> storage::Result<usize>
+    where
+        R: RangeBounds<OffsetDateTime> + Send + Debug,
+    {
+        let source = (tenant_id, source_id);
+        let removed = self.update(self.partition(tenant_id, source_id), |data| {
+            let Some(statuses) = data.statuses.get(&source) else {
+                return 0;
+            };
+            let removed: Vec<OffsetDateTime> =
+                statuses.range(timestamps).map(|(ts, _)| *ts).collect();
+            for ts in &removed {
+                data.remove(self.cell_index, source, *ts);
+            }
+            removed.len()
+        });
+
+        Ok(removed)
+    }
+
+    async fn get_all_statuses<R>(
+        &self,
+        tenant_id: TenantId,
+        timestamps: R,
+        after: Option<TimeKey>,
+        limit: usize,
+    ) -> storage::Result<Vec<Status>>
+    where
+        R: RangeBounds<OffsetDateTime> + Send + Debug,
+    {
+        let first = |ts| (ts, (TenantId::DEFAULT, SourceId::MIN));
+        let start = match (after, timestamps.start_bound()) {
+            (Some((ts, source_id)), _) => Bound::Excluded((ts, (tenant_id, source_id))),
+            (None, Bound::Included(&ts)) => Bound::Included(first(ts)),
+            (None, Bound::Excluded(&ts)) => match ts.checked_add(time::Duration::NANOSECOND) {
+                Some(next) => Bound::Included(first(next)),
+                None => return Ok(Vec::new()),
+            },
+            (None, Bound::Unbounded) => Bound::Unbounded,
+        };
+
+        // Up to `limit` statuses from every partition, of which the first ones
+        // across all of them are kept.
+        let mut statuses = Vec::new();
+        for p in 0..self.partitions.len() {
+            let data = self.read(p);
+            let found = data
+                .by_age
+                .range((start, Bound::Unbounded))
+                .take_while(|(ts, _)| timestamps.contains(ts))
+                .filter(|(_, (tenant, _))| *tenant == tenant_id)
+                .filter_map(|(ts, source)| data.statuses.get(source)?.get(ts))
+                .take(limit)
+                .copied();
+            statuses.extend(found);
+        }
+        statuses.sort_unstable_by_key(|s| (s.timestamp, s.source_id));
+        statuses.truncate(limit);
+        Ok(statuses)
+    }
+
+    async fn latest_many(
+        &self,
+        tenant_id: Option<TenantId>,
+        source_ids: Option<&[SourceId]>,
+    ) -> storage::Result<Vec<Status>> {
+        let latest = |m: &BTreeMap<OffsetDateTime, Status>| m.last_key_value().map(|(_, s)| *s);
+        let statuses = match (tenant_id, source_ids) {
+            (Some(tenant_id), Some(ids)) => ids
+                .iter()
+                .filter_map(|&id| {
+                    let data = self.read(self.partition(tenant_id, id));
+                    data.statuses.get(&(tenant_id, id)).and_then(latest)
+                })
+                .collect(),
+            (tenant_id, ids) => {
+                let mut all = Vec::new();
+                for p in 0..self.partitions.len() {
+                    let data = self.read(p);
+                    let found = data
+                        .statuses
+                        .iter()
+                        .filter(|((tenant, source), _)| {
+                            tenant_id.is_none_or(|t| t == *tenant)
+                                && ids.is_none_or(|ids| ids.contains(source))
+                        })
+                        .filter_map(|(_, m)| latest(m));
+                    all.extend(found);
+                }
+                all.sort_unstable_by_key(|s| (s.tenant_id, s.source_id));
+                all
+            }
+        };
+        Ok(statuses)
+    }
+
+    async fn latest_version(
+        &self,
+        tenant_id: TenantId,
+        source_id: SourceId,
+    ) -> storage::Result<Option<LatestVersion>> {
+        let data = self.read(self.partition(tenant_id, source_id));
+        let latest = data.statuses.get(&(tenant_id, source_id)).and_then(|m| m.last_key_value());
+        Ok(latest.map(|(_, status)| LatestVersion::of(status)))
+    }
+
+    async fn get_cell_statuses(
+        &self,
+        tenant_id: TenantId,
+        cell: &str,
+    ) -> storage::Result<Vec<Status>> {
+        let index = self.cell_index.ok_or(StorageError::CellIndexDisabled)?;
+        let (prefix, exact) = index.scan_prefix(cell)?;
+
+        let mut statuses = Vec::new();
+        for p in 0..self.partitions.len() {
+            let data = self.read(p);
+            let found = data
+                .cells
+                .range(prefix.to_owned()..)
+                .take_while(|(c, _)| c.starts_with(prefix))
+                .flat_map(|(_, keys)| keys)
+                .filter(|((tenant, _), _)| *tenant == tenant_id)
+                .filter_map(|(source, ts)| data.statuses.get(source)?.get(ts))
+                .filter(|s| storage::within_cell(s, exact))
+                .copied();
+            statuses.extend(found);
+        }
+        Ok(statuses)
+    }
+
+    async fn stats(&self, tenant_id: Option<TenantId>) -> storage::Result<StorageStats> {
+        let mut stats = StorageStats { sources: 0, statuses: 0 };
+        for p in 0..self.partitions.len() {
+            let data = self.read(p);
+            let Some(tenant_id) = tenant_id else {
+                stats.sources += data.statuses.len();
+                stats.statuses += data.by_age.len();
+                continue;
+            };
+            for (_, m) in data.statuses.iter().filter(|((tenant, _), _)| *tenant == tenant_id) {
+                stats.sources += 1;
+                stats.statuses += m.len();
+            }
+        }
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use shared::data::{Status, TenantId};
+    use time::OffsetDateTime;
+
+    use super::{MemoryConfig, MemoryStorage};
+    use crate::storage::{test_status as status, DupeStrategy, Storage};
+
+    #[test]
+    fn parse_config() {
+        assert_eq!("".parse::<MemoryConfig>().unwrap(), MemoryConfig::default());
+        assert_eq!(
+            "max_per_source=10,max_total=100,max_age=1h".parse::<MemoryConfig>().unwrap(),
+            MemoryConfig {
+                max_per_source: Some(10),
+                max_total: Some(100),
+                max_age: Some(Duration::from_secs(3600)),
+                partitions: 1,
+            }
+        );
+        assert!("max_age=soon".parse::<MemoryConfig>().is_err());
+        assert!("max_sources=1".parse::<MemoryConfig>().is_err());
+    }
+
+    #[tokio::test]
+    async fn evict_oldest() {
+        let now = OffsetDateTime::now_utc();
+        let ago = |secs| now - Duration::from_secs(secs);
+        let stored =
+            |storage: &MemoryStorage| storage.read(0).by_age.iter().copied().collect::<Vec<_>>();
+        let key = |status: &Status| (status.timestamp, (status.tenant_id, status.source_id));
+
+        let cfg = MemoryConfig {
+            max_per_source: Some(2),
+            max_age: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+        let storage = MemoryStorage::new(&cfg, DupeStrategy::Merge, None);
+        let statuses = [
+            status(1, ago(30)),
+            status(1, ago(20)),
+            status(1, ago(10)),
+            status(2, ago(90)),
+            status(2, ago(15)),
+        ];
+        for status in statuses {
+            storage.persist_status(status).await.unwrap();
+        }
+        assert_eq!(stored(&storage), [key(&statuses[1]), key(&statuses[4]), key(&statuses[2])]);
+
+        let cfg = MemoryConfig { max_total: Some(2), ..Default::default() };
+        let storage = MemoryStorage::new(&cfg, DupeStrategy::Merge, None);
+        let statuses = [status(1, ago(30)), status(2, ago(20)), status(1, ago(10))];
+        for status in statuses {
+            storage.persist_status(status).await.unwrap();
+        }
+        assert_eq!(stored(&storage), [key(&statuses[1]), key(&statuses[2])]);
+        assert_eq!(storage.read(0).statuses.values().map(|s| s.len()).sum::<usize>(), 2);
+    }
+
+    #[tokio::test]
+    async fn tenant_isolation() {
+        let now = OffsetDateTime::now_utc();
+        let tenant: TenantId = "0aaec05a-0e7d-4fd5-abc0-0ba69e3cfe11".parse().unwrap();
+        let storage = MemoryStorage::new(&MemoryConfig::default(), DupeStrategy::Merge, None);
+        let own = status(1, now);
+        let other = Status { tenant_id: tenant, ..status(1, now - Duration::from_secs(10)) };
+        storage.persist_status(own).await.unwrap();
+        storage.persist_status(other).await.unwrap();
+
+        let statuses = storage.get_statuses(tenant, other.source_id, ..).await.unwrap();
+        assert_eq!(statuses.iter().map(|s| s.timestamp).collect::<Vec<_>>(), [other.timestamp]);
+        let latest = storage.latest_many(Some(TenantId::DEFAULT), None).await.unwrap();
+        assert_eq!(latest.iter().map(|s| s.timestamp).collect::<Vec<_>>(), [own.timestamp]);
+        assert_eq!(storage.latest_many(None, None).await.unwrap().len(), 2);
+        assert_eq!(storage.stats(Some(tenant)).await.unwrap().statuses, 1);
+
+        assert_eq!(storage.remove_statuses(tenant, other.source_id, ..).await.unwrap(), 1);
+        assert_eq!(storage.stats(None).await.unwrap().statuses, 1);
+    }
+
+    #[tokio::test]
+    async fn all_statuses_by_time() {
+        let now = OffsetDateTime::now_utc();
+        let ago = |secs| now - Duration::from_secs(secs);
+        let tenant: TenantId = "0aaec05a-0e7d-4fd5-abc0-0ba69e3cfe11".parse().unwrap();
+        let storage = MemoryStorage::new(&MemoryConfig::default(), DupeStrategy::Merge, None);
+        let statuses = [
+            status(2, ago(30)),
+            status(1, ago(20)),
+            status(2, ago(20)),
+            status(1, ago(10)),
+            Status { tenant_id: tenant, ..status(3, ago(20)) },
+        ];
+        for status in statuses {
+            storage.persist_status(status).await.unwrap();
+        }
+        let keys = |statuses: Vec<Status>| {
+            statuses.iter().map(|s| (s.timestamp, s.source_id)).collect::<Vec<_>>()
+        };
+        let key = |status: &Status| (status.timestamp, status.source_id);
+
+        let all = storage.get_all_statuses(TenantId::DEFAULT, .., None, 10).await.unwrap();
+        assert_eq!(keys(all), statuses[..4].iter().map(key).collect::<Vec<_>>());
+        let recent = storage.get_all_statuses(TenantId::DEFAULT, ago(25).., None, 2);
+        assert_eq!(keys(recent.await.unwrap()), [key(&statuses[1]), key(&statuses[2])]);
+        let next =
+            storage.get_all_statuses(TenantId::DEFAULT, ..ago(10), Some(key(&statuses[1])), 2);
+        assert_eq!(keys(next.await.unwrap()), [key(&statuses[2])]);
+        let other = storage.get_all_statuses(tenant, .., None, 10).await.unwrap();
+        assert_eq!(keys(other), [key(&statuses[4])]);
+    }
+
+    #[tokio::test]
+    async fn partitions() {
+        let now = OffsetDateTime::now_utc();
+        let ago = |secs| now - Duration::from_secs(secs);
+        let cfg = MemoryConfig { max_total: Some(6), partitions: 4, ..Default::default() };
+        let storage = MemoryStorage::new(&cfg, DupeStrategy::Merge, None);
+        let statuses: Vec<Status> =
+            (0..8).map(|i| status(i, ago(80 - 10 * u64::from(i)))).collect();
+        storage.persist_batch_atomic(statuses[..4].to_vec()).await.unwrap();
+        for status in &statuses[4..] {
+            storage.persist_status(*status).await.unwrap();
+        }
+        assert!((0..4).filter(|&p| !storage.read(p).by_age.is_empty()).count() > 1);
+
+        // The oldest statuses of all partitions are evicted.
+        let key = |status: &Status| (status.timestamp, status.source_id);
+        let all = storage.get_all_statuses(TenantId::DEFAULT, .., None, 10).await.unwrap();
+        assert_eq!(
+            all.iter().map(key).collect::<Vec<_>>(),
+            statuses[2..].iter().map(key).collect::<Vec<_>>()
+        );
+        let first = storage.get_all_statuses(TenantId::DEFAULT, .., None, 3).await.unwrap();
+        assert_eq!(
+            first.iter().map(key).collect::<Vec<_>>(),
+            statuses[2..5].iter().map(key).collect::<Vec<_>>()
+        );
+        let latest = storage.latest_many(None, None).await.unwrap();
+        assert_eq!(
+            latest.iter().map(|s| s.source_id).collect::<Vec<_>>(),
+            statuses[2..].iter().map(|s| s.source_id).collect::<Vec<_>>()
+        );
+        assert_eq!(storage.stats(None).await.unwrap().statuses, 6);
+    }
 }
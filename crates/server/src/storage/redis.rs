@@ -0,0 +1,797 @@
+//! Redis-backed storage of the latest status per source and a capped window of
+//! recent history.
+//!
+//! Can be used as the primary [`StorageEngine`](super::StorageEngine), or as a
+//! [`LatestCache`] in front of another engine to serve fleet snapshot queries.
+//!
+//! Keys used, relative to the configured prefix:
+//! - `latest`: hash of the CBOR-encoded latest status, keyed by source id;
+//! - `history:<source_id>`: sorted set of CBOR-encoded statuses, scored by
+//!   their Unix timestamp, including the fractional part;
+//! - `tenants`: set of all tenants other than the default one.
+//!
+//! Data of tenants other than the default one is kept under the same keys with
+//! an additional `tenant:<tenant_id>:` prefix.
+
+use std::{
+    fmt::Debug,
+    future::Future,
+    ops::{Bound, RangeBounds},
+    pin::Pin,
+    str::FromStr,
+};
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use shared::{
+    codec::{Cbor, Codec},
+    data::{timestamp, SourceId, Status, TenantId},
+};
+use time::OffsetDateTime;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream},
+    net::TcpStream,
+    sync::Mutex,
+};
+use tracing::warn;
+
+use crate::{
+    metrics,
+    settings::Setting,
+    storage::{self, DupeStrategy, LatestVersion, Storage, StorageError, StorageStats},
+};
+
+/// Connection and retention settings of a Redis storage.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RedisConfig {
+    /// Address of the Redis server, e.g. `127.0.0.1:6379`.
+    pub addr: String,
+    /// Prepended to all keys.
+    pub prefix: String,
+    /// Maximum number of recent statuses kept per source. Only the latest
+    /// status is kept if set to 0.
+    pub history: usize,
+}
+
+impl Default for RedisConfig {
+    fn default() -> Self {
+        Self { addr: "127.0.0.1:6379".to_owned(), prefix: "geo:".to_owned(), history: 1000 }
+    }
+}
+
+/// Parses `host:port[,history=<n>][,prefix=<prefix>]`, with every part being
+/// optional.
+impl FromStr for RedisConfig {
+    type Err = StorageError;
+
+    fn from_str(s: &str) -> storage::Result<Self> {
+        let mut cfg = Self::default();
+        let mut parts = s.split(',');
+        if let Some(addr) = parts.next().filter(|addr| !addr.is_empty()) {
+            cfg.addr = addr.to_owned();
+        }
+        for option in parts {
+            let invalid = || StorageError::InvalidStorageOption { option: option.to_owned() };
+            match option.split_once('=').ok_or_else(invalid)? {
+                ("history", n) => cfg.history = n.parse().map_err(|_| invalid())?,
+                ("prefix", prefix) => cfg.prefix = prefix.to_owned(),
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(cfg)
+    }
+}
+
+pub struct RedisStorage {
+    cfg: RedisConfig,
+    dupe_strategy: Setting<DupeStrategy>,
+    /// Established lazily and re-established after connection errors.
+    conn: Mutex<Option<Connection>>,
+    /// Held while writing, as writes take several commands that depend on
+    /// what the previous ones found.
+    writes: Mutex<()>,
+}
+
+impl RedisStorage {
+    pub fn new(cfg: &RedisConfig, dupe_strategy: impl Into<Setting<DupeStrategy>>) -> Self {
+        Self {
+            cfg: cfg.clone(),
+            dupe_strategy: dupe_strategy.into(),
+            conn: Mutex::new(None),
+            writes: Mutex::new(()),
+        }
+    }
+
+    fn tenant_prefix(&self, tenant_id: TenantId) -> String {
+        match tenant_id.is_default() {
+            true => self.cfg.prefix.clone(),
+            false => format!("{}tenant:{}:", self.cfg.prefix, tenant_id),
+        }
+    }
+
+    fn latest_key(&self, tenant_id: TenantId) -> String {
+        format!("{}latest", self.tenant_prefix(tenant_id))
+    }
+
+    fn history_key(&self, tenant_id: TenantId, source_id: &SourceId) -> String {
+        format!("{}history:{}", self.tenant_prefix(tenant_id), source_id)
+    }
+
+    fn tenants_key(&self) -> String {
+        format!("{}tenants", self.cfg.prefix)
+    }
+
+    /// All tenants with stored data, the default one included.
+    async fn tenants(&self) -> storage::Result<Vec<TenantId>> {
+        let reply = self.query(&[b"SMEMBERS", self.tenants_key().as_bytes()]).await?;
+        let mut tenants = vec![TenantId::DEFAULT];
+        for member in reply.into_array()? {
+            let member = member.into_bulk()?.unwrap_or_default();
+            tenants.push(TenantId::from_uuid(uuid_key(&member)?));
+        }
+        tenants.sort_unstable();
+        tenants.dedup();
+        Ok(tenants)
+    }
+
+    async fn add_tenant(&self, tenant_id: TenantId) -> storage::Result<()> {
+        if !tenant_id.is_default() {
+            let key = self.tenants_key();
+            self.query(&[b"SADD", key.as_bytes(), tenant_id.as_uuid().as_bytes()]).await?;
+        }
+        Ok(())
+    }
+
+    async fn query(&self, args: &[&[u8]]) -> storage::Result<Reply> {
+        let mut conn = self.conn.lock().await;
+        let result = match conn.as_mut() {
+            Some(conn) => conn.query(args).await,
+            None => match Connection::connect(&self.cfg.addr).await {
+                Ok(new) => conn.insert(new).query(args).await,
+                Err(err) => Err(err),
+            },
+        };
+        if matches!(result, Err(StorageError::Io(_))) {
+            *conn = None;
+        }
+        result
+    }
+
+    /// Run `commands` as a single MULTI/EXEC transaction.
+    async fn transaction(&self, commands: &[Vec<&[u8]>]) -> storage::Result<Vec<Reply>> {
+        let mut conn = self.conn.lock().await;
+        let result = match conn.as_mut() {
+            Some(conn) => conn.transaction(commands).await,
+            None => match Connection::connect(&self.cfg.addr).await {
+                Ok(new) => conn.insert(new).transaction(commands).await,
+                Err(err) => Err(err),
+            },
+        };
+        // An error reply inside EXEC's array stops reading it halfway, so the
+        // connection can't be trusted after any error.
+        if result.is_err() {
+            *conn = None;
+        }
+        result
+    }
+
+    async fn latest(
+        &self,
+        tenant_id: TenantId,
+        source_id: &SourceId,
+    ) -> storage::Result<Option<Status>> {
+        let key = self.latest_key(tenant_id);
+        let reply = self.query(&[b"HGET", key.as_bytes(), &source_id.to_key()]).await?;
+        reply.into_bulk()?.as_deref().map(decode).transpose()
+    }
+
+    /// Latest statuses of a single tenant.
+    async fn tenant_latest(
+        &self,
+        tenant_id: TenantId,
+        source_ids: Option<&[SourceId]>,
+    ) -> storage::Result<Vec<Status>> {
+        let key = self.latest_key(tenant_id);
+        match source_ids {
+            Some([]) => Ok(Vec::new()),
+            Some(ids) => {
+                let fields: Vec<_> = ids.iter().map(SourceId::to_key).collect();
+                let mut args: Vec<&[u8]> = vec![b"HMGET", key.as_bytes()];
+                args.extend(fields.iter().map(|f| f.as_slice()));
+                decode_all(self.query(&args).await?.into_array()?)
+            }
+            None => {
+                let reply = self.query(&[b"HGETALL", key.as_bytes()]).await?.into_array()?;
+                // Replies alternate between fields and values.
+                let values = reply.into_iter().skip(1).step_by(2).collect();
+                let mut all = decode_all(values)?;
+                all.sort_by_key(|s| s.source_id);
+                Ok(all)
+            }
+        }
+    }
+
+    /// Replace the set of latest statuses with the given ones.
+    pub async fn reset_latest(&self, statuses: &[Status]) -> storage::Result<()> {
+        let _write = self.writes.lock().await;
+        let mut tenants = self.tenants().await?;
+        tenants.extend(statuses.iter().map(|s| s.tenant_id));
+        tenants.sort_unstable();
+        tenants.dedup();
+
+        for tenant_id in tenants {
+            let key = self.latest_key(tenant_id);
+            self.query(&[b"DEL", key.as_bytes()]).await?;
+            let statuses: Vec<&Status> =
+                statuses.iter().filter(|s| s.tenant_id == tenant_id).collect();
+            if !statuses.is_empty() {
+                self.add_tenant(tenant_id).await?;
+            }
+            for chunk in statuses.chunks(256) {
+                let encoded =
+                    chunk.iter().map(|s| encode(s)).collect::<storage::Result<Vec<_>>>()?;
+                let fields: Vec<_> = chunk.iter().map(|s| s.source_id.to_key()).collect();
+                let mut args: Vec<&[u8]> = vec![b"HSET", key.as_bytes()];
+                for (field, bytes) in fields.iter().zip(&encoded) {
+                    args.push(field);
+                    args.push(bytes);
+                }
+                self.query(&args).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for RedisStorage {
+    async fn persist_status(&self, status: Status) -> storage::Result<()> {
+        let _write = self.writes.lock().await;
+        let history_key = self.history_key(status.tenant_id, &status.source_id);
+        let ts = score(status.timestamp);
+
+        let latest = self.latest(status.tenant_id, &status.source_id).await?;
+        let existing = match latest {
+            Some(latest) if latest.timestamp == status.timestamp => Some(latest),
+            _ => self
+                .query(&[b"ZRANGEBYSCORE", history_key.as_bytes(), ts.as_bytes(), ts.as_bytes()])
+                .await?
+                .into_array()?
+                .into_iter()
+                .next()
+                .map(|reply| reply.into_bulk()?.as_deref().map(decode).transpose())
+                .transpose()?
+                .flatten(),
+        };
+        let status = match (existing, self.dupe_strategy.get()) {
+            (Some(_), DupeStrategy::Drop) => return Ok(()),
+            (Some(existing), DupeStrategy::Merge) => existing.merge(&status),
+            _ => status,
+        };
+        let bytes = encode(&status)?;
+
+        // All writes go in one transaction so that history and the latest
+        // entry can't be left inconsistent by a failure halfway.
+        let tenants_key = self.tenants_key();
+        let tenant_id = status.tenant_id.as_uuid();
+        let keep_from = format!("-{}", self.cfg.history + 1);
+        let latest_key = self.latest_key(status.tenant_id);
+        let source_key = status.source_id.to_key();
+        let mut commands: Vec<Vec<&[u8]>> = Vec::new();
+        if latest.is_none() && !status.tenant_id.is_default() {
+            commands.push(vec![b"SADD", tenants_key.as_bytes(), tenant_id.as_bytes()]);
+        }
+        if self.cfg.history > 0 {
+            commands.extend([
+                vec![b"ZREMRANGEBYSCORE", history_key.as_bytes(), ts.as_bytes(), ts.as_bytes()],
+                vec![b"ZADD", history_key.as_bytes(), ts.as_bytes(), &bytes],
+                vec![b"ZREMRANGEBYRANK", history_key.as_bytes(), b"0", keep_from.as_bytes()],
+            ]);
+        }
+        if latest.map_or(true, |latest| latest.timestamp <= status.timestamp) {
+            commands.push(vec![b"HSET", latest_key.as_bytes(), &source_key, &bytes]);
+        }
+        if !commands.is_empty() {
+            self.transaction(&commands).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn get_statuses<R>(
+        &self,
+        tenant_id: TenantId,
+        source_id: SourceId,
+        timestamps: R,
+    ) -> storage::Result<Vec<Status>>
+    where
+        R: RangeBounds<OffsetDateTime> + Send + Debug,
+    {
+        let (min, max) = score_range(&timestamps);
+        let reply = self
+            .query(&[
+                b"ZRANGEBYSCORE",
+                self.history_key(tenant_id, &source_id).as_bytes(),
+                min.as_bytes(),
+                max.as_bytes(),
+            ])
+            .await?;
+        let mut statuses = decode_all(reply.into_array()?)?;
+
+        // The latest status isn't part of the history if it's disabled.
+        if self.cfg.history == 0 {
+            let latest = self.latest(tenant_id, &source_id).await?;
+            statuses.extend(latest.filter(|s| timestamps.contains(&s.timestamp)));
+        }
+
+        Ok(statuses)
+    }
+
+    async fn remove_statuses<R>(
+        &self,
+        tenant_id: TenantId,
+        source_id: SourceId,
+        timestamps: R,
+    ) -> storage::Result<usize>
+    where
+        R: RangeBounds<OffsetDateTime> + Send + Debug,
+    {
+        let _write = self.writes.lock().await;
+        let history_key = self.history_key(tenant_id, &source_id);
+        let (min, max) = score_range(&timestamps);
+        let mut removed = self
+            .query(&[b"ZREMRANGEBYSCORE", history_key.as_bytes(), min.as_bytes(), max.as_bytes()])
+            .await?
+            .into_integer()? as usize;
+
+        let latest = self.latest(tenant_id, &source_id).await?;
+        if latest.is_some_and(|s| timestamps.contains(&s.timestamp)) {
+            if self.cfg.history == 0 {
+                removed += 1;
+            }
+            let newest = self
+                .query(&[b"ZRANGE", history_key.as_bytes(), b"-1", b"-1"])
+                .await?
+                .into_array()?;
+            let latest_key = self.latest_key(tenant_id);
+            let field = source_id.to_key();
+            let field = field.as_slice();
+            match newest.into_iter().next().map(Reply::into_bulk).transpose()?.flatten() {
+                Some(bytes) => self.query(&[b"HSET", latest_key.as_bytes(), field, &bytes]).await?,
+                None => self.query(&[b"HDEL", latest_key.as_bytes(), field]).await?,
+            };
+        }
+
+        Ok(removed)
+    }
+
+    async fn latest_many(
+        &self,
+        tenant_id: Option<TenantId>,
+        source_ids: Option<&[SourceId]>,
+    ) -> storage::Result<Vec<Status>> {
+        if let Some(tenant_id) = tenant_id {
+            return self.tenant_latest(tenant_id, source_ids).await;
+        }
+        let mut all = Vec::new();
+        for tenant_id in self.tenants().await? {
+            all.extend(self.tenant_latest(tenant_id, source_ids).await?);
+        }
+        Ok(all)
+    }
+
+    /// Only decodes the timestamps of the latest status.
+    async fn latest_version(
+        &self,
+        tenant_id: TenantId,
+        source_id: SourceId,
+    ) -> storage::Result<Option<LatestVersion>> {
+        let key = self.latest_key(tenant_id);
+        let reply = self.query(&[b"HGET", key.as_bytes(), &source_id.to_key()]).await?;
+        reply.into_bulk()?.as_deref().map(decode_version).transpose()
+    }
+
+    async fn get_cell_statuses(
+        &self,
+        _tenant_id: TenantId,
+        _cell: &str,
+    ) -> storage::Result<Vec<Status>> {
+        Err(StorageError::CellIndexDisabled)
+    }
+
+    async fn stats(&self, tenant_id: Option<TenantId>) -> storage::Result<StorageStats> {
+        let tenants = match tenant_id {
+            Some(tenant_id) => vec![tenant_id],
+            None => self.tenants().await?,
+        };
+        let mut stats = StorageStats { sources: 0, statuses: 0 };
+        for tenant_id in tenants {
+            let key = self.latest_key(tenant_id);
+            let sources = self.query(&[b"HKEYS", key.as_bytes()]).await?.into_array()?;
+            stats.sources += sources.len();
+            if self.cfg.history == 0 {
+                stats.statuses += sources.len();
+                continue;
+            }
+            for source in sources {
+                let source = source.into_bulk()?.unwrap_or_default();
+                let source_id = source_key(&source)?;
+                let history_key = self.history_key(tenant_id, &source_id);
+                let count = self.query(&[b"ZCARD", history_key.as_bytes()]).await?;
+                stats.statuses += count.into_integer()? as usize;
+            }
+        }
+        Ok(stats)
+    }
+}
+
+/// Read-through cache of the latest status per source, kept in sync with the
+/// primary storage engine by receiving all writes.
+///
+/// The cache is populated from the primary engine in full and only answers
+/// queries while it's known to be up to date. After a failed write it's
+/// bypassed until the next successful [`LatestCache::refresh`], which storage
+/// maintenance takes care of.
+pub struct LatestCache {
+    redis: RedisStorage,
+    /// Whether the cache is known to be up to date. Held while the cache is
+    /// being written to, so that a refresh can't overwrite statuses persisted
+    /// while the primary engine was being read.
+    valid: Mutex<bool>,
+    hits: metrics::Counter,
+    misses: metrics::Counter,
+}
+
+impl LatestCache {
+    pub fn new(cfg: &RedisConfig, dupe_strategy: impl Into<Setting<DupeStrategy>>) -> Self {
+        Self {
+            redis: RedisStorage::new(cfg, dupe_strategy),
+            valid: Mutex::new(false),
+            hits: metrics::counter("geo_cache_hits_total", "Queries served from the cache."),
+            misses: metrics::counter(
+                "geo_cache_misses_total",
+                "Queries passed through to the primary storage.",
+            ),
+        }
+    }
+
+    /// Repopulate the cache from `engine` unless it's already up to date.
+    pub async fn refresh<S: Storage + Sync>(&self, engine: &S) {
+        let mut valid = self.valid.lock().await;
+        if *valid {
+            return;
+        }
+        let result = async {
+            let latest = engine.latest_many(None, None).await?;
+            self.redis.reset_latest(&latest).await
+        };
+        match result.await {
+            Ok(()) => *valid = true,
+            Err(err) => warn!(%err, "Failed to populate Redis cache"),
+        }
+    }
+
+    /// Mark the cache as stale, e.g. after statuses were removed from the
+    /// primary engine.
+    pub async fn invalidate(&self) {
+        *self.valid.lock().await = false;
+    }
+
+    /// Update the cache with a newly persisted status, unless it's stale
+    /// anyway.
+    pub async fn persist_status(&self, status: Status) {
+        let mut valid = self.valid.lock().await;
+        if !*valid {
+            return;
+        }
+        if let Err(err) = self.redis.persist_status(status).await {
+            warn!(%err, "Failed to update Redis cache");
+            *valid = false;
+        }
+    }
+
+    /// Latest statuses of the given sources, or `None` if the query has to be
+    /// passed through to the primary engine.
+    pub async fn latest_many(
+        &self,
+        tenant_id: TenantId,
+        source_ids: Option<&[SourceId]>,
+    ) -> Option<Vec<Status>> {
+        if !*self.valid.lock().await {
+            self.misses.inc();
+            return None;
+        }
+        match self.redis.latest_many(Some(tenant_id), source_ids).await {
+            Ok(statuses) => {
+                self.hits.inc();
+                Some(statuses)
+            }
+            Err(err) => {
+                warn!(%err, "Failed to query Redis cache");
+                self.misses.inc();
+                None
+            }
+        }
+    }
+}
+
+/// Tenant id stored as a set member.
+fn uuid_key(bytes: &[u8]) -> storage::Result<uuid::Uuid> {
+    uuid::Uuid::from_slice(bytes)
+        .map_err(|_| StorageError::Redis { message: "malformed id".to_owned() })
+}
+
+/// Source id stored as a hash field.
+fn source_key(bytes: &[u8]) -> storage::Result<SourceId> {
+    SourceId::from_key(bytes)
+        .ok_or_else(|| StorageError::Redis { message: "malformed id".to_owned() })
+}
+
+/// Sorted set score of a timestamp: seconds since UNIX epoch, with a fraction
+/// if there is one. Scores are doubles, precise to well under a microsecond
+/// for current timestamps.
+fn score(ts: OffsetDateTime) -> String {
+    (ts.unix_timestamp() as f64 + f64::from(ts.nanosecond()) / 1e9).to_string()
+}
+
+fn score_range<R: RangeBounds<OffsetDateTime>>(timestamps: &R) -> (String, String) {
+    let bound = |bound: Bound<&OffsetDateTime>, unbounded: &str| match bound {
+        Bound::Included(ts) => score(*ts),
+        Bound::Excluded(ts) => format!("({}", score(*ts)),
+        Bound::Unbounded => unbounded.to_owned(),
+    };
+    (bound(timestamps.start_bound(), "-inf"), bound(timestamps.end_bound(), "+inf"))
+}
+
+fn encode(status: &Status) -> storage::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    Cbor.encode(status, &mut bytes)?;
+    Ok(bytes)
+}
+
+fn decode(bytes: &[u8]) -> storage::Result<Status> {
+    Ok(Cbor.decode_one(bytes)?)
+}
+
+/// The fields of a CBOR-encoded status that make up its [`LatestVersion`],
+/// skipping over all others.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Version {
+    #[serde(with = "timestamp")]
+    timestamp: OffsetDateTime,
+    #[serde(default, with = "timestamp::option")]
+    received_at: Option<OffsetDateTime>,
+}
+
+fn decode_version(bytes: &[u8]) -> storage::Result<LatestVersion> {
+    let Version { timestamp, received_at } =
+        ciborium::de::from_reader(bytes).map_err(|_| StorageError::CorruptStatus)?;
+    Ok(LatestVersion { timestamp, received_at })
+}
+
+/// Decodes all non-nil bulk replies into statuses.
+fn decode_all(replies: Vec<Reply>) -> storage::Result<Vec<Status>> {
+    let mut statuses = Vec::with_capacity(replies.len());
+    for reply in replies {
+        if let Some(bytes) = reply.into_bulk()? {
+            statuses.push(decode(&bytes)?);
+        }
+    }
+    Ok(statuses)
+}
+
+/// Reply to a Redis command, as defined by RESP2. Error replies are returned
+/// as [`StorageError::Redis`] instead.
+enum Reply {
+    Simple(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<Reply>>),
+}
+
+impl Reply {
+    fn into_bulk(self) -> storage::Result<Option<Vec<u8>>> {
+        match self {
+            Self::Bulk(bytes) => Ok(bytes),
+            other => Err(unexpected(&other)),
+        }
+    }
+
+    fn into_integer(self) -> storage::Result<i64> {
+        match self {
+            Self::Integer(n) => Ok(n),
+            other => Err(unexpected(&other)),
+        }
+    }
+
+    fn into_array(self) -> storage::Result<Vec<Reply>> {
+        match self {
+            Self::Array(replies) => Ok(replies.unwrap_or_default()),
+            other => Err(unexpected(&other)),
+        }
+    }
+}
+
+fn unexpected(reply: &Reply) -> StorageError {
+    let reply = match reply {
+        Reply::Simple(s) => format!("status {s}"),
+        Reply::Integer(n) => format!("integer {n}"),
+        Reply::Bulk(_) => "bulk string".to_owned(),
+        Reply::Array(_) => "array".to_owned(),
+    };
+    StorageError::Redis { message: format!("unexpected reply: {reply}") }
+}
+
+fn encode_command(request: &mut Vec<u8>, args: &[&[u8]]) {
+    request.extend_from_slice(format!("*{}\r\n", args.len()).as_bytes());
+    for arg in args {
+        request.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        request.extend_from_slice(arg);
+        request.extend_from_slice(b"\r\n");
+    }
+}
+
+/// Minimal RESP2 client connection.
+struct Connection {
+    stream: BufStream<TcpStream>,
+}
+
+impl Connection {
+    async fn connect(addr: &str) -> storage::Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        Ok(Self { stream: BufStream::new(stream) })
+    }
+
+    async fn query(&mut self, args: &[&[u8]]) -> storage::Result<Reply> {
+        let mut request = Vec::new();
+        encode_command(&mut request, args);
+        self.stream.write_all(&request).await?;
+        self.stream.flush().await?;
+        self.read_reply().await
+    }
+
+    /// Send `commands` wrapped in MULTI/EXEC in a single round trip, and
+    /// return their replies. If any of them is rejected while queueing, none
+    /// is applied.
+    async fn transaction(&mut self, commands: &[Vec<&[u8]>]) -> storage::Result<Vec<Reply>> {
+        let mut request = Vec::new();
+        encode_command(&mut request, &[b"MULTI"]);
+        for args in commands {
+            encode_command(&mut request, args);
+        }
+        encode_command(&mut request, &[b"EXEC"]);
+        self.stream.write_all(&request).await?;
+        self.stream.flush().await?;
+
+        // Replies to MULTI and to each queued command, all of which need to be
+        // read even after an error to keep the connection in sync.
+        let mut queued = Ok(());
+        for _ in 0..=commands.len() {
+            if let Err(err) = self.read_reply().await {
+                queued = queued.and(Err(err));
+            }
+        }
+        let exec = self.read_reply().await;
+        queued?;
+        exec?.into_array()
+    }
+
+    fn read_reply(&mut self) -> Pin<Box<dyn Future<Output = storage::Result<Reply>> + Send + '_>> {
+        Box::pin(async move {
+            let line = self.read_line().await?;
+            let (kind, rest) = line.split_at(1);
+            let len = || {
+                rest.parse::<i64>().map_err(|_| StorageError::Redis {
+                    message: format!("malformed reply: {line}"),
+                })
+            };
+            match kind {
+                "+" => Ok(Reply::Simple(rest.to_owned())),
+                "-" => Err(StorageError::Redis { message: rest.to_owned() }),
+                ":" => len().map(Reply::Integer),
+                "$" => match usize::try_from(len()?) {
+                    Ok(len) => {
+                        let mut bytes = vec![0; len + 2];
+                        self.stream.read_exact(&mut bytes).await?;
+                        bytes.truncate(len);
+                        Ok(Reply::Bulk(Some(bytes)))
+                    }
+                    Err(_) => Ok(Reply::Bulk(None)),
+                },
+                "*" => match usize::try_from(len()?) {
+                    Ok(len) => {
+                        let mut replies = Vec::with_capacity(len);
+                        for _ in 0..len {
+                            replies.push(self.read_reply().await?);
+                        }
+                        Ok(Reply::Array(Some(replies)))
+                    }
+                    Err(_) => Ok(Reply::Array(None)),
+                },
+                _ => Err(StorageError::Redis { message: format!("malformed reply: {line}") }),
+            }
+        })
+    }
+
+    async fn read_line(&mut self) -> storage::Result<String> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line).await? == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        let line = line.trim_end_matches("\r\n");
+        if line.is_empty() {
+            return Err(StorageError::Redis { message: "empty reply".to_owned() });
+        }
+        Ok(line.to_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::data::Status;
+    use time::macros::datetime;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::{decode_version, encode, score, Connection, RedisConfig, Reply};
+    use crate::storage::LatestVersion;
+
+    #[test]
+    fn parse_config() {
+        assert_eq!("".parse::<RedisConfig>().unwrap(), RedisConfig::default());
+        assert_eq!(
+            "10.0.0.1:6380,history=5,prefix=fleet:".parse::<RedisConfig>().unwrap(),
+            RedisConfig {
+                addr: "10.0.0.1:6380".to_owned(),
+                prefix: "fleet:".to_owned(),
+                history: 5
+            }
+        );
+        assert!("127.0.0.1:6379,history=-1".parse::<RedisConfig>().is_err());
+        assert!("127.0.0.1:6379,ttl=5".parse::<RedisConfig>().is_err());
+    }
+
+    #[test]
+    fn scores() {
+        assert_eq!(score(datetime!(2021-07-27 05:45:19 UTC)), "1627364719");
+        assert_eq!(score(datetime!(2021-07-27 05:45:19.25 UTC)), "1627364719.25");
+        assert_eq!(score(datetime!(1969-12-31 23:59:58.5 UTC)), "-1.5");
+    }
+
+    #[test]
+    fn version() {
+        let json = r#"{"sourceId":"0aaec05a-0e7d-4fd5-abc0-0ba69e3cfe11","timestamp":1627364719,
+            "position":{"x":24.745278,"y":59.437222},"receivedAt":1627364720}"#;
+        let status: Status = serde_json::from_str(json).unwrap();
+        let version = decode_version(&encode(&status).unwrap()).unwrap();
+        assert_eq!(version, LatestVersion::of(&status));
+        assert!(version.received_at.is_some());
+        assert!(decode_version(b"nope").is_err());
+    }
+
+    #[tokio::test]
+    async fn transaction_replies() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf).await.unwrap();
+            let aborted = "+OK\r\n+QUEUED\r\n-ERR unknown command\r\n-EXECABORT discarded\r\n";
+            stream.write_all(aborted.as_bytes()).await.unwrap();
+            let _ = stream.read(&mut buf).await.unwrap();
+            stream.write_all(b"+OK\r\n+QUEUED\r\n+QUEUED\r\n*2\r\n:1\r\n+OK\r\n").await.unwrap();
+        });
+
+        let mut conn = Connection::connect(&addr).await.unwrap();
+        let commands: Vec<Vec<&[u8]>> = vec![vec![b"SADD", b"k", b"v"], vec![b"NOPE"]];
+        assert!(conn.transaction(&commands).await.is_err());
+        // All replies of the aborted transaction were consumed.
+        let replies = conn.transaction(&commands).await.unwrap();
+        assert!(matches!(replies[..], [Reply::Integer(1), Reply::Simple(_)]));
+    }
+}
@@ -0,0 +1,150 @@
+//! Fleet-wide reports built on top of [`aggregate`](super::aggregate).
+//!
+//! Figures of days that are over can't change anymore, unless statuses
+//! arrive late, so they're computed once and kept in a [`DistanceCache`].
+
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+use serde::{Serialize, Serializer};
+use shared::data::{SourceId, Status, TenantId};
+use time::{Date, UtcOffset};
+
+use super::aggregate::Aggregate;
+
+/// Longest supported report range, in days.
+pub const MAX_DAYS: i64 = 366;
+
+/// Length of a single aggregation bucket of the report.
+pub const DAY: Duration = Duration::from_secs(86_400);
+
+/// Distance travelled by a single source over a single UTC day.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DailyDistance {
+    pub source_id: SourceId,
+    /// Day in `YYYY-MM-DD` format.
+    #[serde(serialize_with = "serialize_date")]
+    pub date: Date,
+    #[serde(flatten)]
+    pub day: Day,
+}
+
+/// Figures of a single source over a single UTC day. Legs and intervals
+/// crossing midnight aren't counted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Day {
+    /// Distance in meters between consecutive positions within the day,
+    /// outliers left out.
+    pub distance: f64,
+    /// Number of statuses within the day.
+    pub fixes: usize,
+    /// Distance in meters according to odometer readings, if any were
+    /// reported.
+    pub odometer_distance: Option<f64>,
+    /// Seconds the ignition was on, if its state was reported, for telling
+    /// how much a vehicle has been in use.
+    pub ignition_time: Option<f64>,
+}
+
+impl From<&Aggregate> for Day {
+    fn from(aggregate: &Aggregate) -> Self {
+        Self {
+            distance: aggregate.distance,
+            fixes: aggregate.count,
+            odometer_distance: aggregate.odometer_distance,
+            ignition_time: aggregate.ignition_time,
+        }
+    }
+}
+
+fn serialize_date<S: Serializer>(date: &Date, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(date)
+}
+
+type DayKey = (TenantId, SourceId, Date);
+
+/// Figures of days that are over, per source.
+#[derive(Debug, Default)]
+pub struct DistanceCache {
+    days: Mutex<HashMap<DayKey, Day>>,
+}
+
+impl DistanceCache {
+    pub fn get(&self, key: DayKey) -> Option<Day> {
+        self.days.lock().ok()?.get(&key).copied()
+    }
+
+    pub fn insert(&self, key: DayKey, day: Day) {
+        if let Ok(mut days) = self.days.lock() {
+            days.insert(key, day);
+        }
+    }
+
+    /// Forget the day a newly persisted status belongs to.
+    pub fn invalidate(&self, status: &Status) {
+        let date = status.timestamp.to_offset(UtcOffset::UTC).date();
+        if let Ok(mut days) = self.days.lock() {
+            days.remove(&(status.tenant_id, status.source_id, date));
+        }
+    }
+
+    /// Forget everything, e.g. after statuses have been removed.
+    pub fn clear(&self) {
+        if let Ok(mut days) = self.days.lock() {
+            days.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::data::{SourceId, Status, TenantId};
+    use time::macros::{date, datetime};
+
+    use super::{DailyDistance, Day, DistanceCache};
+
+    #[test]
+    fn cache() {
+        let source_id: SourceId =
+            serde_json::from_str("\"0aaec05a-0e7d-4fd5-abc0-0ba69e3cfe11\"").unwrap();
+        let key = (TenantId::DEFAULT, source_id, date!(2021 - 07 - 27));
+        let cache = DistanceCache::default();
+        let day = Day { distance: 1_000., fixes: 10, ..Default::default() };
+        cache.insert(key, day);
+        assert_eq!(cache.get(key), Some(day));
+
+        let mut status = Status {
+            source_id,
+            timestamp: datetime!(2021-07-28 01:00 +03:00),
+            position: None,
+            bearing: None,
+            speed: None,
+            satellites: None,
+            fix: None,
+            odometer: None,
+            ignition: None,
+            received_at: None,
+            suspect_timestamp: false,
+            tenant_id: TenantId::DEFAULT,
+        };
+        cache.invalidate(&status);
+        assert_eq!(cache.get(key), None);
+
+        cache.insert(key, day);
+        status.timestamp = datetime!(2021-07-28 00:00 UTC);
+        cache.invalidate(&status);
+        assert!(cache.get(key).is_some());
+        cache.clear();
+        assert!(cache.get(key).is_none());
+
+        let day = Day { ignition_time: Some(3_600.), ..day };
+        let row = DailyDistance { source_id, date: key.2, day };
+        let json = serde_json::to_value(row).unwrap();
+        assert_eq!(json["date"], "2021-07-27");
+        assert_eq!(json["sourceId"], "0aaec05a-0e7d-4fd5-abc0-0ba69e3cfe11");
+        assert_eq!(json["fixes"], 10);
+        assert_eq!(json["odometerDistance"], serde_json::Value::Null);
+        assert_eq!(json["ignitionTime"], 3_600.);
+    }
+}
@@ -0,0 +1,448 @@
+//! Distribution of storage requests over several storage actors.
+//!
+//! Each actor, or shard, is a mailbox of its own in front of the same
+//! [`StorageService`](super::StorageService), handling commands in order. All
+//! writes of a single source go to the same shard, so they're applied in the
+//! order they were sent, while writes of different sources proceed in
+//! parallel.
+//!
+//! Engines don't serialize those writes again: in-memory storage is split into
+//! as many partitions as there are shards, picked by [`shard_of`] as well, so
+//! every shard writes to a partition of its own, and Sled handles concurrent
+//! writers by itself.
+
+use std::{
+    future::Future,
+    hash::{BuildHasher, BuildHasherDefault, DefaultHasher},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use futures_util::future;
+use shared::data::{SourceId, Status, TenantId};
+use tokio::sync::{oneshot, Mutex, MutexGuard};
+
+use super::{QueryResult, Result, StorageCommand, StorageQuery};
+use crate::cq::{Address, CqrsError};
+
+/// Address of a single storage actor.
+pub type StorageAddress = Address<StorageCommand, StorageQuery>;
+
+/// Sending side of the storage actors, routing each request to one of them.
+///
+/// Statuses go to the shard picked by hashing their source, and batches of
+/// statuses are split up accordingly. Atomic batches can't be split: one that
+/// spans several shards is persisted by the first of them, while the others
+/// are held back by a [`Barrier`] until it's done, so that it's ordered with
+/// the other writes of all its sources. Housekeeping commands always go to
+/// the first shard, and queries are spread over all shards in turn.
+#[derive(Clone)]
+pub struct StorageHandler {
+    shards: Arc<[StorageAddress]>,
+    /// Shard to send the next query to.
+    next_query: Arc<AtomicUsize>,
+    /// Held while sending a batch spanning several shards. Barriers of
+    /// different batches have to be queued in the same order on all shards,
+    /// or they would wait for each other forever.
+    spanning: Arc<Mutex<()>>,
+}
+
+impl StorageHandler {
+    /// Panics if `shards` is empty.
+    pub fn new(shards: Vec<StorageAddress>) -> Self {
+        assert!(!shards.is_empty(), "storage needs at least one shard");
+        Self { shards: shards.into(), next_query: Arc::default(), spanning: Arc::default() }
+    }
+
+    pub async fn command(&self, cmd: StorageCommand) -> std::result::Result<Result<()>, CqrsError> {
+        let parts = self.split(cmd);
+        let _spanning = self.lock_spanning(&parts).await;
+        let sent = parts.into_iter().map(|(shard, cmd)| self.shards[shard].command(cmd));
+        join(future::join_all(sent).await)
+    }
+
+    /// Same as [`StorageHandler::command`], but gives up with
+    /// [`CqrsError::Timeout`] if the command isn't processed within `timeout`.
+    pub async fn command_timeout(
+        &self,
+        cmd: StorageCommand,
+        timeout: Duration,
+    ) -> std::result::Result<Result<()>, CqrsError> {
+        let parts = self.split(cmd);
+        let _spanning = self.lock_spanning(&parts).await;
+        let sent =
+            parts.into_iter().map(|(shard, cmd)| self.shards[shard].command_timeout(cmd, timeout));
+        join(future::join_all(sent).await)
+    }
+
+    /// Enqueue a command without waiting for it to be processed.
+    pub async fn notify(&self, cmd: StorageCommand) -> std::result::Result<(), CqrsError> {
+        let parts = self.split(cmd);
+        let _spanning = self.lock_spanning(&parts).await;
+        for (shard, cmd) in parts {
+            self.shards[shard].notify(cmd).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn query(
+        &self,
+        query: StorageQuery,
+    ) -> std::result::Result<Result<QueryResult>, CqrsError> {
+        self.next_shard().query(query).await
+    }
+
+    /// Same as [`StorageHandler::query`], but gives up with
+    /// [`CqrsError::Timeout`] if the query isn't processed within `timeout`.
+    pub async fn query_timeout(
+        &self,
+        query: StorageQuery,
+        timeout: Duration,
+    ) -> std::result::Result<Result<QueryResult>, CqrsError> {
+        self.next_shard().query_timeout(query, timeout).await
+    }
+
+    fn next_shard(&self) -> &StorageAddress {
+        let next = self.next_query.fetch_add(1, Ordering::Relaxed);
+        &self.shards[next % self.shards.len()]
+    }
+
+    /// Shard handling writes of a given source.
+    fn shard(&self, tenant_id: TenantId, source_id: SourceId) -> usize {
+        shard_of(tenant_id, source_id, self.shards.len())
+    }
+
+    /// Splits a command into the parts to send to each shard.
+    fn split(&self, cmd: StorageCommand) -> Vec<(usize, StorageCommand)> {
+        match cmd {
+            StorageCommand::PersistStatus(status) => {
+                let shard = self.shard(status.tenant_id, status.source_id);
+                vec![(shard, StorageCommand::PersistStatus(status))]
+            }
+            StorageCommand::PersistStatuses(statuses) => {
+                let mut batches: Vec<Vec<Status>> = vec![Vec::new(); self.shards.len()];
+                for status in statuses {
+                    batches[self.shard(status.tenant_id, status.source_id)].push(status);
+                }
+                batches
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(_, batch)| !batch.is_empty())
+                    .map(|(shard, batch)| (shard, StorageCommand::PersistStatuses(batch)))
+                    .collect()
+            }
+            StorageCommand::PersistBatch(statuses) => {
+                let mut shards: Vec<usize> =
+                    statuses.iter().map(|s| self.shard(s.tenant_id, s.source_id)).collect();
+                shards.sort_unstable();
+                shards.dedup();
+                match shards[..] {
+                    [] => vec![(0, StorageCommand::PersistBatch(statuses))],
+                    [shard] => vec![(shard, StorageCommand::PersistBatch(statuses))],
+                    [first, ref others @ ..] => {
+                        let (gate, barriers) = Gate::new(others.len());
+                        let held = barriers.into_iter().map(StorageCommand::Barrier);
+                        let mut parts: Vec<_> = others.iter().copied().zip(held).collect();
+                        parts.push((first, StorageCommand::PersistBatchAcross(statuses, gate)));
+                        parts
+                    }
+                }
+            }
+            cmd => vec![(0, cmd)],
+        }
+    }
+
+    /// Locks [`StorageHandler::spanning`] if `parts` make up a batch spanning
+    /// several shards.
+    async fn lock_spanning(&self, parts: &[(usize, StorageCommand)]) -> Option<MutexGuard<'_, ()>> {
+        let spanning = parts.iter().any(|(_, cmd)| matches!(cmd, StorageCommand::Barrier(_)));
+        match spanning {
+            true => Some(self.spanning.lock().await),
+            false => None,
+        }
+    }
+}
+
+/// Handle `cmd` with `handle`, first waiting for the other shards involved if
+/// it's part of a batch spanning several of them.
+///
+/// Shards have to wait before taking any lock that restarting a shard takes as
+/// well. Otherwise a shard waiting for another one to reach its barrier would
+/// keep that one from ever restarting after a failure ahead of it.
+pub async fn synchronize<F, Fut>(cmd: StorageCommand, handle: F) -> Result<()>
+where
+    F: FnOnce(StorageCommand) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    match cmd {
+        StorageCommand::Barrier(barrier) => {
+            barrier.wait().await;
+            Ok(())
+        }
+        StorageCommand::PersistBatchAcross(statuses, mut gate) => {
+            gate.wait().await;
+            let result = handle(StorageCommand::PersistBatch(statuses)).await;
+            drop(gate);
+            result
+        }
+        cmd => handle(cmd).await,
+    }
+}
+
+/// Holds a shard back while another one persists a batch spanning both, see
+/// [`StorageCommand::Barrier`].
+pub struct Barrier {
+    reached: oneshot::Sender<()>,
+    released: oneshot::Receiver<()>,
+}
+
+impl Barrier {
+    /// Signal that all earlier commands of the shard are done, and wait until
+    /// the batch is persisted, or given up on.
+    pub async fn wait(self) {
+        let _ = self.reached.send(());
+        let _ = self.released.await;
+    }
+}
+
+/// Counterpart of the [`Barrier`]s of a batch spanning several shards, held by
+/// the shard persisting it. Releases them when dropped.
+pub struct Gate {
+    reached: Vec<oneshot::Receiver<()>>,
+    _release: Vec<oneshot::Sender<()>>,
+}
+
+impl Gate {
+    fn new(count: usize) -> (Self, Vec<Barrier>) {
+        let (mut reached, mut release, mut barriers) = (Vec::new(), Vec::new(), Vec::new());
+        for _ in 0..count {
+            let (reached_tx, reached_rx) = oneshot::channel();
+            let (release_tx, release_rx) = oneshot::channel();
+            reached.push(reached_rx);
+            release.push(release_tx);
+            barriers.push(Barrier { reached: reached_tx, released: release_rx });
+        }
+        (Self { reached, _release: release }, barriers)
+    }
+
+    /// Wait until all other shards are held back by their [`Barrier`], or
+    /// have given up on it.
+    pub async fn wait(&mut self) {
+        for reached in &mut self.reached {
+            let _ = reached.await;
+        }
+    }
+}
+
+impl From<StorageAddress> for StorageHandler {
+    fn from(address: StorageAddress) -> Self {
+        Self::new(vec![address])
+    }
+}
+
+/// Index of the shard out of `count` handling writes of a given source. Also
+/// used by storage engines to partition their data the same way.
+pub(crate) fn shard_of(tenant_id: TenantId, source_id: SourceId, count: usize) -> usize {
+    // The hasher's keys are fixed, so that sources stick to their shard.
+    let hash = BuildHasherDefault::<DefaultHasher>::default().hash_one((tenant_id, source_id));
+    (hash % count as u64) as usize
+}
+
+/// Combines the outcomes of the parts of a command, failing with the first
+/// error, if any.
+fn join(
+    results: Vec<std::result::Result<Result<()>, CqrsError>>,
+) -> std::result::Result<Result<()>, CqrsError> {
+    let mut outcome = Ok(());
+    for result in results {
+        if let Err(err) = result? {
+            outcome = outcome.and(Err(err));
+        }
+    }
+    Ok(outcome)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::Infallible,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use shared::data::{Status, TenantId};
+    use time::OffsetDateTime;
+    use tokio::sync::RwLock;
+
+    use super::{synchronize, StorageHandler};
+    use crate::{
+        cq,
+        storage::{test_status as status, QueryResult, StorageCommand, StorageError, StorageQuery},
+    };
+
+    type Persisted = Arc<Mutex<Vec<(usize, Status)>>>;
+
+    /// Spawns `count` shards recording the statuses each of them persists,
+    /// taking `delay` for every single status. Like the server, they share a
+    /// lock that's taken for writing on restarts, and they fail on statuses
+    /// with suspect timestamps.
+    fn spawn_shards(count: usize, delay: Duration, persisted: &Persisted) -> StorageHandler {
+        let storage = Arc::new(RwLock::new(()));
+        let shards = (0..count)
+            .map(|shard| {
+                let (lock, persisted) = (storage.clone(), persisted.clone());
+                let on_command = move |cmd| {
+                    let (storage, persisted) = (lock.clone(), persisted.clone());
+                    synchronize(cmd, move |cmd| async move {
+                        let _storage = storage.read().await;
+                        let statuses = match cmd {
+                            StorageCommand::PersistStatus(status) => {
+                                tokio::time::sleep(delay).await;
+                                assert!(!status.suspect_timestamp, "failing on purpose");
+                                vec![status]
+                            }
+                            StorageCommand::PersistStatuses(statuses)
+                            | StorageCommand::PersistBatch(statuses) => statuses,
+                            _ => return Err(StorageError::CompactionUnsupported),
+                        };
+                        let mut persisted = persisted.lock().unwrap();
+                        persisted.extend(statuses.into_iter().map(|s| (shard, s)));
+                        Ok(())
+                    })
+                };
+                let on_query = move |_| async move { Ok(QueryResult::Latest(None)) };
+                let restart = {
+                    let handlers = (on_command.clone(), on_query);
+                    let storage = storage.clone();
+                    move || {
+                        let (handlers, storage) = (handlers.clone(), storage.clone());
+                        async move {
+                            drop(storage.write().await);
+                            Ok::<_, Infallible>(handlers)
+                        }
+                    }
+                };
+                let policy = cq::RestartPolicy {
+                    name: "test",
+                    min_backoff: Duration::from_millis(1),
+                    max_backoff: Duration::from_millis(1),
+                };
+                let (address, mailbox) = cq::bounded(8, on_command, on_query);
+                let mailbox = mailbox.with_ordered_commands();
+                tokio::spawn(mailbox.supervise(4, policy, restart, |_| {}));
+                address
+            })
+            .collect();
+        StorageHandler::new(shards)
+    }
+
+    #[tokio::test]
+    async fn routing() {
+        let persisted = Persisted::default();
+        let handler = spawn_shards(4, Duration::ZERO, &persisted);
+
+        let now = OffsetDateTime::now_utc();
+        let statuses: Vec<Status> = (0..32).map(|source| status(source, now)).collect();
+        let batch = StorageCommand::PersistStatuses(statuses.clone());
+        handler.command(batch).await.unwrap().unwrap();
+        for status in &statuses {
+            let cmd = StorageCommand::PersistStatus(*status);
+            handler.command(cmd).await.unwrap().unwrap();
+        }
+        // Atomic batches stay whole.
+        let batch = StorageCommand::PersistBatch(statuses.clone());
+        handler.command(batch).await.unwrap().unwrap();
+        let persisted: Vec<_> =
+            persisted.lock().unwrap().iter().map(|(shard, s)| (*shard, s.source_id)).collect();
+        assert_eq!(persisted.len(), 96);
+        assert!(persisted[64..].iter().all(|(shard, _)| *shard == persisted[64].0));
+        // Every source sticks to a single shard, and all shards get some.
+        for status in &statuses {
+            let shards: Vec<_> =
+                persisted.iter().filter(|(_, id)| *id == status.source_id).collect();
+            assert_eq!(shards[0], shards[1]);
+        }
+        for shard in 0..4 {
+            assert!(persisted.iter().any(|(s, _)| *s == shard));
+        }
+
+        let result = handler.command(StorageCommand::Compact).await.unwrap();
+        assert!(matches!(result, Err(StorageError::CompactionUnsupported)));
+        let query = StorageQuery::Latest(TenantId::DEFAULT, statuses[0].source_id);
+        assert!(handler.query(query).await.unwrap().unwrap().into_latest().is_some());
+    }
+
+    #[tokio::test]
+    async fn spanning_batch_order() {
+        let persisted = Persisted::default();
+        let handler = spawn_shards(2, Duration::from_millis(20), &persisted);
+        // Sources of either shard. The batch is persisted by the first one,
+        // while single statuses of the second one take a while.
+        let now = OffsetDateTime::now_utc();
+        let on = |shard| {
+            (0..)
+                .map(|source| status(source, now))
+                .find(|s| handler.shard(s.tenant_id, s.source_id) == shard)
+                .unwrap()
+        };
+        let (first, second) = (on(0), on(1));
+        let at = |status: Status, secs| Status {
+            timestamp: status.timestamp + time::Duration::seconds(secs),
+            ..status
+        };
+
+        handler.notify(StorageCommand::PersistStatus(at(second, 0))).await.unwrap();
+        let batch = StorageCommand::PersistBatch(vec![at(first, 1), at(second, 1)]);
+        handler.notify(batch).await.unwrap();
+        handler.notify(StorageCommand::PersistStatus(at(second, 2))).await.unwrap();
+        let last = StorageCommand::PersistStatus(at(second, 3));
+        handler.command(last).await.unwrap().unwrap();
+
+        let persisted = persisted.lock().unwrap();
+        let order: Vec<_> = persisted
+            .iter()
+            .filter(|(_, s)| s.source_id == second.source_id)
+            .map(|(_, s)| (s.timestamp - second.timestamp).whole_seconds())
+            .collect();
+        assert_eq!(order, [0, 1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn restart_during_spanning_batch() {
+        let persisted = Persisted::default();
+        let handler = spawn_shards(2, Duration::from_millis(20), &persisted);
+        let now = OffsetDateTime::now_utc();
+        let on = |shard| {
+            (0..)
+                .map(|source| status(source, now))
+                .find(|s| handler.shard(s.tenant_id, s.source_id) == shard)
+                .unwrap()
+        };
+        let (first, second) = (on(0), on(1));
+        let at = |status: Status, secs| Status {
+            timestamp: status.timestamp + time::Duration::seconds(secs),
+            ..status
+        };
+
+        // The second shard fails while the first one waits for it to reach
+        // the barrier of the batch.
+        let failing = Status { suspect_timestamp: true, ..at(second, 0) };
+        handler.notify(StorageCommand::PersistStatus(failing)).await.unwrap();
+        let batch = StorageCommand::PersistBatch(vec![at(first, 1), at(second, 1)]);
+        handler.notify(batch).await.unwrap();
+        let last = handler.command(StorageCommand::PersistStatus(at(second, 2)));
+        let last = tokio::time::timeout(Duration::from_secs(5), last).await;
+        last.expect("storage stalled").unwrap().unwrap();
+
+        let persisted = persisted.lock().unwrap();
+        let order: Vec<_> = persisted
+            .iter()
+            .filter(|(_, s)| s.source_id == second.source_id)
+            .map(|(_, s)| (s.timestamp - second.timestamp).whole_seconds())
+            .collect();
+        assert_eq!(order, [1, 2]);
+    }
+}
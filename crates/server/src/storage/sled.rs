@@ -1,51 +1,1101 @@
-use std::{fmt::Debug, ops::RangeBounds, path::PathBuf};
+use std::{
+    fmt::Debug,
+    fs, io,
+    ops::{Bound, RangeBounds},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
 
 use async_trait::async_trait;
-use shared::data::{SourceId, Status};
-use sled::Db;
+use futures_util::{stream, StreamExt};
+use shared::data::{SourceId, Status, TenantId};
+use sled::{
+    transaction::{
+        ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+        Transactional, TransactionalTree,
+    },
+    Batch, Db, Tree,
+};
 use time::OffsetDateTime;
+use tokio::sync::{RwLock, RwLockReadGuard};
+use uuid::Uuid;
 
-use crate::storage::{self, DupeStrategy, Storage};
+use crate::{
+    metrics,
+    settings::Setting,
+    storage::{
+        self, codec, CellIndex, DupeStrategy, LatestVersion, StatusStream, Storage, StorageError,
+        StorageStats, TimeKey,
+    },
+};
 
-#[derive(Debug)]
+/// Tree holding all statuses, keyed by `tenant_id` + `source_id` + `timestamp`
+/// seconds + `timestamp` nanoseconds.
+const STATUSES_TREE: &str = "statuses";
+/// Tree holding the most recent status of each source, keyed by `tenant_id` +
+/// `source_id`.
+const LATEST_TREE: &str = "latest";
+/// Spatial index tree, keyed by `tenant_id` + geohash cell + status key, with
+/// empty values.
+const CELLS_TREE: &str = "cells";
+/// Time-ordered index tree, keyed by `tenant_id` + `timestamp` seconds +
+/// `timestamp` nanoseconds + `source_id`, with empty values.
+const BY_TIME_TREE: &str = "by_time";
+/// Key of the layout version in the default tree. Databases without one were
+/// written before keys were prefixed with `tenant_id`, those of version 1
+/// before status keys included nanoseconds, and those of version 2 before the
+/// time-ordered index was kept.
+const LAYOUT_KEY: &str = "layout";
+const LAYOUT_VERSION: u8 = 3;
+/// Key of the precision of the spatial index in the default tree, `0` if the
+/// index is disabled. Cell keys only make sense at the precision they were
+/// written with, so the index is rebuilt whenever it changes.
+const CELL_PRECISION_KEY: &str = "cell_precision";
+
+type StatusKey = [u8; 44];
+type SourceKey = [u8; 32];
+type TimeIndexKey = [u8; 44];
+/// `statuses`, `latest`, `cells` and `by_time` trees within a transaction.
+type TransactionalTrees =
+    (TransactionalTree, TransactionalTree, TransactionalTree, TransactionalTree);
+
+/// Smallest accepted size limit. Sled allocates space in 512 KiB segments, so
+/// even a nearly empty database may take up a few of them.
+const MIN_MAX_SIZE: u64 = 8 << 20;
+/// Number of entries copied at once while compacting.
+const COMPACT_BATCH: usize = 10_000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct SledConfig {
     pub db_dir: PathBuf,
+    /// Size of Sled's page cache in bytes. Sled's default of 1 GiB if not
+    /// specified.
+    pub cache_capacity: Option<u64>,
+    pub flush: FlushPolicy,
+    /// Compress stored pages with zstd.
+    pub compression: bool,
+    /// Once the database grows larger than this many bytes on disk, it's
+    /// compacted, and the oldest statuses are pruned if that isn't enough. At
+    /// least 8 MiB.
+    pub max_size: Option<u64>,
 }
 
 impl Default for SledConfig {
     fn default() -> Self {
-        Self { db_dir: PathBuf::from("./geo_track_sled_db") }
+        Self {
+            db_dir: PathBuf::from("./geo_track_sled_db"),
+            cache_capacity: None,
+            flush: FlushPolicy::default(),
+            compression: false,
+            max_size: None,
+        }
     }
 }
 
-pub struct SledStorage {
+/// Parses a storage directory followed by a comma-separated list of
+/// `key=value` options, e.g.
+/// `./db,cache=256MiB,flush=write,compression=true,max_size=10GiB`. Either
+/// part may be left out.
+impl FromStr for SledConfig {
+    type Err = StorageError;
+
+    fn from_str(s: &str) -> storage::Result<Self> {
+        let mut cfg = Self::default();
+        let mut parts = s.split(',');
+        if let Some(db_dir) = parts.next().filter(|dir| !dir.is_empty()) {
+            cfg.db_dir = PathBuf::from(db_dir);
+        }
+        for option in parts {
+            let invalid = || StorageError::InvalidStorageOption { option: option.to_owned() };
+            match option.split_once('=').ok_or_else(invalid)? {
+                ("cache", size) => cfg.cache_capacity = Some(parse_size(size).ok_or_else(invalid)?),
+                ("flush", "write") => cfg.flush = FlushPolicy::OnWrite,
+                ("flush", every) => {
+                    let every = humantime::parse_duration(every).map_err(|_| invalid())?;
+                    cfg.flush = FlushPolicy::Every(every);
+                }
+                ("compression", enabled) => {
+                    cfg.compression = enabled.parse().map_err(|_| invalid())?;
+                    if cfg.compression && !cfg!(feature = "sled-compression") {
+                        return Err(StorageError::StorageNotCompiled { name: option.to_owned() });
+                    }
+                }
+                ("max_size", size) => {
+                    let size = parse_size(size).filter(|&size| size >= MIN_MAX_SIZE);
+                    cfg.max_size = Some(size.ok_or_else(invalid)?);
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(cfg)
+    }
+}
+
+/// When writes are flushed to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// Periodically in the background. Writes made since the last flush are
+    /// lost if the process crashes.
+    Every(Duration),
+    /// Before each write is acknowledged. Much slower, but nothing is lost.
+    OnWrite,
+}
+
+impl Default for FlushPolicy {
+    fn default() -> Self {
+        Self::Every(Duration::from_millis(500))
+    }
+}
+
+/// A Sled database along with its open trees, replaced as a whole when the
+/// database is compacted.
+struct Trees {
     db: Db,
-    dupe_strategy: DupeStrategy,
+    statuses: Tree,
+    latest: Tree,
+    cells: Tree,
+    by_time: Tree,
+}
+
+impl Trees {
+    fn open(db: Db) -> storage::Result<Self> {
+        Ok(Self {
+            statuses: db.open_tree(STATUSES_TREE)?,
+            latest: db.open_tree(LATEST_TREE)?,
+            cells: db.open_tree(CELLS_TREE)?,
+            by_time: db.open_tree(BY_TIME_TREE)?,
+            db,
+        })
+    }
+
+    /// Bring data stored in an older layout up to date, and rebuild the
+    /// indexes. Keys of each newer layout are longer, so an interrupted
+    /// migration can safely be run again.
+    fn migrate(&self, version: Option<u8>, cell_index: Option<CellIndex>) -> storage::Result<()> {
+        if version.is_none() {
+            let prefix = TenantId::DEFAULT.as_uuid().as_bytes();
+            let mut migrated = 0;
+            for (tree, legacy_len) in [(&self.statuses, 24), (&self.latest, 16)] {
+                migrated += rekey(tree, legacy_len, |key| [prefix.as_slice(), key].concat())?;
+            }
+            if migrated > 0 {
+                tracing::info!(migrated, "Moved stored entries to the default tenant");
+            }
+        }
+        if version < Some(2) {
+            let migrated = rekey(&self.statuses, 40, |key| [key, &[0; 4]].concat())?;
+            if migrated > 0 {
+                tracing::info!(migrated, "Added nanoseconds to stored status keys");
+            }
+        }
+
+        self.cells.clear()?;
+        self.by_time.clear()?;
+        for entry in self.statuses.iter() {
+            let (key, value) = entry?;
+            let status = decode(&key, &value)?;
+            if let Some(cell) = cell_index.and_then(|index| index.cell(&status)) {
+                self.cells.insert(cell_key(status.tenant_id, &cell, &key), &[])?;
+            }
+            let key = time_index_key(status.tenant_id, status.timestamp, status.source_id);
+            self.by_time.insert(key, &[])?;
+        }
+        Ok(())
+    }
+
+    /// Rebuild the spatial index for the given precision, including statuses
+    /// stored while it was disabled.
+    fn reindex_cells(&self, cell_index: Option<CellIndex>) -> storage::Result<()> {
+        self.cells.clear()?;
+        let Some(index) = cell_index else {
+            return Ok(());
+        };
+        let mut indexed = 0;
+        for entry in self.statuses.iter() {
+            let (key, value) = entry?;
+            let status = decode(&key, &value)?;
+            if let Some(cell) = index.cell(&status) {
+                self.cells.insert(cell_key(status.tenant_id, &cell, &key), &[])?;
+                indexed += 1;
+            }
+        }
+        tracing::info!(indexed, precision = index.precision(), "Rebuilt spatial cell index");
+        Ok(())
+    }
+}
+
+/// Storage backed by Sled, which is safe to read from and write to from any
+/// number of tasks. Writes touching several trees are applied in transactions.
+pub struct SledStorage {
+    /// Shared by all operations except compaction, which swaps the database
+    /// out and thus has to wait for them to finish.
+    trees: RwLock<Trees>,
+    cfg: SledConfig,
+    dupe_strategy: Setting<DupeStrategy>,
+    cell_index: Option<CellIndex>,
+    size: metrics::Gauge,
+    /// Held by every open [`StatusStream`], which keeps the database open.
+    streams: Arc<()>,
 }
 
 impl SledStorage {
-    pub fn new(cfg: &SledConfig, dupe_strategy: DupeStrategy) -> storage::Result<Self> {
-        let db = sled::open(&cfg.db_dir)?;
-        Ok(Self { db, dupe_strategy })
+    pub fn new(
+        cfg: &SledConfig,
+        dupe_strategy: impl Into<Setting<DupeStrategy>>,
+        cell_index: Option<CellIndex>,
+    ) -> storage::Result<Self> {
+        let trees = Trees::open(open(cfg, &cfg.db_dir)?)?;
+        let version = trees.db.get(LAYOUT_KEY)?.and_then(|v| v.first().copied());
+        let precision = cell_index.map_or(0, |index| index.precision() as u8);
+        let stored_precision = trees.db.get(CELL_PRECISION_KEY)?.and_then(|v| v.first().copied());
+        if version != Some(LAYOUT_VERSION) {
+            trees.migrate(version, cell_index)?;
+            trees.db.insert(LAYOUT_KEY, &[LAYOUT_VERSION])?;
+        } else if stored_precision != Some(precision) {
+            trees.reindex_cells(cell_index)?;
+        }
+        trees.db.insert(CELL_PRECISION_KEY, &[precision])?;
+        Ok(Self {
+            trees: RwLock::new(trees),
+            cfg: cfg.clone(),
+            dupe_strategy: dupe_strategy.into(),
+            cell_index,
+            size: metrics::gauge("geo_sled_size_bytes", "Size of the Sled database on disk."),
+            streams: Arc::default(),
+        })
+    }
+
+    async fn trees(&self) -> RwLockReadGuard<'_, Trees> {
+        self.trees.read().await
+    }
+
+    /// Update the size gauge, and bring the database back under its size
+    /// limit if it's exceeded. Returns the number of pruned statuses.
+    pub async fn maintain(&self) -> storage::Result<usize> {
+        let size = self.trees().await.db.size_on_disk()?;
+        self.size.set(size as i64);
+        tracing::debug!(size, "Measured Sled database size");
+        let Some(max_size) = self.cfg.max_size.filter(|&max| size > max) else {
+            return Ok(0);
+        };
+
+        // Sled only reclaims space of removed entries over time, so try
+        // compacting before pruning anything.
+        self.compact().await?;
+        let size = self.trees().await.db.size_on_disk()?;
+        if size <= max_size {
+            return Ok(0);
+        }
+        tracing::warn!(size, max_size, "Database exceeds its size limit even after compaction");
+        let excess = (size - max_size) as f64 / size as f64;
+        let pruned = self.prune_oldest(excess).await?;
+        self.compact().await?;
+        Ok(pruned)
+    }
+
+    /// Remove the oldest `share` of all statuses, at least one.
+    async fn prune_oldest(&self, share: f64) -> storage::Result<usize> {
+        let (mut timestamps, sources) = {
+            let trees = self.trees().await;
+            let mut timestamps = Vec::with_capacity(trees.statuses.len());
+            for key in trees.statuses.iter().keys() {
+                timestamps.extend(key_timestamp(&key?));
+            }
+            (timestamps, trees.latest.iter().keys().collect::<Result<Vec<_>, _>>()?)
+        };
+        if timestamps.is_empty() {
+            return Ok(0);
+        }
+        let n = ((timestamps.len() as f64 * share).ceil() as usize).clamp(1, timestamps.len());
+        let (_, &mut cutoff, _) = timestamps.select_nth_unstable(n - 1);
+
+        let mut pruned = 0;
+        for key in sources {
+            let (tenant_id, source_id) = key_ids(&key)?;
+            pruned += self.remove_statuses(tenant_id, source_id, ..=cutoff).await?;
+        }
+        Ok(pruned)
+    }
+
+    /// Rewrite the database into a fresh directory, reclaiming the space of
+    /// removed entries, and swap it in place of the current one. Other
+    /// operations wait until it's done.
+    pub async fn compact(&self) -> storage::Result<()> {
+        let mut trees = self.trees.write().await;
+        // Streams would keep the replaced database open, and later write to
+        // the directory of its replacement.
+        if Arc::strong_count(&self.streams) > 1 {
+            return Err(StorageError::Busy);
+        }
+        let before = trees.db.size_on_disk()?;
+        let dir = self.cfg.db_dir.clone();
+        let compacted = sibling(&dir, "compacting");
+        let replaced = sibling(&dir, "replaced");
+        remove_dir(&compacted)?;
+        remove_dir(&replaced)?;
+
+        {
+            let target = open(&self.cfg, &compacted)?;
+            for name in trees.db.tree_names() {
+                copy_tree(&trees.db.open_tree(&name)?, &target.open_tree(&name)?)?;
+            }
+            target.flush_async().await?;
+        }
+
+        // Sled can't be opened twice, so the current database has to be closed
+        // before its directory is swapped out.
+        *trees = Trees::open(sled::Config::new().temporary(true).open()?)?;
+        let swapped = swap_dirs(&dir, &compacted, &replaced);
+        // Either the compacted database, or the original one if swapping failed.
+        *trees = Trees::open(open(&self.cfg, &dir)?)?;
+        swapped?;
+        remove_dir(&replaced)?;
+
+        let after = trees.db.size_on_disk()?;
+        self.size.set(after as i64);
+        tracing::info!(before, after, "Compacted Sled database");
+        Ok(())
+    }
+
+    /// Writes a single status along with its index entries within a
+    /// transaction over all trees.
+    fn persist_in(
+        &self,
+        (statuses, latest, cells, by_time): &TransactionalTrees,
+        status: Status,
+        dupe_strategy: DupeStrategy,
+    ) -> ConflictableTransactionResult<(), StorageError> {
+        let key = status_key(status.tenant_id, status.source_id, status.timestamp);
+        let source_key = source_key(status.tenant_id, status.source_id);
+        let abort = ConflictableTransactionError::Abort;
+
+        let existing = statuses.get(key)?.map(|v| decode(&key, &v)).transpose().map_err(abort)?;
+        let stored = match (existing, dupe_strategy) {
+            (Some(existing), DupeStrategy::Drop) => existing,
+            (Some(existing), DupeStrategy::Merge) => existing.merge(&status),
+            (_, _) => status,
+        };
+        let value = codec::encode(&stored);
+        statuses.insert(&key[..], value.as_slice())?;
+        if existing.is_none() {
+            let time_key = time_index_key(status.tenant_id, status.timestamp, status.source_id);
+            by_time.insert(&time_key[..], &[])?;
+        }
+
+        if let Some(index) = self.cell_index {
+            let old_cell = existing.and_then(|s| index.cell(&s));
+            let new_cell = index.cell(&stored);
+            if old_cell != new_cell {
+                if let Some(cell) = old_cell {
+                    cells.remove(cell_key(status.tenant_id, &cell, &key))?;
+                }
+                if let Some(cell) = new_cell {
+                    cells.insert(cell_key(status.tenant_id, &cell, &key), &[])?;
+                }
+            }
+        }
+
+        let is_latest = match latest.get(source_key)? {
+            Some(v) => decode(&source_key, &v).map_err(abort)?.timestamp <= stored.timestamp,
+            None => true,
+        };
+        if is_latest {
+            latest.insert(&source_key[..], value)?;
+        }
+        Ok(())
+    }
+
+    async fn flush_if_required(&self, trees: &Trees) -> storage::Result<()> {
+        if self.cfg.flush == FlushPolicy::OnWrite {
+            trees.db.flush_async().await?;
+        }
+        Ok(())
     }
 }
 
 #[async_trait]
 impl Storage for SledStorage {
     #[tracing::instrument(skip(self))]
-    async fn persist_status(&mut self, status: Status) -> storage::Result<()> {
-        todo!();
+    async fn persist_status(&self, status: Status) -> storage::Result<()> {
+        let trees = self.trees().await;
+        let dupe_strategy = self.dupe_strategy.get();
+        (&trees.statuses, &trees.latest, &trees.cells, &trees.by_time)
+            .transaction(|tx| self.persist_in(tx, status, dupe_strategy))
+            .map_err(transaction_error)?;
+
+        self.flush_if_required(&trees).await
+    }
+
+    /// All statuses of the batch are written in a single transaction, which
+    /// is aborted as a whole by the first one that fails.
+    #[tracing::instrument(skip(self, statuses), fields(count = statuses.len()))]
+    async fn persist_batch_atomic(&self, statuses: Vec<Status>) -> storage::Result<()> {
+        let trees = self.trees().await;
+        let dupe_strategy = self.dupe_strategy.get();
+        (&trees.statuses, &trees.latest, &trees.cells, &trees.by_time)
+            .transaction(|tx| {
+                statuses.iter().try_for_each(|status| self.persist_in(tx, *status, dupe_strategy))
+            })
+            .map_err(|err| StorageError::BatchRolledBack {
+                count: statuses.len(),
+                source: Box::new(transaction_error(err)),
+            })?;
+
+        self.flush_if_required(&trees).await
     }
 
     #[tracing::instrument(skip(self))]
     async fn get_statuses<R>(
         &self,
-        _source_id: SourceId,
-        _timestamps: R,
+        tenant_id: TenantId,
+        source_id: SourceId,
+        timestamps: R,
     ) -> storage::Result<Vec<Status>>
     where
         R: RangeBounds<OffsetDateTime> + Send + Debug,
     {
-        todo!();
+        self.trees()
+            .await
+            .statuses
+            .range(key_range(tenant_id, source_id, &timestamps))
+            .map(|entry| {
+                let (key, value) = entry?;
+                decode(&key, &value)
+            })
+            .collect()
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn stream_statuses<R>(
+        &self,
+        tenant_id: TenantId,
+        source_id: SourceId,
+        timestamps: R,
+    ) -> storage::Result<StatusStream>
+    where
+        R: RangeBounds<OffsetDateTime> + Send + Debug,
+    {
+        let streams = self.streams.clone();
+        let entries =
+            self.trees().await.statuses.range(key_range(tenant_id, source_id, &timestamps));
+        let statuses = entries.map(move |entry| {
+            let _open = &streams;
+            let (key, value) = entry?;
+            decode(&key, &value)
+        });
+        Ok(stream::iter(statuses).boxed())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn remove_statuses<R>(
+        &self,
+        tenant_id: TenantId,
+        source_id: SourceId,
+        timestamps: R,
+    ) -> storage::Result<usize>
+    where
+        R: RangeBounds<OffsetDateTime> + Send + Debug,
+    {
+        let trees = self.trees().await;
+        let mut removed = 0;
+        for entry in trees.statuses.range(key_range(tenant_id, source_id, &timestamps)) {
+            let (key, value) = entry?;
+            trees.statuses.remove(&key)?;
+            if let Some(ts) = key_timestamp(&key) {
+                trees.by_time.remove(time_index_key(tenant_id, ts, source_id))?;
+            }
+            if let Some(cell) =
+                self.cell_index.and_then(|index| index.cell(&decode(&key, &value).ok()?))
+            {
+                trees.cells.remove(cell_key(tenant_id, &cell, &key))?;
+            }
+            removed += 1;
+        }
+
+        // Statuses of the source may be persisted meanwhile, so the latest one
+        // is only replaced if it's still among the removed ones.
+        let source_key = source_key(tenant_id, source_id);
+        let newest = trees.statuses.range(key_range(tenant_id, source_id, &..)).next_back();
+        let newest = newest.transpose()?.map(|(_, value)| value);
+        trees
+            .latest
+            .transaction(|latest| {
+                let Some(current) = latest.get(source_key)? else {
+                    return Ok(());
+                };
+                let current =
+                    decode(&source_key, &current).map_err(ConflictableTransactionError::Abort)?;
+                if timestamps.contains(&current.timestamp) {
+                    match &newest {
+                        Some(value) => latest.insert(&source_key[..], value)?,
+                        None => latest.remove(&source_key[..])?,
+                    };
+                }
+                Ok(())
+            })
+            .map_err(transaction_error)?;
+
+        self.flush_if_required(&trees).await?;
+        Ok(removed)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_all_statuses<R>(
+        &self,
+        tenant_id: TenantId,
+        timestamps: R,
+        after: Option<TimeKey>,
+        limit: usize,
+    ) -> storage::Result<Vec<Status>>
+    where
+        R: RangeBounds<OffsetDateTime> + Send + Debug,
+    {
+        let (start, end) = time_index_range(tenant_id, &timestamps);
+        let start = match after {
+            Some((ts, source_id)) => Bound::Excluded(time_index_key(tenant_id, ts, source_id)),
+            None => start,
+        };
+        let trees = self.trees().await;
+        let mut statuses = Vec::new();
+        for entry in trees.by_time.range((start, end)).keys().take(limit) {
+            let key = entry?;
+            let ts = decode_timestamp(&key[16..28]).ok_or(StorageError::CorruptStatus)?;
+            let source_id = SourceId::from_key(&key[28..]).ok_or(StorageError::CorruptStatus)?;
+            let key = status_key(tenant_id, source_id, ts);
+            if let Some(value) = trees.statuses.get(key)? {
+                statuses.push(decode(&key, &value)?);
+            }
+        }
+        Ok(statuses)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn latest_many(
+        &self,
+        tenant_id: Option<TenantId>,
+        source_ids: Option<&[SourceId]>,
+    ) -> storage::Result<Vec<Status>> {
+        let trees = self.trees().await;
+        let tenant_prefix = tenant_id.map(|t| t.as_uuid().as_bytes().to_vec()).unwrap_or_default();
+        match (tenant_id, source_ids) {
+            (Some(tenant_id), Some(ids)) => ids
+                .iter()
+                .map(|&id| source_key(tenant_id, id))
+                .filter_map(|key| Some((key, trees.latest.get(key).transpose()?)))
+                .map(|(key, value)| decode(&key, &value?))
+                .collect(),
+            (_, ids) => {
+                let mut statuses = Vec::new();
+                for entry in trees.latest.scan_prefix(tenant_prefix) {
+                    let (key, value) = entry?;
+                    let status = decode(&key, &value)?;
+                    if ids.is_none_or(|ids| ids.contains(&status.source_id)) {
+                        statuses.push(status);
+                    }
+                }
+                Ok(statuses)
+            }
+        }
+    }
+
+    /// Only decodes the timestamps of the latest status.
+    #[tracing::instrument(skip(self))]
+    async fn latest_version(
+        &self,
+        tenant_id: TenantId,
+        source_id: SourceId,
+    ) -> storage::Result<Option<LatestVersion>> {
+        let trees = self.trees().await;
+        let value = trees.latest.get(source_key(tenant_id, source_id))?;
+        value.map(|value| codec::decode_version(&value)).transpose()
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_cell_statuses(
+        &self,
+        tenant_id: TenantId,
+        cell: &str,
+    ) -> storage::Result<Vec<Status>> {
+        let index = self.cell_index.ok_or(StorageError::CellIndexDisabled)?;
+        let (prefix, exact) = index.scan_prefix(cell)?;
+
+        let trees = self.trees().await;
+        let mut statuses = Vec::new();
+        for entry in trees.cells.scan_prefix(cell_key(tenant_id, prefix, &[])) {
+            let (cell_key, _) = entry?;
+            let status_key = &cell_key[16 + index.precision()..];
+            if let Some(value) = trees.statuses.get(status_key)? {
+                let status = decode(status_key, &value)?;
+                if storage::within_cell(&status, exact) {
+                    statuses.push(status);
+                }
+            }
+        }
+        Ok(statuses)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn stats(&self, tenant_id: Option<TenantId>) -> storage::Result<StorageStats> {
+        let trees = self.trees().await;
+        let Some(tenant_id) = tenant_id else {
+            return Ok(StorageStats {
+                sources: trees.latest.len(),
+                statuses: trees.statuses.len(),
+            });
+        };
+        let prefix = tenant_id.as_uuid().as_bytes();
+        Ok(StorageStats {
+            sources: trees.latest.scan_prefix(prefix).count(),
+            statuses: trees.statuses.scan_prefix(prefix).count(),
+        })
+    }
+}
+
+/// Unwraps the error of a failed transaction.
+fn transaction_error(err: TransactionError<StorageError>) -> StorageError {
+    match err {
+        TransactionError::Abort(err) => err,
+        TransactionError::Storage(err) => err.into(),
+    }
+}
+
+/// Converts a time range into a range of storage keys of a given source.
+fn key_range<R>(
+    tenant_id: TenantId,
+    source_id: SourceId,
+    timestamps: &R,
+) -> (Bound<StatusKey>, Bound<StatusKey>)
+where
+    R: RangeBounds<OffsetDateTime>,
+{
+    let key = |ts| status_key(tenant_id, source_id, ts);
+    let start = match timestamps.start_bound() {
+        Bound::Included(ts) => Bound::Included(key(*ts)),
+        Bound::Excluded(ts) => Bound::Excluded(key(*ts)),
+        Bound::Unbounded => Bound::Included(key_with_suffix(tenant_id, source_id, [0x00; 12])),
+    };
+    let end = match timestamps.end_bound() {
+        Bound::Included(ts) => Bound::Included(key(*ts)),
+        Bound::Excluded(ts) => Bound::Excluded(key(*ts)),
+        Bound::Unbounded => Bound::Included(key_with_suffix(tenant_id, source_id, [0xff; 12])),
+    };
+    (start, end)
+}
+
+/// Converts a time range into a range of time index keys of a given tenant.
+fn time_index_range<R>(
+    tenant_id: TenantId,
+    timestamps: &R,
+) -> (Bound<TimeIndexKey>, Bound<TimeIndexKey>)
+where
+    R: RangeBounds<OffsetDateTime>,
+{
+    let key =
+        |ts: &OffsetDateTime, source| time_index_key_with(tenant_id, encode_timestamp(*ts), source);
+    // Excluded timestamps are bounded from the far side of all sources.
+    let start = match timestamps.start_bound() {
+        Bound::Included(ts) => Bound::Included(key(ts, [0x00; 16])),
+        Bound::Excluded(ts) => Bound::Excluded(key(ts, [0xff; 16])),
+        Bound::Unbounded => Bound::Included(time_index_key_with(tenant_id, [0x00; 12], [0x00; 16])),
+    };
+    let end = match timestamps.end_bound() {
+        Bound::Included(ts) => Bound::Included(key(ts, [0xff; 16])),
+        Bound::Excluded(ts) => Bound::Excluded(key(ts, [0x00; 16])),
+        Bound::Unbounded => Bound::Included(time_index_key_with(tenant_id, [0xff; 12], [0xff; 16])),
+    };
+    (start, end)
+}
+
+/// Encodes a storage key that sorts by `tenant_id` first, then by `source_id`,
+/// then by `timestamp`.
+fn status_key(tenant_id: TenantId, source_id: SourceId, timestamp: OffsetDateTime) -> StatusKey {
+    key_with_suffix(tenant_id, source_id, encode_timestamp(timestamp))
+}
+
+/// Encodes a time index key that sorts by `tenant_id` first, then by
+/// `timestamp`, then by `source_id`.
+fn time_index_key(
+    tenant_id: TenantId,
+    timestamp: OffsetDateTime,
+    source_id: SourceId,
+) -> TimeIndexKey {
+    time_index_key_with(tenant_id, encode_timestamp(timestamp), source_id.to_key())
+}
+
+fn time_index_key_with(tenant_id: TenantId, timestamp: [u8; 12], source: [u8; 16]) -> TimeIndexKey {
+    let mut key = [0; 44];
+    key[..16].copy_from_slice(tenant_id.as_uuid().as_bytes());
+    key[16..28].copy_from_slice(&timestamp);
+    key[28..].copy_from_slice(&source);
+    key
+}
+
+fn encode_timestamp(timestamp: OffsetDateTime) -> [u8; 12] {
+    // Flipping the sign bit makes big-endian byte order match numeric order
+    // for negative timestamps as well. Nanoseconds are never negative.
+    let secs = (timestamp.unix_timestamp() as u64) ^ (1 << 63);
+    let mut bytes = [0; 12];
+    bytes[..8].copy_from_slice(&secs.to_be_bytes());
+    bytes[8..].copy_from_slice(&timestamp.nanosecond().to_be_bytes());
+    bytes
+}
+
+fn key_with_suffix(tenant_id: TenantId, source_id: SourceId, suffix: [u8; 12]) -> StatusKey {
+    let mut key = [0; 44];
+    key[..32].copy_from_slice(&source_key(tenant_id, source_id));
+    key[32..].copy_from_slice(&suffix);
+    key
+}
+
+fn source_key(tenant_id: TenantId, source_id: SourceId) -> SourceKey {
+    let mut key = [0; 32];
+    key[..16].copy_from_slice(tenant_id.as_uuid().as_bytes());
+    key[16..].copy_from_slice(&source_id.to_key());
+    key
+}
+
+fn cell_key(tenant_id: TenantId, cell: &str, status_key: &[u8]) -> Vec<u8> {
+    [tenant_id.as_uuid().as_bytes(), cell.as_bytes(), status_key].concat()
+}
+
+/// Replaces keys of `tree` that are `len` bytes long with `new_key(key)`,
+/// returning the number of replaced keys.
+fn rekey(tree: &Tree, len: usize, new_key: impl Fn(&[u8]) -> Vec<u8>) -> storage::Result<usize> {
+    let mut batch = Batch::default();
+    let mut replaced = 0;
+    for entry in tree.iter() {
+        let (key, value) = entry?;
+        if key.len() == len {
+            batch.insert(new_key(&key), value);
+            batch.remove(key);
+            replaced += 1;
+        }
+    }
+    tree.apply_batch(batch)?;
+    Ok(replaced)
+}
+
+/// Takes the tenant and source from a key that starts with a [`SourceKey`].
+fn key_ids(key: &[u8]) -> storage::Result<(TenantId, SourceId)> {
+    let uuid = |bytes: Option<&[u8]>| {
+        bytes.and_then(|b| Uuid::from_slice(b).ok()).ok_or(StorageError::CorruptStatus)
+    };
+    let tenant_id = TenantId::from_uuid(uuid(key.get(..16))?);
+    let source_id =
+        key.get(16..32).and_then(SourceId::from_key).ok_or(StorageError::CorruptStatus)?;
+    Ok((tenant_id, source_id))
+}
+
+/// Reverses the timestamp encoding of [`status_key`].
+fn key_timestamp(key: &[u8]) -> Option<OffsetDateTime> {
+    decode_timestamp(key.get(32..44)?)
+}
+
+/// Reverses [`encode_timestamp`].
+fn decode_timestamp(bytes: &[u8]) -> Option<OffsetDateTime> {
+    let secs = u64::from_be_bytes(bytes.get(..8)?.try_into().ok()?) ^ (1 << 63);
+    let nanos = u32::from_be_bytes(bytes.get(8..12)?.try_into().ok()?);
+    OffsetDateTime::from_unix_timestamp(secs as i64).ok()?.replace_nanosecond(nanos).ok()
+}
+
+/// Decodes a stored status, taking its tenant and source from `key`, which
+/// starts with a [`SourceKey`].
+fn decode(key: &[u8], value: &[u8]) -> storage::Result<Status> {
+    let (tenant_id, source_id) = key_ids(key)?;
+    codec::decode(tenant_id, source_id, value)
+}
+
+fn open(cfg: &SledConfig, dir: &Path) -> storage::Result<Db> {
+    let flush_every_ms = match cfg.flush {
+        FlushPolicy::Every(every) => Some(every.as_millis().max(1) as u64),
+        FlushPolicy::OnWrite => None,
+    };
+    let mut config = sled::Config::new().path(dir).flush_every_ms(flush_every_ms);
+    if let Some(capacity) = cfg.cache_capacity {
+        config = config.cache_capacity(capacity);
+    }
+    if cfg.compression {
+        config = config.use_compression(true);
+    }
+    Ok(config.open()?)
+}
+
+/// Copies all entries of `src` into `dst`.
+fn copy_tree(src: &Tree, dst: &Tree) -> storage::Result<()> {
+    let mut batch = Batch::default();
+    for (i, entry) in src.iter().enumerate() {
+        let (key, value) = entry?;
+        batch.insert(key, value);
+        if (i + 1) % COMPACT_BATCH == 0 {
+            dst.apply_batch(std::mem::take(&mut batch))?;
+        }
+    }
+    dst.apply_batch(batch)?;
+    Ok(())
+}
+
+/// Moves `dir` to `replaced` and `replacement` into its place, moving `dir`
+/// back if the latter fails.
+fn swap_dirs(dir: &Path, replacement: &Path, replaced: &Path) -> io::Result<()> {
+    fs::rename(dir, replaced)?;
+    fs::rename(replacement, dir).or_else(|err| {
+        fs::rename(replaced, dir)?;
+        Err(err)
+    })
+}
+
+/// Path next to `dir`, with `suffix` appended to its name.
+fn sibling(dir: &Path, suffix: &str) -> PathBuf {
+    let mut name = dir.file_name().unwrap_or_default().to_owned();
+    name.push(format!(".{suffix}"));
+    dir.with_file_name(name)
+}
+
+fn remove_dir(dir: &Path) -> io::Result<()> {
+    match fs::remove_dir_all(dir) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Parses a number of bytes, optionally with a binary unit suffix, e.g.
+/// `512MiB`.
+fn parse_size(s: &str) -> Option<u64> {
+    let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (n, unit) = s.split_at(digits);
+    let shift = match unit {
+        "" | "B" => 0,
+        "KiB" => 10,
+        "MiB" => 20,
+        "GiB" => 30,
+        "TiB" => 40,
+        _ => return None,
+    };
+    n.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ops::Bound,
+        path::PathBuf,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use futures_util::StreamExt;
+    use geo_types::Coord;
+    use shared::data::{Status, TenantId};
+    use time::OffsetDateTime;
+
+    use super::{sibling, status_key, FlushPolicy, SledConfig, SledStorage};
+    use crate::storage::{
+        test_status as status, CellIndex, DupeStrategy, LatestVersion, Storage, StorageError,
+    };
+
+    /// A directory of its own for every test, so that they can run in
+    /// parallel.
+    fn temp_dir(name: &str) -> PathBuf {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let next = NEXT.fetch_add(1, Ordering::Relaxed);
+        std::env::temp_dir().join(format!("geo-track-sled-{name}-{}-{next}", std::process::id()))
+    }
+
+    #[test]
+    fn parse_config() {
+        assert_eq!("".parse::<SledConfig>().unwrap(), SledConfig::default());
+        assert_eq!(
+            "db,cache=64MiB,flush=write,max_size=1GiB".parse::<SledConfig>().unwrap(),
+            SledConfig {
+                db_dir: PathBuf::from("db"),
+                cache_capacity: Some(64 << 20),
+                flush: FlushPolicy::OnWrite,
+                compression: false,
+                max_size: Some(1 << 30),
+            }
+        );
+        assert_eq!(
+            ",flush=2s".parse::<SledConfig>().unwrap().flush,
+            FlushPolicy::Every(Duration::from_secs(2))
+        );
+        assert!("db,cache=64MB".parse::<SledConfig>().is_err());
+        assert!("db,max_size=1MiB".parse::<SledConfig>().is_err());
+        assert!("db,flush=sometimes".parse::<SledConfig>().is_err());
+        assert!("db,compression=yes".parse::<SledConfig>().is_err());
+    }
+
+    #[tokio::test]
+    async fn compact_and_prune() {
+        let dir = temp_dir("cp");
+        let cfg = SledConfig { db_dir: dir.clone(), ..Default::default() };
+        let storage = SledStorage::new(&cfg, DupeStrategy::Merge, None).unwrap();
+        let now = OffsetDateTime::now_utc();
+        let ago = |secs| now - Duration::from_secs(secs);
+        let statuses =
+            [status(1, ago(50)), status(1, ago(40)), status(2, ago(30)), status(2, ago(5))];
+        for status in statuses {
+            storage.persist_status(status).await.unwrap();
+        }
+
+        let stream = storage.stream_statuses(TenantId::DEFAULT, statuses[0].source_id, ..);
+        let mut stream = stream.await.unwrap();
+        assert_eq!(stream.next().await.unwrap().unwrap().timestamp, ago(50));
+        assert!(matches!(storage.compact().await, Err(StorageError::Busy)));
+        drop(stream);
+
+        storage.compact().await.unwrap();
+        assert!(!sibling(&dir, "compacting").exists() && !sibling(&dir, "replaced").exists());
+        assert_eq!(storage.stats(None).await.unwrap().statuses, 4);
+        storage.persist_status(status(3, ago(45))).await.unwrap();
+
+        assert_eq!(storage.prune_oldest(0.5).await.unwrap(), 3);
+        let latest = storage.latest_many(None, None).await.unwrap();
+        assert_eq!(latest.iter().map(|s| s.timestamp).collect::<Vec<_>>(), [ago(5)]);
+        let remaining = storage.get_statuses(TenantId::DEFAULT, statuses[2].source_id, ..);
+        assert_eq!(remaining.await.unwrap().len(), 2);
+
+        drop(storage);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn time_index() {
+        let dir = temp_dir("ti");
+        let cfg = SledConfig { db_dir: dir.clone(), ..Default::default() };
+        let storage = SledStorage::new(&cfg, DupeStrategy::Merge, None).unwrap();
+        let now = OffsetDateTime::now_utc();
+        let ago = |secs| now - Duration::from_secs(secs);
+        let statuses =
+            [status(2, ago(30)), status(1, ago(20)), status(2, ago(20)), status(1, ago(10))];
+        for status in statuses.iter().rev() {
+            storage.persist_status(*status).await.unwrap();
+        }
+        let keys = |statuses: Vec<Status>| {
+            statuses.iter().map(|s| (s.timestamp, s.source_id)).collect::<Vec<_>>()
+        };
+        let key = |status: &Status| (status.timestamp, status.source_id);
+
+        let all = storage.get_all_statuses(TenantId::DEFAULT, .., None, 10).await.unwrap();
+        assert_eq!(keys(all), statuses.iter().map(key).collect::<Vec<_>>());
+        let page =
+            storage.get_all_statuses(TenantId::DEFAULT, ago(30).., Some(key(&statuses[0])), 2);
+        assert_eq!(keys(page.await.unwrap()), [key(&statuses[1]), key(&statuses[2])]);
+        let range = (Bound::Excluded(ago(30)), Bound::Excluded(ago(10)));
+        let within = storage.get_all_statuses(TenantId::DEFAULT, range, None, 10);
+        assert_eq!(keys(within.await.unwrap()), [key(&statuses[1]), key(&statuses[2])]);
+
+        storage.remove_statuses(TenantId::DEFAULT, statuses[1].source_id, ..).await.unwrap();
+        let all = storage.get_all_statuses(TenantId::DEFAULT, .., None, 10).await.unwrap();
+        assert_eq!(keys(all), [key(&statuses[0]), key(&statuses[2])]);
+
+        drop(storage);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn cell_index_precision() {
+        let dir = temp_dir("ci");
+        let cfg =
+            SledConfig { db_dir: dir.clone(), flush: FlushPolicy::OnWrite, ..Default::default() };
+        let now = OffsetDateTime::now_utc();
+        let at = |source| Status {
+            position: Some(Coord { x: 24.7453, y: 59.4372 }),
+            ..status(source, now)
+        };
+        let open = |precision: Option<usize>| {
+            let index = precision.map(|precision| CellIndex::new(precision).unwrap());
+            // Sled's background threads may hold on to the lock of the
+            // database for a moment after it's dropped.
+            for attempt in 1.. {
+                match SledStorage::new(&cfg, DupeStrategy::Merge, index) {
+                    Err(StorageError::Sled(_)) if attempt < 50 => {
+                        std::thread::sleep(Duration::from_millis(20))
+                    }
+                    storage => return storage.unwrap(),
+                }
+            }
+            unreachable!()
+        };
+        async fn cell(storage: &SledStorage, cell: &str) -> usize {
+            storage.get_cell_statuses(TenantId::DEFAULT, cell).await.unwrap().len()
+        }
+
+        let storage = open(Some(6));
+        storage.persist_status(at(1)).await.unwrap();
+        assert_eq!(cell(&storage, "ud9d5h").await, 1);
+        drop(storage);
+        let storage = open(None);
+        storage.persist_status(at(2)).await.unwrap();
+        drop(storage);
+
+        // Statuses stored with another precision, or none, are indexed too.
+        let storage = open(Some(4));
+        assert_eq!(cell(&storage, "ud9d").await, 2);
+        assert_eq!(cell(&storage, "ud9d5h").await, 2);
+        assert_eq!(cell(&storage, "ud9e").await, 0);
+
+        drop(storage);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn atomic_batch() {
+        let dir = temp_dir("ab");
+        let cfg = SledConfig { db_dir: dir.clone(), ..Default::default() };
+        let storage = SledStorage::new(&cfg, DupeStrategy::Merge, None).unwrap();
+        let now = OffsetDateTime::now_utc();
+        let batch = vec![status(1, now), status(2, now), status(1, now)];
+        storage.persist_batch_atomic(batch).await.unwrap();
+        assert_eq!(storage.stats(None).await.unwrap().statuses, 2);
+
+        // A corrupt entry fails merging the second status into it.
+        let corrupt = status(2, now - Duration::from_secs(10));
+        let key = status_key(corrupt.tenant_id, corrupt.source_id, corrupt.timestamp);
+        storage.trees().await.statuses.insert(key, &[]).unwrap();
+        let batch = vec![status(1, now + Duration::from_secs(10)), corrupt];
+        let err = storage.persist_batch_atomic(batch).await.unwrap_err();
+        assert!(matches!(err, StorageError::BatchRolledBack { count: 2, .. }));
+        let kept = storage.get_statuses(TenantId::DEFAULT, status(1, now).source_id, ..).await;
+        assert_eq!(kept.unwrap().iter().map(|s| s.timestamp).collect::<Vec<_>>(), [now]);
+        let latest = storage.latest_many(None, None).await.unwrap();
+        assert_eq!(latest.iter().map(|s| s.timestamp).collect::<Vec<_>>(), [now, now]);
+
+        drop(storage);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_writes() {
+        let dir = temp_dir("cc");
+        let cfg = SledConfig { db_dir: dir.clone(), ..Default::default() };
+        let storage = Arc::new(SledStorage::new(&cfg, DupeStrategy::Merge, None).unwrap());
+        let now = OffsetDateTime::now_utc();
+
+        let writers = (0..8u64).map(|writer| {
+            let storage = storage.clone();
+            tokio::spawn(async move {
+                for n in 0..25 {
+                    let status = status(1, now - Duration::from_secs(n * 8 + writer));
+                    storage.persist_status(status).await.unwrap();
+                }
+            })
+        });
+        for writer in writers.collect::<Vec<_>>() {
+            writer.await.unwrap();
+        }
+
+        assert_eq!(storage.stats(None).await.unwrap().statuses, 200);
+        let latest = storage.latest_many(None, None).await.unwrap();
+        assert_eq!(latest.iter().map(|s| s.timestamp).collect::<Vec<_>>(), [now]);
+        let version = storage.latest_version(TenantId::DEFAULT, latest[0].source_id).await;
+        assert_eq!(version.unwrap(), Some(LatestVersion::of(&latest[0])));
+
+        drop(storage);
+        std::fs::remove_dir_all(dir).unwrap();
     }
 }
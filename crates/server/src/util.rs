@@ -1 +1,5 @@
-pub mod cbor;
+pub mod codec;
+pub mod compression;
+pub mod geodesy;
+pub mod geohash;
+pub mod hex;
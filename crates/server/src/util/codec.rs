@@ -0,0 +1,46 @@
+use std::io;
+
+use bytes::{Buf, BytesMut};
+use shared::{
+    codec::{self, Codec},
+    data::Status,
+};
+use thiserror::Error;
+use tokio_util::codec::Decoder;
+
+/// Errors of [`CodecDecoder`], which besides malformed statuses include those
+/// of reading the underlying stream.
+#[derive(Debug, Error)]
+pub enum DecodeError {
+    #[error(transparent)]
+    Codec(#[from] codec::Error),
+    #[error("IO error")]
+    Io(#[from] io::Error),
+}
+
+/// Decodes a stream of statuses encoded one after another with a [`Codec`].
+/// Whitespace between and after statuses is skipped.
+#[derive(Debug, Default)]
+pub struct CodecDecoder<C>(C);
+
+impl<C: Codec> Decoder for CodecDecoder<C> {
+    type Item = Status;
+    type Error = DecodeError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let Some((status, len)) = self.0.decode(src)? else { return Ok(None) };
+        src.advance(len);
+        Ok(Some(status))
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.decode(src)? {
+            Some(status) => Ok(Some(status)),
+            None if src.iter().all(u8::is_ascii_whitespace) => {
+                src.clear();
+                Ok(None)
+            }
+            None => Err(codec::Error::Truncated.into()),
+        }
+    }
+}
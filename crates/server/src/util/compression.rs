@@ -0,0 +1,199 @@
+//! Decompression of ingested payloads. Devices on metered connections may
+//! compress what they send, and batches of statuses compress extremely well.
+//! The flip side is that a tiny payload can decompress into gigabytes, so
+//! decompressed data is only let through as long as it stays within a ratio
+//! of the compressed data read so far.
+
+use std::{
+    io,
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{ready, Context, Poll},
+};
+
+use async_compression::tokio::bufread::{GzipDecoder, ZstdDecoder};
+use thiserror::Error;
+use tokio::io::{AsyncRead, BufReader, ReadBuf};
+
+use crate::metrics;
+
+/// Decompressed bytes that are always allowed, regardless of the ratio, as
+/// the first block of a stream may well expand past it.
+const ALLOWANCE: u64 = 64 * 1024;
+
+#[derive(Debug, Error)]
+pub enum CompressionError {
+    #[error("unsupported content encoding: {name}")]
+    UnsupportedEncoding { name: String },
+}
+
+/// Compression of a payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Magic number that every zstd frame starts with.
+    pub const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+    /// Wrap `reader` of compressed data into one of decompressed data, which
+    /// fails with [`io::ErrorKind::InvalidData`] once it has produced more
+    /// than `max_ratio` times as many bytes as it has consumed. Concatenated
+    /// gzip members or zstd frames are decompressed one after another.
+    pub fn decoder<R>(self, reader: R, max_ratio: u64) -> Pin<Box<dyn AsyncRead + Send>>
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+    {
+        let consumed = Arc::new(AtomicU64::new(0));
+        let reader = BufReader::new(Counted { inner: reader, count: consumed.clone() });
+        match self {
+            Self::Gzip => {
+                let mut decoder = GzipDecoder::new(reader);
+                decoder.multiple_members(true);
+                Box::pin(Limited::new(decoder, consumed, max_ratio))
+            }
+            Self::Zstd => {
+                let mut decoder = ZstdDecoder::new(reader);
+                decoder.multiple_members(true);
+                Box::pin(Limited::new(decoder, consumed, max_ratio))
+            }
+        }
+    }
+}
+
+/// Parses a `Content-Encoding` header value. Only a single encoding is
+/// supported.
+impl FromStr for Compression {
+    type Err = CompressionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "gzip" | "x-gzip" => Ok(Self::Gzip),
+            "zstd" => Ok(Self::Zstd),
+            name => Err(CompressionError::UnsupportedEncoding { name: name.to_owned() }),
+        }
+    }
+}
+
+/// Counts bytes read from the inner reader.
+struct Counted<R> {
+    inner: R,
+    count: Arc<AtomicU64>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Counted<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        self.count.fetch_add((buf.filled().len() - before) as u64, Ordering::Relaxed);
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Fails reads of decompressed data past the allowed ratio.
+struct Limited<R> {
+    inner: R,
+    /// Compressed bytes consumed by `inner`.
+    consumed: Arc<AtomicU64>,
+    produced: u64,
+    max_ratio: u64,
+    exceeded: metrics::Counter,
+}
+
+impl<R> Limited<R> {
+    fn new(inner: R, consumed: Arc<AtomicU64>, max_ratio: u64) -> Self {
+        let exceeded = metrics::counter(
+            "geo_decompression_limit_exceeded_total",
+            "Compressed payloads cut off for decompressing past the allowed ratio.",
+        );
+        Self { inner, consumed, produced: 0, max_ratio, exceeded }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Limited<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        self.produced += (buf.filled().len() - before) as u64;
+        let consumed = self.consumed.load(Ordering::Relaxed);
+        if self.produced > consumed.saturating_mul(self.max_ratio).max(ALLOWANCE) {
+            self.exceeded.inc();
+            // Reads that fail mustn't hand out any data.
+            buf.set_filled(before);
+            let message = format!("payload decompresses past {}:1", self.max_ratio);
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, message)));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_compression::tokio::bufread::{GzipEncoder, ZstdEncoder};
+    use tokio::io::AsyncReadExt;
+
+    use super::Compression;
+
+    const JSON: &[u8] =
+        br#"{"sourceId":"0aaec05a-0e7d-4fd5-abc0-0ba69e3cfe11","timestamp":1627364719}"#;
+
+    async fn compress(compression: Compression, bytes: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        match compression {
+            Compression::Gzip => GzipEncoder::new(bytes).read_to_end(&mut out).await,
+            Compression::Zstd => ZstdEncoder::new(bytes).read_to_end(&mut out).await,
+        }
+        .unwrap();
+        out
+    }
+
+    async fn decompress(compression: Compression, bytes: Vec<u8>) -> std::io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        let mut decoder = compression.decoder(std::io::Cursor::new(bytes), 100);
+        decoder.read_to_end(&mut out).await?;
+        Ok(out)
+    }
+
+    #[tokio::test]
+    async fn decompress_within_limits() {
+        for compression in [Compression::Gzip, Compression::Zstd] {
+            let once = compress(compression, JSON).await;
+            let twice = [once.clone(), once].concat();
+            assert_eq!(decompress(compression, twice).await.unwrap(), [JSON, JSON].concat());
+        }
+        let zstd = compress(Compression::Zstd, JSON).await;
+        assert!(zstd.starts_with(&Compression::ZSTD_MAGIC));
+    }
+
+    #[tokio::test]
+    async fn reject_bombs() {
+        let zeros = vec![0; 16 * 1024 * 1024];
+        for compression in [Compression::Gzip, Compression::Zstd] {
+            let bomb = compress(compression, &zeros).await;
+            let err = decompress(compression, bomb).await.unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn content_encoding() {
+        assert_eq!("gzip".parse::<Compression>().unwrap(), Compression::Gzip);
+        assert_eq!(" zstd".parse::<Compression>().unwrap(), Compression::Zstd);
+        assert!("br".parse::<Compression>().is_err());
+        assert!("gzip, zstd".parse::<Compression>().is_err());
+    }
+}
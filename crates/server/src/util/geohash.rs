@@ -0,0 +1,121 @@
+//! Minimal [geohash](https://en.wikipedia.org/wiki/Geohash) encoder used for
+//! bucketing positions into spatial cells.
+
+use std::ops::RangeInclusive;
+
+use geo_types::{Coord, Rect};
+
+const ALPHABET: &[u8; 32] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+/// Longest supported geohash, roughly 3.7cm x 1.9cm at the equator.
+pub const MAX_PRECISION: usize = 12;
+
+/// Encodes a `[lon, lat]` position into a geohash of the given length.
+/// `precision` is clamped to `1..=MAX_PRECISION`.
+pub fn encode(position: Coord<f64>, precision: usize) -> String {
+    let precision = precision.clamp(1, MAX_PRECISION);
+    let (mut lon, mut lat) = ((-180.0, 180.0), (-90.0, 90.0));
+    let mut hash = String::with_capacity(precision);
+    let mut even = true;
+
+    while hash.len() < precision {
+        let mut idx = 0;
+        for _ in 0..5 {
+            let (range, value) = if even { (&mut lon, position.x) } else { (&mut lat, position.y) };
+            let mid = (range.0 + range.1) / 2.0;
+            idx <<= 1;
+            if value >= mid {
+                idx |= 1;
+                range.0 = mid;
+            } else {
+                range.1 = mid;
+            }
+            even = !even;
+        }
+        hash.push(ALPHABET[idx] as char);
+    }
+
+    hash
+}
+
+/// Width and height in degrees of cells of the given length.
+pub fn cell_size(precision: usize) -> (f64, f64) {
+    let bits = 5 * precision.clamp(1, MAX_PRECISION) as i32;
+    let (lon_bits, lat_bits) = ((bits + 1) / 2, bits / 2);
+    (360.0 / 2f64.powi(lon_bits), 180.0 / 2f64.powi(lat_bits))
+}
+
+/// Cells of the given length that intersect `bbox`, or `None` if there are
+/// more than `limit` of them.
+pub fn covering(bbox: &Rect<f64>, precision: usize, limit: usize) -> Option<Vec<String>> {
+    let (width, height) = cell_size(precision);
+    let columns = cell_span(bbox.min().x, bbox.max().x, -180.0, 360.0, width);
+    let rows = cell_span(bbox.min().y, bbox.max().y, -90.0, 180.0, height);
+    if (columns.end() - columns.start() + 1) * (rows.end() - rows.start() + 1) > limit as u64 {
+        return None;
+    }
+    let mut cells = Vec::new();
+    for row in rows {
+        for column in columns.clone() {
+            // Encode cell centers to stay clear of rounding errors at the edges.
+            let center = Coord {
+                x: -180.0 + (column as f64 + 0.5) * width,
+                y: -90.0 + (row as f64 + 0.5) * height,
+            };
+            cells.push(encode(center, precision));
+        }
+    }
+    Some(cells)
+}
+
+/// Indices of the cells of size `step` that `min..=max` overlaps, along an
+/// axis starting at `origin` and `length` degrees long.
+fn cell_span(min: f64, max: f64, origin: f64, length: f64, step: f64) -> RangeInclusive<u64> {
+    let last = (length / step) as u64 - 1;
+    let index = |value: f64| (((value - origin) / step).max(0.0) as u64).min(last);
+    index(min)..=index(max)
+}
+
+/// Checks whether a string is a well-formed geohash.
+pub fn is_valid(hash: &str) -> bool {
+    (1..=MAX_PRECISION).contains(&hash.len()) && hash.bytes().all(|b| ALPHABET.contains(&b))
+}
+
+#[cfg(test)]
+mod tests {
+    use geo_types::{Coord, Rect};
+
+    use super::{cell_size, covering, encode, is_valid};
+
+    #[test]
+    fn encode_known_positions() {
+        assert_eq!(encode(Coord { x: -5.6, y: 42.6 }, 5), "ezs42");
+        assert_eq!(encode(Coord { x: 10.407_44, y: 57.649_11 }, 11), "u4pruydqqvj");
+        assert_eq!(encode(Coord { x: 0.0, y: 0.0 }, 1), "s");
+    }
+
+    #[test]
+    fn validate() {
+        assert!(is_valid("ud9wr7m"));
+        assert!(!is_valid(""));
+        assert!(!is_valid("ud9wr7a"));
+        assert!(!is_valid("0123456789bcd"));
+    }
+
+    #[test]
+    fn cover_bbox() {
+        assert_eq!(cell_size(1), (45.0, 45.0));
+        assert_eq!(cell_size(2), (11.25, 5.625));
+
+        let world = Rect::new(Coord { x: -180.0, y: -90.0 }, Coord { x: 180.0, y: 90.0 });
+        assert_eq!(covering(&world, 1, 32).unwrap().len(), 32);
+        assert_eq!(covering(&world, 2, 32), None);
+
+        // Straddles the border of two cells.
+        let bbox = Rect::new(Coord { x: -5.63, y: 42.6 }, Coord { x: -5.6, y: 42.61 });
+        let cells = covering(&bbox, 4, 32).unwrap();
+        assert_eq!(cells, [encode(bbox.min(), 4), encode(bbox.max(), 4)]);
+        assert_eq!(cells, ["ezef", "ezs4"]);
+        assert_eq!(covering(&bbox, 1, 32).unwrap(), ["e"]);
+    }
+}
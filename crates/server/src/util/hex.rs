@@ -0,0 +1,34 @@
+//! Lowercase hexadecimal encoding of binary data.
+
+const DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+pub fn encode(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .flat_map(|b| [DIGITS[usize::from(b >> 4)], DIGITS[usize::from(b & 0xf)]])
+        .map(char::from)
+        .collect()
+}
+
+/// Decodes a hexadecimal string of either case. Returns `None` if it's
+/// malformed.
+pub fn decode(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    let digit = |c: u8| char::from(c).to_digit(16).and_then(|d| u8::try_from(d).ok());
+    s.as_bytes().chunks(2).map(|pair| Some(digit(pair[0])? << 4 | digit(pair[1])?)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode};
+
+    #[test]
+    fn round_trip() {
+        assert_eq!(encode(&[0x00, 0x1f, 0xab]), "001fab");
+        assert_eq!(decode("001fAB"), Some(vec![0x00, 0x1f, 0xab]));
+        assert_eq!(decode("001"), None);
+        assert_eq!(decode("0g"), None);
+    }
+}
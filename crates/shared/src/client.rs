@@ -0,0 +1,840 @@
+//! This module implements the device side of the ingest wire format, so that
+//! firmware can send [`Status`] packets without reimplementing it by hand.
+//! Nothing here allocates: payloads are written into buffers provided by the
+//! caller.
+//!
+//! A status is either sent as a bare CBOR payload, or wrapped into a frame:
+//!
+//! ```text
+//! +-------+-------+-------------+---------+-----------------+
+//! | magic | flags | length (BE) | payload | CRC-32C (BE)    |
+//! | 1     | 1     | 2           | length  | 4, if flagged   |
+//! +-------+-------+-------------+---------+-----------------+
+//! ```
+//!
+//! The low nibble of `flags` holds the [`Encoding`] of the payload, and the
+//! high bit is set if the frame ends with a CRC-32C checksum of everything
+//! preceding it.
+//!
+//! Servers that require API keys expect every TCP connection and every UDP
+//! datagram to start with an `AUTH <key>\n` line (see [`encode_auth`]).
+
+use core::fmt::{self, Display, Write as _};
+
+use geo_types::Coord;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use uom::si::f64::{Angle, Velocity};
+
+use crate::data::{timestamp, Ack, SourceId, Status, TenantId};
+
+/// First byte of every frame.
+pub const FRAME_MAGIC: u8 = b'G';
+/// Length of the frame header preceding the payload.
+pub const FRAME_HEADER_LEN: usize = 4;
+/// Length of the optional checksum following the payload.
+pub const FRAME_CRC_LEN: usize = 4;
+/// Upper bound of the length of an encoded [`Status`] payload, in either
+/// encoding.
+pub const MAX_PAYLOAD_LEN: usize = 110;
+/// Upper bound of the length of a framed [`Status`], in either encoding.
+pub const MAX_FRAME_LEN: usize = FRAME_HEADER_LEN + MAX_PAYLOAD_LEN + FRAME_CRC_LEN;
+
+const FLAG_CRC: u8 = 0x80;
+const FLAG_ENCODING: u8 = 0x0f;
+
+/// Start of the line authenticating a connection or datagram.
+pub const AUTH_PREFIX: &[u8] = b"AUTH ";
+/// Upper bound of the length of the authentication line, newline included.
+pub const MAX_AUTH_LEN: usize = 128;
+
+/// Upper bound of the length of a CBOR-encoded [`Ack`].
+const MAX_ACK_LEN: usize = 32;
+
+/// Errors of encoding and decoding payloads.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The output buffer can't fit the encoded payload.
+    BufferTooSmall,
+    /// The first byte isn't [`FRAME_MAGIC`].
+    InvalidMagic,
+    /// The frame header has unknown flags set.
+    InvalidFlags(u8),
+    /// The frame checksum doesn't match its contents.
+    CrcMismatch,
+    /// The frame is shorter than its header says.
+    Truncated,
+    /// A postcard payload couldn't be encoded or decoded.
+    Postcard(postcard::Error),
+    /// Bytes received from the server aren't a valid [`Ack`].
+    InvalidAck,
+    /// The authentication line is malformed, or the key is invalid.
+    InvalidAuth,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::BufferTooSmall => f.write_str("buffer too small"),
+            Self::InvalidMagic => f.write_str("not a frame"),
+            Self::InvalidFlags(flags) => write!(f, "invalid frame flags: {flags:#04x}"),
+            Self::CrcMismatch => f.write_str("frame checksum mismatch"),
+            Self::Truncated => f.write_str("truncated frame"),
+            Self::Postcard(err) => write!(f, "postcard error: {err}"),
+            Self::InvalidAck => f.write_str("invalid acknowledgment"),
+            Self::InvalidAuth => f.write_str("invalid authentication line"),
+        }
+    }
+}
+
+impl core::error::Error for Error {}
+
+impl From<postcard::Error> for Error {
+    fn from(err: postcard::Error) -> Self {
+        match err {
+            postcard::Error::SerializeBufferFull => Self::BufferTooSmall,
+            err => Self::Postcard(err),
+        }
+    }
+}
+
+/// Encoding of a [`Status`] payload.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Encoding {
+    /// The same CBOR encoding as used for bare payloads. Self-describing, so
+    /// it's safe to extend [`Status`] with new fields.
+    #[default]
+    Cbor,
+    /// [postcard](https://docs.rs/postcard), about half the size of CBOR, but
+    /// only decodable if both sides agree on the exact layout of [`Status`].
+    Postcard,
+}
+
+impl Encoding {
+    const fn flags(self) -> u8 {
+        match self {
+            Self::Cbor => 0,
+            Self::Postcard => 1,
+        }
+    }
+
+    const fn from_flags(flags: u8) -> Option<Self> {
+        match flags & FLAG_ENCODING {
+            0 => Some(Self::Cbor),
+            1 => Some(Self::Postcard),
+            _ => None,
+        }
+    }
+
+    /// Encodes a bare payload into `buf`, returning its length.
+    pub fn encode(self, status: &Status, buf: &mut [u8]) -> Result<usize, Error> {
+        match self {
+            Self::Cbor => encode_cbor(status, buf),
+            Self::Postcard => Ok(postcard::to_slice(&Compact::from(status), buf)?.len()),
+        }
+    }
+}
+
+/// Builds framed [`Status`] payloads.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Encoder {
+    encoding: Encoding,
+    crc: bool,
+}
+
+impl Encoder {
+    /// Creates an encoder of frames without checksums.
+    #[must_use]
+    pub const fn new(encoding: Encoding) -> Self {
+        Self { encoding, crc: false }
+    }
+
+    /// Appends a CRC-32C checksum to each frame, for links that don't already
+    /// guarantee integrity.
+    #[must_use]
+    pub const fn with_crc(self) -> Self {
+        Self { crc: true, ..self }
+    }
+
+    /// Encodes a frame into `buf`, returning its length. A buffer of
+    /// [`MAX_FRAME_LEN`] bytes always suffices.
+    pub fn encode(&self, status: &Status, buf: &mut [u8]) -> Result<usize, Error> {
+        let crc_len = if self.crc { FRAME_CRC_LEN } else { 0 };
+        let end = buf
+            .len()
+            .checked_sub(crc_len)
+            .filter(|&end| end >= FRAME_HEADER_LEN)
+            .ok_or(Error::BufferTooSmall)?;
+        let payload_len = self.encoding.encode(status, &mut buf[FRAME_HEADER_LEN..end])?;
+        let length = u16::try_from(payload_len).map_err(|_| Error::BufferTooSmall)?;
+
+        let flags = self.encoding.flags() | if self.crc { FLAG_CRC } else { 0 };
+        buf[0] = FRAME_MAGIC;
+        buf[1] = flags;
+        buf[2..FRAME_HEADER_LEN].copy_from_slice(&length.to_be_bytes());
+        let mut len = FRAME_HEADER_LEN + payload_len;
+        if self.crc {
+            let crc = crc32c(&buf[..len]);
+            buf[len..len + FRAME_CRC_LEN].copy_from_slice(&crc.to_be_bytes());
+            len += FRAME_CRC_LEN;
+        }
+        Ok(len)
+    }
+}
+
+/// A frame decoded by [`decode_frame`].
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame<'a> {
+    /// Encoding of the payload.
+    pub encoding: Encoding,
+    /// The encoded [`Status`].
+    pub payload: &'a [u8],
+    /// Length of the whole frame, including the header and the checksum.
+    pub len: usize,
+}
+
+/// Decodes the frame at the start of `bytes`, verifying its checksum if it has
+/// one. Returns `None` if the frame isn't complete yet.
+pub fn decode_frame(bytes: &[u8]) -> Result<Option<Frame<'_>>, Error> {
+    match bytes.first() {
+        None => return Ok(None),
+        Some(&FRAME_MAGIC) => {}
+        Some(_) => return Err(Error::InvalidMagic),
+    }
+    let Some(header) = bytes.get(..FRAME_HEADER_LEN) else { return Ok(None) };
+    let flags = header[1];
+    let encoding = Encoding::from_flags(flags)
+        .filter(|_| flags & !(FLAG_CRC | FLAG_ENCODING) == 0)
+        .ok_or(Error::InvalidFlags(flags))?;
+    let payload_len = usize::from(u16::from_be_bytes([header[2], header[3]]));
+
+    let end = FRAME_HEADER_LEN + payload_len;
+    let crc_len = if flags & FLAG_CRC == 0 { 0 } else { FRAME_CRC_LEN };
+    let Some(frame) = bytes.get(..end + crc_len) else { return Ok(None) };
+    if crc_len > 0 {
+        let expected =
+            u32::from_be_bytes([frame[end], frame[end + 1], frame[end + 2], frame[end + 3]]);
+        if crc32c(&frame[..end]) != expected {
+            return Err(Error::CrcMismatch);
+        }
+    }
+    Ok(Some(Frame { encoding, payload: &frame[FRAME_HEADER_LEN..end], len: frame.len() }))
+}
+
+/// Writes the line authenticating a connection or datagram with `key` into
+/// `buf`, returning its length. Keys can't contain whitespace.
+pub fn encode_auth(key: &str, buf: &mut [u8]) -> Result<usize, Error> {
+    let len = AUTH_PREFIX.len() + key.len() + 1;
+    if key.is_empty() || key.bytes().any(|b| b.is_ascii_whitespace()) || len > MAX_AUTH_LEN {
+        return Err(Error::InvalidAuth);
+    }
+    let buf = buf.get_mut(..len).ok_or(Error::BufferTooSmall)?;
+    let (prefix, rest) = buf.split_at_mut(AUTH_PREFIX.len());
+    prefix.copy_from_slice(AUTH_PREFIX);
+    rest[..key.len()].copy_from_slice(key.as_bytes());
+    rest[key.len()] = b'\n';
+    Ok(len)
+}
+
+/// Decodes the authentication line at the start of `bytes`, returning the key
+/// along with the length of the line. Returns `None` if the line isn't
+/// complete yet.
+pub fn decode_auth(bytes: &[u8]) -> Result<Option<(&str, usize)>, Error> {
+    let prefix_len = AUTH_PREFIX.len().min(bytes.len());
+    if bytes[..prefix_len] != AUTH_PREFIX[..prefix_len] {
+        return Err(Error::InvalidAuth);
+    }
+    let Some(end) = bytes.iter().take(MAX_AUTH_LEN).position(|&b| b == b'\n') else {
+        return match bytes.len() < MAX_AUTH_LEN {
+            true => Ok(None),
+            false => Err(Error::InvalidAuth),
+        };
+    };
+    let key = bytes[..end].strip_prefix(AUTH_PREFIX).ok_or(Error::InvalidAuth)?;
+    let key = core::str::from_utf8(key).map_err(|_| Error::InvalidAuth)?;
+    let key = key.strip_suffix('\r').unwrap_or(key);
+    if key.is_empty() || key.contains(char::is_whitespace) {
+        return Err(Error::InvalidAuth);
+    }
+    Ok(Some((key, end + 1)))
+}
+
+/// Decodes a postcard payload. CBOR payloads are left to full-featured CBOR
+/// decoders, which need an allocator.
+pub fn decode_postcard(payload: &[u8]) -> Result<Status, Error> {
+    Ok(postcard::from_bytes::<Compact>(payload)?.into())
+}
+
+/// Decodes the postcard payload at the start of `bytes`, returning it along
+/// with its length.
+#[cfg(feature = "codec")]
+pub(crate) fn take_postcard(bytes: &[u8]) -> Result<(Status, usize), postcard::Error> {
+    let (compact, rest) = postcard::take_from_bytes::<Compact>(bytes)?;
+    Ok((compact.into(), bytes.len() - rest.len()))
+}
+
+/// CRC-32C (Castagnoli) checksum, as used in frames.
+#[must_use]
+pub fn crc32c(data: &[u8]) -> u32 {
+    const POLY: u32 = 0x82f6_3b78;
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ POLY } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// Layout of postcard payloads. Unlike [`Status`], doesn't skip empty fields,
+/// as postcard can't tell which ones were skipped, and leaves out fields set by
+/// the server. Timestamps are always truncated to whole seconds, as postcard
+/// can't tell which of the representations in [`timestamp`] was used.
+/// Satellite count, fix type, odometer and ignition were added to [`Status`]
+/// after this layout was fixed in deployed firmware, so they aren't carried
+/// either.
+#[derive(Serialize, Deserialize)]
+struct Compact {
+    source_id: SourceId,
+    #[serde(with = "time::serde::timestamp")]
+    timestamp: OffsetDateTime,
+    position: Option<Coord<f64>>,
+    bearing: Option<Angle>,
+    speed: Option<Velocity>,
+}
+
+impl From<&Status> for Compact {
+    fn from(status: &Status) -> Self {
+        Self {
+            source_id: status.source_id,
+            timestamp: status.timestamp,
+            position: status.position,
+            bearing: status.bearing,
+            speed: status.speed,
+        }
+    }
+}
+
+impl From<Compact> for Status {
+    fn from(compact: Compact) -> Self {
+        Self {
+            source_id: compact.source_id,
+            timestamp: compact.timestamp,
+            position: compact.position,
+            bearing: compact.bearing,
+            speed: compact.speed,
+            satellites: None,
+            fix: None,
+            odometer: None,
+            ignition: None,
+            received_at: None,
+            suspect_timestamp: false,
+            tenant_id: TenantId::DEFAULT,
+        }
+    }
+}
+
+/// Encodes a status the same way as its `Serialize` implementation does with
+/// a CBOR serializer, except that floats are always written in full
+/// precision, and fields set by the server are left out.
+fn encode_cbor(status: &Status, buf: &mut [u8]) -> Result<usize, Error> {
+    let fields = 2
+        + u64::from(status.position.is_some())
+        + u64::from(status.bearing.is_some())
+        + u64::from(status.speed.is_some());
+
+    let mut w = CborWriter { buf, len: 0 };
+    w.head(MAJOR_MAP, fields)?;
+    w.text("sourceId")?;
+    w.bytes(&status.source_id.to_key())?;
+    w.text("timestamp")?;
+    w.timestamp(status.timestamp)?;
+    if let Some(position) = status.position {
+        w.text("position")?;
+        w.head(MAJOR_MAP, 2)?;
+        w.text("x")?;
+        w.float(position.x)?;
+        w.text("y")?;
+        w.float(position.y)?;
+    }
+    if let Some(bearing) = status.bearing {
+        w.text("bearing")?;
+        w.float(bearing.value)?;
+    }
+    if let Some(speed) = status.speed {
+        w.text("speed")?;
+        w.float(speed.value)?;
+    }
+    Ok(w.len)
+}
+
+const MAJOR_UINT: u8 = 0;
+const MAJOR_NINT: u8 = 1;
+const MAJOR_BYTES: u8 = 2;
+const MAJOR_TEXT: u8 = 3;
+const MAJOR_MAP: u8 = 5;
+const MAJOR_SIMPLE: u8 = 7;
+
+const SIMPLE_FALSE: u8 = 20;
+const SIMPLE_TRUE: u8 = 21;
+const SIMPLE_F64: u8 = 27;
+
+struct CborWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl CborWriter<'_> {
+    fn put(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        let end = self.len + bytes.len();
+        self.buf.get_mut(self.len..end).ok_or(Error::BufferTooSmall)?.copy_from_slice(bytes);
+        self.len = end;
+        Ok(())
+    }
+
+    /// Writes the initial byte of an item, followed by its argument in the
+    /// fewest bytes possible.
+    fn head(&mut self, major: u8, value: u64) -> Result<(), Error> {
+        let major = major << 5;
+        match value {
+            0..=23 => self.put(&[major | value as u8]),
+            24..=0xff => self.put(&[major | 24, value as u8]),
+            0x100..=0xffff => {
+                self.put(&[major | 25])?;
+                self.put(&(value as u16).to_be_bytes())
+            }
+            0x1_0000..=0xffff_ffff => {
+                self.put(&[major | 26])?;
+                self.put(&(value as u32).to_be_bytes())
+            }
+            _ => {
+                self.put(&[major | 27])?;
+                self.put(&value.to_be_bytes())
+            }
+        }
+    }
+
+    fn int(&mut self, value: i64) -> Result<(), Error> {
+        match u64::try_from(value) {
+            Ok(value) => self.head(MAJOR_UINT, value),
+            Err(_) => self.head(MAJOR_NINT, !value as u64),
+        }
+    }
+
+    fn float(&mut self, value: f64) -> Result<(), Error> {
+        self.put(&[MAJOR_SIMPLE << 5 | SIMPLE_F64])?;
+        self.put(&value.to_be_bytes())
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.head(MAJOR_BYTES, bytes.len() as u64)?;
+        self.put(bytes)
+    }
+
+    fn text(&mut self, text: &str) -> Result<(), Error> {
+        self.head(MAJOR_TEXT, text.len() as u64)?;
+        self.put(text.as_bytes())
+    }
+
+    /// Writes a timestamp the same way [`timestamp::serialize`] does.
+    fn timestamp(&mut self, ts: OffsetDateTime) -> Result<(), Error> {
+        let Some(text) = timestamp::as_text(ts) else {
+            return self.int(ts.unix_timestamp());
+        };
+        // Formatting twice, first to learn the length, to avoid a buffer.
+        let mut len = TextLen(0);
+        write!(len, "{text}").map_err(|_| Error::BufferTooSmall)?;
+        self.head(MAJOR_TEXT, len.0 as u64)?;
+        write!(self, "{text}").map_err(|_| Error::BufferTooSmall)
+    }
+}
+
+impl fmt::Write for CborWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.put(s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+/// Counts the bytes of formatted text.
+struct TextLen(usize);
+
+impl fmt::Write for TextLen {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0 += s.len();
+        Ok(())
+    }
+}
+
+/// Reads definite-length CBOR items, returning `None` when running out of
+/// bytes.
+struct CborReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> CborReader<'a> {
+    fn byte(&mut self) -> Option<u8> {
+        let byte = *self.bytes.get(self.pos)?;
+        self.pos += 1;
+        Some(byte)
+    }
+
+    /// Reads the initial byte and the argument of an item. Simple values are
+    /// returned with their number as the argument.
+    fn head(&mut self) -> Option<Result<(u8, u64), Error>> {
+        let initial = self.byte()?;
+        let (major, info) = (initial >> 5, initial & 0x1f);
+        let len = match info {
+            0..=23 => return Some(Ok((major, u64::from(info)))),
+            24 => 1,
+            25 => 2,
+            26 => 4,
+            27 => 8,
+            _ => return Some(Err(Error::InvalidAck)),
+        };
+        let mut value = 0;
+        for _ in 0..len {
+            value = value << 8 | u64::from(self.byte()?);
+        }
+        Some(Ok((major, value)))
+    }
+
+    fn text(&mut self) -> Option<Result<&'a [u8], Error>> {
+        let len = match self.head()? {
+            Ok((MAJOR_TEXT, len)) => usize::try_from(len).unwrap_or(usize::MAX),
+            Ok(_) => return Some(Err(Error::InvalidAck)),
+            Err(err) => return Some(Err(err)),
+        };
+        let text = self.bytes.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(Ok(text))
+    }
+}
+
+/// Decodes a CBOR-encoded [`Ack`] at the start of `bytes`, returning it along
+/// with its length. Returns `None` if it isn't complete yet.
+fn decode_ack(bytes: &[u8]) -> Result<Option<(Ack, usize)>, Error> {
+    fn decode(r: &mut CborReader<'_>) -> Option<Result<Ack, Error>> {
+        let fields = match r.head()? {
+            Ok((MAJOR_MAP, fields)) => fields,
+            Ok(_) => return Some(Err(Error::InvalidAck)),
+            Err(err) => return Some(Err(err)),
+        };
+        let (mut ok, mut seq, mut count) = (None, None, None);
+        for _ in 0..fields {
+            let key = match r.text()? {
+                Ok(key) => key,
+                Err(err) => return Some(Err(err)),
+            };
+            let value = match r.head()? {
+                Ok(value) => value,
+                Err(err) => return Some(Err(err)),
+            };
+            match (key, value) {
+                (b"ok", (MAJOR_SIMPLE, n)) if n == u64::from(SIMPLE_TRUE) => ok = Some(true),
+                (b"ok", (MAJOR_SIMPLE, n)) if n == u64::from(SIMPLE_FALSE) => ok = Some(false),
+                (b"seq", (MAJOR_UINT, n)) => seq = Some(n),
+                (b"count", (MAJOR_UINT, n)) => count = u32::try_from(n).ok(),
+                _ => return Some(Err(Error::InvalidAck)),
+            }
+        }
+        match (ok, seq, count) {
+            (Some(ok), Some(seq), Some(count)) => Some(Ok(Ack { ok, seq, count })),
+            _ => Some(Err(Error::InvalidAck)),
+        }
+    }
+
+    let mut reader = CborReader { bytes, pos: 0 };
+    match decode(&mut reader) {
+        None if bytes.len() >= MAX_ACK_LEN => Err(Error::InvalidAck),
+        None => Ok(None),
+        Some(ack) => Ok(Some((ack?, reader.pos))),
+    }
+}
+
+/// Where a connection stands in the acknowledgment protocol, as tracked by
+/// [`AckTracker`].
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AckState {
+    /// Everything sent has been acknowledged.
+    Idle,
+    /// Some packets are still waiting for an acknowledgment.
+    Waiting,
+    /// The server failed to persist a packet and is closing the connection.
+    /// Everything not acknowledged needs to be resent over a new one.
+    Failed,
+}
+
+/// Progress reported by [`AckTracker::receive`].
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AckEvent {
+    /// Packets up to and including sequence number `seq` have been persisted
+    /// and may be discarded.
+    Acked {
+        /// Sequence number of the last persisted packet.
+        seq: u64,
+    },
+    /// The server failed to persist packets after sequence number `seq`.
+    Failed {
+        /// Sequence number of the last persisted packet.
+        seq: u64,
+    },
+}
+
+/// Client side of the acknowledgment protocol of TCP connections.
+///
+/// Packets sent over a connection are numbered from 1, and the server reports
+/// the number of the last one it persisted. Call [`AckTracker::sent`] for each
+/// packet written, feed whatever's read from the connection to
+/// [`AckTracker::receive`], and [`AckTracker::reset`] it when reconnecting.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone)]
+pub struct AckTracker {
+    sent: u64,
+    acked: u64,
+    failed: bool,
+    buf: [u8; MAX_ACK_LEN],
+    buf_len: usize,
+}
+
+impl Default for AckTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AckTracker {
+    /// Creates a tracker for a new connection.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self { sent: 0, acked: 0, failed: false, buf: [0; MAX_ACK_LEN], buf_len: 0 }
+    }
+
+    /// Records a packet as sent, returning its sequence number.
+    pub fn sent(&mut self) -> u64 {
+        self.sent += 1;
+        self.sent
+    }
+
+    /// Number of packets sent, but not acknowledged yet.
+    #[must_use]
+    pub const fn pending(&self) -> u64 {
+        self.sent - self.acked
+    }
+
+    /// Sequence number of the last acknowledged packet.
+    #[must_use]
+    pub const fn acked(&self) -> u64 {
+        self.acked
+    }
+
+    /// Current state of the connection.
+    #[must_use]
+    pub const fn state(&self) -> AckState {
+        if self.failed {
+            AckState::Failed
+        } else if self.sent == self.acked {
+            AckState::Idle
+        } else {
+            AckState::Waiting
+        }
+    }
+
+    /// Consumes bytes read from the connection until an acknowledgment is
+    /// complete, advancing `bytes` past what's been consumed. Call repeatedly
+    /// until it returns `None` to process everything that's been read.
+    pub fn receive(&mut self, bytes: &mut &[u8]) -> Result<Option<AckEvent>, Error> {
+        while !bytes.is_empty() {
+            let take = bytes.len().min(MAX_ACK_LEN - self.buf_len);
+            self.buf[self.buf_len..self.buf_len + take].copy_from_slice(&bytes[..take]);
+            let buffered = self.buf_len;
+            self.buf_len += take;
+
+            let Some((ack, len)) = decode_ack(&self.buf[..self.buf_len])? else {
+                *bytes = &bytes[take..];
+                continue;
+            };
+            *bytes = &bytes[len - buffered..];
+            self.buf_len = 0;
+
+            if ack.seq < self.acked || ack.seq > self.sent {
+                return Err(Error::InvalidAck);
+            }
+            self.acked = ack.seq;
+            return Ok(Some(if ack.ok {
+                AckEvent::Acked { seq: ack.seq }
+            } else {
+                self.failed = true;
+                AckEvent::Failed { seq: ack.seq }
+            }));
+        }
+        Ok(None)
+    }
+
+    /// Starts over for a new connection. Packets that were still pending need
+    /// to be resent over it.
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::marker::PhantomData;
+
+    use geo_types::Coord;
+    use time::macros::datetime;
+    use uom::si::Quantity;
+    use uuid::Uuid;
+
+    use super::{
+        crc32c, decode_auth, decode_frame, decode_postcard, encode_auth, AckEvent, AckState,
+        AckTracker, Encoder, Encoding, Error, MAX_FRAME_LEN,
+    };
+    use crate::data::{Ack, SourceId, Status, TenantId};
+
+    const FULL: Status = Status {
+        source_id: SourceId::from_uuid(Uuid::from_u128(0x0aaec05a_0e7d_4fd5_abc0_0ba69e3cfe11)),
+        timestamp: datetime!(2021-07-27 08:45:19 +3),
+        position: Some(Coord { x: 24.745_278, y: 59.437_222 }),
+        bearing: Some(Quantity { dimension: PhantomData, units: PhantomData, value: 1.234 }),
+        speed: Some(Quantity { dimension: PhantomData, units: PhantomData, value: 15. }),
+        satellites: None,
+        fix: None,
+        odometer: None,
+        ignition: None,
+        received_at: None,
+        suspect_timestamp: false,
+        tenant_id: TenantId::DEFAULT,
+    };
+
+    const MINIMAL: Status = Status { position: None, bearing: None, speed: None, ..FULL };
+
+    fn assert_same(a: &Status, b: &Status) {
+        assert_eq!(a.source_id, b.source_id);
+        assert_eq!(a.timestamp, b.timestamp);
+        assert_eq!(a.position, b.position);
+        assert_eq!(a.bearing, b.bearing);
+        assert_eq!(a.speed, b.speed);
+    }
+
+    #[test]
+    fn crc32c_check_value() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+    }
+
+    #[test]
+    fn cbor_payload() {
+        for status in [FULL, MINIMAL] {
+            let mut buf = [0; MAX_FRAME_LEN];
+            let len = Encoding::Cbor.encode(&status, &mut buf).unwrap();
+            let decoded: Status = ciborium::de::from_reader(&buf[..len]).unwrap();
+            assert_same(&decoded, &status);
+            assert_eq!(
+                Encoding::Cbor.encode(&status, &mut buf[..len - 1]),
+                Err(Error::BufferTooSmall)
+            );
+        }
+    }
+
+    #[test]
+    fn cbor_subsec_timestamp() {
+        let status = Status { timestamp: datetime!(2021-07-27 05:45:19.25 UTC), ..MINIMAL };
+        let mut buf = [0; MAX_FRAME_LEN];
+        let len = Encoding::Cbor.encode(&status, &mut buf).unwrap();
+        let mut expected = Vec::new();
+        ciborium::ser::into_writer(&status, &mut expected).unwrap();
+        assert_eq!(&buf[..len], expected);
+
+        let decoded: Status = ciborium::de::from_reader(&buf[..len]).unwrap();
+        match cfg!(feature = "subsec-timestamps") {
+            true => assert_eq!(decoded.timestamp, status.timestamp),
+            false => assert_eq!(decoded.timestamp, datetime!(2021-07-27 05:45:19 UTC)),
+        }
+    }
+
+    #[test]
+    fn frames() {
+        for encoder in [
+            Encoder::new(Encoding::Cbor),
+            Encoder::new(Encoding::Cbor).with_crc(),
+            Encoder::new(Encoding::Postcard).with_crc(),
+        ] {
+            let mut buf = [0; MAX_FRAME_LEN];
+            let len = encoder.encode(&FULL, &mut buf).unwrap();
+            assert_eq!(decode_frame(&buf[..len - 1]), Ok(None));
+
+            let frame = decode_frame(&buf[..len]).unwrap().unwrap();
+            assert_eq!(frame.len, len);
+            let decoded = match frame.encoding {
+                Encoding::Cbor => ciborium::de::from_reader(frame.payload).unwrap(),
+                Encoding::Postcard => decode_postcard(frame.payload).unwrap(),
+            };
+            assert_same(&decoded, &FULL);
+        }
+
+        let mut buf = [0; MAX_FRAME_LEN];
+        let len = Encoder::new(Encoding::Postcard).with_crc().encode(&MINIMAL, &mut buf).unwrap();
+        buf[6] ^= 1;
+        assert_eq!(decode_frame(&buf[..len]), Err(Error::CrcMismatch));
+        assert_eq!(decode_frame(&[0xa2]), Err(Error::InvalidMagic));
+        assert_eq!(decode_frame(&[b'G', 0x02, 0, 0]), Err(Error::InvalidFlags(0x02)));
+    }
+
+    #[test]
+    fn auth() {
+        let mut buf = [0; 32];
+        let len = encode_auth("s3cret", &mut buf).unwrap();
+        assert_eq!(&buf[..len], b"AUTH s3cret\n");
+        assert_eq!(encode_auth("two words", &mut buf), Err(Error::InvalidAuth));
+        assert_eq!(encode_auth("s3cret", &mut buf[..4]), Err(Error::BufferTooSmall));
+
+        assert_eq!(decode_auth(b"AUTH s3cret\r\nG"), Ok(Some(("s3cret", 13))));
+        assert_eq!(decode_auth(b"AUT"), Ok(None));
+        assert_eq!(decode_auth(b"AUTH s3c"), Ok(None));
+        assert_eq!(decode_auth(b"AUTH \n"), Err(Error::InvalidAuth));
+        assert_eq!(decode_auth(&[0xa2, 0x68]), Err(Error::InvalidAuth));
+        let long = [b"AUTH ".as_slice(), &[b'k'; 200]].concat();
+        assert_eq!(decode_auth(&long), Err(Error::InvalidAuth));
+    }
+
+    #[test]
+    fn ack_tracker() {
+        let mut encoded = Vec::new();
+        for ack in [
+            Ack { ok: true, seq: 2, count: 2 },
+            Ack { ok: true, seq: 300, count: 298 },
+            Ack { ok: false, seq: 300, count: 1 },
+        ] {
+            ciborium::ser::into_writer(&ack, &mut encoded).unwrap();
+        }
+
+        let mut tracker = AckTracker::new();
+        assert_eq!(tracker.state(), AckState::Idle);
+        for _ in 0..301 {
+            tracker.sent();
+        }
+        assert_eq!(tracker.state(), AckState::Waiting);
+
+        // Split the acknowledgments at an arbitrary point.
+        let (mut first, mut second) = encoded.split_at(10);
+        assert_eq!(tracker.receive(&mut first), Ok(None));
+        assert_eq!(tracker.receive(&mut second), Ok(Some(AckEvent::Acked { seq: 2 })));
+        assert_eq!(tracker.receive(&mut second), Ok(Some(AckEvent::Acked { seq: 300 })));
+        assert_eq!(tracker.pending(), 1);
+        assert_eq!(tracker.receive(&mut second), Ok(Some(AckEvent::Failed { seq: 300 })));
+        assert_eq!(tracker.receive(&mut second), Ok(None));
+        assert_eq!(tracker.state(), AckState::Failed);
+
+        tracker.reset();
+        let mut invalid: &[u8] = &[0xa1, 0x62, b'o', b'k', 0x01];
+        assert_eq!(tracker.receive(&mut invalid), Err(Error::InvalidAck));
+    }
+}
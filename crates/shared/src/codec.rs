@@ -0,0 +1,225 @@
+//! Encodings of [`Status`] packets behind a common [`Codec`] trait, so that
+//! transports and storage pick a format in one place, and new formats only
+//! need a new implementation.
+//!
+//! Unlike [`client`](crate::client), this module needs an allocator, as CBOR
+//! and JSON are decoded with full-featured serde implementations.
+
+use alloc::vec::Vec;
+use core::fmt::{self, Display};
+
+use crate::{
+    client::{self, Encoding, MAX_PAYLOAD_LEN},
+    data::Status,
+};
+
+/// Errors of encoding and decoding statuses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// Malformed CBOR, or CBOR that isn't a [`Status`].
+    Cbor,
+    /// Malformed JSON, or JSON that isn't a [`Status`].
+    Json,
+    /// A postcard payload couldn't be encoded or decoded.
+    Postcard(postcard::Error),
+    /// The input ends in the middle of a status.
+    Truncated,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Cbor => f.write_str("invalid CBOR status"),
+            Self::Json => f.write_str("invalid JSON status"),
+            Self::Postcard(err) => write!(f, "postcard error: {err}"),
+            Self::Truncated => f.write_str("truncated status"),
+        }
+    }
+}
+
+impl core::error::Error for Error {}
+
+/// A format that [`Status`] packets can be encoded in. Several statuses are
+/// encoded one after another, e.g. as a CBOR sequence or newline-delimited
+/// JSON.
+pub trait Codec {
+    /// Appends an encoded status to `buf`.
+    fn encode(&self, status: &Status, buf: &mut Vec<u8>) -> Result<(), Error>;
+
+    /// Decodes the status at the start of `bytes`, returning it along with the
+    /// number of bytes it took up. Returns `None` if it isn't complete yet.
+    fn decode(&self, bytes: &[u8]) -> Result<Option<(Status, usize)>, Error>;
+
+    /// Decodes a payload holding a single status. Anything following the
+    /// status is ignored.
+    fn decode_one(&self, bytes: &[u8]) -> Result<Status, Error> {
+        Ok(self.decode(bytes)?.ok_or(Error::Truncated)?.0)
+    }
+
+    /// Appends several encoded statuses to `buf`.
+    fn encode_batch(&self, statuses: &[Status], buf: &mut Vec<u8>) -> Result<(), Error> {
+        statuses.iter().try_for_each(|status| self.encode(status, buf))
+    }
+
+    /// Decodes all statuses of a complete sequence. Trailing whitespace is
+    /// ignored.
+    fn decode_batch(&self, mut bytes: &[u8]) -> Result<Vec<Status>, Error> {
+        let mut statuses = Vec::new();
+        loop {
+            match self.decode(bytes)? {
+                Some((status, len)) => {
+                    statuses.push(status);
+                    bytes = &bytes[len..];
+                }
+                None if bytes.iter().all(u8::is_ascii_whitespace) => return Ok(statuses),
+                None => return Err(Error::Truncated),
+            }
+        }
+    }
+}
+
+/// CBOR, the default encoding of ingest payloads.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Cbor;
+
+impl Codec for Cbor {
+    fn encode(&self, status: &Status, buf: &mut Vec<u8>) -> Result<(), Error> {
+        ciborium::ser::into_writer(status, buf).map_err(|_| Error::Cbor)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Option<(Status, usize)>, Error> {
+        let mut rest = bytes;
+        match ciborium::de::from_reader(&mut rest) {
+            Ok(status) => Ok(Some((status, bytes.len() - rest.len()))),
+            // Reading from a byte slice only fails when running out of bytes.
+            Err(ciborium::de::Error::Io(_)) => Ok(None),
+            Err(_) => Err(Error::Cbor),
+        }
+    }
+}
+
+/// JSON. Batches are newline-delimited, while single statuses are encoded
+/// without a trailing newline. Whitespace between statuses is skipped when
+/// decoding.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+impl Codec for Json {
+    fn encode(&self, status: &Status, buf: &mut Vec<u8>) -> Result<(), Error> {
+        buf.extend(serde_json::to_vec(status).map_err(|_| Error::Json)?);
+        Ok(())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Option<(Status, usize)>, Error> {
+        let mut statuses = serde_json::Deserializer::from_slice(bytes).into_iter::<Status>();
+        match statuses.next() {
+            Some(Ok(status)) => Ok(Some((status, statuses.byte_offset()))),
+            Some(Err(err)) if err.is_eof() => Ok(None),
+            Some(Err(_)) => Err(Error::Json),
+            None => Ok(None),
+        }
+    }
+
+    fn encode_batch(&self, statuses: &[Status], buf: &mut Vec<u8>) -> Result<(), Error> {
+        for status in statuses {
+            self.encode(status, buf)?;
+            buf.push(b'\n');
+        }
+        Ok(())
+    }
+}
+
+/// [postcard](https://docs.rs/postcard), the compact encoding of
+/// [`Encoding::Postcard`] frames. Only decodable if both sides agree on the
+/// exact layout of [`Status`], and leaves out fields set by the server.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Postcard;
+
+impl Codec for Postcard {
+    fn encode(&self, status: &Status, buf: &mut Vec<u8>) -> Result<(), Error> {
+        let mut payload = [0; MAX_PAYLOAD_LEN];
+        let len = Encoding::Postcard.encode(status, &mut payload).map_err(|err| match err {
+            client::Error::Postcard(err) => Error::Postcard(err),
+            _ => Error::Postcard(postcard::Error::SerializeBufferFull),
+        })?;
+        buf.extend_from_slice(&payload[..len]);
+        Ok(())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Option<(Status, usize)>, Error> {
+        match client::take_postcard(bytes) {
+            Ok((status, len)) => Ok(Some((status, len))),
+            Err(postcard::Error::DeserializeUnexpectedEnd) => Ok(None),
+            Err(err) => Err(Error::Postcard(err)),
+        }
+    }
+}
+
+impl Encoding {
+    /// Codec of payloads in this encoding.
+    #[must_use]
+    pub fn codec(self) -> &'static dyn Codec {
+        match self {
+            Self::Cbor => &Cbor,
+            Self::Postcard => &Postcard,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use geo_types::Coord;
+    use time::macros::datetime;
+
+    use super::{Cbor, Codec, Error, Json, Postcard};
+    use crate::data::{SourceId, Status, TenantId};
+
+    fn status(seconds: u8) -> Status {
+        Status {
+            source_id: serde_json::from_str::<SourceId>("\"0aaec05a-0e7d-4fd5-abc0-0ba69e3cfe11\"")
+                .unwrap(),
+            timestamp: datetime!(2021-07-27 05:45:00 UTC) + time::Duration::seconds(seconds.into()),
+            position: Some(Coord { x: 24.5, y: 59.25 }),
+            bearing: None,
+            speed: None,
+            satellites: None,
+            fix: None,
+            odometer: None,
+            ignition: None,
+            received_at: None,
+            suspect_timestamp: false,
+            tenant_id: TenantId::DEFAULT,
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let statuses = [status(1), status(2), status(3)];
+        let codecs: [&dyn Codec; 3] = [&Cbor, &Json, &Postcard];
+        for codec in codecs {
+            let mut buf = Vec::new();
+            codec.encode_batch(&statuses, &mut buf).unwrap();
+            let decoded = codec.decode_batch(&buf).unwrap();
+            assert_eq!(decoded.len(), 3);
+            assert_eq!(decoded[2].timestamp, statuses[2].timestamp);
+            assert_eq!(decoded[2].position, statuses[2].position);
+
+            // Statuses cut short aren't decoded until they're complete.
+            assert!(codec.decode(&buf[..buf.len() / 3 - 2]).unwrap().is_none());
+            assert_eq!(codec.decode_batch(&buf[..buf.len() - 2]).unwrap_err(), Error::Truncated);
+        }
+    }
+
+    #[test]
+    fn json_whitespace() {
+        let mut buf = b"\n  ".to_vec();
+        Json.encode(&status(1), &mut buf).unwrap();
+        assert!(!buf.ends_with(b"\n"));
+        buf.extend_from_slice(b"\n\n");
+
+        let (decoded, len) = Json.decode(&buf).unwrap().unwrap();
+        assert_eq!(decoded.timestamp, status(1).timestamp);
+        assert!(Json.decode(&buf[len..]).unwrap().is_none());
+        assert!(matches!(Json.decode(b"{\"sourceId\":1}"), Err(Error::Json)));
+    }
+}
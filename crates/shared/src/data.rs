@@ -1,26 +1,132 @@
 //! This module contains data structures that describe sensor information used
 //! for tracking.
 
-use core::fmt::Display;
+use core::{fmt::Display, str::FromStr};
 
 use geo_types::Coord;
 use serde::{Deserialize, Serialize};
 use time::OffsetDateTime;
-use uom::si::f64::{Angle, Velocity};
+use uom::si::f64::{Angle, Length, Velocity};
 use uuid::Uuid;
 
-/// Globally unique identifier of a data source (sensor, vehicle, etc).
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[cfg(feature = "defmt")]
+mod format;
+mod source;
+#[cfg(feature = "units")]
+pub mod units;
+
+pub use source::{CustomId, SourceId, SourceIdError};
+
+/// Identifier of a tenant, an isolated account whose sources and statuses are
+/// invisible to other tenants. Deployments that don't configure any tenants
+/// keep all their data under [`TenantId::DEFAULT`].
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
 #[repr(transparent)]
 #[serde(transparent)]
-pub struct SourceId(Uuid);
+pub struct TenantId(Uuid);
+
+impl TenantId {
+    /// Tenant of all data in single-tenant deployments, as well as of data
+    /// stored before tenants were introduced. Represented by the nil UUID.
+    pub const DEFAULT: Self = Self(Uuid::nil());
+
+    /// Wraps a [`Uuid`] into a [`TenantId`].
+    #[must_use]
+    pub const fn from_uuid(uuid: Uuid) -> Self {
+        Self(uuid)
+    }
 
-impl Display for SourceId {
+    /// Returns the underlying [`Uuid`].
+    #[must_use]
+    pub const fn as_uuid(&self) -> &Uuid {
+        &self.0
+    }
+
+    /// Whether this is [`TenantId::DEFAULT`].
+    #[must_use]
+    pub fn is_default(&self) -> bool {
+        self.0.is_nil()
+    }
+}
+
+impl FromStr for TenantId {
+    type Err = uuid::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Uuid::try_parse(s).map(Self)
+    }
+}
+
+impl Display for TenantId {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         self.0.fmt(f)
     }
 }
 
+/// Kind of GNSS fix a position was determined with, from worst to best.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum FixType {
+    /// No fix; a position, if any, is stale or estimated.
+    #[serde(rename = "none")]
+    NoFix,
+    /// Two-dimensional fix, without altitude.
+    #[serde(rename = "2d")]
+    Fix2d,
+    /// Three-dimensional fix.
+    #[serde(rename = "3d")]
+    Fix3d,
+    /// Fix improved by differential corrections (DGPS, SBAS).
+    #[serde(rename = "dgps")]
+    Dgps,
+    /// Real-time kinematic fix, with centimeter-level accuracy.
+    #[serde(rename = "rtk")]
+    Rtk,
+}
+
+impl FixType {
+    /// All fix types, from worst to best.
+    pub const ALL: [Self; 5] = [Self::NoFix, Self::Fix2d, Self::Fix3d, Self::Dgps, Self::Rtk];
+
+    /// Name of the fix type, as serialized.
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::NoFix => "none",
+            Self::Fix2d => "2d",
+            Self::Fix3d => "3d",
+            Self::Dgps => "dgps",
+            Self::Rtk => "rtk",
+        }
+    }
+}
+
+/// Error of parsing an unknown [`FixType`] name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnknownFixType;
+
+impl Display for UnknownFixType {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("unknown fix type, expected one of none, 2d, 3d, dgps, rtk")
+    }
+}
+
+impl FromStr for FixType {
+    type Err = UnknownFixType;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL.into_iter().find(|fix| fix.as_str() == s).ok_or(UnknownFixType)
+    }
+}
+
+impl Display for FixType {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 /// A data packet from a given source, created at a given time. May optionally
 /// contain geopositional data.
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
@@ -29,8 +135,8 @@ pub struct Status {
     /// Globally unique identifier of the sensor.
     pub source_id: SourceId,
     /// Timestamp of the moment the data in this `Status` packet has been
-    /// collected. Serialized as seconds since UNIX epoch.
-    #[serde(with = "time::serde::timestamp")]
+    /// collected. Serialized as described in [`timestamp`].
+    #[serde(with = "timestamp")]
     pub timestamp: OffsetDateTime,
     /// GPS position. Serialized as [lon, lat].
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -41,11 +147,41 @@ pub struct Status {
     /// Moving speed. Serialized as meters/second.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub speed: Option<Velocity>,
+    /// Number of satellites used for the position fix.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub satellites: Option<u8>,
+    /// Kind of position fix, for telling precise positions from rough ones.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fix: Option<FixType>,
+    /// Total distance travelled according to the vehicle. Serialized as
+    /// meters.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub odometer: Option<Length>,
+    /// Whether the engine (or ignition) is on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ignition: Option<bool>,
+    /// Moment the server received this `Status` packet, as opposed to the
+    /// device-provided `timestamp`. Set on ingest, overwriting anything a
+    /// device may have sent. Serialized as described in [`timestamp`].
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "timestamp::option")]
+    pub received_at: Option<OffsetDateTime>,
+    /// Whether `timestamp` is implausibly far from `received_at`, but the
+    /// status has been stored anyway. Set on ingest.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub suspect_timestamp: bool,
+    /// Tenant owning the source. Set on ingest from the credentials the
+    /// status was submitted with, overwriting anything a device may have sent.
+    #[serde(default, skip_serializing_if = "TenantId::is_default")]
+    pub tenant_id: TenantId,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
 }
 
 impl Status {
     /// Merges optional fields of two [`Status`] values to produce a new value,
-    /// ignoring `source_id` and `timestamp` of `rhs`. If both source values
+    /// ignoring `source_id`, `timestamp` and `tenant_id` of `rhs`. If both source values
     /// have a given field set, the one from `rhs` is used.
     #[must_use]
     pub fn merge(&self, rhs: &Self) -> Self {
@@ -55,6 +191,168 @@ impl Status {
             position: rhs.position.or(self.position),
             bearing: rhs.bearing.or(self.bearing),
             speed: rhs.speed.or(self.speed),
+            satellites: rhs.satellites.or(self.satellites),
+            fix: rhs.fix.or(self.fix),
+            odometer: rhs.odometer.or(self.odometer),
+            ignition: rhs.ignition.or(self.ignition),
+            received_at: rhs.received_at.or(self.received_at),
+            suspect_timestamp: self.suspect_timestamp || rhs.suspect_timestamp,
+            tenant_id: self.tenant_id,
+        }
+    }
+}
+
+/// Acknowledgment sent back to a device over a stream transport once a batch
+/// of its [`Status`] packets has been handled, so it can safely discard its
+/// local copies.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct Ack {
+    /// Whether the whole batch has been persisted. If not, the connection is
+    /// closed afterwards, and everything past `seq` needs to be resent.
+    pub ok: bool,
+    /// Number of packets persisted over the connection so far.
+    pub seq: u64,
+    /// Number of packets in the acknowledged batch.
+    pub count: u32,
+}
+
+pub mod timestamp {
+    //! Serde representation of timestamps, for use with `#[serde(with)]`.
+    //!
+    //! Timestamps are serialized as whole seconds since UNIX epoch, truncating
+    //! any sub-second part. With the `subsec-timestamps` feature, timestamps
+    //! that have a sub-second part are serialized as RFC 3339 strings in UTC
+    //! instead, e.g. `"2021-07-27T05:45:19.25Z"`. Both representations are
+    //! accepted when deserializing, regardless of the feature.
+
+    use core::fmt::{self, Display};
+
+    use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+    use time::{format_description::well_known::Rfc3339, OffsetDateTime, UtcOffset};
+
+    /// Serializes `timestamp` as described in the [module](self) docs.
+    pub fn serialize<S: Serializer>(timestamp: &OffsetDateTime, s: S) -> Result<S::Ok, S::Error> {
+        match as_text(*timestamp) {
+            Some(text) => s.collect_str(&text),
+            None => s.serialize_i64(timestamp.unix_timestamp()),
+        }
+    }
+
+    /// Deserializes a timestamp from either of the representations described
+    /// in the [module](self) docs.
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<OffsetDateTime, D::Error> {
+        d.deserialize_any(Visitor)
+    }
+
+    /// Same as the parent module, for optional timestamps.
+    pub mod option {
+        use serde::{Deserialize, Deserializer, Serializer};
+        use time::OffsetDateTime;
+
+        use super::Timestamp;
+
+        /// Serializes an optional timestamp.
+        pub fn serialize<S: Serializer>(
+            timestamp: &Option<OffsetDateTime>,
+            s: S,
+        ) -> Result<S::Ok, S::Error> {
+            match timestamp {
+                Some(timestamp) => s.serialize_some(&Timestamp(*timestamp)),
+                None => s.serialize_none(),
+            }
+        }
+
+        /// Deserializes an optional timestamp.
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            d: D,
+        ) -> Result<Option<OffsetDateTime>, D::Error> {
+            Ok(Option::<Timestamp>::deserialize(d)?.map(|t| t.0))
+        }
+    }
+
+    struct Timestamp(OffsetDateTime);
+
+    impl Serialize for Timestamp {
+        fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+            serialize(&self.0, s)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Timestamp {
+        fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+            deserialize(d).map(Self)
+        }
+    }
+
+    /// The RFC 3339 representation of `timestamp`, if it is to be serialized
+    /// as one rather than as seconds.
+    pub(crate) fn as_text(timestamp: OffsetDateTime) -> Option<Rfc3339Utc> {
+        let utc = timestamp.to_offset(UtcOffset::UTC);
+        let text = cfg!(feature = "subsec-timestamps")
+            && utc.nanosecond() != 0
+            && (0..=9999).contains(&utc.year());
+        text.then_some(Rfc3339Utc(utc))
+    }
+
+    /// Formats a UTC timestamp in RFC 3339, leaving out trailing zeros of the
+    /// fractional seconds. The `time` crate can't format without `std`.
+    pub(crate) struct Rfc3339Utc(OffsetDateTime);
+
+    impl Display for Rfc3339Utc {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let ts = self.0;
+            write!(
+                f,
+                "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+                ts.year(),
+                u8::from(ts.month()),
+                ts.day(),
+                ts.hour(),
+                ts.minute(),
+                ts.second()
+            )?;
+            let (mut nanos, mut digits) = (ts.nanosecond(), 9);
+            if nanos != 0 {
+                while nanos % 10 == 0 {
+                    nanos /= 10;
+                    digits -= 1;
+                }
+                write!(f, ".{nanos:0digits$}")?;
+            }
+            f.write_str("Z")
+        }
+    }
+
+    struct Visitor;
+
+    impl de::Visitor<'_> for Visitor {
+        type Value = OffsetDateTime;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("seconds since UNIX epoch or an RFC 3339 timestamp")
+        }
+
+        fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+            OffsetDateTime::from_unix_timestamp(v)
+                .map_err(|_| E::invalid_value(de::Unexpected::Signed(v), &self))
+        }
+
+        fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+            let v = i64::try_from(v)
+                .map_err(|_| E::invalid_value(de::Unexpected::Unsigned(v), &self))?;
+            self.visit_i64(v)
+        }
+
+        // Formats such as query strings pass integers as strings as well.
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+            if let Ok(seconds) = v.parse() {
+                return self.visit_i64(seconds);
+            }
+            OffsetDateTime::parse(v, &Rfc3339)
+                .map(|ts| ts.to_offset(UtcOffset::UTC))
+                .map_err(|_| E::invalid_value(de::Unexpected::Str(v), &self))
         }
     }
 }
@@ -69,22 +367,36 @@ mod tests {
     use uom::si::{angle::degree, velocity::kilometer_per_hour, Quantity};
     use uuid::Uuid;
 
-    use crate::data::{SourceId, Status};
+    use crate::data::{Ack, FixType, SourceId, Status, TenantId};
 
     const FULL: Status = Status {
-        source_id: SourceId(Uuid::from_u128(0x0aaec05a_0e7d_4fd5_abc0_0ba69e3cfe11)),
+        source_id: SourceId::Uuid(Uuid::from_u128(0x0aaec05a_0e7d_4fd5_abc0_0ba69e3cfe11)),
         timestamp: datetime!(2021-07-27 08:45:19 +3),
         position: Some(Coord { x: 24.745_278, y: 59.437_222 }),
         bearing: Some(Quantity { dimension: PhantomData, units: PhantomData, value: 1.234 }),
         speed: Some(Quantity { dimension: PhantomData, units: PhantomData, value: 15. }),
+        satellites: None,
+        fix: None,
+        odometer: None,
+        ignition: None,
+        received_at: None,
+        suspect_timestamp: false,
+        tenant_id: TenantId::DEFAULT,
     };
 
     const MINIMAL: Status = Status {
-        source_id: SourceId(Uuid::from_u128(0x0aaec05a_0e7d_4fd5_abc0_0ba69e3cfe11)),
+        source_id: SourceId::Uuid(Uuid::from_u128(0x0aaec05a_0e7d_4fd5_abc0_0ba69e3cfe11)),
         timestamp: datetime!(2021-07-27 08:45:19 +3),
         position: None,
         bearing: None,
         speed: None,
+        satellites: None,
+        fix: None,
+        odometer: None,
+        ignition: None,
+        received_at: None,
+        suspect_timestamp: false,
+        tenant_id: TenantId::DEFAULT,
     };
 
     const FULL_JSON: &str = r###"{
@@ -160,6 +472,25 @@ mod tests {
         assert_eq!(merged2.position, FULL.position);
         assert_eq!(merged2.bearing, FULL.bearing);
         assert_eq!(merged2.speed, FULL.speed);
+
+        let fixed = Status { satellites: Some(9), fix: Some(FixType::Fix3d), ..MINIMAL };
+        let merged = fixed.merge(&Status { fix: Some(FixType::Rtk), ..MINIMAL });
+        assert_eq!(merged.satellites, Some(9));
+        assert_eq!(merged.fix, Some(FixType::Rtk));
+    }
+
+    #[test]
+    fn json_fix() -> serde_json::Result<()> {
+        let status = Status { satellites: Some(7), fix: Some(FixType::Fix2d), ..MINIMAL };
+        let encoded = serde_json::to_string(&status)?;
+        assert!(encoded.ends_with(r#""timestamp":1627364719,"satellites":7,"fix":"2d"}"#));
+
+        let decoded: Status = serde_json::from_str(&encoded)?;
+        assert_eq!(decoded.satellites, Some(7));
+        assert_eq!(decoded.fix, Some(FixType::Fix2d));
+        assert_eq!("dgps".parse(), Ok(FixType::Dgps));
+        assert!("4d".parse::<FixType>().is_err());
+        Ok(())
     }
 
     #[test]
@@ -190,6 +521,24 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn cbor_serialization_ack() -> Result<(), ciborium::ser::Error<std::io::Error>> {
+        let ack = Ack { ok: true, seq: 300, count: 2 };
+        let encoded = cbor_to_bytes(&ack)?;
+        #[rustfmt::skip]
+        assert_eq!(encoded, [
+            // header
+            0xa3,
+            //    "ok"       true
+            0x62, 0x6f, 0x6b, 0xf5,
+            //    "seq"            300
+            0x63, 0x73, 0x65, 0x71, 0x19, 0x01, 0x2c,
+            //    "count"                      2
+            0x65, 0x63, 0x6f, 0x75, 0x6e, 0x74, 0x02,
+        ]);
+        Ok(())
+    }
+
     #[test]
     fn cbor_deserialization_minimal() -> Result<(), ciborium::de::Error<std::io::Error>> {
         let decoded: Status = ciborium::de::from_reader(MINIMAL_CBOR)?;
@@ -233,6 +582,41 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn json_received_at() -> serde_json::Result<()> {
+        let status = Status { received_at: Some(datetime!(2021-07-27 05:45:21 UTC)), ..MINIMAL };
+        let encoded = serde_json::to_string(&status)?;
+        assert!(encoded.ends_with(r#""timestamp":1627364719,"receivedAt":1627364721}"#));
+
+        let decoded: Status = serde_json::from_str(&encoded)?;
+        assert_eq!(decoded.received_at, status.received_at);
+        Ok(())
+    }
+
+    #[test]
+    fn json_subsec_timestamps() -> serde_json::Result<()> {
+        let status = Status { timestamp: datetime!(2021-07-27 05:45:19.25 UTC), ..MINIMAL };
+        let encoded = serde_json::to_string(&status)?;
+        let decoded: Status = serde_json::from_str(&encoded)?;
+        if cfg!(feature = "subsec-timestamps") {
+            assert!(encoded.ends_with(r#""timestamp":"2021-07-27T05:45:19.25Z"}"#));
+            assert_eq!(decoded.timestamp, status.timestamp);
+        } else {
+            assert!(encoded.ends_with(r#""timestamp":1627364719}"#));
+            assert_eq!(decoded.timestamp, MINIMAL.timestamp);
+        }
+
+        let decoded: Status = serde_json::from_str(
+            r#"{"sourceId":"0aaec05a-0e7d-4fd5-abc0-0ba69e3cfe11","timestamp":"2021-07-27T08:45:19.000123+03:00"}"#,
+        )?;
+        assert_eq!(decoded.timestamp, datetime!(2021-07-27 05:45:19.000123 UTC));
+        assert!(serde_json::from_str::<Status>(
+            r#"{"sourceId":"0aaec05a-0e7d-4fd5-abc0-0ba69e3cfe11","timestamp":"yesterday"}"#
+        )
+        .is_err());
+        Ok(())
+    }
+
     #[test]
     fn json_deserialization_minimal() -> serde_json::Result<()> {
         let decoded: Status = serde_json::from_str(MINIMAL_JSON)?;
@@ -0,0 +1,89 @@
+//! [`defmt::Format`] implementations for types whose fields don't implement it
+//! themselves. Everything else derives it behind the `defmt` feature.
+
+use defmt::{write, Format, Formatter};
+use time::OffsetDateTime;
+use uom::si::{angle::radian, length::meter, velocity::meter_per_second};
+use uuid::Uuid;
+
+use super::{SourceId, Status, TenantId};
+
+/// Writes a UUID in its hyphenated form.
+fn write_uuid(f: Formatter<'_>, uuid: &Uuid) {
+    let n = uuid.as_u128();
+    write!(
+        f,
+        "{=u32:08x}-{=u16:04x}-{=u16:04x}-{=u16:04x}-{=u64:012x}",
+        (n >> 96) as u32,
+        (n >> 80) as u16,
+        (n >> 64) as u16,
+        (n >> 48) as u16,
+        n as u64 & 0xffff_ffff_ffff,
+    );
+}
+
+/// Writes a timestamp as seconds since UNIX epoch, with nanoseconds if there
+/// are any.
+fn write_timestamp(f: Formatter<'_>, timestamp: OffsetDateTime) {
+    write!(f, "{=i64}", timestamp.unix_timestamp());
+    if timestamp.nanosecond() != 0 {
+        write!(f, ".{=u32:09}", timestamp.nanosecond());
+    }
+}
+
+impl Format for SourceId {
+    fn format(&self, f: Formatter<'_>) {
+        match self {
+            Self::Uuid(uuid) => write_uuid(f, uuid),
+            Self::Imei(imei) => write!(f, "imei:{=u64:015}", imei),
+            Self::Custom(id) => write!(f, "custom:{=str}", id.as_str()),
+        }
+    }
+}
+
+impl Format for TenantId {
+    fn format(&self, f: Formatter<'_>) {
+        write_uuid(f, &self.0);
+    }
+}
+
+/// Same fields as the [`Debug`] representation, leaving out unset ones. Bearing
+/// is in radians, speed in m/s and odometer in meters.
+impl Format for Status {
+    fn format(&self, f: Formatter<'_>) {
+        write!(f, "Status {{ source_id: {}, timestamp: ", self.source_id);
+        write_timestamp(f, self.timestamp);
+        if let Some(position) = self.position {
+            write!(f, ", position: [{=f64}, {=f64}]", position.x, position.y);
+        }
+        if let Some(bearing) = self.bearing {
+            write!(f, ", bearing: {=f64}", bearing.get::<radian>());
+        }
+        if let Some(speed) = self.speed {
+            write!(f, ", speed: {=f64}", speed.get::<meter_per_second>());
+        }
+        if let Some(satellites) = self.satellites {
+            write!(f, ", satellites: {=u8}", satellites);
+        }
+        if let Some(fix) = self.fix {
+            write!(f, ", fix: {}", fix);
+        }
+        if let Some(odometer) = self.odometer {
+            write!(f, ", odometer: {=f64}", odometer.get::<meter>());
+        }
+        if let Some(ignition) = self.ignition {
+            write!(f, ", ignition: {=bool}", ignition);
+        }
+        if let Some(received_at) = self.received_at {
+            write!(f, ", received_at: ");
+            write_timestamp(f, received_at);
+        }
+        if self.suspect_timestamp {
+            write!(f, ", suspect_timestamp: true");
+        }
+        if !self.tenant_id.is_default() {
+            write!(f, ", tenant_id: {}", self.tenant_id);
+        }
+        write!(f, " }}");
+    }
+}
@@ -0,0 +1,373 @@
+//! Identifiers of data sources. Besides UUIDs, devices commonly identify by
+//! the IMEI of their modem, or by a short code assigned by the operator.
+//!
+//! Storage engines key sources by the 16 bytes of [`SourceId::to_key`]. Keys
+//! of UUID sources are the bytes of the UUID, same as before other kinds of
+//! identifiers were supported, so data stored under them stays readable.
+//! Keys of other sources borrow the UUID variant reserved for future
+//! definition, which no UUID in use has, to mark the kind of identifier:
+//!
+//! ```text
+//! +----------------+--------------+----------------+
+//! | payload[..8]   | 0xe0 | kind  | payload[8..]   |
+//! | 8              | 1            | 7              |
+//! +----------------+--------------+----------------+
+//! ```
+//!
+//! where an IMEI is a big-endian `u64`, and a custom identifier is padded
+//! with zeros.
+
+use core::{
+    cmp::Ordering,
+    fmt::{self, Debug, Display},
+    str::FromStr,
+};
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use uuid::Uuid;
+
+/// Globally unique identifier of a data source (sensor, vehicle, etc).
+///
+/// In text, such as JSON, it's represented as a hyphenated UUID, as `imei:`
+/// followed by 15 digits, or as `custom:` followed by a [`CustomId`]. Binary
+/// formats carry [`SourceId::to_key`] instead. Sources are ordered by kind,
+/// in the order of variants, then by value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SourceId {
+    /// UUID, e.g. generated by the device itself. UUIDs of the variant
+    /// reserved for future definition are taken by keys of other kinds of
+    /// identifiers, and aren't accepted when parsing.
+    Uuid(Uuid),
+    /// International Mobile Equipment Identity of a cellular device. Values
+    /// longer than 15 digits aren't IMEIs, and can't be parsed back.
+    Imei(u64),
+    /// Code assigned by an operator.
+    Custom(CustomId),
+}
+
+const KEY_LEN: usize = SourceId::KEY_LEN;
+
+/// Position of the byte holding the UUID variant, marking keys of non-UUID
+/// sources.
+const MARKER_POS: usize = 8;
+/// Variant reserved for future definition, in the three highest bits.
+const MARKER: u8 = 0b1110_0000;
+const KIND_IMEI: u8 = 1;
+const KIND_CUSTOM: u8 = 2;
+
+const IMEI_PREFIX: &str = "imei:";
+const IMEI_DIGITS: usize = 15;
+const CUSTOM_PREFIX: &str = "custom:";
+
+impl SourceId {
+    /// Length of [`SourceId::to_key`].
+    pub const KEY_LEN: usize = 16;
+
+    /// The smallest source ID, useful as the lower bound of ranges of keys
+    /// that include a source.
+    pub const MIN: Self = Self::Uuid(Uuid::nil());
+
+    /// Wraps a [`Uuid`] into a [`SourceId`].
+    #[must_use]
+    pub const fn from_uuid(uuid: Uuid) -> Self {
+        Self::Uuid(uuid)
+    }
+
+    /// Returns the underlying [`Uuid`], if the source is identified by one.
+    #[must_use]
+    pub const fn as_uuid(&self) -> Option<&Uuid> {
+        match self {
+            Self::Uuid(uuid) => Some(uuid),
+            _ => None,
+        }
+    }
+
+    /// Fixed-length binary key of the source, as laid out in the
+    /// [module](self) docs.
+    #[must_use]
+    pub fn to_key(&self) -> [u8; KEY_LEN] {
+        match self {
+            Self::Uuid(uuid) => *uuid.as_bytes(),
+            Self::Imei(imei) => pack(KIND_IMEI, &imei.to_be_bytes()),
+            Self::Custom(id) => pack(KIND_CUSTOM, id.as_bytes()),
+        }
+    }
+
+    /// Parses a key made by [`SourceId::to_key`], including keys written as
+    /// plain UUIDs before other kinds of identifiers were supported. Returns
+    /// `None` if it's malformed.
+    #[must_use]
+    pub fn from_key(key: &[u8]) -> Option<Self> {
+        let key: [u8; KEY_LEN] = key.try_into().ok()?;
+        if key[MARKER_POS] & MARKER != MARKER {
+            return Some(Self::Uuid(Uuid::from_bytes(key)));
+        }
+        let mut payload = [0; KEY_LEN - 1];
+        payload[..MARKER_POS].copy_from_slice(&key[..MARKER_POS]);
+        payload[MARKER_POS..].copy_from_slice(&key[MARKER_POS + 1..]);
+        match key[MARKER_POS] & !MARKER {
+            KIND_IMEI => {
+                let (imei, rest) = payload.split_at(8);
+                let imei = u64::from_be_bytes(imei.try_into().ok()?);
+                rest.iter().all(|&b| b == 0).then_some(Self::Imei(imei))
+            }
+            KIND_CUSTOM => {
+                let len = payload.iter().position(|&b| b == 0).unwrap_or(payload.len());
+                if payload[len..].iter().any(|&b| b != 0) {
+                    return None;
+                }
+                let id = core::str::from_utf8(&payload[..len]).ok()?;
+                CustomId::new(id).map(Self::Custom)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Lays out the payload of a non-UUID key around the marker byte.
+fn pack(kind: u8, payload: &[u8]) -> [u8; KEY_LEN] {
+    let mut key = [0; KEY_LEN];
+    let (head, tail) = payload.split_at(payload.len().min(MARKER_POS));
+    key[..head.len()].copy_from_slice(head);
+    key[MARKER_POS] = MARKER | kind;
+    key[MARKER_POS + 1..][..tail.len()].copy_from_slice(tail);
+    key
+}
+
+impl Display for SourceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Uuid(uuid) => Display::fmt(uuid, f),
+            Self::Imei(imei) => write!(f, "{IMEI_PREFIX}{imei:0IMEI_DIGITS$}"),
+            Self::Custom(id) => write!(f, "{CUSTOM_PREFIX}{id}"),
+        }
+    }
+}
+
+/// Error of parsing a [`SourceId`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceIdError {
+    /// Neither a prefixed identifier nor a UUID.
+    Uuid,
+    /// A UUID of the variant reserved for keys of other identifiers.
+    ReservedUuid,
+    /// An IMEI that isn't exactly 15 digits.
+    Imei,
+    /// A custom identifier that isn't a valid [`CustomId`].
+    Custom,
+}
+
+impl Display for SourceIdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Uuid => "invalid source ID; expected a UUID, imei:<IMEI> or custom:<ID>",
+            Self::ReservedUuid => "UUIDs of the reserved variant can't be source IDs",
+            Self::Imei => "invalid IMEI; expected 15 digits",
+            Self::Custom => "invalid custom source ID",
+        })
+    }
+}
+
+impl FromStr for SourceId {
+    type Err = SourceIdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(imei) = s.strip_prefix(IMEI_PREFIX) {
+            if imei.len() != IMEI_DIGITS || !imei.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(SourceIdError::Imei);
+            }
+            return imei.parse().map(Self::Imei).map_err(|_| SourceIdError::Imei);
+        }
+        if let Some(id) = s.strip_prefix(CUSTOM_PREFIX) {
+            return CustomId::new(id).map(Self::Custom).ok_or(SourceIdError::Custom);
+        }
+        let uuid = Uuid::try_parse(s).map_err(|_| SourceIdError::Uuid)?;
+        match uuid.as_bytes()[MARKER_POS] & MARKER {
+            MARKER => Err(SourceIdError::ReservedUuid),
+            _ => Ok(Self::Uuid(uuid)),
+        }
+    }
+}
+
+impl Serialize for SourceId {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        match s.is_human_readable() {
+            true => s.collect_str(self),
+            false => s.serialize_bytes(&self.to_key()),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for SourceId {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        match d.is_human_readable() {
+            true => d.deserialize_str(Visitor),
+            false => d.deserialize_bytes(Visitor),
+        }
+    }
+}
+
+struct Visitor;
+
+impl de::Visitor<'_> for Visitor {
+    type Value = SourceId;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a UUID, imei:<IMEI>, custom:<ID> or a 16-byte source key")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        v.parse().map_err(|_| E::invalid_value(de::Unexpected::Str(v), &self))
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+        SourceId::from_key(v).ok_or_else(|| E::invalid_value(de::Unexpected::Bytes(v), &self))
+    }
+}
+
+/// Source identifier assigned by an operator: 1 to [`CustomId::CAPACITY`]
+/// ASCII letters, digits, `-`, `_` and `.`. Stored inline, so that
+/// [`SourceId`] stays `Copy`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CustomId {
+    len: u8,
+    bytes: [u8; Self::CAPACITY],
+}
+
+impl CustomId {
+    /// Maximum length in bytes, as much as fits into a source key.
+    pub const CAPACITY: usize = KEY_LEN - 1;
+
+    /// Validates a custom identifier. Returns `None` if it's empty, too long,
+    /// or contains other characters than allowed.
+    #[must_use]
+    pub fn new(id: &str) -> Option<Self> {
+        let valid = |b: u8| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.');
+        if id.is_empty() || id.len() > Self::CAPACITY || !id.bytes().all(valid) {
+            return None;
+        }
+        let mut bytes = [0; Self::CAPACITY];
+        bytes[..id.len()].copy_from_slice(id.as_bytes());
+        Some(Self { len: id.len() as u8, bytes })
+    }
+
+    /// The identifier as a string slice.
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        // Only ever constructed from ASCII.
+        core::str::from_utf8(self.as_bytes()).unwrap_or_default()
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..usize::from(self.len)]
+    }
+}
+
+impl PartialOrd for CustomId {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for CustomId {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.as_bytes().cmp(other.as_bytes())
+    }
+}
+
+impl Debug for CustomId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl Display for CustomId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::{CustomId, SourceId, SourceIdError};
+
+    const UUID: &str = "0aaec05a-0e7d-4fd5-abc0-0ba69e3cfe11";
+
+    #[test]
+    fn parse_and_display() {
+        let ids = [UUID, "imei:013591002345671", "custom:TRUCK-0042"];
+        for id in ids {
+            assert_eq!(id.parse::<SourceId>().unwrap().to_string(), id);
+        }
+        assert_eq!(
+            "imei:013591002345671".parse::<SourceId>(),
+            Ok(SourceId::Imei(13_591_002_345_671))
+        );
+        assert_eq!("imei:1359100234567".parse::<SourceId>(), Err(SourceIdError::Imei));
+        assert_eq!("imei:01359100234567x".parse::<SourceId>(), Err(SourceIdError::Imei));
+        assert_eq!("custom:".parse::<SourceId>(), Err(SourceIdError::Custom));
+        assert_eq!("custom:a/b".parse::<SourceId>(), Err(SourceIdError::Custom));
+        assert_eq!("custom:0123456789abcdef".parse::<SourceId>(), Err(SourceIdError::Custom));
+        assert_eq!("TRUCK-0042".parse::<SourceId>(), Err(SourceIdError::Uuid));
+        assert_eq!(
+            "0aaec05a-0e7d-4fd5-ebc0-0ba69e3cfe11".parse::<SourceId>(),
+            Err(SourceIdError::ReservedUuid)
+        );
+    }
+
+    #[test]
+    fn keys() {
+        let uuid = Uuid::parse_str(UUID).unwrap();
+        let ids = [
+            SourceId::Uuid(uuid),
+            SourceId::Uuid(Uuid::nil()),
+            SourceId::Imei(13_591_002_345_671),
+            SourceId::Custom(CustomId::new("a").unwrap()),
+            SourceId::Custom(CustomId::new("0123456789abcde").unwrap()),
+        ];
+        for id in ids {
+            assert_eq!(SourceId::from_key(&id.to_key()), Some(id));
+        }
+        // Data keyed by plain UUIDs stays readable.
+        assert_eq!(SourceId::from_key(uuid.as_bytes()), Some(SourceId::Uuid(uuid)));
+
+        let mut key = SourceId::Custom(CustomId::new("ab").unwrap()).to_key();
+        key[12] = b'c';
+        assert_eq!(SourceId::from_key(&key), None);
+        key[8] = 0xe7;
+        assert_eq!(SourceId::from_key(&key), None);
+        assert_eq!(SourceId::from_key(&key[1..]), None);
+    }
+
+    #[test]
+    fn ordering() {
+        let mut ids: Vec<SourceId> =
+            ["custom:b", "imei:000000000000002", "custom:a", UUID, "imei:000000000000001"]
+                .iter()
+                .map(|id| id.parse().unwrap())
+                .collect();
+        ids.sort();
+        assert!(SourceId::MIN <= ids[0]);
+        let ids: Vec<_> = ids.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            ids,
+            [UUID, "imei:000000000000001", "imei:000000000000002", "custom:a", "custom:b"]
+        );
+    }
+
+    #[test]
+    fn serde() {
+        let id: SourceId = "custom:TRUCK-0042".parse().unwrap();
+        let json = serde_json::to_string(&id).unwrap();
+        assert_eq!(json, r#""custom:TRUCK-0042""#);
+        assert_eq!(serde_json::from_str::<SourceId>(&json).unwrap(), id);
+
+        let mut cbor = Vec::new();
+        ciborium::ser::into_writer(&id, &mut cbor).unwrap();
+        assert_eq!(cbor[0], 0x50);
+        assert_eq!(&cbor[1..], id.to_key());
+        assert_eq!(ciborium::de::from_reader::<SourceId, _>(cbor.as_slice()).unwrap(), id);
+    }
+}
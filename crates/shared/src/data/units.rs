@@ -0,0 +1,321 @@
+//! Alternate representation of [`Status`] for consumers that shouldn't have to
+//! guess units: measurements are serialized along with their unit, e.g.
+//! `"speed": {"value": 15.0, "unit": "m/s"}`, and bearing is given in degrees
+//! rather than radians. Odometer readings are given in the unit of distance of
+//! the same [`UnitSystem`] as speed.
+
+use geo_types::Coord;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use time::OffsetDateTime;
+use uom::si::{
+    angle::{degree, radian},
+    f64::{Angle, Length, Velocity},
+    length::{kilometer, meter, mile, nautical_mile},
+    velocity::{kilometer_per_hour, knot, meter_per_second, mile_per_hour},
+};
+
+use super::{timestamp, FixType, SourceId, Status, TenantId};
+
+/// Units to express speed and distance in.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnitSystem {
+    /// Meters per second and meters, same as the plain [`Status`]
+    /// representation.
+    #[default]
+    Si,
+    /// Kilometers per hour and kilometers.
+    Metric,
+    /// Knots and nautical miles.
+    Nautical,
+    /// Miles per hour and miles.
+    Imperial,
+}
+
+impl UnitSystem {
+    /// Unit of speed in this system.
+    #[must_use]
+    pub const fn speed_unit(self) -> Unit {
+        match self {
+            Self::Si => Unit::MetersPerSecond,
+            Self::Metric => Unit::KilometersPerHour,
+            Self::Nautical => Unit::Knots,
+            Self::Imperial => Unit::MilesPerHour,
+        }
+    }
+
+    /// Unit of distance in this system.
+    #[must_use]
+    pub const fn distance_unit(self) -> Unit {
+        match self {
+            Self::Si => Unit::Meters,
+            Self::Metric => Unit::Kilometers,
+            Self::Nautical => Unit::NauticalMiles,
+            Self::Imperial => Unit::Miles,
+        }
+    }
+}
+
+/// Unit of a [`Measure`].
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Unit {
+    /// Meters per second.
+    #[serde(rename = "m/s")]
+    MetersPerSecond,
+    /// Kilometers per hour.
+    #[serde(rename = "km/h")]
+    KilometersPerHour,
+    /// Knots.
+    #[serde(rename = "kn")]
+    Knots,
+    /// Miles per hour.
+    #[serde(rename = "mph")]
+    MilesPerHour,
+    /// Degrees.
+    #[serde(rename = "deg")]
+    Degrees,
+    /// Radians.
+    #[serde(rename = "rad")]
+    Radians,
+    /// Meters.
+    #[serde(rename = "m")]
+    Meters,
+    /// Kilometers.
+    #[serde(rename = "km")]
+    Kilometers,
+    /// Nautical miles.
+    #[serde(rename = "nmi")]
+    NauticalMiles,
+    /// Miles.
+    #[serde(rename = "mi")]
+    Miles,
+}
+
+/// A value along with its unit.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Measure {
+    /// Value in `unit`.
+    pub value: f64,
+    /// Unit of `value`.
+    pub unit: Unit,
+}
+
+impl Measure {
+    /// Expresses `speed` in `unit`. Returns `None` if `unit` isn't a unit of
+    /// speed.
+    #[must_use]
+    pub fn from_velocity(speed: Velocity, unit: Unit) -> Option<Self> {
+        let value = match unit {
+            Unit::MetersPerSecond => speed.get::<meter_per_second>(),
+            Unit::KilometersPerHour => speed.get::<kilometer_per_hour>(),
+            Unit::Knots => speed.get::<knot>(),
+            Unit::MilesPerHour => speed.get::<mile_per_hour>(),
+            _ => return None,
+        };
+        Some(Self { value, unit })
+    }
+
+    /// Expresses `length` in `unit`. Returns `None` if `unit` isn't a unit of
+    /// distance.
+    #[must_use]
+    pub fn from_length(length: Length, unit: Unit) -> Option<Self> {
+        let value = match unit {
+            Unit::Meters => length.get::<meter>(),
+            Unit::Kilometers => length.get::<kilometer>(),
+            Unit::NauticalMiles => length.get::<nautical_mile>(),
+            Unit::Miles => length.get::<mile>(),
+            _ => return None,
+        };
+        Some(Self { value, unit })
+    }
+
+    /// Expresses `angle` in degrees.
+    #[must_use]
+    pub fn from_angle(angle: Angle) -> Self {
+        Self { value: angle.get::<degree>(), unit: Unit::Degrees }
+    }
+
+    /// Converts to a speed. Returns `None` if `unit` isn't a unit of speed.
+    #[must_use]
+    pub fn to_velocity(self) -> Option<Velocity> {
+        match self.unit {
+            Unit::MetersPerSecond => Some(Velocity::new::<meter_per_second>(self.value)),
+            Unit::KilometersPerHour => Some(Velocity::new::<kilometer_per_hour>(self.value)),
+            Unit::Knots => Some(Velocity::new::<knot>(self.value)),
+            Unit::MilesPerHour => Some(Velocity::new::<mile_per_hour>(self.value)),
+            _ => None,
+        }
+    }
+
+    /// Converts to a length. Returns `None` if `unit` isn't a unit of
+    /// distance.
+    #[must_use]
+    pub fn to_length(self) -> Option<Length> {
+        match self.unit {
+            Unit::Meters => Some(Length::new::<meter>(self.value)),
+            Unit::Kilometers => Some(Length::new::<kilometer>(self.value)),
+            Unit::NauticalMiles => Some(Length::new::<nautical_mile>(self.value)),
+            Unit::Miles => Some(Length::new::<mile>(self.value)),
+            _ => None,
+        }
+    }
+
+    /// Converts to an angle. Returns `None` if `unit` isn't a unit of angle.
+    #[must_use]
+    pub fn to_angle(self) -> Option<Angle> {
+        match self.unit {
+            Unit::Degrees => Some(Angle::new::<degree>(self.value)),
+            Unit::Radians => Some(Angle::new::<radian>(self.value)),
+            _ => None,
+        }
+    }
+}
+
+/// A [`Status`] serialized with explicit units, its speed and odometer
+/// expressed in the given [`UnitSystem`]. Deserializes from any supported units, reporting
+/// [`UnitSystem::Si`].
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Copy)]
+pub struct WithUnits(pub Status, pub UnitSystem);
+
+/// Same fields as [`Status`], except for measurements.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+struct Repr {
+    source_id: SourceId,
+    #[serde(with = "timestamp")]
+    timestamp: OffsetDateTime,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    position: Option<Coord<f64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bearing: Option<Measure>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    speed: Option<Measure>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    satellites: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    fix: Option<FixType>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    odometer: Option<Measure>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ignition: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "timestamp::option")]
+    received_at: Option<OffsetDateTime>,
+    #[serde(default, skip_serializing_if = "super::is_false")]
+    suspect_timestamp: bool,
+    #[serde(default, skip_serializing_if = "TenantId::is_default")]
+    tenant_id: TenantId,
+}
+
+impl Serialize for WithUnits {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let Self(status, units) = *self;
+        Repr {
+            source_id: status.source_id,
+            timestamp: status.timestamp,
+            position: status.position,
+            bearing: status.bearing.map(Measure::from_angle),
+            speed: status.speed.and_then(|v| Measure::from_velocity(v, units.speed_unit())),
+            satellites: status.satellites,
+            fix: status.fix,
+            odometer: status.odometer.and_then(|d| Measure::from_length(d, units.distance_unit())),
+            ignition: status.ignition,
+            received_at: status.received_at,
+            suspect_timestamp: status.suspect_timestamp,
+            tenant_id: status.tenant_id,
+        }
+        .serialize(s)
+    }
+}
+
+impl<'de> Deserialize<'de> for WithUnits {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let repr = Repr::deserialize(d)?;
+        let status = Status {
+            source_id: repr.source_id,
+            timestamp: repr.timestamp,
+            position: repr.position,
+            bearing: convert(repr.bearing, Measure::to_angle, "a unit of angle")?,
+            speed: convert(repr.speed, Measure::to_velocity, "a unit of speed")?,
+            satellites: repr.satellites,
+            fix: repr.fix,
+            odometer: convert(repr.odometer, Measure::to_length, "a unit of distance")?,
+            ignition: repr.ignition,
+            received_at: repr.received_at,
+            suspect_timestamp: repr.suspect_timestamp,
+            tenant_id: repr.tenant_id,
+        };
+        Ok(Self(status, UnitSystem::Si))
+    }
+}
+
+/// Converts an optional measure, failing if it's in a unit of the wrong kind.
+fn convert<T, E: de::Error>(
+    measure: Option<Measure>,
+    to: fn(Measure) -> Option<T>,
+    expected: &str,
+) -> Result<Option<T>, E> {
+    measure
+        .map(|m| to(m).ok_or_else(|| E::invalid_value(de::Unexpected::Other("unit"), &expected)))
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use float_eq::assert_float_eq;
+    use time::macros::datetime;
+    use uom::si::{
+        angle::degree,
+        f64::{Angle, Length, Velocity},
+        length::kilometer,
+        velocity::meter_per_second,
+    };
+    use uuid::Uuid;
+
+    use super::{UnitSystem, WithUnits};
+    use crate::data::{SourceId, Status, TenantId};
+
+    #[test]
+    fn units() -> serde_json::Result<()> {
+        let status = Status {
+            source_id: SourceId::from_uuid(Uuid::from_u128(0x0aaec05a_0e7d_4fd5_abc0_0ba69e3cfe11)),
+            timestamp: datetime!(2021-07-27 05:45:19 UTC),
+            position: None,
+            bearing: Some(Angle::new::<degree>(90.)),
+            speed: Some(Velocity::new::<meter_per_second>(15.)),
+            satellites: None,
+            fix: None,
+            odometer: Some(Length::new::<kilometer>(12_345.)),
+            ignition: Some(true),
+            received_at: None,
+            suspect_timestamp: false,
+            tenant_id: TenantId::DEFAULT,
+        };
+
+        let encoded = serde_json::to_string(&WithUnits(status, UnitSystem::Metric))?;
+        let value: serde_json::Value = serde_json::from_str(&encoded)?;
+        assert_eq!(value["bearing"]["unit"], "deg");
+        assert_float_eq!(value["bearing"]["value"].as_f64().unwrap(), 90., abs <= 0.000_001);
+        assert_eq!(value["speed"]["unit"], "km/h");
+        assert_float_eq!(value["speed"]["value"].as_f64().unwrap(), 54., abs <= 0.000_001);
+        assert_eq!(value["odometer"]["unit"], "km");
+        assert_float_eq!(value["odometer"]["value"].as_f64().unwrap(), 12_345., abs <= 0.000_001);
+        assert_eq!(value["ignition"], true);
+
+        for units in [UnitSystem::Si, UnitSystem::Nautical, UnitSystem::Imperial] {
+            let encoded = serde_json::to_string(&WithUnits(status, units))?;
+            let WithUnits(decoded, _) = serde_json::from_str(&encoded)?;
+            assert_float_eq!(decoded.speed.unwrap().value, 15., abs <= 0.000_001);
+            assert_float_eq!(decoded.bearing.unwrap().get::<degree>(), 90., abs <= 0.000_001);
+            assert_float_eq!(decoded.odometer.unwrap().get::<kilometer>(), 12_345., abs <= 0.001);
+        }
+
+        let bad_unit = encoded.replace("km/h", "deg");
+        assert!(serde_json::from_str::<WithUnits>(&bad_unit).is_err());
+        Ok(())
+    }
+}
@@ -3,10 +3,20 @@
 //! rest.
 //!
 //! The crate is marked `no_std`, which makes it possible to use it even on
-//! small embedded devices.
+//! small embedded devices. With the `defmt` feature, all data types implement
+//! `defmt::Format`, so that firmware can log them without `core::fmt`. The
+//! `codec` feature adds [`codec`], which encodes statuses in every supported
+//! format behind one trait, but needs an allocator.
 
 #![cfg_attr(not(test), no_std)]
 #![forbid(unsafe_code)]
 #![deny(missing_docs)]
 
+#[cfg(feature = "codec")]
+extern crate alloc;
+
+pub mod client;
+#[cfg(feature = "codec")]
+pub mod codec;
 pub mod data;
+pub mod track;
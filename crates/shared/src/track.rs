@@ -0,0 +1,259 @@
+//! Processing of tracks, the statuses of a single source ordered by time, into
+//! shapes that suit charting and exporting.
+//!
+//! Nothing here allocates: a [`Track`] borrows its statuses, resampling yields
+//! statuses one at a time, and splitting yields tracks borrowing parts of the
+//! original one.
+
+use core::f64::consts::{PI, TAU};
+
+use geo_types::Coord;
+use time::{Duration, OffsetDateTime};
+use uom::si::{angle::radian, f64::Angle};
+
+use crate::data::Status;
+
+/// Statuses of a single source, ordered by timestamp, such as the result of a
+/// history query. Statuses out of order make the results meaningless.
+#[derive(Debug, Clone, Copy)]
+pub struct Track<'a> {
+    statuses: &'a [Status],
+}
+
+impl<'a> Track<'a> {
+    /// Wraps statuses ordered by timestamp into a [`Track`].
+    #[must_use]
+    pub const fn new(statuses: &'a [Status]) -> Self {
+        Self { statuses }
+    }
+
+    /// The statuses of the track.
+    #[must_use]
+    pub const fn statuses(&self) -> &'a [Status] {
+        self.statuses
+    }
+
+    /// Statuses at every multiple of `interval` since UNIX epoch between the
+    /// first and the last status of the track, so that resampled tracks of
+    /// different sources line up.
+    ///
+    /// Position, bearing, speed and odometer are interpolated linearly
+    /// between the statuses around each point in time, and left out unless
+    /// both of them have a value. Bearing turns the shorter way around.
+    /// Satellites, fix and ignition hold the value of the earlier status. An
+    /// interpolated status is suspect if either of the statuses is, and has no
+    /// receive time. Yields nothing if `interval` isn't positive.
+    #[must_use]
+    pub fn resample(&self, interval: Duration) -> Resample<'a> {
+        let next = match (self.statuses.first(), interval.is_positive()) {
+            (Some(first), true) => {
+                let step = interval.whole_nanoseconds();
+                let start = first.timestamp.unix_timestamp_nanos();
+                let aligned = start.div_euclid(step) * step;
+                let aligned = if aligned < start { aligned + step } else { aligned };
+                OffsetDateTime::from_unix_timestamp_nanos(aligned).ok()
+            }
+            _ => None,
+        };
+        Resample { statuses: self.statuses, interval, next, index: 0 }
+    }
+
+    /// Splits the track wherever consecutive statuses are further apart in
+    /// time than `max_gap`, e.g. when the source lost its signal, so that
+    /// charts and exports don't draw a line across the gap. Yields nothing for
+    /// an empty track.
+    #[must_use]
+    pub fn split_on_gaps(&self, max_gap: Duration) -> SplitOnGaps<'a> {
+        SplitOnGaps { rest: self.statuses, max_gap }
+    }
+}
+
+/// Whether consecutive statuses `prev` and `next` are further apart in time
+/// than `max_gap`. Useful for splitting tracks on the fly, same as
+/// [`Track::split_on_gaps`] would.
+#[must_use]
+pub fn is_gap(prev: &Status, next: &Status, max_gap: Duration) -> bool {
+    next.timestamp - prev.timestamp > max_gap
+}
+
+/// Iterator returned by [`Track::resample`].
+#[derive(Debug, Clone)]
+pub struct Resample<'a> {
+    statuses: &'a [Status],
+    interval: Duration,
+    /// Point in time of the next status, if any.
+    next: Option<OffsetDateTime>,
+    /// Index of the last status at or before `next`.
+    index: usize,
+}
+
+impl Iterator for Resample<'_> {
+    type Item = Status;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let t = self.next?;
+        while self.statuses.get(self.index + 1).is_some_and(|s| s.timestamp <= t) {
+            self.index += 1;
+        }
+        let before = &self.statuses[self.index];
+        let status = match self.statuses.get(self.index + 1) {
+            _ if before.timestamp == t => Status { received_at: None, ..*before },
+            Some(after) => interpolate(before, after, t),
+            None => {
+                self.next = None;
+                return None;
+            }
+        };
+        self.next = t.checked_add(self.interval);
+        Some(status)
+    }
+}
+
+/// Status at `t`, which is strictly between the timestamps of `a` and `b`.
+fn interpolate(a: &Status, b: &Status, t: OffsetDateTime) -> Status {
+    let f = (t - a.timestamp).as_seconds_f64() / (b.timestamp - a.timestamp).as_seconds_f64();
+    let lerp = |x: f64, y: f64| x + (y - x) * f;
+    Status {
+        timestamp: t,
+        position: a
+            .position
+            .zip(b.position)
+            .map(|(p, q)| Coord { x: lerp(p.x, q.x), y: lerp(p.y, q.y) }),
+        bearing: a.bearing.zip(b.bearing).map(|(x, y)| {
+            let turn = shortest_turn((y - x).value);
+            let mut bearing = x.value + turn * f;
+            if bearing < 0.0 {
+                bearing += TAU;
+            } else if bearing >= TAU {
+                bearing -= TAU;
+            }
+            Angle::new::<radian>(bearing)
+        }),
+        speed: a.speed.zip(b.speed).map(|(x, y)| x + (y - x) * f),
+        odometer: a.odometer.zip(b.odometer).map(|(x, y)| x + (y - x) * f),
+        received_at: None,
+        suspect_timestamp: a.suspect_timestamp || b.suspect_timestamp,
+        ..*a
+    }
+}
+
+/// Reduces a difference of angles in radians to `[-π, π)`.
+fn shortest_turn(radians: f64) -> f64 {
+    // Truncates, `core` can't round floats.
+    let turn = radians - TAU * ((radians / TAU) as i64 as f64);
+    if turn >= PI {
+        turn - TAU
+    } else if turn < -PI {
+        turn + TAU
+    } else {
+        turn
+    }
+}
+
+/// Iterator returned by [`Track::split_on_gaps`].
+#[derive(Debug, Clone)]
+pub struct SplitOnGaps<'a> {
+    rest: &'a [Status],
+    max_gap: Duration,
+}
+
+impl<'a> Iterator for SplitOnGaps<'a> {
+    type Item = Track<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.rest.is_empty() {
+            return None;
+        }
+        let end = self
+            .rest
+            .windows(2)
+            .position(|w| is_gap(&w[0], &w[1], self.max_gap))
+            .map_or(self.rest.len(), |i| i + 1);
+        let (track, rest) = self.rest.split_at(end);
+        self.rest = rest;
+        Some(Track::new(track))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use float_eq::assert_float_eq;
+    use geo_types::Coord;
+    use time::{macros::datetime, Duration};
+    use uom::si::{
+        angle::degree,
+        f64::{Angle, Velocity},
+        velocity::meter_per_second,
+    };
+    use uuid::Uuid;
+
+    use super::Track;
+    use crate::data::{SourceId, Status, TenantId};
+
+    fn status(second: i64, x: f64) -> Status {
+        Status {
+            source_id: SourceId::Uuid(Uuid::from_u128(0x0aaec05a_0e7d_4fd5_abc0_0ba69e3cfe11)),
+            timestamp: datetime!(2021-07-27 05:45:00 UTC) + Duration::seconds(second),
+            position: Some(Coord { x, y: 59.0 }),
+            bearing: None,
+            speed: Some(Velocity::new::<meter_per_second>(x)),
+            satellites: Some(second as u8),
+            fix: None,
+            odometer: None,
+            ignition: None,
+            received_at: None,
+            suspect_timestamp: false,
+            tenant_id: TenantId::DEFAULT,
+        }
+    }
+
+    #[test]
+    fn resample() {
+        let statuses = [status(1, 0.0), status(3, 4.0), status(3, 8.0), status(9, 2.0)];
+        let resampled: Vec<_> = Track::new(&statuses).resample(Duration::seconds(2)).collect();
+        let seconds: Vec<_> = resampled.iter().map(|s| s.timestamp.second()).collect();
+        assert_eq!(seconds, [2, 4, 6, 8]);
+        for (status, x) in resampled.iter().zip([2.0, 7.0, 5.0, 3.0]) {
+            assert_float_eq!(status.position.unwrap().x, x, abs <= 1e-9);
+        }
+        assert_float_eq!(resampled[1].speed.unwrap().get::<meter_per_second>(), 7.0, abs <= 1e-9);
+        let satellites: Vec<_> = resampled.iter().map(|s| s.satellites.unwrap()).collect();
+        assert_eq!(satellites, [1, 3, 3, 3]);
+
+        // Exact hits keep the status as is.
+        let resampled: Vec<_> = Track::new(&statuses).resample(Duration::seconds(3)).collect();
+        assert_eq!(resampled.len(), 3);
+        assert_eq!(resampled[0].position, statuses[2].position);
+
+        assert_eq!(Track::new(&statuses).resample(Duration::ZERO).count(), 0);
+        assert_eq!(Track::new(&[]).resample(Duration::seconds(1)).count(), 0);
+    }
+
+    #[test]
+    fn resample_bearing() {
+        let mut a = status(0, 0.0);
+        let mut b = status(2, 0.0);
+        a.bearing = Some(Angle::new::<degree>(350.0));
+        b.bearing = Some(Angle::new::<degree>(30.0));
+        let resampled: Vec<_> = Track::new(&[a, b]).resample(Duration::seconds(1)).collect();
+        assert_eq!(resampled.len(), 3);
+        assert_float_eq!(resampled[1].bearing.unwrap().get::<degree>(), 10.0, abs <= 1e-9);
+
+        a.bearing = Some(Angle::new::<degree>(10.0));
+        b.bearing = Some(Angle::new::<degree>(330.0));
+        let resampled: Vec<_> = Track::new(&[a, b]).resample(Duration::seconds(1)).collect();
+        assert_float_eq!(resampled[1].bearing.unwrap().get::<degree>(), 350.0, abs <= 1e-9);
+    }
+
+    #[test]
+    fn split_on_gaps() {
+        let statuses = [status(0, 0.0), status(5, 0.0), status(66, 0.0), status(70, 0.0)];
+        let track = Track::new(&statuses);
+        let lengths: Vec<_> =
+            track.split_on_gaps(Duration::minutes(1)).map(|t| t.statuses().len()).collect();
+        assert_eq!(lengths, [2, 2]);
+        assert_eq!(track.split_on_gaps(Duration::minutes(2)).count(), 1);
+        assert_eq!(track.split_on_gaps(Duration::ZERO).count(), 4);
+        assert_eq!(Track::new(&[]).split_on_gaps(Duration::ZERO).count(), 0);
+    }
+}